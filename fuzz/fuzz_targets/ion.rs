@@ -11,5 +11,9 @@ fuzz_target!(|func: Func| {
     let _ = env_logger::try_init();
     log::trace!("func:\n{:?}", func);
     let env = regalloc2::fuzzing::func::machine_env();
-    let _out = regalloc2::fuzzing::ion::run(&func, &env, false).expect("regalloc did not succeed");
+    let opts = regalloc2::RegallocOptions {
+        verbose_log: false,
+        ..Default::default()
+    };
+    let _out = regalloc2::fuzzing::ion::run(&func, &env, &opts).expect("regalloc did not succeed");
 });
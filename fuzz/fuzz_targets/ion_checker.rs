@@ -8,10 +8,16 @@ use regalloc2::fuzzing::arbitrary::{Arbitrary, Result, Unstructured};
 use regalloc2::fuzzing::checker::Checker;
 use regalloc2::fuzzing::func::{Func, Options};
 use regalloc2::fuzzing::fuzz_target;
+use regalloc2::{Block, Function, OperandConstraint, PReg, RegClass};
 
 #[derive(Clone, Debug)]
 struct TestCase {
     func: Func,
+    reg_swaps: bool,
+    scratch_reg: bool,
+    stack_to_stack_moves: bool,
+    optimize_spill_placement: bool,
+    reg_costs: bool,
 }
 
 impl Arbitrary<'_> for TestCase {
@@ -25,22 +31,100 @@ impl Arbitrary<'_> for TestCase {
                     fixed_nonallocatable: true,
                     clobbers: true,
                     control_flow: true,
+                    critical_edges: true,
+                    branch_operands: true,
+                    entry_liveins: true,
+                    edge_effects: true,
                     reducible: false,
                     block_params: true,
                     always_local_uses: false,
                     reftypes: true,
+                    prog_moves: false,
+                    float_vregs: false,
+                    pinned_vregs: false,
+                    stack_constraints: false,
+                    distinct_defs: true,
+                    tied_uses: true,
+                    commutative: true,
                 },
             )?,
+            reg_swaps: bool::arbitrary(u)?,
+            scratch_reg: bool::arbitrary(u)?,
+            stack_to_stack_moves: bool::arbitrary(u)?,
+            optimize_spill_placement: bool::arbitrary(u)?,
+            reg_costs: bool::arbitrary(u)?,
         })
     }
 }
 
+/// Does `func` name `preg` anywhere, so that it cannot be reserved as
+/// a scratch register?
+fn mentions_preg(func: &Func, preg: PReg) -> bool {
+    if func.entry_liveins().iter().any(|&(_, p)| p == preg) {
+        return true;
+    }
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        let last = func.block_insns(block).last();
+        for succ_idx in 0..func.block_succs(block).len() {
+            let defs = func.branch_edge_defs(block, last, succ_idx);
+            if defs.iter().any(|&(_, p)| p == preg) {
+                return true;
+            }
+        }
+        for inst in func.block_insns(block).iter() {
+            for op in func.inst_operands(inst) {
+                if op.constraint() == OperandConstraint::FixedReg(preg) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
 fuzz_target!(|testcase: TestCase| {
     let func = testcase.func;
     let _ = env_logger::try_init();
     log::trace!("func:\n{:?}", func);
-    let env = regalloc2::fuzzing::func::machine_env();
-    let out = regalloc2::fuzzing::ion::run(&func, &env, true).expect("regalloc did not succeed");
+    let mut env = regalloc2::fuzzing::func::machine_env();
+    env.reg_swaps_by_class[0] = testcase.reg_swaps;
+    env.stack_to_stack_moves_by_class[0] = testcase.stack_to_stack_moves;
+    if testcase.scratch_reg {
+        // Reserve a non-preferred register that the function doesn't
+        // name as the scratch register.
+        let non_preferred = &mut env.non_preferred_regs_by_class[RegClass::Int as usize];
+        if let Some(i) = non_preferred
+            .iter()
+            .rposition(|&preg| !mentions_preg(&func, preg))
+        {
+            env.scratch_by_class[RegClass::Int as usize] = Some(non_preferred.remove(i));
+        }
+    }
+    if testcase.reg_costs {
+        // Make every third register of each class costlier, cutting
+        // across the preferred and non-preferred lists.
+        for class in [RegClass::Int, RegClass::Float] {
+            let regs = env.preferred_regs_by_class[class as usize]
+                .iter()
+                .chain(env.non_preferred_regs_by_class[class as usize].iter())
+                .cloned()
+                .collect::<Vec<_>>();
+            for (i, preg) in regs.into_iter().enumerate() {
+                if i % 3 == 0 {
+                    env.reg_costs.push((preg, 500 * (1 + i as u32 % 2)));
+                }
+            }
+        }
+    }
+    let opts = regalloc2::RegallocOptions {
+        verbose_log: true,
+        allow_critical_edges: true,
+        optimize_spill_placement: testcase.optimize_spill_placement,
+        ..Default::default()
+    };
+    regalloc2::validate(&func, &env, &opts).expect("generated function is invalid");
+    let out = regalloc2::fuzzing::ion::run(&func, &env, &opts).expect("regalloc did not succeed");
 
     let mut checker = Checker::new(&func, &env);
     checker.prepare(&out);
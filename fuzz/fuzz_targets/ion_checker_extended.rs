@@ -0,0 +1,76 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+#![no_main]
+use regalloc2::fuzzing::arbitrary::{Arbitrary, Result, Unstructured};
+use regalloc2::fuzzing::checker::Checker;
+use regalloc2::fuzzing::func::{Func, Options};
+use regalloc2::fuzzing::fuzz_target;
+use regalloc2::fuzzing::interp::Interpreter;
+
+/// Like `ion_checker`, but also generating program moves,
+/// `Float`-class vregs, pinned vregs and `Stack` constraints, and
+/// also interpreting the allocated function along a random path.
+#[derive(Clone, Debug)]
+struct TestCase {
+    func: Func,
+    optimize_spill_placement: bool,
+    seed: u64,
+}
+
+impl Arbitrary<'_> for TestCase {
+    fn arbitrary(u: &mut Unstructured) -> Result<TestCase> {
+        let options = Options {
+            reused_inputs: true,
+            fixed_regs: true,
+            fixed_nonallocatable: true,
+            clobbers: true,
+            control_flow: true,
+            critical_edges: false,
+            branch_operands: true,
+            entry_liveins: true,
+            edge_effects: true,
+            reducible: false,
+            block_params: true,
+            always_local_uses: false,
+            reftypes: true,
+            prog_moves: bool::arbitrary(u)?,
+            float_vregs: bool::arbitrary(u)?,
+            pinned_vregs: bool::arbitrary(u)?,
+            stack_constraints: bool::arbitrary(u)?,
+            distinct_defs: bool::arbitrary(u)?,
+            tied_uses: bool::arbitrary(u)?,
+            commutative: bool::arbitrary(u)?,
+        };
+        Ok(TestCase {
+            func: Func::arbitrary_with_options(u, &options)?,
+            optimize_spill_placement: bool::arbitrary(u)?,
+            seed: u64::arbitrary(u)?,
+        })
+    }
+}
+
+fuzz_target!(|testcase: TestCase| {
+    let func = testcase.func;
+    let _ = env_logger::try_init();
+    log::trace!("func:\n{:?}", func);
+    let env = regalloc2::fuzzing::func::machine_env();
+    let opts = regalloc2::RegallocOptions {
+        verbose_log: true,
+        optimize_spill_placement: testcase.optimize_spill_placement,
+        ..Default::default()
+    };
+    regalloc2::validate(&func, &env, &opts).expect("generated function is invalid");
+    let out = regalloc2::fuzzing::ion::run(&func, &env, &opts).expect("regalloc did not succeed");
+
+    let mut checker = Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().expect("checker failed");
+
+    let mut interp = Interpreter::new(&func, &out);
+    interp
+        .run(testcase.seed, 10_000)
+        .expect("interpreter failed");
+});
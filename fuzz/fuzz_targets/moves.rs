@@ -11,9 +11,9 @@ use regalloc2::{Allocation, PReg, RegClass, SpillSlot};
 use std::collections::{HashMap, HashSet};
 
 fn is_stack_alloc(alloc: Allocation) -> bool {
-    // Treat registers 20..=29 as fixed stack slots.
+    // Treat registers 21..=29 as fixed stack slots.
     if let Some(reg) = alloc.as_reg() {
-        reg.index() > 20
+        (21..=29).contains(&reg.index())
     } else {
         alloc.is_stack()
     }
@@ -23,6 +23,9 @@ fn is_stack_alloc(alloc: Allocation) -> bool {
 struct TestCase {
     moves: Vec<(Allocation, Allocation)>,
     available_pregs: Vec<Allocation>,
+    swaps: bool,
+    dedicated_scratch: bool,
+    stack_to_stack_moves: bool,
 }
 
 impl Arbitrary<'_> for TestCase {
@@ -30,6 +33,9 @@ impl Arbitrary<'_> for TestCase {
         let mut ret = TestCase {
             moves: vec![],
             available_pregs: vec![],
+            swaps: false,
+            dedicated_scratch: false,
+            stack_to_stack_moves: false,
         };
         let mut written = HashSet::new();
         // An arbitrary sequence of moves between registers 0 to 29
@@ -66,6 +72,9 @@ impl Arbitrary<'_> for TestCase {
             let reg = PReg::new(30 + i, RegClass::Int);
             ret.available_pregs.push(Allocation::reg(reg));
         }
+        ret.swaps = bool::arbitrary(u)?;
+        ret.dedicated_scratch = bool::arbitrary(u)?;
+        ret.stack_to_stack_moves = bool::arbitrary(u)?;
         Ok(ret)
     }
 }
@@ -77,6 +86,15 @@ fuzz_target!(|testcase: TestCase| {
         par.add(src, dst, ());
     }
 
+    // Optionally take out cycles among registers as swaps, to be
+    // performed after all other moves.
+    let swaps = if testcase.swaps {
+        par.take_swap_cycles(|alloc| alloc.is_reg() && !is_stack_alloc(alloc))
+    } else {
+        Default::default()
+    };
+    log::trace!("swaps: {:?}", swaps);
+
     let moves = par.resolve();
     log::trace!("raw resolved moves: {:?}", moves);
 
@@ -91,8 +109,16 @@ fuzz_target!(|testcase: TestCase| {
         Allocation::stack(SpillSlot::new(slot))
     };
     let preferred_victim = PReg::new(0, RegClass::Int);
+    // Register 33, if reserved, is never a source or dest.
+    let dedicated_scratch = if testcase.dedicated_scratch {
+        Some(Allocation::reg(PReg::new(33, RegClass::Int)))
+    } else {
+        None
+    };
     let scratch_resolver =
-        MoveAndScratchResolver::new(get_reg, get_stackslot, is_stack_alloc, preferred_victim);
+        MoveAndScratchResolver::new(get_reg, get_stackslot, is_stack_alloc, preferred_victim)
+            .with_dedicated_scratch(dedicated_scratch)
+            .with_stack_to_stack_moves(testcase.stack_to_stack_moves);
     let moves = scratch_resolver.compute(moves);
     log::trace!("resolved moves: {:?}", moves);
 
@@ -107,13 +133,23 @@ fuzz_target!(|testcase: TestCase| {
     // Simulate the sequence of moves.
     let mut locations: HashMap<Allocation, Allocation> = HashMap::new();
     for (src, dst, _) in moves {
-        if is_stack_alloc(src) && is_stack_alloc(dst) {
+        if is_stack_alloc(src) && is_stack_alloc(dst) && !testcase.stack_to_stack_moves {
             panic!("Stack-to-stack move!");
         }
 
         let data = locations.get(&src).cloned().unwrap_or(src);
         locations.insert(dst, data);
     }
+    for (a, b) in swaps {
+        if is_stack_alloc(a) || is_stack_alloc(b) {
+            panic!("Swap with a stack location!");
+        }
+
+        let data_a = locations.get(&a).cloned().unwrap_or(a);
+        let data_b = locations.get(&b).cloned().unwrap_or(b);
+        locations.insert(a, data_b);
+        locations.insert(b, data_a);
+    }
     log::trace!("simulated final state: {:?}", locations);
 
     // Assert that the expected register-moves occurred.
@@ -128,6 +164,7 @@ fuzz_target!(|testcase: TestCase| {
                 // available preg or a scratch stackslot.
                 assert!(
                     testcase.available_pregs.contains(&reg)
+                        || Some(reg) == dedicated_scratch
                         || (reg.is_stack() && reg.as_stack().unwrap().index() >= 32)
                 );
             }
@@ -26,10 +26,22 @@ impl Arbitrary<'_> for TestCase {
                     fixed_nonallocatable: true,
                     clobbers: true,
                     control_flow: true,
+                    critical_edges: false,
+                    branch_operands: false,
+                    entry_liveins: false,
+                    edge_effects: false,
                     reducible: false,
                     always_local_uses: false,
                     block_params: true,
                     reftypes: true,
+                    prog_moves: true,
+                    float_vregs: true,
+                    // Pinned vregs are used without being defined.
+                    pinned_vregs: false,
+                    stack_constraints: true,
+                    distinct_defs: true,
+                    tied_uses: true,
+                    commutative: true,
                 },
             )?,
         })
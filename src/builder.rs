@@ -0,0 +1,414 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! A builder for functions that own their data.
+//!
+//! An embedder whose IR does not map directly onto the `Function`
+//! trait, or a test that just needs a function to allocate, can
+//! describe the function instruction by instruction to a
+//! `FunctionBuilder` and get back a `SerializableFunction`, which
+//! implements `Function` over owned vectors. The builder keeps the
+//! CFG consistent: a branch's successors become the successors of
+//! its block, and the block becomes a predecessor of each of them.
+
+use crate::serialize::{BlockData, InstData, SerializableFunction};
+use crate::{Block, Inst, Operand, PReg, PRegSet, RegClass, VReg};
+use alloc::vec;
+
+/// Builds a `SerializableFunction`.
+///
+/// Instructions are numbered in the order in which they are added,
+/// and the allocator requires each block's instructions to follow
+/// those of the block before it, so a block's instructions must all
+/// be added before any instruction of a later block. Blocks
+/// themselves may be created at any time, e.g. to be named as the
+/// target of a branch before they are filled in. The first block
+/// created is the entry block unless `set_entry_block` says
+/// otherwise.
+#[derive(Clone, Debug)]
+pub struct FunctionBuilder {
+    f: SerializableFunction,
+    /// The block of the last instruction added.
+    last_block: Option<Block>,
+}
+
+impl Default for FunctionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FunctionBuilder {
+    /// Create a builder for an empty function. Spillslots are one
+    /// slot in size for both register classes until set otherwise.
+    pub fn new() -> Self {
+        FunctionBuilder {
+            f: SerializableFunction {
+                insts: vec![],
+                blocks: vec![],
+                entry_block: Block::new(0),
+                num_vregs: 0,
+                reftype_vregs: vec![],
+                debug_value_labels: vec![],
+                pinned_vregs: vec![],
+                entry_liveins: vec![],
+                spillslot_size: [1, 1],
+                multi_spillslot_named_by_last_slot: false,
+                allow_multiple_vreg_defs: false,
+            },
+            last_block: None,
+        }
+    }
+
+    /// Create a new, empty block.
+    pub fn add_block(&mut self) -> Block {
+        let block = Block::new(self.f.blocks.len());
+        self.f.blocks.push(BlockData {
+            first: Inst::invalid(),
+            end: Inst::invalid(),
+            preds: vec![],
+            succs: vec![],
+            params: vec![],
+            branch_args: vec![],
+            edge_clobbers: vec![],
+            edge_defs: vec![],
+        });
+        block
+    }
+
+    /// Make `block` the entry block.
+    pub fn set_entry_block(&mut self, block: Block) {
+        self.f.entry_block = block;
+    }
+
+    /// Append a parameter to `block`.
+    pub fn add_block_param(&mut self, block: Block, vreg: VReg) {
+        self.f.blocks[block.index()].params.push(vreg);
+    }
+
+    fn push_inst(&mut self, block: Block, data: InstData) -> Inst {
+        let inst = Inst::new(self.f.insts.len());
+        if let Some(last_block) = self.last_block {
+            assert!(
+                block >= last_block,
+                "inst{} added to block{} after instructions of block{}",
+                inst.index(),
+                block.index(),
+                last_block.index()
+            );
+        }
+        self.last_block = Some(block);
+        let b = &mut self.f.blocks[block.index()];
+        if b.first.is_invalid() {
+            b.first = inst;
+        }
+        b.end = inst.next();
+        self.f.insts.push(data);
+        inst
+    }
+
+    /// Append an instruction with the given operands to `block`.
+    pub fn add_inst(&mut self, block: Block, operands: &[Operand]) -> Inst {
+        self.push_inst(
+            block,
+            InstData {
+                operands: operands.to_vec(),
+                clobbers: PRegSet::empty(),
+                is_ret: false,
+                is_branch: false,
+                is_move: None,
+                is_safepoint: false,
+                commutative: None,
+            },
+        )
+    }
+
+    /// Append a program move from `src` to `dst` to `block`. The
+    /// arguments are in the order of `Function::is_move`. The
+    /// allocator implements the move with edits, and the embedder
+    /// should not emit it.
+    pub fn add_move(&mut self, block: Block, src: VReg, dst: VReg) -> Inst {
+        let src = Operand::reg_use(src);
+        let dst = Operand::reg_def(dst);
+        self.push_inst(
+            block,
+            InstData {
+                operands: vec![src, dst],
+                clobbers: PRegSet::empty(),
+                is_ret: false,
+                is_branch: false,
+                is_move: Some((src, dst)),
+                is_safepoint: false,
+                commutative: None,
+            },
+        )
+    }
+
+    /// Append a return, which ends `block`, with the given operands.
+    pub fn add_ret(&mut self, block: Block, operands: &[Operand]) -> Inst {
+        let inst = self.add_inst(block, operands);
+        self.f.insts[inst.index()].is_ret = true;
+        inst
+    }
+
+    /// Append a branch to `succs`, which ends `block`, with the given
+    /// operands. The branch passes no arguments to its successors'
+    /// blockparams until `set_branch_args` says otherwise.
+    pub fn add_branch(&mut self, block: Block, operands: &[Operand], succs: &[Block]) -> Inst {
+        let inst = self.add_inst(block, operands);
+        self.f.insts[inst.index()].is_branch = true;
+        for &succ in succs {
+            let b = &mut self.f.blocks[block.index()];
+            b.succs.push(succ);
+            b.branch_args.push(vec![]);
+            b.edge_clobbers.push(PRegSet::empty());
+            b.edge_defs.push(vec![]);
+            self.f.blocks[succ.index()].preds.push(block);
+        }
+        inst
+    }
+
+    /// Set the arguments that the branch ending `block` passes to the
+    /// blockparams of its `succ_idx`th successor.
+    pub fn set_branch_args(&mut self, block: Block, succ_idx: usize, args: &[VReg]) {
+        self.f.blocks[block.index()].branch_args[succ_idx] = args.to_vec();
+    }
+
+    /// Set the registers that are clobbered only on the edge from
+    /// `block` to its `succ_idx`th successor; see
+    /// `Function::branch_edge_clobbers`.
+    pub fn set_edge_clobbers(&mut self, block: Block, succ_idx: usize, clobbers: PRegSet) {
+        self.f.blocks[block.index()].edge_clobbers[succ_idx] = clobbers;
+    }
+
+    /// Add a vreg that is defined in `preg` only on the edge from
+    /// `block` to its `succ_idx`th successor; see
+    /// `Function::branch_edge_defs`.
+    pub fn add_edge_def(&mut self, block: Block, succ_idx: usize, vreg: VReg, preg: PReg) {
+        self.f.blocks[block.index()].edge_defs[succ_idx].push((vreg, preg));
+    }
+
+    /// Set the registers that `inst` clobbers.
+    pub fn set_clobbers(&mut self, inst: Inst, clobbers: PRegSet) {
+        self.f.insts[inst.index()].clobbers = clobbers;
+    }
+
+    /// Make `inst` a safepoint, at which reftyped values must be on
+    /// the stack.
+    pub fn set_safepoint(&mut self, inst: Inst) {
+        self.f.insts[inst.index()].is_safepoint = true;
+    }
+
+    /// Declare operands `a` and `b` of `inst` commutative; see
+    /// `Function::commutative_operands`.
+    pub fn set_commutative_operands(&mut self, inst: Inst, a: usize, b: usize) {
+        self.f.insts[inst.index()].commutative = Some((a, b));
+    }
+
+    /// Mark `vreg` as reference-typed.
+    pub fn add_reftype_vreg(&mut self, vreg: VReg) {
+        self.f.reftype_vregs.push(vreg);
+    }
+
+    /// Ask for the locations of `vreg` from `from` (inclusive) to
+    /// `to` (exclusive) to be reported under `label`.
+    pub fn add_debug_value_label(&mut self, vreg: VReg, from: Inst, to: Inst, label: u32) {
+        self.f.debug_value_labels.push((vreg, from, to, label));
+    }
+
+    /// Pin `vreg` to `preg`; see `Function::is_pinned_vreg`.
+    pub fn pin_vreg(&mut self, vreg: VReg, preg: PReg) {
+        self.f.pinned_vregs.push((vreg, preg));
+    }
+
+    /// Make `vreg` live-in to the function in `preg`; see
+    /// `Function::entry_liveins`.
+    pub fn add_entry_livein(&mut self, vreg: VReg, preg: PReg) {
+        self.f.entry_liveins.push((vreg, preg));
+    }
+
+    /// Set the number of spillslots that a value of `class` needs.
+    pub fn set_spillslot_size(&mut self, class: RegClass, size: usize) {
+        self.f.spillslot_size[class as usize] = size;
+    }
+
+    /// See `Function::multi_spillslot_named_by_last_slot`.
+    pub fn set_multi_spillslot_named_by_last_slot(&mut self, value: bool) {
+        self.f.multi_spillslot_named_by_last_slot = value;
+    }
+
+    /// See `Function::allow_multiple_vreg_defs`.
+    pub fn set_allow_multiple_vreg_defs(&mut self, value: bool) {
+        self.f.allow_multiple_vreg_defs = value;
+    }
+
+    /// Finish the function. `num_vregs` is one more than the highest
+    /// vreg index that the function mentions.
+    pub fn build(mut self) -> SerializableFunction {
+        let f = &mut self.f;
+
+        // Give blocks without instructions an empty range where their
+        // instructions would be, so that `validate()` can name them.
+        let mut next = Inst::new(0);
+        for b in &mut f.blocks {
+            if b.first.is_invalid() {
+                b.first = next;
+                b.end = next;
+            }
+            next = b.end;
+        }
+
+        let operands = f.insts.iter().flat_map(|inst| {
+            inst.operands
+                .iter()
+                .filter(|op| op.as_fixed_nonallocatable().is_none())
+                .map(|op| op.vreg())
+        });
+        let blocks = f.blocks.iter().flat_map(|b| {
+            b.params
+                .iter()
+                .chain(b.branch_args.iter().flatten())
+                .cloned()
+                .chain(b.edge_defs.iter().flatten().map(|&(vreg, _)| vreg))
+        });
+        f.num_vregs = operands
+            .chain(blocks)
+            .chain(f.reftype_vregs.iter().cloned())
+            .chain(f.debug_value_labels.iter().map(|&(vreg, ..)| vreg))
+            .chain(f.pinned_vregs.iter().map(|&(vreg, _)| vreg))
+            .chain(f.entry_liveins.iter().map(|&(vreg, _)| vreg))
+            .map(|vreg| vreg.vreg() + 1)
+            .max()
+            .unwrap_or(0);
+
+        f.debug_value_labels.sort_by_key(|&(vreg, ..)| vreg);
+        self.f
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{check, int, test_env};
+    use crate::{run, validate, Function, RegallocOptions, ValidationError};
+
+    #[test]
+    fn test_diamond() {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let right = b.add_block();
+        let join = b.add_block();
+
+        b.add_entry_livein(int(0), PReg::new(0, RegClass::Int));
+        b.add_inst(entry, &[Operand::reg_def(int(1)), Operand::reg_use(int(0))]);
+        b.add_branch(entry, &[Operand::reg_use(int(1))], &[left, right]);
+        b.add_move(left, int(1), int(2));
+        b.add_branch(left, &[], &[join]);
+        b.set_branch_args(left, 0, &[int(2)]);
+        let call = b.add_inst(right, &[Operand::reg_def(int(3))]);
+        b.set_clobbers(call, PRegSet::empty().with(PReg::new(1, RegClass::Int)));
+        b.set_safepoint(call);
+        b.add_branch(right, &[], &[join]);
+        b.set_branch_args(right, 0, &[int(3)]);
+        b.add_block_param(join, int(4));
+        b.add_reftype_vreg(int(4));
+        b.add_ret(join, &[Operand::reg_use(int(4)), Operand::reg_use(int(0))]);
+        let f = b.build();
+
+        assert_eq!(f.num_vregs(), 5);
+        assert_eq!(f.block_preds(join), &[left, right]);
+        assert_eq!(f.block_succs(entry), &[left, right]);
+        assert_eq!(f.block_insns(right).first(), call);
+        assert!(f.is_move(Inst::new(2)).is_some());
+
+        let env = test_env(4);
+        validate(&f, &env, &RegallocOptions::default()).unwrap();
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+    }
+
+    #[test]
+    fn test_preds_succs() {
+        // A loop whose header branches to itself, the exit, and the
+        // exit again on a second edge.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let header = b.add_block();
+        let exit = b.add_block();
+
+        b.add_branch(entry, &[], &[header]);
+        b.add_inst(header, &[Operand::reg_def(int(0))]);
+        b.add_branch(header, &[], &[header, exit, exit]);
+        b.add_ret(exit, &[]);
+        let f = b.build();
+
+        assert_eq!(f.block_succs(header), &[header, exit, exit]);
+        assert_eq!(f.block_preds(header), &[entry, header]);
+        assert_eq!(f.block_preds(exit), &[header, header]);
+        for block in 0..f.num_blocks() {
+            let block = Block::new(block);
+            for &succ in f.block_succs(block) {
+                let succs = f.block_succs(block).iter().filter(|&&s| s == succ);
+                let preds = f.block_preds(succ).iter().filter(|&&p| p == block);
+                assert_eq!(succs.count(), preds.count());
+            }
+            for &pred in f.block_preds(block) {
+                assert!(f.block_succs(pred).contains(&block));
+            }
+        }
+        assert!(f.block_preds(entry).is_empty());
+        assert!(f.block_succs(exit).is_empty());
+    }
+
+    #[test]
+    fn test_branch_args() {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let right = b.add_block();
+
+        b.add_inst(entry, &[Operand::reg_def(int(0))]);
+        let branch = b.add_branch(entry, &[], &[left, right]);
+        b.add_block_param(left, int(1));
+        b.add_ret(left, &[Operand::reg_use(int(1))]);
+        b.add_block_param(right, int(2));
+        b.add_block_param(right, int(3));
+        b.add_ret(right, &[Operand::reg_use(int(2)), Operand::reg_use(int(3))]);
+
+        // Until they are set, a branch passes no arguments.
+        let mut unset = b.clone();
+        unset.set_branch_args(entry, 0, &[int(0)]);
+        let f = unset.build();
+        assert!(f.branch_blockparams(entry, branch, 1).is_empty());
+        let env = test_env(4);
+        let errors = validate(&f, &env, &RegallocOptions::default())
+            .unwrap_err()
+            .errors;
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            ValidationError::BlockparamArity {
+                inst,
+                succ,
+                expected: 2,
+                actual: 0,
+            } if inst == branch && succ == right
+        ));
+
+        b.set_branch_args(entry, 0, &[int(0)]);
+        b.set_branch_args(entry, 1, &[int(0), int(0)]);
+        let f = b.build();
+        for (i, &succ) in f.block_succs(entry).iter().enumerate() {
+            assert_eq!(
+                f.branch_blockparams(entry, branch, i).len(),
+                f.block_params(succ).len()
+            );
+        }
+        validate(&f, &env, &RegallocOptions::default()).unwrap();
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+    }
+}
@@ -5,7 +5,10 @@
 
 //! Lightweight CFG analyses.
 
-use crate::{domtree, postorder, Block, Function, Inst, ProgPoint, RegAllocError};
+use crate::{
+    domtree, postorder, Block, Function, Inst, OperandKind, PRegSet, ProgPoint, RegAllocError,
+};
+use alloc::{vec, vec::Vec};
 use smallvec::{smallvec, SmallVec};
 
 #[derive(Clone, Debug)]
@@ -44,8 +47,6 @@ impl CFGInfo {
         let mut insn_block = vec![Block::invalid(); f.num_insts()];
         let mut block_entry = vec![ProgPoint::before(Inst::invalid()); f.num_blocks()];
         let mut block_exit = vec![ProgPoint::before(Inst::invalid()); f.num_blocks()];
-        let mut backedge_in = vec![0; f.num_blocks()];
-        let mut backedge_out = vec![0; f.num_blocks()];
 
         for block in 0..f.num_blocks() {
             let block = Block::new(block);
@@ -70,51 +71,38 @@ impl CFGInfo {
 
             // Check branch-arg condition: if any successors have more
             // than one predecessor (given above, there will only be
-            // one such successor), then the last instruction of this
-            // block (the branch) cannot have any args other than the
-            // blockparams.
-            let mut require_no_branch_args = false;
-            for &succ in f.block_succs(block) {
+            // one such successor), then the edge moves are placed
+            // before the branch at the end of this block, so the
+            // branch may not write any registers: those writes would
+            // happen after the edge moves. (Reads are fine; see
+            // `Env::fixup_branch_args`.)
+            //
+            // For the same reason, edge clobbers and edge defs are
+            // only allowed on edges to blocks with one predecessor.
+            let last = f.block_insns(block).last();
+            let mut require_no_branch_defs = false;
+            for (i, &succ) in f.block_succs(block).iter().enumerate() {
                 let preds = f.block_preds(succ).len() + if succ == f.entry_block() { 1 } else { 0 };
                 if preds > 1 {
-                    require_no_branch_args = true;
+                    require_no_branch_defs = true;
+                    if f.is_branch(last)
+                        && (f.branch_edge_clobbers(block, last, i) != PRegSet::empty()
+                            || !f.branch_edge_defs(block, last, i).is_empty())
+                    {
+                        return Err(RegAllocError::DisallowedEdgeEffects(last));
+                    }
                 }
             }
-            if require_no_branch_args {
-                let last = f.block_insns(block).last();
-                if !f.inst_operands(last).is_empty() {
-                    return Err(RegAllocError::DisallowedBranchArg(last));
-                }
-            }
-
-            for &succ in f.block_succs(block) {
-                if succ.index() <= block.index() {
-                    backedge_in[succ.index()] += 1;
-                    backedge_out[block.index()] += 1;
-                }
+            if require_no_branch_defs
+                && f.inst_operands(last)
+                    .iter()
+                    .any(|op| op.kind() != OperandKind::Use)
+            {
+                return Err(RegAllocError::DisallowedBranchArg(last));
             }
         }
 
-        let mut approx_loop_depth = vec![];
-        let mut backedge_stack: SmallVec<[usize; 4]> = smallvec![];
-        let mut cur_depth = 0;
-        for block in 0..f.num_blocks() {
-            if backedge_in[block] > 0 {
-                cur_depth += 1;
-                backedge_stack.push(backedge_in[block]);
-            }
-
-            approx_loop_depth.push(cur_depth);
-
-            while backedge_stack.len() > 0 && backedge_out[block] > 0 {
-                backedge_out[block] -= 1;
-                *backedge_stack.last_mut().unwrap() -= 1;
-                if *backedge_stack.last().unwrap() == 0 {
-                    cur_depth -= 1;
-                    backedge_stack.pop();
-                }
-            }
-        }
+        let approx_loop_depth = compute_approx_loop_depth(f);
 
         Ok(CFGInfo {
             postorder,
@@ -130,3 +118,42 @@ impl CFGInfo {
         domtree::dominates(&self.domtree[..], a, b)
     }
 }
+
+/// Compute an approximate loop depth for each block; see
+/// `CFGInfo::approx_loop_depth`.
+pub fn compute_approx_loop_depth<F: Function>(f: &F) -> Vec<u32> {
+    let mut backedge_in = vec![0; f.num_blocks()];
+    let mut backedge_out = vec![0; f.num_blocks()];
+    for block in 0..f.num_blocks() {
+        let block = Block::new(block);
+        for &succ in f.block_succs(block) {
+            if succ.index() <= block.index() {
+                backedge_in[succ.index()] += 1;
+                backedge_out[block.index()] += 1;
+            }
+        }
+    }
+
+    let mut approx_loop_depth = vec![];
+    let mut backedge_stack: SmallVec<[usize; 4]> = smallvec![];
+    let mut cur_depth = 0;
+    for block in 0..f.num_blocks() {
+        if backedge_in[block] > 0 {
+            cur_depth += 1;
+            backedge_stack.push(backedge_in[block]);
+        }
+
+        approx_loop_depth.push(cur_depth);
+
+        while backedge_stack.len() > 0 && backedge_out[block] > 0 {
+            backedge_out[block] -= 1;
+            *backedge_stack.last_mut().unwrap() -= 1;
+            if *backedge_stack.last().unwrap() == 0 {
+                cur_depth -= 1;
+                backedge_stack.pop();
+            }
+        }
+    }
+
+    approx_loop_depth
+}
@@ -55,6 +55,13 @@
 //! virtual registers, but by representing a special "top" value, but
 //! the semantics are the same.)
 //!
+//! Also for efficiency, each distinct set of vregs is interned once
+//! (with a small bitmask summary for fast membership tests) and the
+//! state at a program point only stores set IDs, in a vector sorted
+//! by allocation. Meets and the other set operations are memoized on
+//! these IDs, and the fixpoint is found by sweeping the blocks in
+//! reverse postorder until nothing changes.
+//!
 //! The dataflow analysis state at each program point (each point
 //! before or after an instruction) is:
 //!
@@ -67,6 +74,10 @@
 //!
 //!       A' = A[alloc_d → A[alloc_s]]
 //!
+//!   - `Edit::Swap` inserted by RA:       [ alloc_a :=: alloc_b ]
+//!
+//!       A' = A[alloc_a → A[alloc_b], alloc_b → A[alloc_a]]
+//!
 //!   - statement in pre-regalloc function [ V_i := op V_j, V_k, ... ]
 //!     with allocated form                [ A_i := op A_j, A_k, ... ]
 //!
@@ -92,25 +103,47 @@
 //! check that the allocation for each op arg (input/use) contains the
 //! symbol corresponding to the original virtual register specified
 //! for this arg.
+//!
+//! When it does not, the checker also searches backwards from the
+//! op for a `CheckerWitness`: a path through the CFG, and the moves,
+//! defs and clobbers along it, that explain how the allocation came
+//! to hold the wrong value.
 
 #![allow(dead_code)]
 
 use crate::{
-    Allocation, AllocationKind, Block, Edit, Function, Inst, InstOrEdit, InstPosition, MachineEnv,
-    Operand, OperandConstraint, OperandKind, OperandPos, Output, PReg, PRegSet, VReg,
+    input_reused_by, postorder, Allocation, AllocationKind, Block, Edit, Function, FxHashMap,
+    FxHashSet, Inst, InstOrEdit, InstPosition, MachineEnv, Operand, OperandConstraint, OperandKind,
+    OperandPos, Output, PReg, PRegSet, VReg,
 };
-use fxhash::{FxHashMap, FxHashSet};
+use alloc::{collections::VecDeque, format, string::String, vec, vec::Vec};
+use core::cmp::Ordering;
+use core::default::Default;
+use core::result::Result;
 use smallvec::{smallvec, SmallVec};
-use std::default::Default;
-use std::hash::Hash;
-use std::result::Result;
 
 /// A set of errors detected by the regalloc checker.
 #[derive(Clone, Debug)]
 pub struct CheckerErrors {
-    errors: Vec<CheckerError>,
+    /// All errors found, in block order.
+    pub errors: Vec<CheckerError>,
+}
+
+impl core::fmt::Display for CheckerErrors {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for CheckerErrors {}
+
 /// A single error detected by the regalloc checker.
 #[derive(Clone, Debug)]
 pub enum CheckerError {
@@ -122,6 +155,8 @@ pub enum CheckerError {
         inst: Inst,
         op: Operand,
         alloc: Allocation,
+        /// How the allocation came to hold no value of the vreg.
+        witness: CheckerWitness,
     },
     ConflictedValueInAllocation {
         inst: Inst,
@@ -133,6 +168,8 @@ pub enum CheckerError {
         op: Operand,
         alloc: Allocation,
         actual: FxHashSet<VReg>,
+        /// How the allocation came to hold the wrong values.
+        witness: CheckerWitness,
     },
     ConstraintViolated {
         inst: Inst,
@@ -155,6 +192,11 @@ pub enum CheckerError {
         alloc: Allocation,
         expected_alloc: Allocation,
     },
+    AllocationIsNotDistinct {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
     AllocationIsNotStack {
         inst: Inst,
         op: Operand,
@@ -173,92 +215,423 @@ pub enum CheckerError {
         into: Allocation,
         from: Allocation,
     },
+    StackSwap {
+        a: Allocation,
+        b: Allocation,
+    },
 }
 
-/// Abstract state for an allocation.
-///
-/// Equivalent to a set of virtual register names, with the
-/// universe-set as top and empty set as bottom lattice element. The
-/// meet-function is thus set intersection.
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum CheckerValue {
-    /// The lattice top-value: this value could be equivalent to any
-    /// vreg (i.e., the universe set).
-    Universe,
-    /// The set of VRegs that this value is equal to.
-    VRegs(FxHashSet<VReg>),
-}
-
-impl CheckerValue {
-    fn vregs(&self) -> Option<&FxHashSet<VReg>> {
+impl core::fmt::Display for CheckerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
-            CheckerValue::Universe => None,
-            CheckerValue::VRegs(vregs) => Some(vregs),
+            Self::MissingAllocation { inst, op } => {
+                write!(f, "inst{}: {} has no allocation", inst.index(), op)
+            }
+            Self::UnknownValueInAllocation {
+                inst,
+                op,
+                alloc,
+                witness,
+            } => write!(
+                f,
+                "inst{}: {} in {} holds an unknown value{}",
+                inst.index(),
+                op,
+                alloc,
+                witness
+            ),
+            Self::ConflictedValueInAllocation { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} in {} holds conflicting values",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::IncorrectValuesInAllocation {
+                inst,
+                op,
+                alloc,
+                actual,
+                witness,
+            } => write!(
+                f,
+                "inst{}: {} in {} holds {}, not {}{}",
+                inst.index(),
+                op,
+                alloc,
+                display_vregs(actual),
+                op.vreg(),
+                witness
+            ),
+            Self::ConstraintViolated { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} in {} violates its constraint",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsNotReg { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} must be in a register but is in {}",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsNotFixedReg { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is in {}, not its fixed register",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsNotReuse {
+                inst,
+                op,
+                alloc,
+                expected_alloc,
+            } => write!(
+                f,
+                "inst{}: {} is in {}, not in {} with the {}",
+                inst.index(),
+                op,
+                alloc,
+                expected_alloc,
+                if op.kind() == OperandKind::Use {
+                    "operand it is tied to"
+                } else {
+                    "input it reuses"
+                }
+            ),
+            Self::AllocationIsNotDistinct { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is in {}, with the input it must differ from",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsNotStack { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} must be on the stack but is in {}",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::ConflictedValueInStackmap { inst, alloc } => write!(
+                f,
+                "inst{}: stackmap slot {} holds conflicting values",
+                inst.index(),
+                alloc
+            ),
+            Self::NonRefValuesInStackmap { inst, alloc, vregs } => write!(
+                f,
+                "inst{}: stackmap slot {} holds {}, none of which is a reference",
+                inst.index(),
+                alloc,
+                display_vregs(vregs)
+            ),
+            Self::StackToStackMove { into, from } => {
+                write!(f, "move {} -> {} is between two stack slots", from, into)
+            }
+            Self::StackSwap { a, b } => {
+                write!(f, "swap {} <-> {} involves a stack slot", a, b)
+            }
         }
     }
+}
 
-    fn vregs_mut(&mut self) -> Option<&mut FxHashSet<VReg>> {
-        match self {
-            CheckerValue::Universe => None,
-            CheckerValue::VRegs(vregs) => Some(vregs),
+/// Format a set of vregs in order, as `{v1, v2}`.
+fn display_vregs(vregs: &FxHashSet<VReg>) -> String {
+    let mut vregs: Vec<_> = vregs.iter().cloned().collect();
+    vregs.sort_unstable();
+    let vregs: Vec<_> = vregs.iter().map(|vreg| format!("{}", vreg)).collect();
+    format!("{{{}}}", vregs.join(", "))
+}
+
+/// How an allocation came to lack the vreg that an operand expected
+/// in it: a path through the CFG to the operand's block, and the
+/// steps along that path that last wrote the allocation, following
+/// the value back through moves to where it originated.
+///
+/// The checker's analysis merges all paths, so this is one path along
+/// which the value goes wrong; there may be others.
+#[derive(Clone, Debug, Default)]
+pub struct CheckerWitness {
+    /// The blocks along the path, from the block where the wrong
+    /// value originated to the block of the error.
+    pub path: Vec<Block>,
+    /// The steps that determined the allocation's value, in program
+    /// order.
+    pub steps: Vec<WitnessStep>,
+}
+
+impl core::fmt::Display for CheckerWitness {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if self.path.is_empty() {
+            return Ok(());
         }
+        let path: Vec<_> = self
+            .path
+            .iter()
+            .map(|block| format!("block{}", block.index()))
+            .collect();
+        write!(f, "\n  path: {}", path.join(" -> "))?;
+        for step in &self.steps {
+            write!(f, "\n  {}", step)?;
+        }
+        Ok(())
     }
 }
 
-impl Default for CheckerValue {
-    fn default() -> CheckerValue {
-        CheckerValue::Universe
-    }
+/// One step of a `CheckerWitness`.
+#[derive(Clone, Debug)]
+pub struct WitnessStep {
+    /// The block in which the step happens.
+    pub block: Block,
+    /// If the step happens on the edge out of `block`, the successor
+    /// at the other end of that edge.
+    pub succ: Option<Block>,
+    /// What happens.
+    pub kind: WitnessStepKind,
 }
 
-impl CheckerValue {
-    /// Meet function of the abstract-interpretation value
-    /// lattice. Returns a boolean value indicating whether `self` was
-    /// changed.
-    fn meet_with(&mut self, other: &CheckerValue) {
-        match (self, other) {
-            (_, CheckerValue::Universe) => {
-                // Nothing.
+impl core::fmt::Display for WitnessStep {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.succ {
+            Some(succ) => write!(f, "block{} -> block{}: ", self.block.index(), succ.index())?,
+            None => write!(f, "block{}: ", self.block.index())?,
+        }
+        match self.kind {
+            WitnessStepKind::Entry { alloc, vreg } => {
+                write!(f, "{} does not hold {} on entry", alloc, vreg)
             }
-            (this @ CheckerValue::Universe, _) => {
-                *this = other.clone();
+            WitnessStepKind::Overwrite { inst, op, alloc } => {
+                write!(f, "inst{}: {} writes {}", inst.index(), op, alloc)
+            }
+            WitnessStepKind::Def { inst, op, alloc } => {
+                write!(f, "inst{}: {} in {}", inst.index(), op, alloc)
             }
-            (CheckerValue::VRegs(my_vregs), CheckerValue::VRegs(other_vregs)) => {
-                my_vregs.retain(|vreg| other_vregs.contains(vreg));
+            WitnessStepKind::Clobber { inst, alloc } => {
+                write!(f, "inst{}: clobbers {}", inst.index(), alloc)
             }
+            WitnessStepKind::Redefine { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} in {} leaves older copies of {} stale",
+                inst.index(),
+                op,
+                alloc,
+                op.vreg()
+            ),
+            WitnessStepKind::Reclaim { inst, alloc, vreg } => write!(
+                f,
+                "inst{}: safepoint reclaims {} in {}, which is not in the stackmap",
+                inst.index(),
+                vreg,
+                alloc
+            ),
+            WitnessStepKind::Move { from, into } => write!(f, "move {} -> {}", from, into),
+            WitnessStepKind::Swap { a, b } => write!(f, "swap {} <-> {}", a, b),
+            WitnessStepKind::Rename {
+                inst: Some(inst),
+                src,
+                dst,
+            } => write!(f, "inst{}: program move {} -> {}", inst.index(), src, dst),
+            WitnessStepKind::Rename {
+                inst: None,
+                src,
+                dst,
+            } => write!(f, "blockparam {} := {}", dst, src),
+        }
+    }
+}
+
+/// What happens in a `WitnessStep`.
+#[derive(Clone, Debug)]
+pub enum WitnessStepKind {
+    /// The allocation does not hold the vreg on entry to the function.
+    Entry { alloc: Allocation, vreg: VReg },
+    /// An instruction writes another vreg into the allocation.
+    Overwrite {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
+    /// An instruction defines the vreg into another allocation, and
+    /// that value is not moved into this one.
+    Def {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
+    /// An instruction clobbers the allocation.
+    Clobber { inst: Inst, alloc: Allocation },
+    /// An instruction redefines the vreg into another allocation, so
+    /// the value that the allocation holds is stale.
+    Redefine {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
+    /// A safepoint reclaims the reference in a stack slot that is not
+    /// in its stackmap.
+    Reclaim {
+        inst: Inst,
+        alloc: Allocation,
+        vreg: VReg,
+    },
+    /// An edit inserted by the allocator moves the value; the witness
+    /// follows it back to `from`.
+    Move { from: Allocation, into: Allocation },
+    /// An edit inserted by the allocator swaps two allocations.
+    Swap { a: Allocation, b: Allocation },
+    /// A program move (with its instruction) or a blockparam
+    /// assignment (without) copies `src` into `dst`; the witness
+    /// follows `src` back.
+    Rename {
+        inst: Option<Inst>,
+        src: VReg,
+        dst: VReg,
+    },
+}
+
+/// A set of vregs, interned in `VRegSets`: two allocations hold the
+/// same set of vregs exactly when they hold the same id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct VRegSetId(u32);
+
+/// The interned sets of vregs that the analysis has seen, and the
+/// results of the lattice operations on them, so that each distinct
+/// operation is only computed once.
+#[derive(Debug, Default)]
+struct VRegSets {
+    /// The members of each set, sorted.
+    members: Vec<Vec<VReg>>,
+    /// For each set, a mask with the bit for `vreg_bit(vreg)` of each
+    /// member set, which answers most membership queries without
+    /// searching the members.
+    masks: Vec<u64>,
+    ids: FxHashMap<Vec<VReg>, VRegSetId>,
+    // The memo maps below are never bounded or cleared. Interned
+    // sets are never freed, so every memoized result stays valid for
+    // the life of the checker, which covers one function. An entry is
+    // only added when an operation produces a set it has not produced
+    // from the same inputs before, so the maps grow with the distinct
+    // work the analysis does, not with the number of times it
+    // revisits a block. `find_errors()` and the witness search replay
+    // the same transfer functions over the fixpoint that `analyze()`
+    // reached, so keeping the maps lets them hit the memo instead of
+    // recomputing sets.
+    meets: FxHashMap<(VRegSetId, VRegSetId), VRegSetId>,
+    removals: FxHashMap<(VRegSetId, VReg), VRegSetId>,
+    copies: FxHashMap<(VRegSetId, VReg, VReg), VRegSetId>,
+    without_refs: FxHashMap<VRegSetId, VRegSetId>,
+}
+
+fn vreg_bit(vreg: VReg) -> u64 {
+    1 << (vreg.vreg() % 64)
+}
+
+impl VRegSets {
+    fn intern(&mut self, members: Vec<VReg>) -> VRegSetId {
+        if let Some(&id) = self.ids.get(&members) {
+            return id;
         }
+        let id = VRegSetId(self.members.len() as u32);
+        let mask = members.iter().fold(0, |mask, &vreg| mask | vreg_bit(vreg));
+        self.members.push(members.clone());
+        self.masks.push(mask);
+        self.ids.insert(members, id);
+        id
     }
 
-    fn from_reg(reg: VReg) -> CheckerValue {
-        CheckerValue::VRegs(std::iter::once(reg).collect())
+    fn members(&self, set: VRegSetId) -> &[VReg] {
+        &self.members[set.0 as usize][..]
     }
 
-    fn remove_vreg(&mut self, reg: VReg) {
-        match self {
-            CheckerValue::Universe => {
-                panic!("Cannot remove VReg from Universe set (we do not have the full list of vregs available");
-            }
-            CheckerValue::VRegs(vregs) => {
-                vregs.remove(&reg);
-            }
+    fn contains(&self, set: VRegSetId, vreg: VReg) -> bool {
+        self.masks[set.0 as usize] & vreg_bit(vreg) != 0
+            && self.members(set).binary_search(&vreg).is_ok()
+    }
+
+    fn to_hash_set(&self, set: VRegSetId) -> FxHashSet<VReg> {
+        self.members(set).iter().cloned().collect()
+    }
+
+    /// The meet function of the lattice: set intersection.
+    fn meet(&mut self, a: VRegSetId, b: VRegSetId) -> VRegSetId {
+        if a == b {
+            return a;
+        }
+        let key = (a.min(b), a.max(b));
+        if let Some(&set) = self.meets.get(&key) {
+            return set;
         }
+        let members = self
+            .members(a)
+            .iter()
+            .cloned()
+            .filter(|&vreg| self.contains(b, vreg))
+            .collect();
+        let set = self.intern(members);
+        self.meets.insert(key, set);
+        set
     }
 
-    fn copy_vreg(&mut self, src: VReg, dst: VReg) {
-        match self {
-            CheckerValue::Universe => {
-                // Nothing.
-            }
-            CheckerValue::VRegs(vregs) => {
-                if vregs.contains(&src) {
-                    vregs.insert(dst);
-                }
-            }
+    fn remove(&mut self, set: VRegSetId, vreg: VReg) -> VRegSetId {
+        if !self.contains(set, vreg) {
+            return set;
+        }
+        if let Some(&result) = self.removals.get(&(set, vreg)) {
+            return result;
         }
+        let members = self
+            .members(set)
+            .iter()
+            .cloned()
+            .filter(|&v| v != vreg)
+            .collect();
+        let result = self.intern(members);
+        self.removals.insert((set, vreg), result);
+        result
     }
 
-    fn empty() -> CheckerValue {
-        CheckerValue::VRegs(FxHashSet::default())
+    /// Add `dst` to the set if it contains `src`.
+    fn copy(&mut self, set: VRegSetId, src: VReg, dst: VReg) -> VRegSetId {
+        if !self.contains(set, src) || self.contains(set, dst) {
+            return set;
+        }
+        if let Some(&result) = self.copies.get(&(set, src, dst)) {
+            return result;
+        }
+        let mut members = self.members(set).to_vec();
+        let pos = members.binary_search(&dst).unwrap_err();
+        members.insert(pos, dst);
+        let result = self.intern(members);
+        self.copies.insert((set, src, dst), result);
+        result
+    }
+
+    fn remove_refs(&mut self, set: VRegSetId, reftyped_vregs: &FxHashSet<VReg>) -> VRegSetId {
+        if let Some(&result) = self.without_refs.get(&set) {
+            return result;
+        }
+        let members = self
+            .members(set)
+            .iter()
+            .cloned()
+            .filter(|vreg| !reftyped_vregs.contains(vreg))
+            .collect();
+        let result = self.intern(members);
+        self.without_refs.insert(set, result);
+        result
+    }
+
+    fn display(&self, set: VRegSetId) -> String {
+        let vregs: Vec<_> = self
+            .members(set)
+            .iter()
+            .map(|vreg| format!("{}", vreg))
+            .collect();
+        format!("{{ {} }}", vregs.join(" "))
     }
 }
 
@@ -291,32 +664,32 @@ fn visit_all_vregs<F: Function, V: FnMut(VReg)>(f: &F, mut v: V) {
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum CheckerState {
     Top,
-    Allocations(FxHashMap<Allocation, CheckerValue>),
+    /// The set of vregs that each allocation with a known value
+    /// holds, sorted by allocation. Any other allocation holds an
+    /// unknown value.
+    Allocations(Vec<(Allocation, VRegSetId)>),
 }
 
 impl CheckerState {
-    fn get_value(&self, alloc: &Allocation) -> Option<&CheckerValue> {
+    fn get_value(&self, alloc: &Allocation) -> Option<VRegSetId> {
         match self {
             CheckerState::Top => None,
-            CheckerState::Allocations(allocs) => allocs.get(alloc),
-        }
-    }
-
-    fn get_values_mut(&mut self) -> impl Iterator<Item = &mut CheckerValue> {
-        match self {
-            CheckerState::Top => panic!("Cannot get mutable values iterator on Top state"),
-            CheckerState::Allocations(allocs) => allocs.values_mut(),
+            CheckerState::Allocations(allocs) => allocs
+                .binary_search_by_key(alloc, |&(alloc, _)| alloc)
+                .ok()
+                .map(|i| allocs[i].1),
         }
     }
 
-    fn get_mappings(&self) -> impl Iterator<Item = (&Allocation, &CheckerValue)> {
-        match self {
-            CheckerState::Top => panic!("Cannot get mappings iterator on Top state"),
-            CheckerState::Allocations(allocs) => allocs.iter(),
+    /// Whether `alloc` is known to hold `vreg`.
+    fn holds(&self, alloc: Allocation, vreg: VReg, sets: &VRegSets) -> bool {
+        match self.get_value(&alloc) {
+            Some(val) => sets.contains(val, vreg),
+            None => false,
         }
     }
 
-    fn get_mappings_mut(&mut self) -> impl Iterator<Item = (&Allocation, &mut CheckerValue)> {
+    fn get_mappings_mut(&mut self) -> impl Iterator<Item = &mut (Allocation, VRegSetId)> {
         match self {
             CheckerState::Top => panic!("Cannot get mutable mappings iterator on Top state"),
             CheckerState::Allocations(allocs) => allocs.iter_mut(),
@@ -326,30 +699,20 @@ impl CheckerState {
     /// Transition from a "top" (undefined/unanalyzed) state to an empty set of allocations.
     fn become_defined(&mut self) {
         match self {
-            CheckerState::Top => *self = CheckerState::Allocations(FxHashMap::default()),
+            CheckerState::Top => *self = CheckerState::Allocations(Vec::new()),
             _ => {}
         }
     }
 
-    fn set_value(&mut self, alloc: Allocation, value: CheckerValue) {
+    fn set_value(&mut self, alloc: Allocation, value: VRegSetId) {
         match self {
             CheckerState::Top => {
                 panic!("Cannot set value on Top state");
             }
             CheckerState::Allocations(allocs) => {
-                allocs.insert(alloc, value);
-            }
-        }
-    }
-
-    fn copy_vreg(&mut self, src: VReg, dst: VReg) {
-        match self {
-            CheckerState::Top => {
-                // Nothing.
-            }
-            CheckerState::Allocations(allocs) => {
-                for value in allocs.values_mut() {
-                    value.copy_vreg(src, dst);
+                match allocs.binary_search_by_key(&alloc, |&(alloc, _)| alloc) {
+                    Ok(i) => allocs[i].1 = value,
+                    Err(i) => allocs.insert(i, (alloc, value)),
                 }
             }
         }
@@ -361,12 +724,14 @@ impl CheckerState {
                 panic!("Cannot remove value on Top state");
             }
             CheckerState::Allocations(allocs) => {
-                allocs.remove(alloc);
+                if let Ok(i) = allocs.binary_search_by_key(alloc, |&(alloc, _)| alloc) {
+                    allocs.remove(i);
+                }
             }
         }
     }
 
-    fn initial_with_pinned_vregs<F: Function>(f: &F) -> CheckerState {
+    fn initial_with_liveins<F: Function>(f: &F, sets: &mut VRegSets) -> CheckerState {
         // Scan the function, looking for all vregs that are pinned
         // vregs, gathering them with their PRegs.
         let mut pinned_vregs: FxHashMap<VReg, PReg> = FxHashMap::default();
@@ -376,14 +741,25 @@ impl CheckerState {
             }
         });
 
-        let mut allocs = FxHashMap::default();
+        let mut allocs: FxHashMap<Allocation, Vec<VReg>> = FxHashMap::default();
         for (vreg, preg) in pinned_vregs {
-            allocs.insert(
-                Allocation::reg(preg),
-                CheckerValue::VRegs(std::iter::once(vreg).collect()),
-            );
+            allocs.insert(Allocation::reg(preg), vec![vreg]);
         }
 
+        // Entry liveins arrive in their given locations.
+        for &(vreg, preg) in f.entry_liveins() {
+            allocs.entry(Allocation::reg(preg)).or_default().push(vreg);
+        }
+
+        let mut allocs: Vec<_> = allocs
+            .into_iter()
+            .map(|(alloc, mut vregs)| {
+                vregs.sort_unstable();
+                vregs.dedup();
+                (alloc, sets.intern(vregs))
+            })
+            .collect();
+        allocs.sort_unstable();
         CheckerState::Allocations(allocs)
     }
 }
@@ -394,47 +770,16 @@ impl Default for CheckerState {
     }
 }
 
-impl std::fmt::Display for CheckerValue {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            CheckerValue::Universe => {
-                write!(f, "top")
-            }
-            CheckerValue::VRegs(vregs) => {
-                write!(f, "{{ ")?;
-                for vreg in vregs {
-                    write!(f, "{} ", vreg)?;
-                }
-                write!(f, "}}")?;
-                Ok(())
-            }
-        }
-    }
-}
-
-/// Meet function for analysis value: meet individual values at
-/// matching allocations, and intersect keys (remove key-value pairs
-/// only on one side). Returns boolean flag indicating whether `into`
-/// changed.
-fn merge_map<K: Copy + Clone + PartialEq + Eq + Hash>(
-    into: &mut FxHashMap<K, CheckerValue>,
-    from: &FxHashMap<K, CheckerValue>,
-) {
-    into.retain(|k, _| from.contains_key(k));
-    for (k, into_v) in into.iter_mut() {
-        let from_v = from.get(k).unwrap();
-        into_v.meet_with(from_v);
-    }
-}
-
 impl CheckerState {
     /// Create a new checker state.
     fn new() -> CheckerState {
         Default::default()
     }
 
-    /// Merge this checker state with another at a CFG join-point.
-    fn meet_with(&mut self, other: &CheckerState) {
+    /// Merge this checker state with another at a CFG join-point:
+    /// meet the values of allocations that both states know, and
+    /// forget the values of allocations that only one state knows.
+    fn meet_with(&mut self, other: &CheckerState, sets: &mut VRegSets) {
         match (self, other) {
             (_, CheckerState::Top) => {
                 // Nothing.
@@ -446,7 +791,22 @@ impl CheckerState {
                 CheckerState::Allocations(my_allocations),
                 CheckerState::Allocations(other_allocations),
             ) => {
-                merge_map(my_allocations, other_allocations);
+                let mut merged = Vec::with_capacity(my_allocations.len());
+                let (mut i, mut j) = (0, 0);
+                while i < my_allocations.len() && j < other_allocations.len() {
+                    let (alloc, value) = my_allocations[i];
+                    let (other_alloc, other_value) = other_allocations[j];
+                    match alloc.cmp(&other_alloc) {
+                        Ordering::Less => i += 1,
+                        Ordering::Greater => j += 1,
+                        Ordering::Equal => {
+                            merged.push((alloc, sets.meet(value, other_value)));
+                            i += 1;
+                            j += 1;
+                        }
+                    }
+                }
+                *my_allocations = merged;
             }
         }
     }
@@ -456,25 +816,32 @@ impl CheckerState {
         inst: Inst,
         op: Operand,
         alloc: Allocation,
-        val: &CheckerValue,
         allocs: &[Allocation],
         checker: &Checker<'a, F>,
+        sets: &VRegSets,
     ) -> Result<(), CheckerError> {
         if alloc == Allocation::none() {
             return Err(CheckerError::MissingAllocation { inst, op });
         }
+        let val = self.get_value(&alloc);
 
         if op.as_fixed_nonallocatable().is_none() {
             match val {
-                CheckerValue::Universe => {
-                    return Err(CheckerError::UnknownValueInAllocation { inst, op, alloc });
+                None => {
+                    return Err(CheckerError::UnknownValueInAllocation {
+                        inst,
+                        op,
+                        alloc,
+                        witness: CheckerWitness::default(),
+                    });
                 }
-                CheckerValue::VRegs(vregs) if !vregs.contains(&op.vreg()) => {
+                Some(vregs) if !sets.contains(vregs, op.vreg()) => {
                     return Err(CheckerError::IncorrectValuesInAllocation {
                         inst,
                         op,
                         alloc,
-                        actual: vregs.clone(),
+                        actual: sets.to_hash_set(vregs),
+                        witness: CheckerWitness::default(),
                     });
                 }
                 _ => {}
@@ -494,8 +861,8 @@ impl CheckerState {
         pos: InstPosition,
         checkinst: &CheckerInst,
         checker: &Checker<'a, F>,
+        sets: &VRegSets,
     ) -> Result<(), CheckerError> {
-        let default_val = Default::default();
         match checkinst {
             &CheckerInst::Op {
                 inst,
@@ -506,9 +873,8 @@ impl CheckerState {
                 // Skip Use-checks at the After point if there are any
                 // reused inputs: the Def which reuses the input
                 // happens early.
-                let has_reused_input = operands
-                    .iter()
-                    .any(|op| matches!(op.constraint(), OperandConstraint::Reuse(_)));
+                let has_reused_input =
+                    (0..operands.len()).any(|i| input_reused_by(operands, i).is_some());
                 if has_reused_input && pos == InstPosition::After {
                     return Ok(());
                 }
@@ -526,46 +892,53 @@ impl CheckerState {
                         continue;
                     }
                     if op.kind() == OperandKind::Def {
+                        // A def's value is not checked, only where
+                        // it is put.
+                        self.check_constraint(inst, *op, *alloc, allocs, checker)?;
                         continue;
                     }
 
-                    let val = self.get_value(alloc).unwrap_or(&default_val);
                     trace!(
                         "checker: checkinst {:?}: op {:?}, alloc {:?}, checker value {:?}",
                         checkinst,
                         op,
                         alloc,
-                        val
+                        self.get_value(alloc).map(|val| sets.display(val))
                     );
-                    self.check_val(inst, *op, *alloc, val, allocs, checker)?;
+                    self.check_val(inst, *op, *alloc, allocs, checker, sets)?;
                 }
             }
             &CheckerInst::Safepoint { inst, ref allocs } => {
                 for &alloc in allocs {
-                    let val = self.get_value(&alloc).unwrap_or(&default_val);
+                    let val = self.get_value(&alloc);
                     trace!(
                         "checker: checkinst {:?}: safepoint slot {}, checker value {:?}",
                         checkinst,
                         alloc,
-                        val
+                        val.map(|val| sets.display(val))
                     );
 
-                    let reffy = val
-                        .vregs()
-                        .expect("checker value should not be Universe set")
+                    let vregs = match val {
+                        Some(val) => sets.members(val),
+                        None => &[],
+                    };
+                    let reffy = vregs
                         .iter()
                         .any(|vreg| checker.reftyped_vregs.contains(vreg));
                     if !reffy {
                         return Err(CheckerError::NonRefValuesInStackmap {
                             inst,
                             alloc,
-                            vregs: val.vregs().unwrap().clone(),
+                            vregs: vregs.iter().cloned().collect(),
                         });
                     }
                 }
             }
             &CheckerInst::Move { into, from } => {
-                // Ensure that the allocator never returns stack-to-stack moves.
+                // Ensure that the allocator never returns stack-to-stack
+                // moves, unless the target can do them. (A move between
+                // spillslots carries no class, so we can only check
+                // that some class allows them.)
                 let is_stack = |alloc: Allocation| {
                     if let Some(reg) = alloc.as_reg() {
                         checker.stack_pregs.contains(reg)
@@ -573,10 +946,24 @@ impl CheckerState {
                         alloc.is_stack()
                     }
                 };
-                if is_stack(into) && is_stack(from) {
+                let stack_to_stack_ok = checker
+                    .machine_env
+                    .stack_to_stack_moves_by_class
+                    .contains(&true);
+                if is_stack(into) && is_stack(from) && !stack_to_stack_ok {
                     return Err(CheckerError::StackToStackMove { into, from });
                 }
             }
+            &CheckerInst::Swap { a, b } => {
+                // Swaps are only ever generated between registers.
+                let is_reg = |alloc: Allocation| {
+                    let reg = alloc.as_reg();
+                    matches!(reg, Some(reg) if !checker.stack_pregs.contains(reg))
+                };
+                if !is_reg(a) || !is_reg(b) {
+                    return Err(CheckerError::StackSwap { a, b });
+                }
+            }
             &CheckerInst::ParallelMove { .. } => {
                 // This doesn't need verification; we just update
                 // according to the move semantics in the step
@@ -587,14 +974,13 @@ impl CheckerState {
                 // `src` is satisfied.
                 if let OperandConstraint::FixedReg(preg) = src.constraint() {
                     let alloc = Allocation::reg(preg);
-                    let val = self.get_value(&alloc).unwrap_or(&default_val);
                     trace!(
                         "checker: checkinst {:?}: cheker value in {:?} is {:?}",
                         checkinst,
                         alloc,
-                        val
+                        self.get_value(&alloc).map(|val| sets.display(val))
                     );
-                    self.check_val(inst, src, alloc, val, &[alloc], checker)?;
+                    self.check_val(inst, src, alloc, &[alloc], checker, sets)?;
                 }
                 // Note that we don't do anything with `dst`
                 // here. That is implicitly checked whenever `dst` is
@@ -606,7 +992,12 @@ impl CheckerState {
     }
 
     /// Update according to instruction.
-    fn update<'a, F: Function>(&mut self, checkinst: &CheckerInst, checker: &Checker<'a, F>) {
+    fn update<'a, F: Function>(
+        &mut self,
+        checkinst: &CheckerInst,
+        checker: &Checker<'a, F>,
+        sets: &mut VRegSets,
+    ) {
         self.become_defined();
 
         match checkinst {
@@ -617,17 +1008,30 @@ impl CheckerState {
                 // that sequence saves an undefined value and restores
                 // it, so has no effect.) The checker needs to avoid
                 // putting Universe lattice values into the map.
-                if let Some(val) = self.get_value(&from).cloned() {
+                if let Some(val) = self.get_value(&from) {
                     trace!(
                         "checker: checkinst {:?} updating: move {:?} -> {:?} val {:?}",
                         checkinst,
                         from,
                         into,
-                        val
+                        sets.display(val)
                     );
                     self.set_value(into, val);
                 }
             }
+            &CheckerInst::Swap { a, b } => {
+                // As with moves, either value may be absent.
+                let val_a = self.get_value(&a);
+                let val_b = self.get_value(&b);
+                match val_b {
+                    Some(val) => self.set_value(a, val),
+                    None => self.remove_value(&a),
+                }
+                match val_a {
+                    Some(val) => self.set_value(b, val),
+                    None => self.remove_value(&b),
+                }
+            }
             &CheckerInst::ParallelMove { ref moves } => {
                 // First, build map of actions for each vreg in an
                 // alloc. If an alloc has a reg V_i before a parallel
@@ -637,6 +1041,7 @@ impl CheckerState {
                 // it must be removed first from allocs' vreg sets.
                 let mut additions: FxHashMap<VReg, SmallVec<[VReg; 2]>> = FxHashMap::default();
                 let mut deletions: FxHashSet<VReg> = FxHashSet::default();
+                let mut mask = 0;
 
                 for &(dest, src) in moves {
                     deletions.insert(dest);
@@ -644,25 +1049,39 @@ impl CheckerState {
                         .entry(src)
                         .or_insert_with(|| smallvec![])
                         .push(dest);
+                    mask |= vreg_bit(dest) | vreg_bit(src);
                 }
 
                 // Now process each allocation's set of vreg labels,
                 // first deleting those labels that were updated by
                 // this parallel move, then adding back labels
-                // redefined by the move.
-                for value in self.get_values_mut() {
-                    if let Some(vregs) = value.vregs_mut() {
-                        let mut insertions: SmallVec<[VReg; 2]> = smallvec![];
-                        for &vreg in vregs.iter() {
-                            if let Some(additions) = additions.get(&vreg) {
-                                insertions.extend(additions.iter().cloned());
-                            }
-                        }
-                        for &d in &deletions {
-                            vregs.remove(&d);
+                // redefined by the move. Many allocations hold the
+                // same set, so compute each new set once.
+                let mut results: FxHashMap<VRegSetId, VRegSetId> = FxHashMap::default();
+                for (_, value) in self.get_mappings_mut() {
+                    if sets.masks[value.0 as usize] & mask == 0 {
+                        continue;
+                    }
+                    if let Some(&result) = results.get(value) {
+                        *value = result;
+                        continue;
+                    }
+                    let mut vregs: Vec<VReg> = sets
+                        .members(*value)
+                        .iter()
+                        .cloned()
+                        .filter(|vreg| !deletions.contains(vreg))
+                        .collect();
+                    for vreg in sets.members(*value) {
+                        if let Some(additions) = additions.get(vreg) {
+                            vregs.extend(additions.iter().cloned());
                         }
-                        vregs.extend(insertions);
                     }
+                    vregs.sort_unstable();
+                    vregs.dedup();
+                    let result = sets.intern(vregs);
+                    results.insert(*value, result);
+                    *value = result;
                 }
             }
             &CheckerInst::Op {
@@ -679,8 +1098,9 @@ impl CheckerState {
                     if op.kind() != OperandKind::Def {
                         continue;
                     }
-                    self.remove_vreg(op.vreg());
-                    self.set_value(*alloc, CheckerValue::from_reg(op.vreg()));
+                    self.remove_vreg(op.vreg(), sets);
+                    let value = sets.intern(vec![op.vreg()]);
+                    self.set_value(*alloc, value);
                 }
                 for clobber in clobbers {
                     self.remove_value(&Allocation::reg(*clobber));
@@ -691,33 +1111,27 @@ impl CheckerState {
                     if alloc.is_reg() {
                         continue;
                     }
-                    if !allocs.contains(&alloc) {
+                    if !allocs.contains(alloc) {
                         // Remove all reftyped vregs as labels.
-                        let new_vregs = value
-                            .vregs()
-                            .unwrap()
-                            .difference(&checker.reftyped_vregs)
-                            .cloned()
-                            .collect();
-                        *value = CheckerValue::VRegs(new_vregs);
+                        *value = sets.remove_refs(*value, &checker.reftyped_vregs);
                     }
                 }
             }
             &CheckerInst::ProgramMove { inst: _, src, dst } => {
                 // Remove all earlier instances of `dst`: this vreg is
                 // now stale (it is being overwritten).
-                self.remove_vreg(dst.vreg());
+                self.remove_vreg(dst.vreg(), sets);
                 // Define `dst` wherever `src` occurs.
                 for (_, value) in self.get_mappings_mut() {
-                    value.copy_vreg(src.vreg(), dst.vreg());
+                    *value = sets.copy(*value, src.vreg(), dst.vreg());
                 }
             }
         }
     }
 
-    fn remove_vreg(&mut self, vreg: VReg) {
+    fn remove_vreg(&mut self, vreg: VReg, sets: &mut VRegSets) {
         for (_, value) in self.get_mappings_mut() {
-            value.remove_vreg(vreg);
+            *value = sets.remove(*value, vreg);
         }
     }
 
@@ -757,7 +1171,10 @@ impl CheckerState {
                 }
             }
             OperandConstraint::Reuse(idx) => {
-                if alloc.kind() != AllocationKind::Reg {
+                // A use tied to another use may be wherever that use
+                // is; one tied to a def is where the def's own
+                // constraint puts it.
+                if op.kind() == OperandKind::Def && alloc.kind() != AllocationKind::Reg {
                     return Err(CheckerError::AllocationIsNotReg { inst, op, alloc });
                 }
                 if alloc != allocs[idx] {
@@ -769,11 +1186,31 @@ impl CheckerState {
                     });
                 }
             }
+            OperandConstraint::DistinctFrom(idx) => {
+                match alloc.as_reg() {
+                    Some(preg) if !checker.machine_env.fixed_stack_slots.contains(&preg) => {}
+                    _ => return Err(CheckerError::AllocationIsNotReg { inst, op, alloc }),
+                }
+                if alloc == allocs[idx] {
+                    return Err(CheckerError::AllocationIsNotDistinct { inst, op, alloc });
+                }
+            }
         }
         Ok(())
     }
 }
 
+/// A point in the search for a `CheckerWitness`: the start of
+/// `block`, where `alloc` does not hold `vreg`, reached from the node
+/// `parent` with `steps` (last first) on the way.
+struct WitnessNode {
+    block: Block,
+    alloc: Allocation,
+    vreg: VReg,
+    parent: Option<usize>,
+    steps: Vec<WitnessStep>,
+}
+
 /// An instruction representation in the checker's BB summary.
 #[derive(Clone, Debug)]
 pub(crate) enum CheckerInst {
@@ -781,6 +1218,9 @@ pub(crate) enum CheckerInst {
     /// spillslots).
     Move { into: Allocation, from: Allocation },
 
+    /// An exchange of the contents of two registers.
+    Swap { a: Allocation, b: Allocation },
+
     /// A parallel move in the original program. Simultaneously moves
     /// from all source vregs to all corresponding dest vregs,
     /// permitting overlap in the src and dest sets and doing all
@@ -825,12 +1265,13 @@ pub(crate) enum CheckerInst {
 #[derive(Debug)]
 pub struct Checker<'a, F: Function> {
     f: &'a F,
-    bb_in: FxHashMap<Block, CheckerState>,
-    bb_insts: FxHashMap<Block, Vec<CheckerInst>>,
+    bb_in: Vec<CheckerState>,
+    bb_insts: Vec<Vec<CheckerInst>>,
     edge_insts: FxHashMap<(Block, Block), Vec<CheckerInst>>,
     reftyped_vregs: FxHashSet<VReg>,
     machine_env: &'a MachineEnv,
     stack_pregs: PRegSet,
+    sets: VRegSets,
 }
 
 impl<'a, F: Function> Checker<'a, F> {
@@ -839,15 +1280,14 @@ impl<'a, F: Function> Checker<'a, F> {
     /// methods to add abstract instructions to each BB before
     /// invoking `run()` to check for errors.
     pub fn new(f: &'a F, machine_env: &'a MachineEnv) -> Checker<'a, F> {
-        let mut bb_in = FxHashMap::default();
-        let mut bb_insts = FxHashMap::default();
+        let mut bb_in = vec![CheckerState::default(); f.num_blocks()];
+        let bb_insts = vec![vec![]; f.num_blocks()];
         let mut edge_insts = FxHashMap::default();
         let mut reftyped_vregs = FxHashSet::default();
+        let mut sets = VRegSets::default();
 
         for block in 0..f.num_blocks() {
             let block = Block::new(block);
-            bb_in.insert(block, Default::default());
-            bb_insts.insert(block, vec![]);
             for &succ in f.block_succs(block) {
                 edge_insts.insert((block, succ), vec![]);
             }
@@ -857,7 +1297,7 @@ impl<'a, F: Function> Checker<'a, F> {
             reftyped_vregs.insert(vreg);
         }
 
-        bb_in.insert(f.entry_block(), CheckerState::initial_with_pinned_vregs(f));
+        bb_in[f.entry_block().index()] = CheckerState::initial_with_liveins(f, &mut sets);
 
         let mut stack_pregs = PRegSet::empty();
         for &preg in &machine_env.fixed_stack_slots {
@@ -872,6 +1312,7 @@ impl<'a, F: Function> Checker<'a, F> {
             reftyped_vregs,
             machine_env,
             stack_pregs,
+            sets,
         }
     }
 
@@ -902,6 +1343,28 @@ impl<'a, F: Function> Checker<'a, F> {
                 }
             }
         }
+
+        // Edits on split critical edges happen on the edge, after
+        // the branch's effects on that edge but before any
+        // blockparam assignments.
+        for &(from_block, to_block, ref edit) in &out.edge_edits {
+            trace!(
+                "checker: adding edit {:?} on edge block{} -> block{}",
+                edit,
+                from_block.index(),
+                to_block.index()
+            );
+            let checkinst = match *edit {
+                Edit::Move { from, to } => CheckerInst::Move { into: to, from },
+                Edit::Swap { a, b } => CheckerInst::Swap { a, b },
+            };
+            let edge_insts = self.edge_insts.get_mut(&(from_block, to_block)).unwrap();
+            let pos = edge_insts
+                .iter()
+                .position(|inst| matches!(inst, CheckerInst::ParallelMove { .. }))
+                .unwrap_or(edge_insts.len());
+            edge_insts.insert(pos, checkinst);
+        }
     }
 
     /// For each original instruction, create an `Op`.
@@ -917,7 +1380,7 @@ impl<'a, F: Function> Checker<'a, F> {
             let allocs = safepoint_slots.remove(&inst).unwrap_or_else(|| vec![]);
 
             let checkinst = CheckerInst::Safepoint { inst, allocs };
-            self.bb_insts.get_mut(&block).unwrap().push(checkinst);
+            self.bb_insts[block.index()].push(checkinst);
         }
 
         // If this is a move, handle specially. Note that the
@@ -953,28 +1416,90 @@ impl<'a, F: Function> Checker<'a, F> {
                 dst: dst_op,
             };
             trace!("checker: adding inst {:?}", checkinst);
-            self.bb_insts.get_mut(&block).unwrap().push(checkinst);
-        }
-        // Skip normal checks if this is a branch: the blockparams do
-        // not exist in post-regalloc code, and the edge-moves have to
-        // be inserted before the branch rather than after.
-        else if !self.f.is_branch(inst) {
-            let operands: Vec<_> = self.f.inst_operands(inst).iter().cloned().collect();
-            let allocs: Vec<_> = out.inst_allocs(inst).iter().cloned().collect();
+            self.bb_insts[block.index()].push(checkinst);
+        } else {
+            // Check the operands of any instruction, including a
+            // branch. A branch's blockparam args do not exist in
+            // post-regalloc code, but it may read other operands and
+            // clobber registers.
             let clobbers: Vec<_> = self.f.inst_clobbers(inst).into_iter().collect();
-            let checkinst = CheckerInst::Op {
-                inst,
-                operands,
-                allocs,
-                clobbers,
-            };
-            trace!("checker: adding inst {:?}", checkinst);
-            self.bb_insts.get_mut(&block).unwrap().push(checkinst);
-        }
-        // Instead, if this is a branch, emit a ParallelMove on each
-        // outgoing edge as necessary to handle blockparams.
-        else {
+            if !self.f.is_branch(inst)
+                || !self.f.inst_operands(inst).is_empty()
+                || !clobbers.is_empty()
+            {
+                let mut operands: Vec<_> = self.f.inst_operands(inst).iter().cloned().collect();
+                // If the commutative inputs swapped roles, check each
+                // against the other's constraint, and any def that
+                // reuses one against the other.
+                if let Some((a, b)) = self.f.commutative_operands(inst) {
+                    if out.is_commuted(inst) {
+                        let (op_a, op_b) = (operands[a], operands[b]);
+                        operands[a] =
+                            Operand::new(op_a.vreg(), op_b.constraint(), op_a.kind(), op_a.pos());
+                        operands[b] =
+                            Operand::new(op_b.vreg(), op_a.constraint(), op_b.kind(), op_b.pos());
+                        for op in operands.iter_mut() {
+                            let reused = match (op.kind(), op.constraint()) {
+                                (OperandKind::Def, OperandConstraint::Reuse(i)) if i == a => b,
+                                (OperandKind::Def, OperandConstraint::Reuse(i)) if i == b => a,
+                                _ => continue,
+                            };
+                            *op = Operand::new(
+                                op.vreg(),
+                                OperandConstraint::Reuse(reused),
+                                op.kind(),
+                                op.pos(),
+                            );
+                        }
+                    }
+                }
+                let allocs: Vec<_> = out.inst_allocs(inst).iter().cloned().collect();
+                let checkinst = CheckerInst::Op {
+                    inst,
+                    operands,
+                    allocs,
+                    clobbers,
+                };
+                trace!("checker: adding inst {:?}", checkinst);
+                self.bb_insts[block.index()].push(checkinst);
+            }
+            if !self.f.is_branch(inst) {
+                return;
+            }
+            // If this is a branch, emit the effects particular to
+            // each outgoing edge: its clobbers and defs, and then a
+            // ParallelMove as necessary to handle blockparams.
             for (i, &succ) in self.f.block_succs(block).iter().enumerate() {
+                let edge_defs = self.f.branch_edge_defs(block, inst, i);
+                let mut edge_clobbers = self.f.branch_edge_clobbers(block, inst, i);
+                for &(_, preg) in edge_defs {
+                    edge_clobbers.remove(preg);
+                }
+                let clobbers: Vec<_> = edge_clobbers.into_iter().collect();
+                if !edge_defs.is_empty() || !clobbers.is_empty() {
+                    let checkinst = CheckerInst::Op {
+                        inst,
+                        operands: edge_defs
+                            .iter()
+                            .map(|&(vreg, preg)| Operand::reg_fixed_def(vreg, preg))
+                            .collect(),
+                        allocs: edge_defs
+                            .iter()
+                            .map(|&(_, preg)| Allocation::reg(preg))
+                            .collect(),
+                        clobbers,
+                    };
+                    trace!(
+                        "checker: adding inst {:?} on edge to block{}",
+                        checkinst,
+                        succ.index()
+                    );
+                    self.edge_insts
+                        .get_mut(&(block, succ))
+                        .unwrap()
+                        .push(checkinst);
+                }
+
                 let args = self.f.branch_blockparams(block, inst, i);
                 let params = self.f.block_params(succ);
                 assert_eq!(
@@ -999,69 +1524,71 @@ impl<'a, F: Function> Checker<'a, F> {
 
     fn handle_edit(&mut self, block: Block, edit: &Edit) {
         trace!("checker: adding edit {:?}", edit);
-        match edit {
-            &Edit::Move { from, to } => {
-                self.bb_insts
-                    .get_mut(&block)
-                    .unwrap()
-                    .push(CheckerInst::Move { into: to, from });
+        match *edit {
+            Edit::Move { from, to } => {
+                self.bb_insts[block.index()].push(CheckerInst::Move { into: to, from });
+            }
+            Edit::Swap { a, b } => {
+                self.bb_insts[block.index()].push(CheckerInst::Swap { a, b });
             }
         }
     }
 
     /// Perform the dataflow analysis to compute checker state at each BB entry.
-    fn analyze(&mut self) {
-        let mut queue = Vec::new();
-        let mut queue_set = FxHashSet::default();
-
-        queue.push(self.f.entry_block());
-        queue_set.insert(self.f.entry_block());
-
-        while !queue.is_empty() {
-            let block = queue.pop().unwrap();
-            queue_set.remove(&block);
-            let mut state = self.bb_in.get(&block).cloned().unwrap();
-            trace!("analyze: block {} has state {:?}", block.index(), state);
-            for inst in self.bb_insts.get(&block).unwrap() {
-                state.update(inst, self);
-                trace!("analyze: inst {:?} -> state {:?}", inst, state);
-            }
+    fn analyze(&mut self, sets: &mut VRegSets) {
+        // Visit blocks in reverse postorder, so that a block is
+        // usually visited after all of its predecessors, and sweep
+        // until no block's entry state changes; only blocks whose
+        // entry state changed since they were last visited need to
+        // be visited again.
+        let f = self.f;
+        let mut rpo = postorder::calculate(f.num_blocks(), f.entry_block(), |block| {
+            f.block_succs(block)
+        });
+        rpo.reverse();
+        let mut dirty = vec![false; f.num_blocks()];
+        dirty[f.entry_block().index()] = true;
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &rpo {
+                if !dirty[block.index()] {
+                    continue;
+                }
+                dirty[block.index()] = false;
 
-            for &succ in self.f.block_succs(block) {
-                let mut new_state = state.clone();
-                for edge_inst in self.edge_insts.get(&(block, succ)).unwrap() {
-                    new_state.update(edge_inst, self);
-                    trace!(
-                        "analyze: succ {:?}: inst {:?} -> state {:?}",
-                        succ,
-                        edge_inst,
-                        new_state
-                    );
+                let mut state = self.bb_in[block.index()].clone();
+                trace!("analyze: block {} has state {:?}", block.index(), state);
+                for inst in &self.bb_insts[block.index()] {
+                    state.update(inst, self, sets);
+                    trace!("analyze: inst {:?} -> state {:?}", inst, state);
                 }
 
-                let cur_succ_in = self.bb_in.get(&succ).unwrap();
-                trace!(
-                    "meeting state {:?} for block {} with state {:?} for block {}",
-                    new_state,
-                    block.index(),
-                    cur_succ_in,
-                    succ.index()
-                );
-                new_state.meet_with(cur_succ_in);
-                let changed = &new_state != cur_succ_in;
-                trace!(" -> {:?}, changed {}", new_state, changed);
+                for &succ in f.block_succs(block) {
+                    let mut new_state = state.clone();
+                    for edge_inst in self.edge_insts.get(&(block, succ)).unwrap() {
+                        new_state.update(edge_inst, self, sets);
+                        trace!(
+                            "analyze: succ {:?}: inst {:?} -> state {:?}",
+                            succ,
+                            edge_inst,
+                            new_state
+                        );
+                    }
 
-                if changed {
-                    trace!(
-                        "analyze: block {} state changed from {:?} to {:?}; pushing onto queue",
-                        succ.index(),
-                        cur_succ_in,
-                        new_state
-                    );
-                    self.bb_in.insert(succ, new_state);
-                    if !queue_set.contains(&succ) {
-                        queue.push(succ);
-                        queue_set.insert(succ);
+                    let cur_succ_in = &self.bb_in[succ.index()];
+                    new_state.meet_with(cur_succ_in, sets);
+                    if &new_state != cur_succ_in {
+                        trace!(
+                            "analyze: block {} state changed from {:?} to {:?}",
+                            succ.index(),
+                            cur_succ_in,
+                            new_state
+                        );
+                        self.bb_in[succ.index()] = new_state;
+                        dirty[succ.index()] = true;
+                        changed = true;
                     }
                 }
             }
@@ -1071,19 +1598,38 @@ impl<'a, F: Function> Checker<'a, F> {
     /// Using BB-start state computed by `analyze()`, step the checker state
     /// through each BB and check each instruction's register allocations
     /// for errors.
-    fn find_errors(&self) -> Result<(), CheckerErrors> {
+    fn find_errors(&self, sets: &mut VRegSets) -> Result<(), CheckerErrors> {
         let mut errors = vec![];
-        for (block, input) in &self.bb_in {
+        for (block, input) in self.bb_in.iter().enumerate() {
+            let block = Block::new(block);
             let mut state = input.clone();
-            for inst in self.bb_insts.get(block).unwrap() {
-                if let Err(e) = state.check(InstPosition::Before, inst, self) {
+            for (index, inst) in self.bb_insts[block.index()].iter().enumerate() {
+                if let Err(e) = state.check(InstPosition::Before, inst, self, sets) {
                     trace!("Checker error: {:?}", e);
-                    errors.push(e);
+                    errors.push(self.with_witness(e, block, None, index, sets));
                 }
-                state.update(inst, self);
-                if let Err(e) = state.check(InstPosition::After, inst, self) {
+                state.update(inst, self, sets);
+                if let Err(e) = state.check(InstPosition::After, inst, self, sets) {
                     trace!("Checker error: {:?}", e);
-                    errors.push(e);
+                    errors.push(self.with_witness(e, block, None, index + 1, sets));
+                }
+            }
+            for &succ in self.f.block_succs(block) {
+                let edge_insts = self.edge_insts.get(&(block, succ)).unwrap();
+                if edge_insts.is_empty() {
+                    continue;
+                }
+                let mut state = state.clone();
+                for (index, inst) in edge_insts.iter().enumerate() {
+                    if let Err(e) = state.check(InstPosition::Before, inst, self, sets) {
+                        trace!("Checker error: {:?}", e);
+                        errors.push(self.with_witness(e, block, Some(succ), index, sets));
+                    }
+                    state.update(inst, self, sets);
+                    if let Err(e) = state.check(InstPosition::After, inst, self, sets) {
+                        trace!("Checker error: {:?}", e);
+                        errors.push(self.with_witness(e, block, Some(succ), index + 1, sets));
+                    }
                 }
             }
         }
@@ -1095,18 +1641,325 @@ impl<'a, F: Function> Checker<'a, F> {
         }
     }
 
+    /// Attach a witness to an error about the value in an
+    /// allocation, found before the `index`th checker instruction of
+    /// `block`, or of its edge to `succ` if given.
+    fn with_witness(
+        &self,
+        mut error: CheckerError,
+        block: Block,
+        succ: Option<Block>,
+        index: usize,
+        sets: &mut VRegSets,
+    ) -> CheckerError {
+        match &mut error {
+            CheckerError::UnknownValueInAllocation {
+                op, alloc, witness, ..
+            }
+            | CheckerError::IncorrectValuesInAllocation {
+                op, alloc, witness, ..
+            } => {
+                *witness = self.witness(block, succ, index, *alloc, op.vreg(), sets);
+            }
+            _ => {}
+        }
+        error
+    }
+
+    /// The states before each of `insts` when stepping through them
+    /// from `state`, followed by the state after them.
+    fn states_through(
+        &self,
+        mut state: CheckerState,
+        insts: &[CheckerInst],
+        sets: &mut VRegSets,
+    ) -> Vec<CheckerState> {
+        let mut states = Vec::with_capacity(insts.len() + 1);
+        for inst in insts {
+            states.push(state.clone());
+            state.update(inst, self, sets);
+        }
+        states.push(state);
+        states
+    }
+
+    /// Explain why `alloc` does not hold `vreg` before the `index`th
+    /// checker instruction of `block`, or of its edge to `succ` if
+    /// given.
+    ///
+    /// This walks backwards from that point, following the value in
+    /// `alloc` back through the moves that put it there, until it
+    /// finds the step that wrote the value or removed `vreg` from it.
+    /// At the start of a block, the value must already be wrong
+    /// along the edge from some predecessor, or the meet of the
+    /// predecessors' states would not be wrong; the walk searches
+    /// those predecessors breadth-first, so that it finds the
+    /// shortest path back to where the value went wrong.
+    fn witness(
+        &self,
+        block: Block,
+        succ: Option<Block>,
+        index: usize,
+        alloc: Allocation,
+        vreg: VReg,
+        sets: &mut VRegSets,
+    ) -> CheckerWitness {
+        let mut root = WitnessNode {
+            block,
+            alloc,
+            vreg,
+            parent: None,
+            steps: vec![],
+        };
+        let insts = &self.bb_insts[block.index()];
+        let mut found = match succ {
+            None => {
+                let insts = &insts[..index];
+                let states = self.states_through(self.bb_in[block.index()].clone(), insts, sets);
+                self.witness_walk(&mut root, None, insts, &states, sets)
+            }
+            Some(succ) => {
+                // Walk back over the edge from the block's end state,
+                // then over the whole block.
+                let states = self.states_through(self.bb_in[block.index()].clone(), insts, sets);
+                let edge_insts = &self.edge_insts[&(block, succ)][..index];
+                let edge_states =
+                    self.states_through(states.last().unwrap().clone(), edge_insts, sets);
+                self.witness_walk(&mut root, Some(succ), edge_insts, &edge_states, sets)
+                    || self.witness_walk(&mut root, None, insts, &states, sets)
+            }
+        };
+
+        let mut nodes = vec![root];
+        let mut visited = FxHashSet::default();
+        visited.insert((block, alloc, vreg));
+        let mut queue = VecDeque::new();
+        if !found {
+            queue.push_back(0);
+        }
+        'search: while let Some(i) = queue.pop_front() {
+            let (block, alloc, vreg) = (nodes[i].block, nodes[i].alloc, nodes[i].vreg);
+            if block == self.f.entry_block() {
+                let kind = WitnessStepKind::Entry { alloc, vreg };
+                let succ = None;
+                nodes[i].steps.push(WitnessStep { block, succ, kind });
+                nodes.truncate(i + 1);
+                found = true;
+                break;
+            }
+            for &pred in self.f.block_preds(block) {
+                if self.bb_in[pred.index()] == CheckerState::Top {
+                    continue;
+                }
+                let pred_states = self.states_through(
+                    self.bb_in[pred.index()].clone(),
+                    &self.bb_insts[pred.index()],
+                    sets,
+                );
+                let edge_insts = &self.edge_insts[&(pred, block)];
+                let edge_states =
+                    self.states_through(pred_states.last().unwrap().clone(), edge_insts, sets);
+                if edge_states.last().unwrap().holds(alloc, vreg, sets) {
+                    continue;
+                }
+
+                let mut node = WitnessNode {
+                    block: pred,
+                    alloc,
+                    vreg,
+                    parent: Some(i),
+                    steps: vec![],
+                };
+                let done =
+                    self.witness_walk(&mut node, Some(block), edge_insts, &edge_states, sets)
+                        || self.witness_walk(
+                            &mut node,
+                            None,
+                            &self.bb_insts[pred.index()],
+                            &pred_states,
+                            sets,
+                        );
+                let key = (node.block, node.alloc, node.vreg);
+                nodes.push(node);
+                if done {
+                    found = true;
+                    break 'search;
+                }
+                if visited.insert(key) {
+                    queue.push_back(nodes.len() - 1);
+                } else {
+                    nodes.pop();
+                }
+            }
+        }
+
+        // Without an origin (which should not happen), report the
+        // steps in the error's own block.
+        let mut i = if found { nodes.len() - 1 } else { 0 };
+        let mut path = vec![];
+        let mut steps = vec![];
+        loop {
+            path.push(nodes[i].block);
+            steps.push(core::mem::take(&mut nodes[i].steps));
+            match nodes[i].parent {
+                Some(parent) => i = parent,
+                None => break,
+            }
+        }
+        let steps = steps
+            .into_iter()
+            .flat_map(|steps| steps.into_iter().rev())
+            .collect();
+        CheckerWitness { path, steps }
+    }
+
+    /// Walk the witness search back over `insts`, which are in
+    /// `node.block` or on its edge to `succ`, with `states` before
+    /// each of them. Returns whether the walk found the origin.
+    fn witness_walk(
+        &self,
+        node: &mut WitnessNode,
+        succ: Option<Block>,
+        insts: &[CheckerInst],
+        states: &[CheckerState],
+        sets: &VRegSets,
+    ) -> bool {
+        for (inst, before) in insts.iter().zip(states.iter()).rev() {
+            if let Some((kind, done)) =
+                self.witness_step(inst, before, &mut node.alloc, &mut node.vreg, sets)
+            {
+                let block = node.block;
+                node.steps.push(WitnessStep { block, succ, kind });
+                if done {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Step the witness search back over `inst`, given that `alloc`
+    /// does not hold `vreg` after it and `before` is the state before
+    /// it. Returns what `inst` did to the value, if anything, and
+    /// whether that is where the value originated; otherwise,
+    /// updates `alloc` and `vreg` to what must have been wrong before
+    /// `inst`.
+    fn witness_step(
+        &self,
+        inst: &CheckerInst,
+        before: &CheckerState,
+        alloc: &mut Allocation,
+        vreg: &mut VReg,
+        sets: &VRegSets,
+    ) -> Option<(WitnessStepKind, bool)> {
+        // Whether `alloc` holds `vreg` before `inst`, so that `inst`
+        // must be what removes it.
+        let held = before.holds(*alloc, *vreg, sets);
+        match *inst {
+            CheckerInst::Move { into, from } => {
+                // A move from an allocation without a value does not
+                // change the destination.
+                if into == *alloc && before.get_value(&from).is_some() {
+                    *alloc = from;
+                    return Some((WitnessStepKind::Move { from, into }, false));
+                }
+            }
+            CheckerInst::Swap { a, b } => {
+                if a == *alloc || b == *alloc {
+                    *alloc = if a == *alloc { b } else { a };
+                    return Some((WitnessStepKind::Swap { a, b }, false));
+                }
+            }
+            CheckerInst::ParallelMove { ref moves } => {
+                if let Some(&(dst, src)) = moves.iter().find(|&&(dst, _)| dst == *vreg) {
+                    *vreg = src;
+                    let kind = WitnessStepKind::Rename {
+                        inst: None,
+                        src,
+                        dst,
+                    };
+                    return Some((kind, false));
+                }
+            }
+            CheckerInst::ProgramMove { inst, src, dst } => {
+                if dst.vreg() == *vreg {
+                    *vreg = src.vreg();
+                    let kind = WitnessStepKind::Rename {
+                        inst: Some(inst),
+                        src: src.vreg(),
+                        dst: dst.vreg(),
+                    };
+                    return Some((kind, false));
+                }
+            }
+            CheckerInst::Op {
+                inst,
+                ref operands,
+                ref allocs,
+                ref clobbers,
+            } => {
+                // Clobbers take effect after defs.
+                if let Some(preg) = alloc.as_reg() {
+                    if clobbers.contains(&preg) {
+                        let kind = WitnessStepKind::Clobber {
+                            inst,
+                            alloc: *alloc,
+                        };
+                        return Some((kind, true));
+                    }
+                }
+                let mut defs = operands
+                    .iter()
+                    .zip(allocs.iter())
+                    .filter(|(op, _)| op.kind() == OperandKind::Def);
+                if let Some((&op, &alloc)) = defs.clone().find(|&(_, def)| def == alloc) {
+                    return Some((WitnessStepKind::Overwrite { inst, op, alloc }, true));
+                }
+                // The value of a def never reaches back before it.
+                if let Some((&op, &alloc)) = defs.find(|(op, _)| op.vreg() == *vreg) {
+                    let kind = if held {
+                        WitnessStepKind::Redefine { inst, op, alloc }
+                    } else {
+                        WitnessStepKind::Def { inst, op, alloc }
+                    };
+                    return Some((kind, true));
+                }
+            }
+            CheckerInst::Safepoint { inst, ref allocs } => {
+                if held && !alloc.is_reg() && !allocs.contains(alloc) {
+                    let kind = WitnessStepKind::Reclaim {
+                        inst,
+                        alloc: *alloc,
+                        vreg: *vreg,
+                    };
+                    return Some((kind, true));
+                }
+            }
+        }
+        None
+    }
+
     /// Find any errors, returning `Err(CheckerErrors)` with all errors found
     /// or `Ok(())` otherwise.
     pub fn run(mut self) -> Result<(), CheckerErrors> {
-        self.analyze();
-        let result = self.find_errors();
+        // The interned sets are taken out of the checker while it
+        // steps through states, which need them mutably.
+        let mut sets = core::mem::take(&mut self.sets);
+        self.analyze(&mut sets);
+        let result = self.find_errors(&mut sets);
+        if trace_enabled!() && log::log_enabled!(log::Level::Trace) {
+            self.trace_result(&mut sets);
+        }
+        result
+    }
 
+    fn trace_result(&self, sets: &mut VRegSets) {
         trace!("=== CHECKER RESULT ===");
-        fn print_state(state: &CheckerState) {
+        fn print_state(state: &CheckerState, sets: &VRegSets) {
             if let CheckerState::Allocations(allocs) = state {
                 let mut s = vec![];
-                for (alloc, state) in allocs {
-                    s.push(format!("{} := {}", alloc, state));
+                for &(alloc, value) in allocs {
+                    s.push(format!("{} := {}", alloc, sets.display(value)));
                 }
                 trace!("    {{ {} }}", s.join(", "))
             }
@@ -1117,9 +1970,9 @@ impl<'a, F: Function> Checker<'a, F> {
         for bb in 0..self.f.num_blocks() {
             let bb = Block::new(bb);
             trace!("block{}:", bb.index());
-            let insts = self.bb_insts.get(&bb).unwrap();
-            let mut state = self.bb_in.get(&bb).unwrap().clone();
-            print_state(&state);
+            let insts = &self.bb_insts[bb.index()];
+            let mut state = self.bb_in[bb.index()].clone();
+            print_state(&state, sets);
             for inst in insts {
                 match inst {
                     &CheckerInst::Op {
@@ -1139,6 +1992,9 @@ impl<'a, F: Function> Checker<'a, F> {
                     &CheckerInst::Move { from, into } => {
                         trace!("    {} -> {}", from, into);
                     }
+                    &CheckerInst::Swap { a, b } => {
+                        trace!("    {} <-> {}", a, b);
+                    }
                     &CheckerInst::Safepoint { ref allocs, .. } => {
                         let mut slotargs = vec![];
                         for &slot in allocs {
@@ -1153,30 +2009,167 @@ impl<'a, F: Function> Checker<'a, F> {
                         panic!("unexpected parallel_move in body (non-edge)")
                     }
                 }
-                state.update(inst, &self);
-                print_state(&state);
+                state.update(inst, self, sets);
+                print_state(&state, sets);
             }
 
             for &succ in self.f.block_succs(bb) {
                 trace!("  succ {:?}:", succ);
                 let mut state = state.clone();
                 for edge_inst in self.edge_insts.get(&(bb, succ)).unwrap() {
-                    match edge_inst {
-                        &CheckerInst::ParallelMove { ref moves } => {
+                    match *edge_inst {
+                        CheckerInst::ParallelMove { ref moves } => {
                             let moves = moves
                                 .iter()
                                 .map(|(dest, src)| format!("{} -> {}", src, dest))
                                 .collect::<Vec<_>>();
                             trace!("    parallel_move {}", moves.join(", "));
                         }
-                        _ => panic!("unexpected edge_inst: not a parallel move"),
+                        CheckerInst::Move { from, into } => {
+                            trace!("    {} -> {}", from, into);
+                        }
+                        CheckerInst::Swap { a, b } => {
+                            trace!("    {} <-> {}", a, b);
+                        }
+                        CheckerInst::Op {
+                            ref operands,
+                            ref clobbers,
+                            ..
+                        } => {
+                            trace!("    edge defs: {:?} clobbers:{:?}", operands, clobbers);
+                        }
+                        _ => panic!("unexpected edge_inst: not a move or edge effect"),
                     }
-                    state.update(edge_inst, &self);
-                    print_state(&state);
+                    state.update(edge_inst, self, sets);
+                    print_state(&state, sets);
                 }
             }
         }
+    }
+}
 
-        result
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{int, test_env};
+    use crate::{FunctionBuilder, ProgPoint, RegClass};
+    use alloc::collections::BTreeSet;
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_vreg_sets() {
+        // Apply random operations to interned sets and to plain sets
+        // side by side. Vregs 0 and 64 share a mask bit.
+        let mut sets = VRegSets::default();
+        let mut plain: Vec<BTreeSet<VReg>> = vec![
+            BTreeSet::new(),
+            [0, 1, 64].iter().map(|&i| int(i)).collect(),
+        ];
+        let mut ids: Vec<_> = plain
+            .iter()
+            .map(|set| sets.intern(set.iter().cloned().collect()))
+            .collect();
+
+        let mut seed = 1u64;
+        let mut rand = |n: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        let vregs = [0, 1, 2, 3, 64, 65].map(int);
+        for _ in 0..2000 {
+            let (i, j) = (rand(plain.len()), rand(plain.len()));
+            let (a, b) = (vregs[rand(vregs.len())], vregs[rand(vregs.len())]);
+            let (result, expected) = match rand(3) {
+                0 => (
+                    sets.meet(ids[i], ids[j]),
+                    plain[i].intersection(&plain[j]).cloned().collect(),
+                ),
+                1 => {
+                    let mut expected = plain[i].clone();
+                    expected.remove(&a);
+                    (sets.remove(ids[i], a), expected)
+                }
+                _ => {
+                    let mut expected = plain[i].clone();
+                    if expected.contains(&a) {
+                        expected.insert(b);
+                    }
+                    (sets.copy(ids[i], a, b), expected)
+                }
+            };
+            assert_eq!(sets.to_hash_set(result), expected.iter().cloned().collect());
+            for &vreg in &vregs {
+                assert_eq!(sets.contains(result, vreg), expected.contains(&vreg));
+            }
+            assert_eq!(sets.intern(expected.iter().cloned().collect()), result);
+            ids.push(result);
+            plain.push(expected);
+        }
+    }
+
+    #[test]
+    fn test_witness() {
+        // v0 reaches block2 in p1 through block1, but along the
+        // critical edge from block0, an edge move puts v1 in p1.
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        let b1 = b.add_block();
+        let b2 = b.add_block();
+        b.add_inst(b0, &[Operand::reg_def(int(0))]);
+        b.add_inst(b0, &[Operand::reg_def(int(1))]);
+        b.add_branch(b0, &[], &[b1, b2]);
+        let jump = b.add_branch(b1, &[], &[b2]);
+        let ret = b.add_ret(b2, &[Operand::reg_use(int(0))]);
+        let f = b.build();
+
+        let p = |i| Allocation::reg(PReg::new(i, RegClass::Int));
+        let out = Output {
+            num_spillslots: 0,
+            edits: vec![(
+                ProgPoint::before(jump),
+                Edit::Move {
+                    from: p(0),
+                    to: p(1),
+                },
+            )],
+            edge_edits: vec![(
+                b0,
+                b2,
+                Edit::Move {
+                    from: p(2),
+                    to: p(1),
+                },
+            )],
+            allocs: vec![p(0), p(2), p(1)],
+            inst_alloc_offsets: vec![0, 1, 2, 2, 2],
+            safepoint_slots: vec![],
+            debug_locations: vec![],
+            debug_annotations: vec![],
+            vreg_locations: vec![],
+            commuted: vec![],
+            stats: Default::default(),
+        };
+
+        let env = test_env(4);
+        let mut checker = Checker::new(&f, &env);
+        checker.prepare(&out);
+        let errors = checker.run().unwrap_err().errors;
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            CheckerError::IncorrectValuesInAllocation { inst, witness, .. } => {
+                assert_eq!(*inst, ret);
+                assert_eq!(witness.path, vec![b0, b2]);
+            }
+            e => panic!("unexpected error {}", e),
+        }
+        assert_eq!(
+            errors[0].to_string(),
+            "inst4: Use: v0i reg in p1i holds {}, not v0\n  \
+             path: block0 -> block2\n  \
+             block0: inst1: Def: v1i reg writes p2i\n  \
+             block0 -> block2: move p2i -> p1i"
+        );
     }
 }
@@ -13,6 +13,7 @@
 //   https://www.cs.rice.edu/~keith/EMBED/dom.pdf
 
 use crate::Block;
+use alloc::{vec, vec::Vec};
 
 // Helper
 fn merge_sets(
@@ -10,12 +10,14 @@ use crate::{
 
 use super::arbitrary::Result as ArbitraryResult;
 use super::arbitrary::{Arbitrary, Unstructured};
+use alloc::{format, vec, vec::Vec};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum InstOpcode {
     Op,
     Ret,
     Branch,
+    Move,
 }
 
 #[derive(Clone, Debug)]
@@ -24,6 +26,7 @@ pub struct InstData {
     operands: Vec<Operand>,
     clobbers: Vec<PReg>,
     is_safepoint: bool,
+    commutative: Option<(usize, usize)>,
 }
 
 impl InstData {
@@ -37,6 +40,16 @@ impl InstData {
             operands,
             clobbers: vec![],
             is_safepoint: false,
+            commutative: None,
+        }
+    }
+    pub fn mov(dst: VReg, src: VReg) -> InstData {
+        InstData {
+            op: InstOpcode::Move,
+            operands: vec![Operand::reg_use(src), Operand::reg_def(dst)],
+            clobbers: vec![],
+            is_safepoint: false,
+            commutative: None,
         }
     }
     pub fn branch() -> InstData {
@@ -45,6 +58,7 @@ impl InstData {
             operands: vec![],
             clobbers: vec![],
             is_safepoint: false,
+            commutative: None,
         }
     }
     pub fn ret() -> InstData {
@@ -53,6 +67,7 @@ impl InstData {
             operands: vec![],
             clobbers: vec![],
             is_safepoint: false,
+            commutative: None,
         }
     }
 }
@@ -68,6 +83,30 @@ pub struct Func {
     num_vregs: usize,
     reftype_vregs: Vec<VReg>,
     debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
+    entry_liveins: Vec<(VReg, PReg)>,
+    edge_effects: Vec<EdgeEffects>,
+    pinned_vregs: Vec<(VReg, PReg)>,
+}
+
+/// Clobbers and defs on the edge to a branch's successor.
+#[derive(Clone)]
+struct EdgeEffects {
+    block: Block,
+    succ_idx: usize,
+    clobbers: PRegSet,
+    defs: Vec<(VReg, PReg)>,
+}
+
+impl Func {
+    fn edge_effects(&self, block: Block, succ_idx: usize) -> Option<&EdgeEffects> {
+        self.edge_effects
+            .iter()
+            .find(|e| e.block == block && e.succ_idx == succ_idx)
+    }
+
+    fn is_pinned_preg(&self, preg: PReg) -> bool {
+        self.pinned_vregs.iter().any(|&(_, p)| p == preg)
+    }
 }
 
 impl Function for Func {
@@ -124,14 +163,45 @@ impl Function for Func {
         &self.debug_value_labels[..]
     }
 
-    fn is_move(&self, _: Inst) -> Option<(Operand, Operand)> {
-        None
+    fn is_move(&self, insn: Inst) -> Option<(Operand, Operand)> {
+        let inst = &self.insts[insn.index()];
+        match inst.op {
+            InstOpcode::Move => Some((inst.operands[0], inst.operands[1])),
+            _ => None,
+        }
+    }
+
+    fn is_pinned_vreg(&self, vreg: VReg) -> Option<PReg> {
+        self.pinned_vregs
+            .iter()
+            .find(|&&(v, _)| v == vreg)
+            .map(|&(_, preg)| preg)
+    }
+
+    fn entry_liveins(&self) -> &[(VReg, PReg)] {
+        &self.entry_liveins[..]
+    }
+
+    fn branch_edge_clobbers(&self, block: Block, _: Inst, succ_idx: usize) -> PRegSet {
+        self.edge_effects(block, succ_idx)
+            .map(|e| e.clobbers)
+            .unwrap_or(PRegSet::empty())
+    }
+
+    fn branch_edge_defs(&self, block: Block, _: Inst, succ_idx: usize) -> &[(VReg, PReg)] {
+        self.edge_effects(block, succ_idx)
+            .map(|e| &e.defs[..])
+            .unwrap_or(&[])
     }
 
     fn inst_operands(&self, insn: Inst) -> &[Operand] {
         &self.insts[insn.index()].operands[..]
     }
 
+    fn commutative_operands(&self, insn: Inst) -> Option<(usize, usize)> {
+        self.insts[insn.index()].commutative
+    }
+
     fn inst_clobbers(&self, insn: Inst) -> PRegSet {
         let mut set = PRegSet::default();
         for &preg in &self.insts[insn.index()].clobbers {
@@ -174,6 +244,9 @@ impl FuncBuilder {
                 num_vregs: 0,
                 reftype_vregs: vec![],
                 debug_value_labels: vec![],
+                entry_liveins: vec![],
+                edge_effects: vec![],
+                pinned_vregs: vec![],
             },
             insts_per_block: vec![],
         }
@@ -241,6 +314,35 @@ impl Arbitrary<'_> for OperandConstraint {
     }
 }
 
+fn arbitrary_class(u: &mut Unstructured, opts: &Options) -> ArbitraryResult<RegClass> {
+    if opts.float_vregs && bool::arbitrary(u)? {
+        Ok(RegClass::Float)
+    } else {
+        Ok(RegClass::Int)
+    }
+}
+
+fn arbitrary_constraint(
+    u: &mut Unstructured,
+    opts: &Options,
+) -> ArbitraryResult<OperandConstraint> {
+    if opts.stack_constraints && u.ratio(1, 4)? {
+        Ok(OperandConstraint::Stack)
+    } else {
+        OperandConstraint::arbitrary(u)
+    }
+}
+
+/// Would another use of `vreg` with `constraint` make an instruction
+/// refer to it more than once, with one of those uses on the stack?
+fn is_second_stack_use(operands: &[Operand], vreg: VReg, constraint: OperandConstraint) -> bool {
+    operands.iter().any(|op| {
+        op.vreg() == vreg
+            && (op.constraint() == OperandConstraint::Stack
+                || constraint == OperandConstraint::Stack)
+    })
+}
+
 fn choose_dominating_block(
     idom: &[Block],
     mut block: Block,
@@ -273,13 +375,35 @@ pub struct Options {
     pub fixed_nonallocatable: bool,
     pub clobbers: bool,
     pub control_flow: bool,
+    pub critical_edges: bool,
+    pub branch_operands: bool,
+    pub entry_liveins: bool,
+    pub edge_effects: bool,
     pub reducible: bool,
     pub block_params: bool,
     pub always_local_uses: bool,
     pub reftypes: bool,
+    /// Generate program moves, reported by `is_move`.
+    pub prog_moves: bool,
+    /// Generate `Float`-class vregs as well as `Int` ones.
+    pub float_vregs: bool,
+    /// Pin a few vregs to allocatable registers; they are used and
+    /// defined only by program moves.
+    pub pinned_vregs: bool,
+    /// Generate `Stack` constraints on operands, including those of
+    /// safepoints.
+    pub stack_constraints: bool,
+    /// Generate defs that must be in a different register from one
+    /// of their instruction's uses.
+    pub distinct_defs: bool,
+    /// Generate uses tied to another use of the same vreg.
+    pub tied_uses: bool,
+    /// Declare the input that a def reuses commutative with another
+    /// input.
+    pub commutative: bool,
 }
 
-impl std::default::Default for Options {
+impl core::default::Default for Options {
     fn default() -> Self {
         Options {
             reused_inputs: false,
@@ -287,10 +411,21 @@ impl std::default::Default for Options {
             fixed_nonallocatable: false,
             clobbers: false,
             control_flow: true,
+            critical_edges: false,
+            branch_operands: false,
+            entry_liveins: false,
+            edge_effects: false,
             reducible: false,
             block_params: true,
             always_local_uses: false,
             reftypes: false,
+            prog_moves: false,
+            float_vregs: false,
+            pinned_vregs: false,
+            stack_constraints: false,
+            distinct_defs: false,
+            tied_uses: false,
+            commutative: false,
         }
     }
 }
@@ -359,7 +494,14 @@ impl Func {
                     min_backedge_dest = succ;
                 }
             }
-            builder.add_edge(Block::new(pred), Block::new(succ));
+            // Optionally leave the edge block out and branch directly
+            // from the fork, creating a critical edge; the edge block
+            // then ends in a return.
+            if opts.critical_edges && succ != pred + 1 && bool::arbitrary(u)? {
+                builder.add_edge(Block::new(pred - 1), Block::new(succ));
+            } else {
+                builder.add_edge(Block::new(pred), Block::new(succ));
+            }
         }
 
         builder.compute_doms();
@@ -375,16 +517,64 @@ impl Func {
 
         builder.compute_doms();
 
+        // Optionally pin a few vregs, each to its own preferred
+        // register. They are implicitly live-in, so that register is
+        // kept out of clobbers, fixed constraints and liveins below.
+        if opts.pinned_vregs {
+            for _ in 0..u.int_in_range(0..=2)? {
+                let preg = PReg::new(u.int_in_range(0..=23)?, arbitrary_class(u, opts)?);
+                if builder.f.is_pinned_preg(preg) {
+                    continue;
+                }
+                let vreg = VReg::new(builder.f.num_vregs, preg.class());
+                builder.f.num_vregs += 1;
+                builder.f.pinned_vregs.push((vreg, preg));
+            }
+        }
+        let mut pinned_defined = vec![false; builder.f.pinned_vregs.len()];
+
+        // Optionally give a branch with several successors effects on
+        // its edge to one of them, like a call with an exceptional
+        // successor. The target must have a single predecessor.
+        let mut edge_into = vec![None; num_blocks];
+        if opts.edge_effects {
+            for block in 0..num_blocks {
+                let succs = &builder.f.block_succs[block];
+                if succs.len() < 2 || !bool::arbitrary(u)? {
+                    continue;
+                }
+                let succ_idx = u.int_in_range(0..=succs.len() - 1)?;
+                let succ = succs[succ_idx];
+                if succ.index() == 0 || builder.f.block_preds[succ.index()].len() != 1 {
+                    continue;
+                }
+                let mut clobbers = PRegSet::empty();
+                for _ in 0..u.int_in_range(0..=8)? {
+                    let preg = PReg::new(u.int_in_range(0..=31)?, arbitrary_class(u, opts)?);
+                    if !builder.f.is_pinned_preg(preg) {
+                        clobbers.add(preg);
+                    }
+                }
+                edge_into[succ.index()] = Some(builder.f.edge_effects.len());
+                builder.f.edge_effects.push(EdgeEffects {
+                    block: Block::new(block),
+                    succ_idx,
+                    clobbers,
+                    defs: vec![],
+                });
+            }
+        }
+
         let mut vregs_by_block = vec![];
         let mut vregs_by_block_to_be_defined = vec![];
         let mut block_params = vec![vec![]; num_blocks];
         for block in 0..num_blocks {
             let mut vregs = vec![];
             for _ in 0..u.int_in_range(5..=15)? {
-                let vreg = VReg::new(builder.f.num_vregs, RegClass::Int);
+                let vreg = VReg::new(builder.f.num_vregs, arbitrary_class(u, opts)?);
                 builder.f.num_vregs += 1;
                 vregs.push(vreg);
-                if opts.reftypes && bool::arbitrary(u)? {
+                if opts.reftypes && vreg.class() == RegClass::Int && bool::arbitrary(u)? {
                     builder.f.reftype_vregs.push(vreg);
                 }
                 if bool::arbitrary(u)? {
@@ -408,11 +598,39 @@ impl Func {
             }
             vregs_by_block.push(vregs.clone());
             vregs_by_block_to_be_defined.push(vec![]);
-            let mut max_block_params = u.int_in_range(0..=std::cmp::min(3, vregs.len() / 3))?;
+            let mut max_block_params = u.int_in_range(0..=core::cmp::min(3, vregs.len() / 3))?;
             for &vreg in &vregs {
                 if block > 0 && opts.block_params && bool::arbitrary(u)? && max_block_params > 0 {
                     block_params[block].push(vreg);
                     max_block_params -= 1;
+                } else if let Some(e) =
+                    edge_into[block].filter(|&e| builder.f.edge_effects[e].defs.len() < 2)
+                {
+                    // Defined on the edge into this block, in a
+                    // register or fixed stack slot.
+                    let preg = PReg::new(u.int_in_range(0..=62)?, vreg.class());
+                    let pinned = builder.f.is_pinned_preg(preg);
+                    let defs = &mut builder.f.edge_effects[e].defs;
+                    if defs.iter().any(|&(_, p)| p == preg) || pinned || bool::arbitrary(u)? {
+                        vregs_by_block_to_be_defined.last_mut().unwrap().push(vreg);
+                    } else {
+                        defs.push((vreg, preg));
+                    }
+                } else if block == 0
+                    && opts.entry_liveins
+                    && builder.f.block_preds[0].is_empty()
+                    && bool::arbitrary(u)?
+                {
+                    // Arrives in an allocatable register or a fixed
+                    // stack slot, distinct from other liveins.
+                    let preg = PReg::new(u.int_in_range(0..=62)?, vreg.class());
+                    if builder.f.entry_liveins.iter().any(|&(_, p)| p == preg)
+                        || builder.f.is_pinned_preg(preg)
+                    {
+                        vregs_by_block_to_be_defined.last_mut().unwrap().push(vreg);
+                    } else {
+                        builder.f.entry_liveins.push((vreg, preg));
+                    }
                 } else {
                     vregs_by_block_to_be_defined.last_mut().unwrap().push(vreg);
                 }
@@ -423,9 +641,55 @@ impl Func {
 
         for block in 0..num_blocks {
             let mut avail = block_params[block].clone();
+            if block == 0 {
+                avail.extend(builder.f.entry_liveins.iter().map(|&(vreg, _)| vreg));
+            }
+            if let Some(e) = edge_into[block] {
+                avail.extend(builder.f.edge_effects[e].defs.iter().map(|&(vreg, _)| vreg));
+            }
             let mut remaining_nonlocal_uses = u.int_in_range(0..=3)?;
             while let Some(vreg) = vregs_by_block_to_be_defined[block].pop() {
-                let def_constraint = OperandConstraint::arbitrary(u)?;
+                if opts.prog_moves && !builder.f.pinned_vregs.is_empty() && u.ratio(1, 8)? {
+                    // Define a pinned vreg, at most once, by a move
+                    // into it.
+                    let i = u.int_in_range(0..=builder.f.pinned_vregs.len() - 1)?;
+                    let pinned = builder.f.pinned_vregs[i].0;
+                    let srcs: Vec<VReg> = avail
+                        .iter()
+                        .cloned()
+                        .filter(|src| {
+                            src.class() == pinned.class() && !builder.f.reftype_vregs.contains(src)
+                        })
+                        .collect();
+                    if !pinned_defined[i] && !srcs.is_empty() {
+                        let src = *u.choose(&srcs[..])?;
+                        builder.add_inst(Block::new(block), InstData::mov(pinned, src));
+                        pinned_defined[i] = true;
+                    }
+                }
+                if opts.prog_moves && u.ratio(1, 4)? {
+                    // Define the vreg by a move from an available
+                    // vreg (or a pinned one) of the same class and
+                    // reftype-ness.
+                    let is_ref = builder.f.reftype_vregs.contains(&vreg);
+                    let srcs: Vec<VReg> = avail
+                        .iter()
+                        .chain(builder.f.pinned_vregs.iter().map(|(vreg, _)| vreg))
+                        .cloned()
+                        .filter(|src| {
+                            src.class() == vreg.class()
+                                && builder.f.reftype_vregs.contains(src) == is_ref
+                        })
+                        .collect();
+                    if !srcs.is_empty() {
+                        let src = *u.choose(&srcs[..])?;
+                        builder.add_inst(Block::new(block), InstData::mov(vreg, src));
+                        avail.push(vreg);
+                        continue;
+                    }
+                }
+
+                let def_constraint = arbitrary_constraint(u, opts)?;
                 let def_pos = if bool::arbitrary(u)? {
                     OperandPos::Early
                 } else {
@@ -462,7 +726,10 @@ impl Func {
                     } else {
                         break;
                     };
-                    let use_constraint = OperandConstraint::arbitrary(u)?;
+                    let use_constraint = arbitrary_constraint(u, opts)?;
+                    if is_second_stack_use(&operands[..], vreg, use_constraint) {
+                        break;
+                    }
                     operands.push(Operand::new(
                         vreg,
                         use_constraint,
@@ -471,26 +738,95 @@ impl Func {
                     ));
                     allocations.push(Allocation::none());
                 }
+                if operands.len() > 1 && opts.tied_uses && bool::arbitrary(u)? {
+                    // Read one of the inputs again through another
+                    // slot, which must be in the same place.
+                    // Not of a `Stack` use, though, as the tied use
+                    // may be given a fixed register below.
+                    let tied = u.int_in_range(1..=(operands.len() - 1))?;
+                    if operands[tied].constraint() != OperandConstraint::Stack {
+                        operands.push(Operand::new(
+                            operands[tied].vreg(),
+                            OperandConstraint::Reuse(tied),
+                            OperandKind::Use,
+                            OperandPos::Early,
+                        ));
+                        allocations.push(Allocation::none());
+                    }
+                }
                 let mut clobbers: Vec<PReg> = vec![];
+                let mut commutative = None;
                 if operands.len() > 1 && opts.reused_inputs && bool::arbitrary(u)? {
-                    // Make the def a reused input.
+                    // Make the def a reused input, if it is of the
+                    // same class.
                     let op = operands[0];
                     debug_assert_eq!(op.kind(), OperandKind::Def);
                     let reused = u.int_in_range(1..=(operands.len() - 1))?;
-                    operands[0] = Operand::new(
-                        op.vreg(),
-                        OperandConstraint::Reuse(reused),
-                        op.kind(),
-                        OperandPos::Late,
-                    );
-                    // Make sure reused input is a Reg.
-                    let op = operands[reused];
-                    operands[reused] = Operand::new(
-                        op.vreg(),
-                        OperandConstraint::Reg,
-                        op.kind(),
-                        OperandPos::Early,
-                    );
+                    if operands[reused].class() == op.class() {
+                        operands[0] = Operand::new(
+                            op.vreg(),
+                            OperandConstraint::Reuse(reused),
+                            op.kind(),
+                            OperandPos::Late,
+                        );
+                        // Make sure reused input is a Reg.
+                        let op = operands[reused];
+                        operands[reused] = Operand::new(
+                            op.vreg(),
+                            OperandConstraint::Reg,
+                            op.kind(),
+                            OperandPos::Early,
+                        );
+                        // Maybe let the def reuse another input
+                        // instead.
+                        if opts.commutative && bool::arbitrary(u)? {
+                            let other = u.int_in_range(1..=(operands.len() - 1))?;
+                            let other_op = operands[other];
+                            if other != reused
+                                && other_op.class() == op.class()
+                                && matches!(
+                                    other_op.constraint(),
+                                    OperandConstraint::Reg | OperandConstraint::Any
+                                )
+                            {
+                                commutative = Some((reused, other));
+                            }
+                        }
+                        // Maybe state the reuse from the input's
+                        // side instead, by tying it to the def.
+                        if commutative.is_none()
+                            && opts.tied_uses
+                            && !operands.iter().any(|o| o.as_tied_use() == Some(reused))
+                            && bool::arbitrary(u)?
+                        {
+                            operands[0] = Operand::new(
+                                operands[0].vreg(),
+                                OperandConstraint::Reg,
+                                OperandKind::Def,
+                                OperandPos::Late,
+                            );
+                            operands[reused] = Operand::new(
+                                operands[reused].vreg(),
+                                OperandConstraint::Reuse(0),
+                                OperandKind::Use,
+                                OperandPos::Early,
+                            );
+                        }
+                    }
+                } else if operands.len() > 1 && opts.distinct_defs && bool::arbitrary(u)? {
+                    // Make the def differ from an input of the same
+                    // class.
+                    let op = operands[0];
+                    debug_assert_eq!(op.kind(), OperandKind::Def);
+                    let other = u.int_in_range(1..=core::cmp::min(operands.len() - 1, 15))?;
+                    if operands[other].class() == op.class() {
+                        operands[0] = Operand::new(
+                            op.vreg(),
+                            OperandConstraint::DistinctFrom(other),
+                            op.kind(),
+                            op.pos(),
+                        );
+                    }
                 } else if opts.fixed_regs && bool::arbitrary(u)? {
                     let mut fixed_early = vec![];
                     let mut fixed_late = vec![];
@@ -498,12 +834,12 @@ impl Func {
                         // Pick an operand and make it a fixed reg.
                         let i = u.int_in_range(0..=(operands.len() - 1))?;
                         let op = operands[i];
-                        let fixed_reg = PReg::new(u.int_in_range(0..=62)?, RegClass::Int);
+                        let fixed_reg = PReg::new(u.int_in_range(0..=62)?, op.class());
                         let fixed_list = match op.pos() {
                             OperandPos::Early => &mut fixed_early,
                             OperandPos::Late => &mut fixed_late,
                         };
-                        if fixed_list.contains(&fixed_reg) {
+                        if fixed_list.contains(&fixed_reg) || builder.f.is_pinned_preg(fixed_reg) {
                             break;
                         }
                         if op.kind() != OperandKind::Def && op.pos() == OperandPos::Late {
@@ -532,11 +868,11 @@ impl Func {
                     }
                 } else if opts.clobbers && bool::arbitrary(u)? {
                     for _ in 0..u.int_in_range(0..=5)? {
-                        let reg = u.int_in_range(0..=30)?;
-                        if clobbers.iter().any(|r| r.hw_enc() == reg) {
+                        let reg = PReg::new(u.int_in_range(0..=30)?, arbitrary_class(u, opts)?);
+                        if clobbers.contains(&reg) || builder.f.is_pinned_preg(reg) {
                             break;
                         }
-                        clobbers.push(PReg::new(reg, RegClass::Int));
+                        clobbers.push(reg);
                     }
                 } else if opts.fixed_nonallocatable && bool::arbitrary(u)? {
                     operands.push(Operand::fixed_nonallocatable(PReg::new(63, RegClass::Int)));
@@ -555,6 +891,7 @@ impl Func {
                         operands,
                         clobbers,
                         is_safepoint,
+                        commutative,
                     },
                 );
                 avail.push(vreg);
@@ -564,26 +901,74 @@ impl Func {
             // the block.
             if builder.f.block_succs[block].len() > 0 {
                 let mut params = vec![];
-                for &succ in &builder.f.block_succs[block] {
+                for succ in builder.f.block_succs[block].clone() {
                     let mut args = vec![];
-                    for _ in 0..builder.f.block_params_in[succ.index()].len() {
+                    for i in 0..builder.f.block_params_in[succ.index()].len() {
+                        let class = builder.f.block_params_in[succ.index()][i].class();
                         let dom_block = choose_dominating_block(
                             &builder.idom[..],
                             Block::new(block),
                             false,
                             u,
                         )?;
-                        let vreg = if dom_block.is_valid() && bool::arbitrary(u)? {
-                            u.choose(&vregs_by_block[dom_block.index()][..])?
+                        let vregs = if dom_block.is_valid() && bool::arbitrary(u)? {
+                            &vregs_by_block[dom_block.index()][..]
                         } else {
-                            u.choose(&avail[..])?
+                            &avail[..]
                         };
-                        args.push(*vreg);
+                        let vregs: Vec<VReg> = vregs
+                            .iter()
+                            .cloned()
+                            .filter(|v| v.class() == class)
+                            .collect();
+                        let vreg = if vregs.is_empty() {
+                            // Nothing of the parameter's class is
+                            // available: define a new vreg for it.
+                            let vreg = VReg::new(builder.f.num_vregs, class);
+                            builder.f.num_vregs += 1;
+                            builder.add_inst(
+                                Block::new(block),
+                                InstData {
+                                    op: InstOpcode::Op,
+                                    operands: vec![Operand::reg_def(vreg)],
+                                    clobbers: vec![],
+                                    is_safepoint: false,
+                                    commutative: None,
+                                },
+                            );
+                            avail.push(vreg);
+                            vreg
+                        } else {
+                            *u.choose(&vregs[..])?
+                        };
+                        args.push(vreg);
                     }
                     params.push(args);
                 }
                 builder.set_block_params_out(Block::new(block), params);
-                builder.add_inst(Block::new(block), InstData::branch());
+                let mut branch = InstData::branch();
+                if opts.branch_operands && !avail.is_empty() {
+                    for _ in 0..u.int_in_range(0..=2)? {
+                        let vreg = *u.choose(&avail[..])?;
+                        let constraint = if opts.stack_constraints && u.ratio(1, 4)? {
+                            OperandConstraint::Stack
+                        } else if bool::arbitrary(u)? {
+                            OperandConstraint::Any
+                        } else {
+                            OperandConstraint::Reg
+                        };
+                        if is_second_stack_use(&branch.operands[..], vreg, constraint) {
+                            break;
+                        }
+                        branch.operands.push(Operand::new(
+                            vreg,
+                            constraint,
+                            OperandKind::Use,
+                            OperandPos::Early,
+                        ));
+                    }
+                }
+                builder.add_inst(Block::new(block), branch);
             } else {
                 builder.add_inst(Block::new(block), InstData::ret());
             }
@@ -595,12 +980,18 @@ impl Func {
     }
 }
 
-impl std::fmt::Debug for Func {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for Func {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "{{\n")?;
         for vreg in self.reftype_vregs() {
             write!(f, "  REF: {}\n", vreg)?;
         }
+        for (vreg, preg) in self.entry_liveins() {
+            writeln!(f, "  LIVEIN: {} in {}", vreg, preg)?;
+        }
+        for (vreg, preg) in &self.pinned_vregs {
+            writeln!(f, "  PINNED: {} in {}", vreg, preg)?;
+        }
         for (i, blockrange) in self.blocks.iter().enumerate() {
             let succs = self.block_succs[i]
                 .iter()
@@ -646,8 +1037,20 @@ impl std::fmt::Debug for Func {
                     self.insts[inst.index()].operands,
                     self.insts[inst.index()].clobbers
                 )?;
+                if let Some((a, b)) = self.insts[inst.index()].commutative {
+                    writeln!(f, "    commutative: {} {}", a, b)?;
+                }
                 if let InstOpcode::Branch = self.insts[inst.index()].op {
                     write!(f, "    params: {}\n", params_out)?;
+                    for e in self.edge_effects.iter().filter(|e| e.block.index() == i) {
+                        writeln!(
+                            f,
+                            "    edge to block{}: clobber:{:?} defs:{:?}",
+                            self.block_succs[i][e.succ_idx].index(),
+                            e.clobbers,
+                            e.defs
+                        )?;
+                    }
                 }
             }
         }
@@ -657,16 +1060,19 @@ impl std::fmt::Debug for Func {
 }
 
 pub fn machine_env() -> MachineEnv {
-    fn regs(r: std::ops::Range<usize>) -> Vec<PReg> {
-        r.map(|i| PReg::new(i, RegClass::Int)).collect()
+    use RegClass::{Float, Int};
+    fn regs(r: core::ops::Range<usize>, class: RegClass) -> Vec<PReg> {
+        r.map(|i| PReg::new(i, class)).collect()
     }
-    let preferred_regs_by_class: [Vec<PReg>; 2] = [regs(0..24), vec![]];
-    let non_preferred_regs_by_class: [Vec<PReg>; 2] = [regs(24..32), vec![]];
-    let fixed_stack_slots = regs(32..63);
+    let preferred_regs_by_class: [Vec<PReg>; 2] = [regs(0..24, Int), regs(0..24, Float)];
+    let non_preferred_regs_by_class: [Vec<PReg>; 2] = [regs(24..32, Int), regs(24..32, Float)];
+    let mut fixed_stack_slots = regs(32..63, Int);
+    fixed_stack_slots.extend(regs(32..63, Float));
     // Register 63 is reserved for use as a fixed non-allocatable register.
     MachineEnv {
         preferred_regs_by_class,
         non_preferred_regs_by_class,
         fixed_stack_slots,
+        ..MachineEnv::default()
     }
 }
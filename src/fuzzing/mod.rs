@@ -28,5 +28,8 @@ pub mod ion {
 pub mod checker {
     pub use crate::checker::*;
 }
+pub mod interp {
+    pub use crate::interp::*;
+}
 
 pub use libfuzzer_sys::{arbitrary, fuzz_target};
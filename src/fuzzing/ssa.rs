@@ -5,6 +5,7 @@
 
 //! SSA-related utilities.
 
+use alloc::vec;
 use std::collections::HashSet;
 
 use crate::cfg::CFGInfo;
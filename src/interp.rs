@@ -0,0 +1,355 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Interpreter: validates an allocation by running the function on
+//! concrete values.
+//!
+//! Where the checker computes a fixpoint over sets of vregs, the
+//! interpreter executes one path through the function twice in
+//! lockstep: once on vregs, as presented to the allocator, and once
+//! on a simulated register file and stack, as the allocator's
+//! `Output` says to (with its edits and edge edits applied). Every
+//! def produces a value that is a hash of the instruction's position
+//! in the execution trace and of the values it read, so that a stale
+//! value from an earlier iteration of a loop never looks right.
+//! Clobbers, edge clobbers and unlisted stack slots at safepoints
+//! poison what they hold. At each instruction, each operand that is
+//! read must find in its allocation the value that its vreg has in
+//! the vreg execution.
+//!
+//! The path is chosen by a seed, so that running many seeds explores
+//! many paths; and an execution stops after a given number of
+//! instructions, so that loops terminate.
+
+use crate::{
+    Allocation, AllocationKind, Block, Edit, Function, FxHashMap, FxHashSet, Inst, InstOrEdit,
+    Operand, OperandKind, Output, PReg, VReg,
+};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A discrepancy between the two executions.
+#[derive(Clone, Debug)]
+pub enum InterpError {
+    /// An operand's allocation did not hold the value of its vreg.
+    /// `actual` is `None` if the allocation held no value, or a value
+    /// that was clobbered.
+    WrongValue {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+        expected: u64,
+        actual: Option<u64>,
+    },
+    /// A stack slot given for a safepoint did not hold a reference.
+    NonRefValueInStackmap { inst: Inst, alloc: Allocation },
+}
+
+impl fmt::Display for InterpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpError::WrongValue {
+                inst,
+                op,
+                alloc,
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "inst{}: {} in {} is {:#x}, expected {:#x}",
+                inst.index(),
+                op,
+                alloc,
+                actual,
+                expected
+            ),
+            InterpError::WrongValue {
+                inst,
+                op,
+                alloc,
+                expected,
+                actual: None,
+            } => write!(
+                f,
+                "inst{}: {} in {} has no value, expected {:#x}",
+                inst.index(),
+                op,
+                alloc,
+                expected
+            ),
+            InterpError::NonRefValueInStackmap { inst, alloc } => write!(
+                f,
+                "inst{}: stackmap slot {} does not hold a reference",
+                inst.index(),
+                alloc
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InterpError {}
+
+/// Mix two words into a hash (the finalizer of SplitMix64).
+fn mix(a: u64, b: u64) -> u64 {
+    let mut x = a ^ b.wrapping_add(0x9e37_79b9_7f4a_7c15).rotate_left(17);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// The value that a register holds on entry to the function.
+fn initial_value(preg: PReg) -> u64 {
+    mix(0x1a7e_11e5, preg.index() as u64)
+}
+
+/// Runs a function and its allocation side by side.
+pub struct Interpreter<'a, F: Function> {
+    f: &'a F,
+    out: &'a Output,
+    safepoint_slots: FxHashMap<Inst, Vec<Allocation>>,
+    reftype_vregs: FxHashSet<VReg>,
+
+    /// Values of vregs in the original function.
+    vregs: FxHashMap<VReg, u64>,
+    /// Values of allocations in the allocated function. An
+    /// allocation that is missing holds garbage.
+    allocs: FxHashMap<Allocation, u64>,
+    /// How many instructions have been executed.
+    steps: usize,
+}
+
+impl<'a, F: Function> Interpreter<'a, F> {
+    /// Create an interpreter for `f` as allocated by `out`.
+    pub fn new(f: &'a F, out: &'a Output) -> Self {
+        let mut safepoint_slots: FxHashMap<Inst, Vec<Allocation>> = FxHashMap::default();
+        for &(progpoint, slot) in &out.safepoint_slots {
+            safepoint_slots
+                .entry(progpoint.inst())
+                .or_default()
+                .push(slot);
+        }
+        Interpreter {
+            f,
+            out,
+            safepoint_slots,
+            reftype_vregs: f.reftype_vregs().iter().cloned().collect(),
+            vregs: FxHashMap::default(),
+            allocs: FxHashMap::default(),
+            steps: 0,
+        }
+    }
+
+    /// Execute the path chosen by `seed` from the entry block until
+    /// it returns, or until `fuel` instructions have been executed,
+    /// and check that both executions agree.
+    pub fn run(&mut self, seed: u64, fuel: usize) -> Result<(), InterpError> {
+        self.vregs.clear();
+        self.allocs.clear();
+        self.steps = 0;
+
+        for index in 0..PReg::NUM_INDEX {
+            let preg = PReg::from_index(index);
+            self.allocs
+                .insert(Allocation::reg(preg), initial_value(preg));
+        }
+        for &(vreg, preg) in self.f.entry_liveins() {
+            let value = mix(0x11_7e1e, vreg.vreg() as u64);
+            self.vregs.insert(vreg, value);
+            self.allocs.insert(Allocation::reg(preg), value);
+        }
+
+        let mut block = self.f.entry_block();
+        loop {
+            for inst_or_edit in self.out.block_insts_and_edits(self.f, block) {
+                match inst_or_edit {
+                    InstOrEdit::Inst(inst) => {
+                        if self.steps == fuel {
+                            return Ok(());
+                        }
+                        self.steps += 1;
+                        self.exec_inst(inst)?;
+                    }
+                    InstOrEdit::Edit(edit) => self.exec_edit(edit),
+                }
+            }
+
+            let last = self.f.block_insns(block).last();
+            let succs = self.f.block_succs(block);
+            if self.f.is_ret(last) || succs.is_empty() {
+                return Ok(());
+            }
+            let succ_idx = (mix(seed, self.steps as u64) % succs.len() as u64) as usize;
+            let succ = succs[succ_idx];
+            self.exec_edge(block, last, succ_idx, succ);
+            block = succ;
+        }
+    }
+
+    /// The value of `vreg` in the original function, if it has one.
+    /// A pinned vreg that has not been defined yet holds the
+    /// register's value on entry.
+    fn vreg_value(&self, vreg: VReg) -> Option<u64> {
+        self.vregs
+            .get(&vreg)
+            .cloned()
+            .or_else(|| self.f.is_pinned_vreg(vreg).map(initial_value))
+    }
+
+    /// Check that `alloc` holds the value of `op`'s vreg, returning
+    /// that value.
+    fn read(&self, inst: Inst, op: Operand, alloc: Allocation) -> Result<u64, InterpError> {
+        let actual = self.allocs.get(&alloc).cloned();
+        match self.vreg_value(op.vreg()) {
+            Some(expected) if actual != Some(expected) => Err(InterpError::WrongValue {
+                inst,
+                op,
+                alloc,
+                expected,
+                actual,
+            }),
+            Some(expected) => Ok(expected),
+            // The original function reads a vreg that it never
+            // defined; whatever the allocation holds will do.
+            None => Ok(actual.unwrap_or(0)),
+        }
+    }
+
+    fn exec_edit(&mut self, edit: &Edit) {
+        match *edit {
+            Edit::Move { from, to } => match self.allocs.get(&from).cloned() {
+                Some(value) => {
+                    self.allocs.insert(to, value);
+                }
+                None => {
+                    self.allocs.remove(&to);
+                }
+            },
+            Edit::Swap { a, b } => {
+                let value_a = self.allocs.remove(&a);
+                let value_b = self.allocs.remove(&b);
+                if let Some(value) = value_b {
+                    self.allocs.insert(a, value);
+                }
+                if let Some(value) = value_a {
+                    self.allocs.insert(b, value);
+                }
+            }
+        }
+    }
+
+    fn exec_inst(&mut self, inst: Inst) -> Result<(), InterpError> {
+        // A safepoint may reclaim any stack slot not in its stackmap
+        // that holds a reference, unless a vreg that is not reftyped
+        // holds the same value.
+        if self.f.requires_refs_on_stack(inst) {
+            let mut refs = FxHashSet::default();
+            let mut non_refs = FxHashSet::default();
+            for (vreg, &value) in &self.vregs {
+                if self.reftype_vregs.contains(vreg) {
+                    refs.insert(value);
+                } else {
+                    non_refs.insert(value);
+                }
+            }
+            let slots = self.safepoint_slots.get(&inst).cloned().unwrap_or_default();
+            for &alloc in &slots {
+                match self.allocs.get(&alloc) {
+                    Some(value) if !refs.contains(value) => {
+                        return Err(InterpError::NonRefValueInStackmap { inst, alloc });
+                    }
+                    _ => {}
+                }
+            }
+            self.allocs.retain(|alloc, value| {
+                alloc.kind() != AllocationKind::Stack
+                    || slots.contains(alloc)
+                    || !refs.contains(value)
+                    || non_refs.contains(value)
+            });
+        }
+
+        // Program moves have no allocations: the allocator implements
+        // them with edits. Only the register of a pinned source can
+        // be checked here.
+        if let Some((src, dst)) = self.f.is_move(inst) {
+            let value = match self.f.is_pinned_vreg(src.vreg()) {
+                Some(preg) => {
+                    let op = Operand::reg_fixed_use(src.vreg(), preg);
+                    self.read(inst, op, Allocation::reg(preg))?
+                }
+                None => self.vreg_value(src.vreg()).unwrap_or(0),
+            };
+            self.vregs.insert(dst.vreg(), value);
+            return Ok(());
+        }
+
+        let operands = self.f.inst_operands(inst);
+        let allocs = self.out.inst_allocs(inst);
+        let mut hash = mix(self.steps as u64, inst.index() as u64);
+        for (&op, &alloc) in operands.iter().zip(allocs.iter()) {
+            if op.kind() != OperandKind::Def && op.as_fixed_nonallocatable().is_none() {
+                hash = mix(hash, self.read(inst, op, alloc)?);
+            }
+        }
+
+        for preg in self.f.inst_clobbers(inst) {
+            self.allocs.remove(&Allocation::reg(preg));
+        }
+        for (i, (&op, &alloc)) in operands.iter().zip(allocs.iter()).enumerate() {
+            if op.kind() != OperandKind::Use && op.as_fixed_nonallocatable().is_none() {
+                let value = mix(hash, i as u64);
+                self.vregs.insert(op.vreg(), value);
+                self.allocs.insert(alloc, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the edge from `block`, which ends in `branch`, to its
+    /// `succ_idx`th successor `succ`.
+    fn exec_edge(&mut self, block: Block, branch: Inst, succ_idx: usize, succ: Block) {
+        for preg in self.f.branch_edge_clobbers(block, branch, succ_idx) {
+            self.allocs.remove(&Allocation::reg(preg));
+        }
+        for &(vreg, preg) in self.f.branch_edge_defs(block, branch, succ_idx) {
+            let value = mix(mix(self.steps as u64, vreg.vreg() as u64), succ_idx as u64);
+            self.vregs.insert(vreg, value);
+            self.allocs.insert(Allocation::reg(preg), value);
+        }
+
+        // Edits on a split critical edge come after the branch's
+        // effects on the edge.
+        let start = self
+            .out
+            .edge_edits
+            .partition_point(|&(from, to, _)| (from, to) < (block, succ));
+        for &(from, to, ref edit) in &self.out.edge_edits[start..] {
+            if (from, to) != (block, succ) {
+                break;
+            }
+            self.exec_edit(edit);
+        }
+
+        // Blockparams are assigned in parallel; the allocator has
+        // already implemented that with edits.
+        let values: Vec<_> = self
+            .f
+            .branch_blockparams(block, branch, succ_idx)
+            .iter()
+            .map(|&arg| self.vreg_value(arg))
+            .collect();
+        for (&param, value) in self.f.block_params(succ).iter().zip(values) {
+            match value {
+                Some(value) => {
+                    self.vregs.insert(param, value);
+                }
+                None => {
+                    self.vregs.remove(&param);
+                }
+            }
+        }
+    }
+}
@@ -12,19 +12,20 @@
 
 //! Data structures for backtracking allocator.
 
+use super::heuristics::Heuristics;
 use super::liveranges::SpillWeight;
 use crate::cfg::CFGInfo;
 use crate::index::ContainerComparator;
 use crate::indexset::IndexSet;
 use crate::{
-    define_index, Allocation, Block, Edit, Function, Inst, MachineEnv, Operand, PReg, ProgPoint,
-    RegClass, VReg,
+    define_index, Allocation, Block, Edit, Function, FxHashMap, FxHashSet, Inst, MachineEnv,
+    Operand, PReg, ProgPoint, RegClass, VReg,
 };
-use fxhash::FxHashSet;
+use alloc::collections::BTreeMap;
+use alloc::{string::String, vec::Vec};
+use core::cmp::Ordering;
+use core::fmt::Debug;
 use smallvec::SmallVec;
-use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::fmt::Debug;
 
 /// A range from `from` (inclusive) to `to` (exclusive).
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -64,13 +65,13 @@ impl CodeRange {
     }
 }
 
-impl std::cmp::PartialOrd for CodeRange {
+impl core::cmp::PartialOrd for CodeRange {
     #[inline(always)]
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
-impl std::cmp::Ord for CodeRange {
+impl core::cmp::Ord for CodeRange {
     #[inline(always)]
     fn cmp(&self, other: &Self) -> Ordering {
         if self.to <= other.from {
@@ -291,6 +292,9 @@ pub struct VRegData {
 pub struct PRegData {
     pub allocations: LiveRangeSet,
     pub is_stack: bool,
+    /// The cost per use of allocating a value here, from
+    /// `MachineEnv::reg_costs`.
+    pub cost: u32,
 }
 
 #[derive(Clone, Debug)]
@@ -363,9 +367,13 @@ impl BlockparamIn {
 }
 
 #[derive(Clone, Debug)]
-pub struct Env<'a, F: Function> {
+pub struct Env<'a, F: Function, H: Heuristics> {
     pub func: &'a F,
     pub env: &'a MachineEnv,
+    // The allocatable registers of each class, grouped into tiers in
+    // the order in which `RegTraversalIter` tries them.
+    pub reg_tiers_by_class: &'a [Vec<Vec<PReg>>; 2],
+    pub heuristics: &'a H,
     pub cfginfo: CFGInfo,
     pub liveins: Vec<IndexSet>,
     pub liveouts: Vec<IndexSet>,
@@ -379,7 +387,7 @@ pub struct Env<'a, F: Function> {
     pub pregs: Vec<PRegData>,
     pub allocation_queue: PrioQueue,
     pub safepoints: Vec<Inst>, // Sorted list of safepoint insts.
-    pub safepoints_per_vreg: HashMap<usize, HashSet<Inst>>,
+    pub safepoints_per_vreg: FxHashMap<usize, FxHashSet<Inst>>,
 
     pub spilled_bundles: Vec<LiveBundleIndex>,
     pub spillslots: Vec<SpillSlotData>,
@@ -413,6 +421,14 @@ pub struct Env<'a, F: Function> {
     // was to the approprate PReg.
     pub multi_fixed_reg_fixups: Vec<MultiFixedRegFixup>,
 
+    // Instructions with uses tied to other uses, which get no
+    // liverange uses of their own: their allocations are copied from
+    // the uses they are tied to at the very end.
+    pub tied_use_insts: Vec<Inst>,
+
+    // Instructions whose commutative inputs swapped roles, sorted.
+    pub commuted: Vec<Inst>,
+
     pub inserted_moves: Vec<InsertedMove>,
 
     // Output:
@@ -429,15 +445,17 @@ pub struct Env<'a, F: Function> {
 
     // For debug output only: a list of textual annotations at every
     // ProgPoint to insert into the final allocated program listing.
-    pub debug_annotations: std::collections::HashMap<ProgPoint, Vec<String>>,
+    pub debug_annotations: FxHashMap<ProgPoint, Vec<String>>,
     pub annotations_enabled: bool,
 
+    pub spill_placement_enabled: bool,
+
     // Cached allocation for `try_to_allocate_bundle_to_reg` to avoid allocating
     // a new HashSet on every call.
     pub conflict_set: FxHashSet<LiveBundleIndex>,
 }
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     /// Get the VReg (with bundled RegClass) from a vreg index.
     #[inline]
     pub fn vreg(&self, index: VRegIndex) -> VReg {
@@ -490,7 +508,7 @@ impl SpillSlotList {
 
 #[derive(Clone, Debug)]
 pub struct PrioQueue {
-    pub heap: std::collections::BinaryHeap<PrioQueueEntry>,
+    pub heap: alloc::collections::BinaryHeap<PrioQueueEntry>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -529,28 +547,28 @@ impl LiveRangeKey {
     }
 }
 
-impl std::cmp::PartialEq for LiveRangeKey {
+impl core::cmp::PartialEq for LiveRangeKey {
     #[inline(always)]
     fn eq(&self, other: &Self) -> bool {
         self.to > other.from && self.from < other.to
     }
 }
-impl std::cmp::Eq for LiveRangeKey {}
-impl std::cmp::PartialOrd for LiveRangeKey {
+impl core::cmp::Eq for LiveRangeKey {}
+impl core::cmp::PartialOrd for LiveRangeKey {
     #[inline(always)]
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
-impl std::cmp::Ord for LiveRangeKey {
+impl core::cmp::Ord for LiveRangeKey {
     #[inline(always)]
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
         if self.to <= other.from {
-            std::cmp::Ordering::Less
+            core::cmp::Ordering::Less
         } else if self.from >= other.to {
-            std::cmp::Ordering::Greater
+            core::cmp::Ordering::Greater
         } else {
-            std::cmp::Ordering::Equal
+            core::cmp::Ordering::Equal
         }
     }
 }
@@ -560,7 +578,7 @@ pub struct PrioQueueComparator<'a> {
 }
 impl<'a> ContainerComparator for PrioQueueComparator<'a> {
     type Ix = LiveBundleIndex;
-    fn compare(&self, a: Self::Ix, b: Self::Ix) -> std::cmp::Ordering {
+    fn compare(&self, a: Self::Ix, b: Self::Ix) -> core::cmp::Ordering {
         self.prios[a.index()].cmp(&self.prios[b.index()])
     }
 }
@@ -568,7 +586,7 @@ impl<'a> ContainerComparator for PrioQueueComparator<'a> {
 impl PrioQueue {
     pub fn new() -> Self {
         PrioQueue {
-            heap: std::collections::BinaryHeap::new(),
+            heap: alloc::collections::BinaryHeap::new(),
         }
     }
 
@@ -617,6 +635,7 @@ pub enum InsertMovePrio {
     MultiFixedRegInitial,
     MultiFixedRegSecondary,
     ReusedInput,
+    BranchArg,
     OutEdgeMoves,
 }
 
@@ -672,6 +691,8 @@ pub struct Stats {
     pub blockparam_ins_count: usize,
     pub blockparam_outs_count: usize,
     pub halfmoves_count: usize,
+    pub spill_placement_hoisted_stores: usize,
+    pub spill_placement_removed_moves: usize,
     pub edits_count: usize,
 }
 
@@ -1,9 +1,11 @@
 //! Debugging output.
 
-use super::Env;
+use super::{Env, Heuristics};
 use crate::{Block, Function, ProgPoint};
+use alloc::string::{String, ToString};
+use alloc::{format, vec, vec::Vec};
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     pub fn dump_state(&self) {
         trace!("Bundles:");
         for (i, b) in self.bundles.iter().enumerate() {
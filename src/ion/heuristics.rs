@@ -0,0 +1,173 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Pluggable heuristics for the allocator's cost model.
+
+use crate::cfg::CFGInfo;
+use crate::{Block, CodeRange, Inst, OperandConstraint, ProgPoint};
+
+/// The cost model that guides allocation: how costly each use is to
+/// spill, in which order bundles are allocated, and where a bundle
+/// that cannot be allocated whole is split.
+///
+/// Every method has a default that implements the allocator's
+/// standard behavior, which `DefaultHeuristics` uses; an
+/// implementation need only override the methods it wants to tune.
+/// Heuristics never affect correctness, only the quality of the
+/// allocation. Pass an implementation to `run_with_heuristics()`.
+pub trait Heuristics {
+    /// The spill weight of a use (or, if `is_def`, a def) with
+    /// `constraint`, in a block at `loop_depth`. A bundle's spill
+    /// weight is the sum of the weights of its uses, divided by its
+    /// priority; bundles with lower spill weights are evicted and
+    /// split in favor of those with higher ones.
+    ///
+    /// The weight is stored with the precision of a bfloat16, and
+    /// must be finite and non-negative.
+    fn use_spill_weight(
+        &self,
+        constraint: OperandConstraint,
+        loop_depth: u32,
+        is_def: bool,
+    ) -> f32 {
+        // A bonus of 1000 for one loop level, 4000 for two loop
+        // levels, 16000 for three loop levels, etc. Avoids
+        // exponentiation.
+        let loop_depth = core::cmp::min(10, loop_depth);
+        let hot_bonus: f32 = (0..loop_depth).fold(1000.0, |a, _| a * 4.0);
+        let def_bonus: f32 = if is_def { 2000.0 } else { 0.0 };
+        let constraint_bonus: f32 = match constraint {
+            OperandConstraint::Any => 1000.0,
+            OperandConstraint::Reg
+            | OperandConstraint::FixedReg(_)
+            | OperandConstraint::DistinctFrom(_) => 2000.0,
+            _ => 0.0,
+        };
+        hot_bonus + def_bonus + constraint_bonus
+    }
+
+    /// The priority of a bundle whose live ranges are `ranges`, in
+    /// order. Bundles with higher priorities are allocated first.
+    fn bundle_priority<I: Iterator<Item = CodeRange>>(&self, ranges: I) -> u32 {
+        // The priority is simply the total "length" -- the number of
+        // instructions covered by all ranges.
+        ranges.map(|range| range.len() as u32).sum()
+    }
+
+    /// The point at which to split a bundle that starts at
+    /// `bundle_start` and cannot be allocated to a register past
+    /// `conflict`, so that the first part can be. The result is
+    /// clamped to lie between the two.
+    fn split_point(
+        &self,
+        loops: &LoopInfo,
+        bundle_start: ProgPoint,
+        conflict: ProgPoint,
+    ) -> ProgPoint {
+        // If the conflict is within a deeper loop than the bundle
+        // start, hoist the split to just before the first loop header
+        // between them.
+        let start_block = loops.block(bundle_start.inst());
+        let conflict_block = loops.block(conflict.inst());
+        let start_depth = loops.loop_depth(start_block);
+        if loops.loop_depth(conflict_block) > start_depth {
+            for block in (start_block.index() + 1)..=conflict_block.index() {
+                let block = Block::new(block);
+                if loops.loop_depth(block) > start_depth {
+                    return loops.block_entry(block);
+                }
+            }
+        }
+        conflict
+    }
+}
+
+/// The allocator's standard heuristics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultHeuristics;
+
+impl Heuristics for DefaultHeuristics {}
+
+/// The loop structure of the function being allocated, for
+/// `Heuristics::split_point`.
+pub struct LoopInfo<'a> {
+    cfginfo: &'a CFGInfo,
+}
+
+impl<'a> LoopInfo<'a> {
+    pub(crate) fn new(cfginfo: &'a CFGInfo) -> Self {
+        Self { cfginfo }
+    }
+
+    /// The block containing `inst`.
+    pub fn block(&self, inst: Inst) -> Block {
+        self.cfginfo.insn_block[inst.index()]
+    }
+
+    /// The approximate loop depth of `block`: exact if the CFG is
+    /// reducible and its blocks are in RPO.
+    pub fn loop_depth(&self, block: Block) -> u32 {
+        self.cfginfo.approx_loop_depth[block.index()]
+    }
+
+    /// The point before the first instruction of `block`.
+    pub fn block_entry(&self, block: Block) -> ProgPoint {
+        self.cfginfo.block_entry[block.index()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{check, int, test_env};
+    use crate::{run_with_heuristics, FunctionBuilder, Operand, RegallocOptions};
+    use alloc::vec::Vec;
+
+    /// Weighs all uses alike, allocates short bundles first and splits
+    /// right at the conflict.
+    struct Flat;
+
+    impl Heuristics for Flat {
+        fn use_spill_weight(&self, _: OperandConstraint, _: u32, _: bool) -> f32 {
+            1000.0
+        }
+        fn bundle_priority<I: Iterator<Item = CodeRange>>(&self, ranges: I) -> u32 {
+            let len: u32 = ranges.map(|range| range.len() as u32).sum();
+            1000u32.saturating_sub(len).max(1)
+        }
+        fn split_point(&self, _: &LoopInfo, _: ProgPoint, conflict: ProgPoint) -> ProgPoint {
+            conflict
+        }
+    }
+
+    #[test]
+    fn test_custom_heuristics() {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        for i in 0..4 {
+            b.add_inst(entry, &[Operand::reg_def(int(i))]);
+        }
+        for i in 0..4 {
+            b.add_inst(
+                entry,
+                &[
+                    Operand::reg_def(int(4 + i)),
+                    Operand::reg_use(int(i)),
+                    Operand::reg_use(int((i + 1) % 4)),
+                ],
+            );
+        }
+        b.add_ret(
+            entry,
+            &(4..8).map(|i| Operand::any_use(int(i))).collect::<Vec<_>>(),
+        );
+        let f = b.build();
+
+        let env = test_env(3);
+        let out = run_with_heuristics(&f, &env, &RegallocOptions::default(), &Flat).unwrap();
+        assert!(out.num_spillslots > 0);
+        check(&f, &env, &out);
+    }
+}
@@ -13,46 +13,28 @@
 //! Live-range computation.
 
 use super::{
-    CodeRange, Env, InsertMovePrio, LiveBundle, LiveBundleIndex, LiveRange, LiveRangeFlag,
-    LiveRangeIndex, LiveRangeKey, LiveRangeListEntry, LiveRangeSet, PRegData, PRegIndex, RegClass,
-    SpillSetIndex, Use, VRegData, VRegIndex, SLOT_NONE,
+    CodeRange, Env, Heuristics, InsertMovePrio, LiveBundle, LiveBundleIndex, LiveRange,
+    LiveRangeFlag, LiveRangeIndex, LiveRangeKey, LiveRangeListEntry, LiveRangeSet, PRegData,
+    PRegIndex, RegClass, SpillSetIndex, Use, VRegData, VRegIndex, SLOT_NONE,
 };
 use crate::indexset::IndexSet;
 use crate::ion::data_structures::{
     BlockparamIn, BlockparamOut, FixedRegFixupLevel, MultiFixedRegFixup,
 };
 use crate::{
-    Allocation, Block, Function, Inst, InstPosition, Operand, OperandConstraint, OperandKind,
-    OperandPos, PReg, ProgPoint, RegAllocError, VReg,
+    input_reused_by, tied_to_use, Allocation, Block, Function, FxHashMap, FxHashSet, Inst,
+    InstPosition, Operand, OperandConstraint, OperandKind, OperandPos, PReg, PRegSet, ProgPoint,
+    RegAllocError, VReg,
 };
-use fxhash::{FxHashMap, FxHashSet};
+use alloc::collections::VecDeque;
+use alloc::{format, vec, vec::Vec};
 use slice_group_by::GroupByMut;
 use smallvec::{smallvec, SmallVec};
-use std::collections::{HashSet, VecDeque};
 
 /// A spill weight computed for a certain Use.
 #[derive(Clone, Copy, Debug)]
 pub struct SpillWeight(f32);
 
-#[inline(always)]
-pub fn spill_weight_from_constraint(
-    constraint: OperandConstraint,
-    loop_depth: usize,
-    is_def: bool,
-) -> SpillWeight {
-    // A bonus of 1000 for one loop level, 4000 for two loop levels,
-    // 16000 for three loop levels, etc. Avoids exponentiation.
-    let loop_depth = std::cmp::min(10, loop_depth);
-    let hot_bonus: f32 = (0..loop_depth).fold(1000.0, |a, _| a * 4.0);
-    let def_bonus: f32 = if is_def { 2000.0 } else { 0.0 };
-    let constraint_bonus: f32 = match constraint {
-        OperandConstraint::Any => 1000.0,
-        OperandConstraint::Reg | OperandConstraint::FixedReg(_) => 2000.0,
-        _ => 0.0,
-    };
-    SpillWeight(hot_bonus + def_bonus + constraint_bonus)
-}
-
 impl SpillWeight {
     /// Convert a floating-point weight to a u16 that can be compactly
     /// stored in a `Use`. We simply take the top 16 bits of the f32; this
@@ -91,14 +73,14 @@ impl SpillWeight {
     }
 }
 
-impl std::ops::Add<SpillWeight> for SpillWeight {
+impl core::ops::Add<SpillWeight> for SpillWeight {
     type Output = SpillWeight;
     fn add(self, other: SpillWeight) -> Self {
         SpillWeight(self.0 + other.0)
     }
 }
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     pub fn create_pregs_and_vregs(&mut self) {
         // Create PRegs from the env.
         self.pregs.resize(
@@ -106,11 +88,15 @@ impl<'a, F: Function> Env<'a, F> {
             PRegData {
                 allocations: LiveRangeSet::new(),
                 is_stack: false,
+                cost: 0,
             },
         );
         for &preg in &self.env.fixed_stack_slots {
             self.pregs[preg.index()].is_stack = true;
         }
+        for &(preg, cost) in &self.env.reg_costs {
+            self.pregs[preg.index()].cost = cost;
+        }
         for class in 0..self.preferred_victim_by_class.len() {
             self.preferred_victim_by_class[class] = self.env.non_preferred_regs_by_class[class]
                 .last()
@@ -261,12 +247,12 @@ impl<'a, F: Function> Env<'a, F> {
         let operand = u.operand;
         let constraint = operand.constraint();
         let block = self.cfginfo.insn_block[u.pos.inst().index()];
-        let loop_depth = self.cfginfo.approx_loop_depth[block.index()] as usize;
-        let weight = spill_weight_from_constraint(
+        let loop_depth = self.cfginfo.approx_loop_depth[block.index()];
+        let weight = SpillWeight::from_f32(self.heuristics.use_spill_weight(
             constraint,
             loop_depth,
             operand.kind() != OperandKind::Use,
-        );
+        ));
         u.weight = weight.to_bits();
 
         trace!(
@@ -317,6 +303,41 @@ impl<'a, F: Function> Env<'a, F> {
         self.liveins[block.index()].get(vreg.index())
     }
 
+    /// The vregs defined, in fixed registers, on the edge into
+    /// `block`. Only a block with a single predecessor can have
+    /// these (this is checked when computing the `CFGInfo`).
+    pub fn edge_defs_into(&self, block: Block) -> &'a [(VReg, PReg)] {
+        let func = self.func;
+        let pred = match func.block_preds(block) {
+            &[pred] => pred,
+            _ => return &[],
+        };
+        let last = func.block_insns(pred).last();
+        if !func.is_branch(last) {
+            return &[];
+        }
+        let succ_idx = func
+            .block_succs(pred)
+            .iter()
+            .position(|&succ| succ == block)
+            .unwrap();
+        func.branch_edge_defs(pred, last, succ_idx)
+    }
+
+    /// The registers clobbered along any edge out of the branch
+    /// `inst` at the end of `block`: the union of its edge clobbers
+    /// and the registers of its edge defs.
+    pub fn branch_edge_clobbers(&self, block: Block, inst: Inst) -> PRegSet {
+        let mut clobbers = PRegSet::empty();
+        for i in 0..self.func.block_succs(block).len() {
+            clobbers.union_from(self.func.branch_edge_clobbers(block, inst, i));
+            for &(_, preg) in self.func.branch_edge_defs(block, inst, i) {
+                clobbers.add(preg);
+            }
+        }
+        clobbers
+    }
+
     pub fn compute_liveness(&mut self) -> Result<(), RegAllocError> {
         // Create initial LiveIn and LiveOut bitsets.
         for _ in 0..self.func.num_blocks() {
@@ -389,6 +410,10 @@ impl<'a, F: Function> Env<'a, F> {
                 live.set(blockparam.vreg(), false);
                 self.observe_vreg_class(blockparam);
             }
+            for &(vreg, _) in self.edge_defs_into(block) {
+                live.set(vreg.vreg(), false);
+                self.observe_vreg_class(vreg);
+            }
 
             for &pred in self.func.block_preds(block) {
                 if self.liveouts[pred.index()].union_with(&live) {
@@ -404,12 +429,21 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         // Check that there are no liveins to the entry block, except
-        // for pinned vregs. (The client should create a virtual
-        // instruction that defines any other liveins if necessary.)
-        for livein in self.liveins[self.func.entry_block().index()].iter() {
+        // for pinned vregs and the declared entry liveins. The latter
+        // are defined by moves at the top of the entry block, so they
+        // are only allowed if control never re-enters it.
+        let entry = self.func.entry_block();
+        let entry_liveins = self.func.entry_liveins();
+        if !entry_liveins.is_empty() && !self.func.block_preds(entry).is_empty() {
+            trace!("entry liveins given, but entry block has preds");
+            return Err(RegAllocError::EntryLivein);
+        }
+        for livein in self.liveins[entry.index()].iter() {
             let livein = self.vreg(VRegIndex::new(livein));
-            if self.func.is_pinned_vreg(livein).is_none() {
-                trace!("non-pinned-vreg livein to entry block: {}", livein);
+            if self.func.is_pinned_vreg(livein).is_none()
+                && !entry_liveins.iter().any(|&(vreg, _)| vreg == livein)
+            {
+                trace!("undeclared livein to entry block: {}", livein);
                 return Err(RegAllocError::EntryLivein);
             }
         }
@@ -417,9 +451,223 @@ impl<'a, F: Function> Env<'a, F> {
         Ok(())
     }
 
-    pub fn build_liveranges(&mut self) {
+    /// Check every instruction's operand constraints for
+    /// combinations that no allocation can satisfy, so that they are
+    /// reported as errors rather than tripping up the allocator later.
+    pub fn check_operand_constraints(&self) -> Result<(), RegAllocError> {
+        let mut allocatable = PRegSet::empty();
+        let mut num_regs = [0; 2];
+        for class in [RegClass::Int, RegClass::Float] {
+            for &preg in self.env.preferred_regs_by_class[class as usize]
+                .iter()
+                .chain(self.env.non_preferred_regs_by_class[class as usize].iter())
+            {
+                allocatable.add(preg);
+                num_regs[class as usize] += 1;
+            }
+        }
+
+        for inst in 0..self.func.num_insts() {
+            let inst = Inst::new(inst);
+            let operands = self.func.inst_operands(inst);
+
+            // As in `build_liveranges`, the presence of a reused
+            // input extends all other uses to the Late point, and a
+            // def that must differ from an input extends that input.
+            let mut reused_input = None;
+            let mut distinct_inputs: SmallVec<[VReg; 2]> = smallvec![];
+            for (i, op) in operands.iter().enumerate() {
+                if let Some(input) = input_reused_by(operands, i) {
+                    reused_input = operands.get(input).map(|op| op.vreg());
+                }
+                if let OperandConstraint::DistinctFrom(input) = op.constraint() {
+                    distinct_inputs.extend(operands.get(input).map(|op| op.vreg()));
+                }
+            }
+
+            // Fixed registers claimed at each point, with the index
+            // of the claiming operand, and the vregs that need an
+            // allocatable register at each point, with the fixed
+            // register they need if any. A vreg used in several fixed
+            // registers at once occupies each of them.
+            let mut fixed: SmallVec<[(InstPosition, PReg, usize); 8]> = smallvec![];
+            let mut needs_reg: SmallVec<[(InstPosition, VReg, Option<PReg>); 8]> = smallvec![];
+            for (i, op) in operands.iter().enumerate() {
+                // A use tied to another use needs nothing beyond what
+                // that use needs.
+                if op.as_fixed_nonallocatable().is_some() || tied_to_use(operands, i).is_some() {
+                    continue;
+                }
+                let (early, late) = match (op.kind(), op.pos()) {
+                    (OperandKind::Use, OperandPos::Early) => (true, false),
+                    (OperandKind::Def, OperandPos::Late) => (false, true),
+                    _ => (true, true),
+                };
+                let requires_reg = match op.constraint() {
+                    OperandConstraint::Reg
+                    | OperandConstraint::Reuse(_)
+                    | OperandConstraint::DistinctFrom(_) => true,
+                    OperandConstraint::FixedReg(preg) => allocatable.contains(preg),
+                    OperandConstraint::Any | OperandConstraint::Stack => false,
+                };
+                let extended = op.kind() == OperandKind::Use
+                    && ((reused_input.is_some() && reused_input != Some(op.vreg()))
+                        || distinct_inputs.contains(&op.vreg()));
+
+                for &(pos, at) in &[(InstPosition::Before, early), (InstPosition::After, late)] {
+                    if let OperandConstraint::FixedReg(preg) = op.constraint() {
+                        if at {
+                            if let Some(&(_, _, j)) = fixed.iter().find(|&&(p, r, j)| {
+                                p == pos && r == preg && operands[j].vreg() != op.vreg()
+                            }) {
+                                return Err(RegAllocError::FixedRegConflict {
+                                    inst,
+                                    operands: (j, i),
+                                    preg,
+                                });
+                            }
+                            fixed.push((pos, preg, i));
+                        }
+                    }
+                    let preg = match op.constraint() {
+                        OperandConstraint::FixedReg(preg) => Some(preg),
+                        _ => None,
+                    };
+                    let need = (pos, op.vreg(), preg);
+                    if requires_reg && (at || extended) && !needs_reg.contains(&need) {
+                        needs_reg.push(need);
+                    }
+                }
+            }
+
+            for &pos in &[InstPosition::Before, InstPosition::After] {
+                for class in [RegClass::Int, RegClass::Float] {
+                    let at = |&&(p, vreg, _): &&(InstPosition, VReg, Option<PReg>)| {
+                        p == pos && vreg.class() == class
+                    };
+                    // Each distinct fixed register counts once, as does
+                    // each vreg that is not already in one of them.
+                    let mut pregs: SmallVec<[PReg; 8]> = smallvec![];
+                    let mut needed = 0;
+                    for &(_, vreg, preg) in needs_reg.iter().filter(at) {
+                        match preg {
+                            Some(preg) if !pregs.contains(&preg) => {
+                                pregs.push(preg);
+                                needed += 1;
+                            }
+                            Some(_) => {}
+                            None if !needs_reg
+                                .iter()
+                                .filter(at)
+                                .any(|&(_, v, p)| v == vreg && p.is_some()) =>
+                            {
+                                needed += 1;
+                            }
+                            None => {}
+                        }
+                    }
+                    let available = num_regs[class as usize];
+                    if needed > available {
+                        return Err(RegAllocError::TooManyRegOperands {
+                            inst,
+                            class,
+                            needed,
+                            available,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// If a def of `inst` reuses one of the instruction's commutative
+    /// inputs, whose vreg is live after `inst` (per `live`) while the
+    /// other input's is not, returns the index of the def, of the
+    /// input it reuses and of the other input, which it should reuse
+    /// instead.
+    fn commute_reused_input(&self, inst: Inst, live: &IndexSet) -> Option<(usize, usize, usize)> {
+        let (a, b) = self.func.commutative_operands(inst)?;
+        if self.func.is_branch(inst) || self.func.is_move(inst).is_some() {
+            return None;
+        }
+        let operands = self.func.inst_operands(inst);
+        let (def, from) =
+            operands
+                .iter()
+                .enumerate()
+                .find_map(|(i, op)| match (op.kind(), op.constraint()) {
+                    (OperandKind::Def, OperandConstraint::Reuse(r)) if r == a || r == b => {
+                        Some((i, r))
+                    }
+                    _ => None,
+                })?;
+        let to = if from == a { b } else { a };
+        let (from_vreg, to_vreg) = (operands[from].vreg(), operands[to].vreg());
+
+        // Both inputs must be plain early register uses, and their
+        // vregs must not appear elsewhere in the instruction, so that
+        // swapping their constraints leaves the instruction's demand
+        // for registers at each point as it was.
+        let plain = |op: &Operand| {
+            op.kind() == OperandKind::Use
+                && op.pos() == OperandPos::Early
+                && matches!(
+                    op.constraint(),
+                    OperandConstraint::Reg | OperandConstraint::Any
+                )
+        };
+        if !plain(&operands[from])
+            || !plain(&operands[to])
+            || from_vreg.class() != to_vreg.class()
+            || from_vreg == to_vreg
+            || self.func.is_pinned_vreg(from_vreg).is_some()
+            || self.func.is_pinned_vreg(to_vreg).is_some()
+        {
+            return None;
+        }
+        let elsewhere = operands.iter().enumerate().any(|(i, op)| {
+            i != from
+                && i != to
+                && op.as_fixed_nonallocatable().is_none()
+                && (op.vreg() == from_vreg || op.vreg() == to_vreg)
+        });
+        if elsewhere || !live.get(from_vreg.vreg()) || live.get(to_vreg.vreg()) {
+            return None;
+        }
+        trace!(
+            "inst{}: def {} reuses input {} rather than {}",
+            inst.index(),
+            def,
+            to,
+            from
+        );
+        Some((def, from, to))
+    }
+
+    /// The index of the input that a def of `inst` with a
+    /// `Reuse(idx)` constraint reuses: `idx`, unless the commutative
+    /// inputs of `inst` swapped roles.
+    pub fn reused_input(&self, inst: Inst, idx: usize) -> usize {
+        match self.func.commutative_operands(inst) {
+            Some((a, b)) if self.commuted.binary_search(&inst).is_ok() => {
+                if idx == a {
+                    b
+                } else if idx == b {
+                    a
+                } else {
+                    idx
+                }
+            }
+            _ => idx,
+        }
+    }
+
+    pub fn build_liveranges(&mut self) -> Result<(), RegAllocError> {
         for &vreg in self.func.reftype_vregs() {
-            self.safepoints_per_vreg.insert(vreg.vreg(), HashSet::new());
+            self.safepoints_per_vreg
+                .insert(vreg.vreg(), FxHashSet::default());
         }
 
         // Create Uses and Defs referring to VRegs, and place the Uses
@@ -493,8 +741,15 @@ impl<'a, F: Function> Env<'a, F> {
             // For each instruction, in reverse order, process
             // operands and clobbers.
             for inst in insns.rev().iter() {
-                // Mark clobbers with CodeRanges on PRegs.
-                for clobber in self.func.inst_clobbers(inst) {
+                // Mark clobbers with CodeRanges on PRegs. Edge
+                // clobbers of a branch are included here too: we
+                // cannot tell them apart per successor, so a value
+                // live across the branch avoids all of them.
+                let mut clobbers = self.func.inst_clobbers(inst);
+                if self.func.is_branch(inst) {
+                    clobbers.union_from(self.branch_edge_clobbers(block, inst));
+                }
+                for clobber in clobbers {
                     // Clobber range is at After point only: an
                     // instruction can still take an input in a reg
                     // that it later clobbers. (In other words, the
@@ -507,21 +762,43 @@ impl<'a, F: Function> Env<'a, F> {
                     self.add_liverange_to_preg(range, clobber);
                 }
 
+                // If a def reuses one of two commutative inputs that
+                // is still live afterward, while the other is not,
+                // swap the inputs' roles: reusing the other input
+                // saves a copy.
+                let commuted = self.commute_reused_input(inst, &live);
+                if commuted.is_some() {
+                    self.commuted.push(inst);
+                }
+
                 // Does the instruction have any input-reusing
                 // outputs? This is important below to establish
                 // proper interference wrt other inputs. We note the
                 // *vreg* that is reused, not the index.
                 let mut reused_input = None;
-                for op in self.func.inst_operands(inst) {
-                    if let OperandConstraint::Reuse(i) = op.constraint() {
-                        debug_assert!(self.func.inst_operands(inst)[i]
-                            .as_fixed_nonallocatable()
-                            .is_none());
-                        reused_input = Some(self.func.inst_operands(inst)[i].vreg());
+                let operands = self.func.inst_operands(inst);
+                for def in 0..operands.len() {
+                    if let Some(i) = input_reused_by(operands, def) {
+                        let i = match commuted {
+                            Some((_, from, to)) if i == from => to,
+                            _ => i,
+                        };
+                        debug_assert!(operands[i].as_fixed_nonallocatable().is_none());
+                        reused_input = Some(operands[i].vreg());
                         break;
                     }
                 }
 
+                // Likewise, note the inputs that some def must not
+                // share a register with: extending them to the After
+                // point makes them interfere with the defs.
+                let mut distinct_inputs: SmallVec<[VReg; 2]> = smallvec![];
+                for op in self.func.inst_operands(inst) {
+                    if let OperandConstraint::DistinctFrom(i) = op.constraint() {
+                        distinct_inputs.push(self.func.inst_operands(inst)[i].vreg());
+                    }
+                }
+
                 // If this is a move, handle specially.
                 if let Some((src, dst)) = self.func.is_move(inst) {
                     // We can completely skip the move if it is
@@ -935,8 +1212,39 @@ impl<'a, F: Function> Env<'a, F> {
                 // constraint, and (ii) move the def to Early position
                 // to reserve the register for the whole instruction.
                 let mut operand_rewrites: FxHashMap<usize, Operand> = FxHashMap::default();
+                if let Some((def, from, to)) = commuted {
+                    let operands = self.func.inst_operands(inst);
+                    let (def_op, from_op, to_op) = (operands[def], operands[from], operands[to]);
+                    operand_rewrites.insert(
+                        def,
+                        Operand::new(
+                            def_op.vreg(),
+                            OperandConstraint::Reuse(to),
+                            def_op.kind(),
+                            def_op.pos(),
+                        ),
+                    );
+                    operand_rewrites.insert(
+                        from,
+                        Operand::new(
+                            from_op.vreg(),
+                            to_op.constraint(),
+                            OperandKind::Use,
+                            OperandPos::Early,
+                        ),
+                    );
+                    operand_rewrites.insert(
+                        to,
+                        Operand::new(
+                            to_op.vreg(),
+                            from_op.constraint(),
+                            OperandKind::Use,
+                            OperandPos::Early,
+                        ),
+                    );
+                }
                 let mut late_def_fixed: SmallVec<[PReg; 8]> = smallvec![];
-                for &operand in self.func.inst_operands(inst) {
+                for (i, &operand) in self.func.inst_operands(inst).iter().enumerate() {
                     if let OperandConstraint::FixedReg(preg) = operand.constraint() {
                         match operand.pos() {
                             OperandPos::Late => {
@@ -946,10 +1254,13 @@ impl<'a, F: Function> Env<'a, F> {
                                 // into place for a late use *after*
                                 // the early point (i.e. in the middle
                                 // of the instruction).
-                                assert!(
-                                    operand.kind() == OperandKind::Def,
-                                    "Invalid operand: fixed constraint on Use/Mod at Late point"
-                                );
+                                if operand.kind() != OperandKind::Def {
+                                    return Err(RegAllocError::InvalidFixedRegOperand {
+                                        inst,
+                                        operand: i,
+                                        preg,
+                                    });
+                                }
 
                                 late_def_fixed.push(preg);
                             }
@@ -964,8 +1275,13 @@ impl<'a, F: Function> Env<'a, F> {
                     if let OperandConstraint::FixedReg(preg) = operand.constraint() {
                         match operand.pos() {
                             OperandPos::Early if live.get(operand.vreg().vreg()) => {
-                                assert!(operand.kind() == OperandKind::Use,
-                                            "Invalid operand: fixed constraint on Def/Mod at Early position");
+                                if operand.kind() != OperandKind::Use {
+                                    return Err(RegAllocError::InvalidFixedRegOperand {
+                                        inst,
+                                        operand: i,
+                                        preg,
+                                    });
+                                }
 
                                 // If we have a constraint at the
                                 // Early point for a fixed preg, and
@@ -1059,6 +1375,11 @@ impl<'a, F: Function> Env<'a, F> {
                             {
                                 ProgPoint::after(inst)
                             }
+                            (OperandKind::Use, OperandPos::Early)
+                                if distinct_inputs.contains(&operand.vreg()) =>
+                            {
+                                ProgPoint::after(inst)
+                            }
                             (OperandKind::Use, OperandPos::Early) => ProgPoint::before(inst),
                         };
 
@@ -1082,6 +1403,17 @@ impl<'a, F: Function> Env<'a, F> {
                             continue;
                         }
 
+                        // A use tied to another use of the same vreg
+                        // at the same point adds nothing to the
+                        // liverange; it is given the other use's
+                        // allocation once that is final.
+                        if tied_to_use(self.func.inst_operands(inst), i).is_some() {
+                            if self.tied_use_insts.last() != Some(&inst) {
+                                self.tied_use_insts.push(inst);
+                            }
+                            continue;
+                        }
+
                         match operand.kind() {
                             OperandKind::Def | OperandKind::Mod => {
                                 trace!("Def of {} at {:?}", operand.vreg(), pos);
@@ -1176,6 +1508,13 @@ impl<'a, F: Function> Env<'a, F> {
                 }
             }
 
+            // Edge defs, like block parameters below, define vregs
+            // at the very beginning of the block. If dead, they need
+            // no range at all: nothing refers to them.
+            for &(vreg, _) in self.edge_defs_into(block) {
+                live.set(vreg.vreg(), false);
+            }
+
             // Block parameters define vregs at the very beginning of
             // the block. Remove their live vregs from the live set
             // here.
@@ -1293,6 +1632,7 @@ impl<'a, F: Function> Env<'a, F> {
 
         self.blockparam_ins.sort_unstable_by_key(|x| x.key());
         self.blockparam_outs.sort_unstable_by_key(|x| x.key());
+        self.commuted.sort_unstable();
         self.prog_move_srcs.sort_unstable_by_key(|(pos, _)| *pos);
         self.prog_move_dsts.sort_unstable_by_key(|(pos, _)| *pos);
 
@@ -1302,9 +1642,11 @@ impl<'a, F: Function> Env<'a, F> {
         self.stats.initial_liverange_count = self.ranges.len();
         self.stats.blockparam_ins_count = self.blockparam_ins.len();
         self.stats.blockparam_outs_count = self.blockparam_outs.len();
+
+        Ok(())
     }
 
-    pub fn fixup_multi_fixed_vregs(&mut self) {
+    pub fn fixup_multi_fixed_vregs(&mut self) -> Result<(), RegAllocError> {
         // Do a fixed-reg cleanup pass: if there are any LiveRanges with
         // multiple uses (or defs) at the same ProgPoint and there is
         // more than one FixedReg constraint at that ProgPoint, we
@@ -1346,7 +1688,9 @@ impl<'a, F: Function> Env<'a, F> {
                                 first_reg_slot.get_or_insert(u.slot);
                                 first_stack_slot.get_or_insert(u.slot);
                             }
-                            OperandConstraint::Reg | OperandConstraint::Reuse(_) => {
+                            OperandConstraint::Reg
+                            | OperandConstraint::Reuse(_)
+                            | OperandConstraint::DistinctFrom(_) => {
                                 first_reg_slot.get_or_insert(u.slot);
                                 requires_reg = true;
                             }
@@ -1361,9 +1705,12 @@ impl<'a, F: Function> Env<'a, F> {
                                 }
                             }
                             // Maybe this could be supported in this future...
-                            OperandConstraint::Stack => panic!(
-                                "multiple uses of vreg with a Stack constraint are not supported"
-                            ),
+                            OperandConstraint::Stack => {
+                                return Err(RegAllocError::MultipleStackUses {
+                                    inst: u.pos.inst(),
+                                    vreg: u.operand.vreg(),
+                                });
+                            }
                         }
                     }
 
@@ -1439,5 +1786,316 @@ impl<'a, F: Function> Env<'a, F> {
                 extra_clobbers.clear();
             }
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::checker::{Checker, CheckerError};
+    use crate::testutil::{check, int, test_env};
+    use crate::{
+        run, validate, Allocation, FunctionBuilder, Inst, Operand, OperandConstraint, OperandKind,
+        OperandPos, Output, PReg, PRegSet, ProgPoint, RegAllocError, RegClass, RegallocOptions,
+    };
+    use alloc::vec;
+
+    fn p(i: usize) -> PReg {
+        PReg::new(i, RegClass::Int)
+    }
+
+    /// Allocates `v2 = add v0, v1`, whose def reuses `v0` and whose
+    /// inputs are commutative, returning whether they were commuted.
+    /// `v1` is live afterward if `keep_v1`.
+    fn commuted(keep_v1: bool) -> bool {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        b.add_entry_livein(int(0), PReg::new(0, RegClass::Int));
+        b.add_entry_livein(int(1), PReg::new(1, RegClass::Int));
+        let add = b.add_inst(
+            entry,
+            &[
+                Operand::reg_reuse_def(int(2), 1),
+                Operand::reg_use(int(0)),
+                Operand::reg_use(int(1)),
+            ],
+        );
+        b.set_commutative_operands(add, 1, 2);
+        let mut rets = vec![Operand::reg_use(int(0)), Operand::reg_use(int(2))];
+        if keep_v1 {
+            rets.push(Operand::reg_use(int(1)));
+        }
+        b.add_ret(entry, &rets);
+        let f = b.build();
+
+        let env = test_env(4);
+        validate(&f, &env, &RegallocOptions::default()).unwrap();
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+        let allocs = out.inst_allocs(add);
+        if out.is_commuted(add) {
+            assert_eq!(allocs[0], allocs[2]);
+            true
+        } else {
+            assert_eq!(allocs[0], allocs[1]);
+            false
+        }
+    }
+
+    #[test]
+    fn test_commutative() {
+        // `v0` is live afterward while `v1` is not: reusing `v1`
+        // instead saves a copy.
+        assert!(commuted(false));
+        // Both are live afterward: either reuse needs a copy, so the
+        // declared one stands.
+        assert!(!commuted(true));
+    }
+
+    /// Allocates `v2 = op v0, v1`, whose def is written to the
+    /// register that the use of `v0`, tied to it, is read from,
+    /// returning the number of moves before the instruction. `v0` is
+    /// live afterward if `keep_v0`.
+    fn tied_to_def(keep_v0: bool) -> usize {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        b.add_entry_livein(int(0), PReg::new(0, RegClass::Int));
+        b.add_entry_livein(int(1), PReg::new(1, RegClass::Int));
+        let op = b.add_inst(
+            entry,
+            &[
+                Operand::reg_def(int(2)),
+                Operand::reg_tied_use(int(0), 0),
+                Operand::reg_use(int(1)),
+            ],
+        );
+        let mut rets = vec![Operand::reg_use(int(1)), Operand::reg_use(int(2))];
+        if keep_v0 {
+            rets.push(Operand::reg_use(int(0)));
+        }
+        b.add_ret(entry, &rets);
+        let f = b.build();
+
+        let env = test_env(4);
+        validate(&f, &env, &RegallocOptions::default()).unwrap();
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+        let allocs = out.inst_allocs(op);
+        assert_eq!(allocs[0], allocs[1]);
+        assert_ne!(allocs[0], allocs[2]);
+        out.edits
+            .iter()
+            .filter(|(pos, _)| *pos == ProgPoint::before(op))
+            .count()
+    }
+
+    #[test]
+    fn test_use_tied_to_def() {
+        // As with a reused input, v0 is copied into the def's register
+        // only if it must outlive the instruction.
+        assert_eq!(tied_to_def(false), 0);
+        assert_eq!(tied_to_def(true), 1);
+    }
+
+    /// Allocates `v2 = op v0, v1`, whose def must not share the
+    /// register of `v0`, and checks that it does not. `v0` is live
+    /// afterward if `keep_v0`. Then checks that the checker rejects
+    /// the allocation with the def moved into `v0`'s register.
+    fn distinct_def(keep_v0: bool) {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        b.add_entry_livein(int(0), PReg::new(0, RegClass::Int));
+        b.add_entry_livein(int(1), PReg::new(1, RegClass::Int));
+        let op = b.add_inst(
+            entry,
+            &[
+                Operand::reg_distinct_def(int(2), 1),
+                Operand::reg_use(int(0)),
+                Operand::reg_use(int(1)),
+            ],
+        );
+        let mut rets = vec![Operand::reg_use(int(1)), Operand::reg_use(int(2))];
+        if keep_v0 {
+            rets.push(Operand::reg_use(int(0)));
+        }
+        b.add_ret(entry, &rets);
+        let f = b.build();
+
+        let env = test_env(4);
+        validate(&f, &env, &RegallocOptions::default()).unwrap();
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+        let allocs = out.inst_allocs(op);
+        assert!(allocs[0].is_reg());
+        assert_ne!(allocs[0], allocs[1]);
+
+        let mut bad = out.clone();
+        let offset = bad.inst_alloc_offsets[op.index()] as usize;
+        bad.allocs[offset] = bad.allocs[offset + 1];
+        let mut checker = Checker::new(&f, &env);
+        checker.prepare(&bad);
+        let errors = checker.run().unwrap_err().errors;
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            CheckerError::AllocationIsNotDistinct { inst, .. } if *inst == op
+        )));
+    }
+
+    #[test]
+    fn test_distinct_def() {
+        // Whether or not v0 outlives the instruction, v2 may not take
+        // its register.
+        distinct_def(false);
+        distinct_def(true);
+    }
+
+    #[test]
+    fn test_edge_effects() {
+        // The edge to b1 clobbers p0 and p1 and defines v1 in p3, so
+        // v0 must cross the branch in p2; v1 is used where it arrives.
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        let b1 = b.add_block();
+        b.add_inst(b0, &[Operand::reg_def(int(0))]);
+        b.add_branch(b0, &[], &[b1]);
+        b.set_edge_clobbers(b0, 0, PRegSet::empty().with(p(0)).with(p(1)));
+        b.add_edge_def(b0, 0, int(1), p(3));
+        let ret = b.add_ret(
+            b1,
+            &[
+                Operand::reg_use(int(0)),
+                Operand::reg_fixed_use(int(1), p(3)),
+            ],
+        );
+        let f = b.build();
+
+        let env = test_env(4);
+        validate(&f, &env, &RegallocOptions::default()).unwrap();
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+        assert_eq!(
+            out.inst_allocs(ret),
+            &[Allocation::reg(p(2)), Allocation::reg(p(3))]
+        );
+        assert!(out.edits.is_empty());
+    }
+
+    /// Allocates a function that defines `v0` through `v3` and then
+    /// runs `operands` as one instruction, with `num_regs` registers
+    /// per class. Returns that instruction and the result.
+    fn run_operands(
+        operands: &[Operand],
+        num_regs: usize,
+    ) -> (Inst, Result<Output, RegAllocError>) {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        for v in 0..4 {
+            b.add_inst(entry, &[Operand::reg_def(int(v))]);
+        }
+        let inst = b.add_inst(entry, operands);
+        b.add_ret(entry, &[]);
+        let f = b.build();
+        (
+            inst,
+            run(&f, &test_env(num_regs), &RegallocOptions::default()),
+        )
+    }
+
+    #[test]
+    fn test_constraint_errors() {
+        // Two vregs in one fixed register at once.
+        let (inst, result) = run_operands(
+            &[
+                Operand::reg_fixed_use(int(0), p(0)),
+                Operand::reg_fixed_use(int(1), p(0)),
+            ],
+            4,
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            RegAllocError::FixedRegConflict {
+                inst,
+                operands: (0, 1),
+                preg: p(0),
+            }
+        );
+        // One vreg in two fixed registers at once is fine.
+        let (_, result) = run_operands(
+            &[
+                Operand::reg_fixed_use(int(0), p(0)),
+                Operand::reg_fixed_use(int(0), p(1)),
+            ],
+            4,
+        );
+        assert!(result.is_ok());
+
+        // More register uses than registers.
+        let (inst, result) = run_operands(
+            &[
+                Operand::reg_use(int(0)),
+                Operand::reg_use(int(1)),
+                Operand::reg_fixed_use(int(2), p(0)),
+            ],
+            2,
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            RegAllocError::TooManyRegOperands {
+                inst,
+                class: RegClass::Int,
+                needed: 3,
+                available: 2,
+            }
+        );
+        // A vreg in two fixed registers occupies both of them.
+        let (inst, result) = run_operands(
+            &[
+                Operand::reg_fixed_use(int(0), p(0)),
+                Operand::reg_fixed_use(int(0), p(1)),
+                Operand::reg_use(int(1)),
+            ],
+            2,
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            RegAllocError::TooManyRegOperands {
+                inst,
+                class: RegClass::Int,
+                needed: 3,
+                available: 2,
+            }
+        );
+
+        // A fixed register on a late use, which nothing can be moved
+        // into in the middle of the instruction.
+        let late = Operand::new(
+            int(0),
+            OperandConstraint::FixedReg(p(0)),
+            OperandKind::Use,
+            OperandPos::Late,
+        );
+        let (inst, result) = run_operands(&[late], 4);
+        assert_eq!(
+            result.unwrap_err(),
+            RegAllocError::InvalidFixedRegOperand {
+                inst,
+                operand: 0,
+                preg: p(0),
+            }
+        );
+
+        // Two stack uses of one vreg.
+        let stack = Operand::new(
+            int(0),
+            OperandConstraint::Stack,
+            OperandKind::Use,
+            OperandPos::Early,
+        );
+        let (inst, result) = run_operands(&[stack, stack], 4);
+        assert_eq!(
+            result.unwrap_err(),
+            RegAllocError::MultipleStackUses { inst, vreg: int(0) }
+        );
     }
 }
@@ -13,15 +13,17 @@
 //! Bundle merging.
 
 use super::{
-    Env, LiveBundleIndex, LiveRangeIndex, LiveRangeKey, SpillSet, SpillSetIndex, SpillSlotIndex,
-    VRegIndex,
+    Env, Heuristics, LiveBundleIndex, LiveRangeIndex, LiveRangeKey, SpillSet, SpillSetIndex,
+    SpillSlotIndex, VRegIndex,
 };
 use crate::{
-    ion::data_structures::BlockparamOut, Function, Inst, OperandConstraint, OperandKind, PReg,
+    input_reused_by, ion::data_structures::BlockparamOut, Function, Inst, OperandConstraint,
+    OperandKind, PReg,
 };
+use alloc::format;
 use smallvec::smallvec;
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     pub fn merge_bundles(&mut self, from: LiveBundleIndex, to: LiveBundleIndex) -> bool {
         if from == to {
             // Merge bundle into self -- trivial merge.
@@ -133,7 +135,7 @@ impl<'a, F: Function> Env<'a, F> {
             // `to` bundle is empty -- just move the list over from
             // `from` and set `bundle` up-link on all ranges.
             trace!(" -> to bundle{} is empty; trivial merge", to.index());
-            let list = std::mem::replace(&mut self.bundles[from.index()].ranges, smallvec![]);
+            let list = core::mem::replace(&mut self.bundles[from.index()].ranges, smallvec![]);
             for entry in &list {
                 self.ranges[entry.index.index()].bundle = to;
 
@@ -171,7 +173,7 @@ impl<'a, F: Function> Env<'a, F> {
         // Two non-empty lists of LiveRanges: concatenate and
         // sort. This is faster than a mergesort-like merge into a new
         // list, empirically.
-        let from_list = std::mem::replace(&mut self.bundles[from.index()].ranges, smallvec![]);
+        let from_list = core::mem::replace(&mut self.bundles[from.index()].ranges, smallvec![]);
         for entry in &from_list {
             self.ranges[entry.index.index()].bundle = to;
         }
@@ -214,7 +216,7 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         if self.bundles[from.index()].spillset != self.bundles[to.index()].spillset {
-            let from_vregs = std::mem::replace(
+            let from_vregs = core::mem::replace(
                 &mut self.spillsets[self.bundles[from.index()].spillset.index()].vregs,
                 smallvec![],
             );
@@ -321,9 +323,11 @@ impl<'a, F: Function> Env<'a, F> {
 
             // Attempt to merge Reuse-constraint operand outputs with the
             // corresponding inputs.
-            for op in self.func.inst_operands(inst) {
-                if let OperandConstraint::Reuse(reuse_idx) = op.constraint() {
+            let operands = self.func.inst_operands(inst);
+            for (i, op) in operands.iter().enumerate() {
+                if let Some(reuse_idx) = input_reused_by(operands, i) {
                     let src_vreg = op.vreg();
+                    let reuse_idx = self.reused_input(inst, reuse_idx);
                     let dst_vreg = self.func.inst_operands(inst)[reuse_idx].vreg();
                     if self.func.is_pinned_vreg(src_vreg).is_some()
                         || self.func.is_pinned_vreg(dst_vreg).is_some()
@@ -412,6 +416,21 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
+        // Hint each entry livein toward the register it arrives in,
+        // so that its definition at entry needs no move.
+        let entry_point = self.cfginfo.block_entry[self.func.entry_block().index()];
+        for &(vreg, preg) in self.func.entry_liveins() {
+            let first = match self.vregs[vreg.vreg()].ranges.first() {
+                Some(entry) if entry.range.from == entry_point => entry.index,
+                _ => continue,
+            };
+            let bundle = self.ranges[first.index()].bundle;
+            let spillset = self.bundles[bundle.index()].spillset;
+            if self.spillsets[spillset.index()].reg_hint == PReg::invalid() {
+                self.spillsets[spillset.index()].reg_hint = preg;
+            }
+        }
+
         trace!("done merging bundles");
     }
 
@@ -425,13 +444,12 @@ impl<'a, F: Function> Env<'a, F> {
     }
 
     pub fn compute_bundle_prio(&self, bundle: LiveBundleIndex) -> u32 {
-        // The priority is simply the total "length" -- the number of
-        // instructions covered by all LiveRanges.
-        let mut total = 0;
-        for entry in &self.bundles[bundle.index()].ranges {
-            total += entry.range.len() as u32;
-        }
-        total
+        self.heuristics.bundle_priority(
+            self.bundles[bundle.index()]
+                .ranges
+                .iter()
+                .map(|entry| entry.range),
+        )
     }
 
     pub fn queue_bundles(&mut self) {
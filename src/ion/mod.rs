@@ -14,12 +14,19 @@
 //! its design.
 
 use crate::cfg::CFGInfo;
-use crate::{Function, MachineEnv, Output, PReg, ProgPoint, RegAllocError, RegClass};
-use std::collections::HashMap;
+use crate::indexset::IndexSet;
+use crate::split_edges::SplitEdges;
+use crate::{
+    Allocation, Function, FxHashMap, Liveness, MachineEnv, Output, PReg, ProgPoint, RegAllocError,
+    RegClass, RegallocOptions, VReg,
+};
+use alloc::{vec, vec::Vec};
 
 pub(crate) mod data_structures;
+pub(crate) mod heuristics;
 pub use data_structures::Stats;
 use data_structures::*;
+use heuristics::*;
 pub(crate) mod reg_traversal;
 use reg_traversal::*;
 pub(crate) mod requirement;
@@ -35,19 +42,25 @@ use smallvec::smallvec;
 pub(crate) mod dump;
 pub(crate) mod moves;
 pub(crate) mod spill;
+pub(crate) mod spill_placement;
 pub(crate) mod stackmap;
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     pub(crate) fn new(
         func: &'a F,
         env: &'a MachineEnv,
+        reg_tiers_by_class: &'a [Vec<Vec<PReg>>; 2],
+        heuristics: &'a H,
         cfginfo: CFGInfo,
         annotations_enabled: bool,
+        spill_placement_enabled: bool,
     ) -> Self {
         let n = func.num_insts();
         Self {
             func,
             env,
+            reg_tiers_by_class,
+            heuristics,
             cfginfo,
 
             liveins: Vec::with_capacity(func.num_blocks()),
@@ -61,7 +74,7 @@ impl<'a, F: Function> Env<'a, F> {
             pregs: vec![],
             allocation_queue: PrioQueue::new(),
             safepoints: vec![],
-            safepoints_per_vreg: HashMap::new(),
+            safepoints_per_vreg: FxHashMap::default(),
             spilled_bundles: vec![],
             spillslots: vec![],
             slots_by_size: vec![],
@@ -75,6 +88,8 @@ impl<'a, F: Function> Env<'a, F> {
             prog_move_merges: Vec::with_capacity(n / 2),
 
             multi_fixed_reg_fixups: vec![],
+            tied_use_insts: vec![],
+            commuted: vec![],
             inserted_moves: vec![],
             edits: Vec::with_capacity(n),
             allocs: Vec::with_capacity(4 * n),
@@ -85,18 +100,21 @@ impl<'a, F: Function> Env<'a, F> {
 
             stats: Stats::default(),
 
-            debug_annotations: std::collections::HashMap::new(),
+            debug_annotations: FxHashMap::default(),
             annotations_enabled,
 
+            spill_placement_enabled,
+
             conflict_set: Default::default(),
         }
     }
 
     pub(crate) fn init(&mut self) -> Result<(), RegAllocError> {
         self.create_pregs_and_vregs();
+        self.check_operand_constraints()?;
         self.compute_liveness()?;
-        self.build_liveranges();
-        self.fixup_multi_fixed_vregs();
+        self.build_liveranges()?;
+        self.fixup_multi_fixed_vregs()?;
         self.merge_vreg_bundles();
         self.queue_bundles();
         if trace_enabled!() {
@@ -110,28 +128,196 @@ impl<'a, F: Function> Env<'a, F> {
         self.try_allocating_regs_for_spilled_bundles();
         self.allocate_spillslots();
         self.apply_allocations_and_insert_moves();
+        self.fixup_branch_args()?;
+        self.apply_tied_uses();
+        if self.spill_placement_enabled {
+            self.optimize_spill_placement();
+        }
         self.resolve_inserted_moves();
         self.compute_stackmaps();
         Ok(())
     }
+
+    /// The sorted vregs in a live-in or live-out set.
+    fn live_vregs(&self, set: &IndexSet) -> Vec<VReg> {
+        let mut vregs: Vec<VReg> = set.iter().map(|v| self.vreg(VRegIndex::new(v))).collect();
+        vregs.sort_unstable();
+        vregs
+    }
+
+    /// The results of `compute_liveness()` and `build_liveranges()`.
+    fn liveness(&self) -> Liveness {
+        let ranges = self
+            .vregs
+            .iter()
+            .map(|vreg| {
+                let mut ranges: Vec<CodeRange> = vreg
+                    .ranges
+                    .iter()
+                    .map(|entry| self.ranges[entry.index.index()].range)
+                    .collect();
+                ranges.sort_unstable_by_key(|range| range.from);
+                // Ranges are split at block boundaries; rejoin those
+                // that meet.
+                ranges.dedup_by(|next, prev| {
+                    if prev.to == next.from {
+                        prev.to = next.to;
+                        true
+                    } else {
+                        false
+                    }
+                });
+                ranges
+            })
+            .collect();
+        Liveness {
+            liveins: self.liveins.iter().map(|s| self.live_vregs(s)).collect(),
+            liveouts: self.liveouts.iter().map(|s| self.live_vregs(s)).collect(),
+            ranges,
+            classes: self.vregs.iter().map(|vreg| vreg.class).collect(),
+        }
+    }
+
+    /// The allocation of each vreg's liveranges, once they are final.
+    fn vreg_locations(&self) -> Vec<(VReg, ProgPoint, ProgPoint, Allocation)> {
+        let mut locations: Vec<(VReg, ProgPoint, ProgPoint, Allocation)> = vec![];
+        for (i, data) in self.vregs.iter().enumerate() {
+            let vreg = match data.class {
+                Some(class) => VReg::new(i, class),
+                None => continue,
+            };
+            let pinned_alloc = self.func.is_pinned_vreg(vreg);
+            for entry in &data.ranges {
+                let alloc = pinned_alloc
+                    .map(Allocation::reg)
+                    .unwrap_or_else(|| self.get_alloc_for_range(entry.index));
+                if let Some(last) = locations.last_mut() {
+                    // A range split without a move between the halves.
+                    if last.0 == vreg && last.2 == entry.range.from && last.3 == alloc {
+                        last.2 = entry.range.to;
+                        continue;
+                    }
+                }
+                locations.push((vreg, entry.range.from, entry.range.to, alloc));
+            }
+        }
+        locations
+    }
+}
+
+pub fn liveness<F: Function>(
+    func: &F,
+    options: &RegallocOptions,
+) -> Result<Liveness, RegAllocError> {
+    if options.allow_critical_edges {
+        if let Some(split) = SplitEdges::new(func)? {
+            let cfginfo = CFGInfo::new(&split)?;
+            let mut liveness = liveness_with_cfginfo(&split, cfginfo)?;
+            split.finish_liveness(&mut liveness);
+            return Ok(liveness);
+        }
+    }
+
+    let cfginfo = CFGInfo::new(func)?;
+    liveness_with_cfginfo(func, cfginfo)
+}
+
+fn liveness_with_cfginfo<F: Function>(
+    func: &F,
+    cfginfo: CFGInfo,
+) -> Result<Liveness, RegAllocError> {
+    // Liveness does not depend on the machine.
+    let mach_env = MachineEnv::default();
+    let reg_tiers = reg_tiers_by_class(&mach_env);
+    let mut env = Env::new(
+        func,
+        &mach_env,
+        &reg_tiers,
+        &DefaultHeuristics,
+        cfginfo,
+        false,
+        false,
+    );
+    env.create_pregs_and_vregs();
+    env.compute_liveness()?;
+    env.build_liveranges()?;
+    Ok(env.liveness())
 }
 
 pub fn run<F: Function>(
     func: &F,
     mach_env: &MachineEnv,
-    enable_annotations: bool,
+    options: &RegallocOptions,
 ) -> Result<Output, RegAllocError> {
+    run_with_heuristics(func, mach_env, options, &DefaultHeuristics)
+}
+
+pub fn run_with_heuristics<F: Function, H: Heuristics>(
+    func: &F,
+    mach_env: &MachineEnv,
+    options: &RegallocOptions,
+    heuristics: &H,
+) -> Result<Output, RegAllocError> {
+    if options.allow_critical_edges {
+        if let Some(split) = SplitEdges::new(func)? {
+            let mut cfginfo = CFGInfo::new(&split)?;
+            cfginfo.approx_loop_depth = split.approx_loop_depth();
+            let mut output = run_with_cfginfo(&split, mach_env, cfginfo, options, heuristics)?;
+            split.finish_output(&mut output);
+            return Ok(output);
+        }
+    }
+
     let cfginfo = CFGInfo::new(func)?;
+    run_with_cfginfo(func, mach_env, cfginfo, options, heuristics)
+}
 
-    let mut env = Env::new(func, mach_env, cfginfo, enable_annotations);
+fn run_with_cfginfo<F: Function, H: Heuristics>(
+    func: &F,
+    mach_env: &MachineEnv,
+    cfginfo: CFGInfo,
+    options: &RegallocOptions,
+    heuristics: &H,
+) -> Result<Output, RegAllocError> {
+    let enable_annotations = options.verbose_log || options.debug_annotations;
+    let reg_tiers = reg_tiers_by_class(mach_env);
+    let mut env = Env::new(
+        func,
+        mach_env,
+        &reg_tiers,
+        heuristics,
+        cfginfo,
+        enable_annotations,
+        options.optimize_spill_placement,
+    );
     env.init()?;
 
     env.run()?;
 
-    if enable_annotations {
+    if options.verbose_log {
         env.dump_results();
     }
 
+    let debug_annotations = if options.debug_annotations {
+        let mut annotations = env
+            .debug_annotations
+            .drain()
+            .flat_map(|(pos, strs)| strs.into_iter().map(move |s| (pos, s)))
+            .collect::<Vec<_>>();
+        // Stable sort: annotations at the same point stay in the
+        // order in which they were generated.
+        annotations.sort_by_key(|&(pos, _)| pos);
+        annotations
+    } else {
+        vec![]
+    };
+
+    let vreg_locations = if options.vreg_locations {
+        env.vreg_locations()
+    } else {
+        vec![]
+    };
+
     Ok(Output {
         edits: env
             .edits
@@ -143,6 +329,154 @@ pub fn run<F: Function>(
         num_spillslots: env.num_spillslots as usize,
         debug_locations: env.debug_locations,
         safepoint_slots: env.safepoint_slots,
+        edge_edits: vec![],
+        debug_annotations,
+        vreg_locations,
+        commuted: env.commuted,
         stats: env.stats,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use crate::testutil::{check, int, test_env};
+    use crate::{
+        compute_liveness, run, Block, Function, FunctionBuilder, Inst, Operand, OperandPos,
+        ProgPoint, RegAllocError, RegallocOptions,
+    };
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_liveness_diamond() {
+        // v1 is passed to join from right only, so it is live into
+        // right but not out of it.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let right = b.add_block();
+        let join = b.add_block();
+        b.add_inst(entry, &[Operand::reg_def(int(0))]);
+        b.add_inst(entry, &[Operand::reg_def(int(1))]);
+        b.add_branch(entry, &[], &[left, right]);
+        b.add_inst(left, &[Operand::reg_def(int(2)), Operand::reg_use(int(0))]);
+        b.add_branch(left, &[], &[join]);
+        b.set_branch_args(left, 0, &[int(2)]);
+        b.add_branch(right, &[], &[join]);
+        b.set_branch_args(right, 0, &[int(1)]);
+        b.add_block_param(join, int(3));
+        b.add_ret(join, &[Operand::reg_use(int(3)), Operand::reg_use(int(0))]);
+        let f = b.build();
+
+        let liveness = compute_liveness(&f, &RegallocOptions::default()).unwrap();
+        assert_eq!(
+            liveness.liveins,
+            vec![vec![], vec![int(0)], vec![int(0), int(1)], vec![int(0)]]
+        );
+        assert_eq!(
+            liveness.liveouts,
+            vec![vec![int(0), int(1)], vec![int(0)], vec![int(0)], vec![]]
+        );
+        // v0 is live from its def to the return, v1 from its def to
+        // the end of right, skipping left.
+        let v0 = liveness.vreg_ranges(int(0));
+        assert_eq!(v0.len(), 1);
+        assert_eq!(v0[0].from, ProgPoint::after(Inst::new(0)));
+        assert!(liveness.is_live_at(int(0), ProgPoint::before(Inst::new(6))));
+        assert!(!liveness.is_live_at(int(0), ProgPoint::after(Inst::new(6))));
+        assert!(liveness.is_live_at(int(1), ProgPoint::before(Inst::new(5))));
+        assert!(!liveness.is_live_at(int(1), ProgPoint::before(Inst::new(3))));
+        assert!(liveness.is_live_at(int(3), ProgPoint::before(Inst::new(6))));
+    }
+
+    #[test]
+    fn test_liveness_critical_edge() {
+        // entry -> join is critical; v1 is passed along it.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let join = b.add_block();
+        b.add_inst(entry, &[Operand::reg_def(int(0))]);
+        b.add_inst(entry, &[Operand::reg_def(int(1))]);
+        b.add_branch(entry, &[], &[left, join]);
+        b.set_branch_args(entry, 1, &[int(1)]);
+        b.add_branch(left, &[], &[join]);
+        b.set_branch_args(left, 0, &[int(0)]);
+        b.add_block_param(join, int(2));
+        b.add_ret(join, &[Operand::reg_use(int(2)), Operand::reg_use(int(0))]);
+        let f = b.build();
+
+        assert_eq!(
+            compute_liveness(&f, &RegallocOptions::default()).unwrap_err(),
+            RegAllocError::CritEdge(entry, join)
+        );
+        let options = RegallocOptions {
+            allow_critical_edges: true,
+            ..Default::default()
+        };
+        let liveness = compute_liveness(&f, &options).unwrap();
+        assert_eq!(liveness.liveins, vec![vec![], vec![int(0)], vec![int(0)]]);
+        assert_eq!(liveness.liveouts, vec![vec![int(0)], vec![int(0)], vec![]]);
+        let end = ProgPoint::before(Inst::new(f.num_insts()));
+        for ranges in &liveness.ranges {
+            assert!(ranges.iter().all(|range| range.to <= end));
+        }
+        assert!(liveness.is_live_at(int(1), ProgPoint::before(Inst::new(2))));
+    }
+
+    #[test]
+    fn test_vreg_locations() {
+        // Six vregs live at once in three registers, so some are
+        // spilled and reloaded.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let exit = b.add_block();
+        for i in 0..6 {
+            b.add_inst(entry, &[Operand::reg_def(int(i))]);
+        }
+        for i in 0..6 {
+            b.add_inst(
+                entry,
+                &[
+                    Operand::reg_def(int(6 + i)),
+                    Operand::reg_use(int(i)),
+                    Operand::reg_use(int((i + 1) % 6)),
+                ],
+            );
+        }
+        b.add_branch(entry, &[], &[exit]);
+        let uses: Vec<_> = (0..12).map(|i| Operand::any_use(int(i))).collect();
+        b.add_ret(exit, &uses);
+        let f = b.build();
+
+        let env = test_env(3);
+        let options = RegallocOptions {
+            vreg_locations: true,
+            ..Default::default()
+        };
+        let out = run(&f, &env, &options).unwrap();
+        check(&f, &env, &out);
+        assert!(out.num_spillslots > 0);
+
+        let locs = &out.vreg_locations;
+        for pair in locs.windows(2) {
+            let ((v1, _, to1, _), (v2, from2, _, _)) = (pair[0], pair[1]);
+            assert!(v1 < v2 || (v1 == v2 && to1 <= from2));
+        }
+        for block in 0..f.num_blocks() {
+            for inst in f.block_insns(Block::new(block)).iter() {
+                for (op, &alloc) in f.inst_operands(inst).iter().zip(out.inst_allocs(inst)) {
+                    let pos = match op.pos() {
+                        OperandPos::Early => ProgPoint::before(inst),
+                        OperandPos::Late => ProgPoint::after(inst),
+                    };
+                    let loc = locs
+                        .iter()
+                        .find(|&&(vreg, from, to, _)| vreg == op.vreg() && from <= pos && pos < to)
+                        .unwrap();
+                    assert_eq!(loc.3, alloc, "{} at {:?}", op, pos);
+                }
+            }
+        }
+    }
+}
@@ -13,8 +13,8 @@
 //! Move resolution.
 
 use super::{
-    Env, InsertMovePrio, InsertedMove, LiveRangeFlag, LiveRangeIndex, RedundantMoveEliminator,
-    VRegIndex, SLOT_NONE,
+    Env, Heuristics, InsertMovePrio, InsertedMove, LiveRangeFlag, LiveRangeIndex,
+    RedundantMoveEliminator, VRegIndex, SLOT_NONE,
 };
 use crate::ion::data_structures::{
     BlockparamIn, BlockparamOut, CodeRange, FixedRegFixupLevel, LiveRangeKey, PosWithPrio,
@@ -22,14 +22,16 @@ use crate::ion::data_structures::{
 use crate::ion::reg_traversal::RegTraversalIter;
 use crate::moves::{MoveAndScratchResolver, ParallelMoves};
 use crate::{
-    Allocation, Block, Edit, Function, Inst, InstPosition, OperandConstraint, OperandKind,
-    OperandPos, PReg, ProgPoint, RegClass, SpillSlot, VReg,
+    input_reused_by, tied_to_use, Allocation, Block, Edit, Function, FxHashMap, Inst, InstPosition,
+    OperandConstraint, OperandKind, OperandPos, PReg, ProgPoint, RegAllocError, RegClass,
+    SpillSlot, VReg,
 };
-use fxhash::FxHashMap;
+use alloc::{format, vec, vec::Vec};
+use core::fmt::Debug;
+use slice_group_by::GroupBy;
 use smallvec::{smallvec, SmallVec};
-use std::fmt::Debug;
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     pub fn is_start_of_block(&self, pos: ProgPoint) -> bool {
         let block = self.cfginfo.insn_block[pos.inst().index()];
         pos == self.cfginfo.block_entry[block.index()]
@@ -173,6 +175,8 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         let debug_labels = self.func.debug_value_labels();
+        let entry_liveins = self.func.entry_liveins();
+        let entry_point = self.cfginfo.block_entry[self.func.entry_block().index()];
 
         let mut half_moves: Vec<HalfMove> = Vec::with_capacity(6 * self.func.num_insts());
         let mut reuse_input_insts = Vec::with_capacity(self.func.num_insts() / 2);
@@ -286,6 +290,34 @@ impl<'a, F: Function> Env<'a, F> {
                     }
                 }
 
+                // Does this range start at the top of a block where the
+                // vreg arrives in a fixed location, as an entry livein
+                // or an edge def? If so, move it from that location.
+                // These moves are ordered first at the block entry so
+                // that they read the incoming values before anything
+                // else is written.
+                if self.is_start_of_block(range.from) {
+                    let arrivals = if range.from == entry_point {
+                        entry_liveins
+                    } else {
+                        self.edge_defs_into(self.cfginfo.insn_block[range.from.inst().index()])
+                    };
+                    if let Some(&(_, preg)) = arrivals
+                        .iter()
+                        .find(|&&(livein, _)| livein.vreg() == vreg.index())
+                    {
+                        if alloc != Allocation::reg(preg) {
+                            self.insert_move(
+                                range.from,
+                                InsertMovePrio::InEdgeMoves,
+                                Allocation::reg(preg),
+                                alloc,
+                                self.vreg(vreg),
+                            );
+                        }
+                    }
+                }
+
                 // The block-to-block edge-move logic is not
                 // applicable to pinned vregs, which are always in one
                 // PReg (so never need moves within their own vreg
@@ -488,8 +520,14 @@ impl<'a, F: Function> Env<'a, F> {
                     if slot != SLOT_NONE {
                         self.set_alloc(inst, slot as usize, alloc);
                     }
+                    // A def that reuses an input, or an input tied to
+                    // a def.
                     if let OperandConstraint::Reuse(_) = operand.constraint() {
-                        reuse_input_insts.push(inst);
+                        if operand.kind() == OperandKind::Def
+                            || tied_to_use(self.func.inst_operands(inst), slot as usize).is_none()
+                        {
+                            reuse_input_insts.push(inst);
+                        }
                     }
                 }
 
@@ -510,9 +548,9 @@ impl<'a, F: Function> Env<'a, F> {
                             // this case returns the index of the first
                             // entry that is greater as an `Err`.
                             if label_vreg.vreg() < vreg.index() {
-                                std::cmp::Ordering::Less
+                                core::cmp::Ordering::Less
                             } else {
-                                std::cmp::Ordering::Greater
+                                core::cmp::Ordering::Greater
                             }
                         })
                         .unwrap_err();
@@ -531,8 +569,8 @@ impl<'a, F: Function> Env<'a, F> {
                             continue;
                         }
 
-                        let from = std::cmp::max(label_from, range.from);
-                        let to = std::cmp::min(label_to, range.to);
+                        let from = core::cmp::max(label_from, range.from);
+                        let to = core::cmp::min(label_to, range.to);
 
                         self.debug_locations.push((label, from, to, alloc));
                     }
@@ -679,21 +717,10 @@ impl<'a, F: Function> Env<'a, F> {
                     // N.B.: though semantically the edge moves happen
                     // after the branch, we must insert them before
                     // the branch because otherwise, of course, they
-                    // would never execute. This is correct even in
-                    // the presence of branches that read register
-                    // inputs (e.g. conditional branches on some RISCs
-                    // that branch on reg zero/not-zero, or any
-                    // indirect branch), but for a very subtle reason:
-                    // all cases of such branches will (or should)
-                    // have multiple successors, and thus due to
-                    // critical-edge splitting, their successors will
-                    // have only the single predecessor, and we prefer
-                    // to insert at the head of the successor in that
-                    // case (rather than here). We make this a
-                    // requirement, in fact: the user of this library
-                    // shall not read registers in a branch
-                    // instruction of there is only one successor per
-                    // the given CFG information.
+                    // would never execute. If the branch reads
+                    // operands, the moves may overwrite them;
+                    // `fixup_branch_args` takes care of that case
+                    // once all moves are known.
                     ProgPoint::before(from_last_insn),
                     InsertMovePrio::OutEdgeMoves,
                 )
@@ -728,7 +755,7 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         // Handle multi-fixed-reg constraints by copying.
-        for fixup in std::mem::replace(&mut self.multi_fixed_reg_fixups, vec![]) {
+        for fixup in core::mem::replace(&mut self.multi_fixed_reg_fixups, vec![]) {
             let from_alloc = self.get_alloc(fixup.pos.inst(), fixup.from_slot as usize);
             let to_alloc = Allocation::reg(PReg::from_index(fixup.to_preg.index()));
             trace!(
@@ -798,7 +825,9 @@ impl<'a, F: Function> Env<'a, F> {
             let mut input_reused: SmallVec<[usize; 4]> = smallvec![];
             for output_idx in 0..self.func.inst_operands(inst).len() {
                 let operand = self.func.inst_operands(inst)[output_idx];
-                if let OperandConstraint::Reuse(input_idx) = operand.constraint() {
+                if let Some(input_idx) = input_reused_by(self.func.inst_operands(inst), output_idx)
+                {
+                    let input_idx = self.reused_input(inst, input_idx);
                     debug_assert!(!input_reused.contains(&input_idx));
                     debug_assert_eq!(operand.pos(), OperandPos::Late);
                     input_reused.push(input_idx);
@@ -840,8 +869,8 @@ impl<'a, F: Function> Env<'a, F> {
             .sort_unstable_by_key(|((_, inst), _)| *inst);
         self.prog_move_dsts
             .sort_unstable_by_key(|((_, inst), _)| inst.prev());
-        let prog_move_srcs = std::mem::replace(&mut self.prog_move_srcs, vec![]);
-        let prog_move_dsts = std::mem::replace(&mut self.prog_move_dsts, vec![]);
+        let prog_move_srcs = core::mem::replace(&mut self.prog_move_srcs, vec![]);
+        let prog_move_dsts = core::mem::replace(&mut self.prog_move_dsts, vec![]);
         debug_assert_eq!(prog_move_srcs.len(), prog_move_dsts.len());
         for (&((_, from_inst), from_alloc), &((to_vreg, to_inst), to_alloc)) in
             prog_move_srcs.iter().zip(prog_move_dsts.iter())
@@ -875,6 +904,118 @@ impl<'a, F: Function> Env<'a, F> {
         self.debug_locations.sort_unstable();
     }
 
+    /// Edge moves into a successor with multiple predecessors are
+    /// placed before the branch that ends the predecessor, so they
+    /// may overwrite locations that the branch itself reads. For each
+    /// branch operand so affected, copy the value beforehand to a
+    /// location that the edge moves leave alone, and have the branch
+    /// read it from there.
+    pub fn fixup_branch_args(&mut self) -> Result<(), RegAllocError> {
+        let func = self.func;
+        let mut edge_moves: Vec<(Inst, Allocation, Allocation)> = self
+            .inserted_moves
+            .iter()
+            .filter(|m| {
+                m.pos_prio.prio == InsertMovePrio::OutEdgeMoves as u32
+                    && m.from_alloc != m.to_alloc
+                    && !func.inst_operands(m.pos_prio.pos.inst()).is_empty()
+            })
+            .map(|m| (m.pos_prio.pos.inst(), m.from_alloc, m.to_alloc))
+            .collect();
+        edge_moves.sort_unstable_by_key(|&(inst, _, _)| inst);
+
+        for moves in edge_moves.linear_group_by_key(|&(inst, _, _)| inst) {
+            let inst = moves[0].0;
+            let pos = ProgPoint::before(inst);
+            let key = LiveRangeKey::from_range(&CodeRange {
+                from: pos,
+                to: pos.next(),
+            });
+            for (i, &operand) in func.inst_operands(inst).iter().enumerate() {
+                let alloc = self.get_alloc(inst, i);
+                if operand.as_fixed_nonallocatable().is_some()
+                    || tied_to_use(func.inst_operands(inst), i).is_some()
+                    || !moves.iter().any(|&(_, _, to)| to == alloc)
+                {
+                    continue;
+                }
+                trace!(
+                    "branch inst{} operand {} in {} is overwritten by edge moves",
+                    inst.index(),
+                    i,
+                    alloc
+                );
+
+                // Look for a register that holds nothing at this
+                // point and is not touched by the edge moves.
+                let class = operand.class();
+                let free_reg = self.env.preferred_regs_by_class[class as usize]
+                    .iter()
+                    .chain(self.env.non_preferred_regs_by_class[class as usize].iter())
+                    .cloned()
+                    .find(|&preg| {
+                        let reg = Allocation::reg(preg);
+                        !moves.iter().any(|&(_, from, to)| from == reg || to == reg)
+                            && !self.pregs[preg.index()]
+                                .allocations
+                                .btree
+                                .contains_key(&key)
+                    });
+                let new_alloc = match operand.constraint() {
+                    OperandConstraint::Reg => free_reg.map(Allocation::reg),
+                    OperandConstraint::Any => Some(match free_reg {
+                        Some(preg) => Allocation::reg(preg),
+                        None => self.allocate_spillslot(func.spillslot_size(class) as u32),
+                    }),
+                    OperandConstraint::Stack => {
+                        Some(self.allocate_spillslot(func.spillslot_size(class) as u32))
+                    }
+                    OperandConstraint::FixedReg(_)
+                    | OperandConstraint::Reuse(_)
+                    | OperandConstraint::DistinctFrom(_) => None,
+                };
+                let new_alloc = new_alloc.ok_or(RegAllocError::DisallowedBranchArg(inst))?;
+                if let Some(preg) = new_alloc.as_reg() {
+                    // Reserve the register so that it is not picked
+                    // as a scratch register for the edge moves.
+                    self.add_liverange_to_preg(
+                        CodeRange {
+                            from: pos,
+                            to: pos.next(),
+                        },
+                        preg,
+                    );
+                }
+                self.insert_move(
+                    pos,
+                    InsertMovePrio::BranchArg,
+                    alloc,
+                    new_alloc,
+                    operand.vreg(),
+                );
+                self.set_alloc(inst, i, new_alloc);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Give each use that is tied to another use the final
+    /// allocation of that use, which holds the same vreg at the same
+    /// point, so no move is needed.
+    pub fn apply_tied_uses(&mut self) {
+        for inst in core::mem::take(&mut self.tied_use_insts) {
+            let operands = self.func.inst_operands(inst);
+            for i in 0..operands.len() {
+                if let Some(idx) = tied_to_use(operands, i) {
+                    let alloc = self.get_alloc(inst, idx);
+                    trace!("tied use inst{} operand {}: {}", inst.index(), i, alloc);
+                    self.set_alloc(inst, i, alloc);
+                }
+            }
+        }
+    }
+
     pub fn resolve_inserted_moves(&mut self) {
         // For each program point, gather all moves together. Then
         // resolve (see cases below).
@@ -885,8 +1026,8 @@ impl<'a, F: Function> Env<'a, F> {
         // Redundant-move elimination state tracker.
         let mut redundant_moves = RedundantMoveEliminator::default();
 
-        fn redundant_move_process_side_effects<'a, F: Function>(
-            this: &Env<'a, F>,
+        fn redundant_move_process_side_effects<'a, F: Function, H: Heuristics>(
+            this: &Env<'a, F, H>,
             redundant_moves: &mut RedundantMoveEliminator,
             from: ProgPoint,
             to: ProgPoint,
@@ -986,10 +1127,20 @@ impl<'a, F: Function> Env<'a, F> {
                     parallel_moves.add(m.from_alloc, m.to_alloc, Some(m.to_vreg));
                 }
 
+                // If the target can exchange registers directly, take
+                // cycles among registers out as swaps, so they need
+                // neither a scratch register nor a stack slot.
+                let swaps = if self.env.reg_swaps_by_class[regclass as usize] {
+                    parallel_moves.take_swap_cycles(|alloc| {
+                        matches!(alloc.as_reg(), Some(preg) if !self.pregs[preg.index()].is_stack)
+                    })
+                } else {
+                    smallvec![]
+                };
+
                 let resolved = parallel_moves.resolve();
                 let mut scratch_iter = RegTraversalIter::new(
-                    self.env,
-                    regclass,
+                    &self.reg_tiers_by_class[regclass as usize],
                     PReg::invalid(),
                     PReg::invalid(),
                     0,
@@ -1007,7 +1158,7 @@ impl<'a, F: Function> Env<'a, F> {
                             .contains_key(&key)
                         {
                             let alloc = Allocation::reg(preg);
-                            if moves
+                            if self.inserted_moves[start..i]
                                 .iter()
                                 .any(|m| m.from_alloc == alloc || m.to_alloc == alloc)
                             {
@@ -1016,7 +1167,11 @@ impl<'a, F: Function> Env<'a, F> {
                                 // marked used at progpoint: edge move
                                 // liveranges meet but don't overlap
                                 // so otherwise we may incorrectly
-                                // overwrite a source reg.
+                                // overwrite a source reg. This
+                                // includes moves to the same place,
+                                // which may stand for a move that
+                                // the spill placement pass found
+                                // redundant.
                                 continue;
                             }
                             return Some(alloc);
@@ -1048,6 +1203,12 @@ impl<'a, F: Function> Env<'a, F> {
                     get_stackslot,
                     is_stack_alloc,
                     preferred_victim,
+                )
+                .with_dedicated_scratch(
+                    self.env.scratch_by_class[regclass as usize].map(Allocation::reg),
+                )
+                .with_stack_to_stack_moves(
+                    self.env.stack_to_stack_moves_by_class[regclass as usize],
                 );
 
                 let resolved = scratch_resolver.compute(resolved);
@@ -1076,6 +1237,13 @@ impl<'a, F: Function> Env<'a, F> {
                         trace!("    -> redundant move elided");
                     }
                 }
+
+                for (a, b) in swaps {
+                    trace!("  swap: {} <-> {}", a, b);
+                    redundant_moves.clear_alloc(a);
+                    redundant_moves.clear_alloc(b);
+                    self.edits.push((pos_prio, Edit::Swap { a, b }));
+                }
             }
         }
 
@@ -1090,10 +1258,13 @@ impl<'a, F: Function> Env<'a, F> {
         if self.annotations_enabled {
             for i in 0..self.edits.len() {
                 let &(pos_prio, ref edit) = &self.edits[i];
-                match edit {
-                    &Edit::Move { from, to } => {
+                match *edit {
+                    Edit::Move { from, to } => {
                         self.annotate(pos_prio.pos, format!("move {} -> {}", from, to));
                     }
+                    Edit::Swap { a, b } => {
+                        self.annotate(pos_prio.pos, format!("swap {} <-> {}", a, b));
+                    }
                 }
             }
         }
@@ -1108,3 +1279,222 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::testutil::{check, int, test_env};
+    use crate::{
+        run, Allocation, Edit, FunctionBuilder, MachineEnv, Operand, OperandConstraint,
+        OperandKind, OperandPos, Output, PReg, ProgPoint, RegClass, RegallocOptions, SpillSlot,
+    };
+    use alloc::vec::Vec;
+
+    fn p(i: usize) -> PReg {
+        PReg::new(i, RegClass::Int)
+    }
+
+    fn mv(from: Allocation, to: Allocation) -> Edit {
+        Edit::Move { from, to }
+    }
+
+    fn reg(i: usize) -> Allocation {
+        Allocation::reg(p(i))
+    }
+
+    fn edits_at(out: &Output, pos: ProgPoint) -> Vec<Edit> {
+        out.edits
+            .iter()
+            .filter(|&&(at, _)| at == pos)
+            .map(|(_, edit)| edit.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_branch_operand() {
+        // v0 stays in p0 until the return, so each branch to join
+        // needs a move into join's param, placed before the branch.
+        // The branch in left reads v2, which that move must not
+        // overwrite.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let right = b.add_block();
+        let join = b.add_block();
+        b.add_inst(entry, &[Operand::reg_fixed_def(int(0), p(0))]);
+        b.add_branch(entry, &[], &[left, right]);
+        b.add_inst(left, &[Operand::reg_def(int(2))]);
+        let branch = b.add_branch(left, &[Operand::reg_use(int(2))], &[join]);
+        b.set_branch_args(left, 0, &[int(0)]);
+        b.add_branch(right, &[], &[join]);
+        b.set_branch_args(right, 0, &[int(0)]);
+        b.add_block_param(join, int(3));
+        b.add_ret(
+            join,
+            &[
+                Operand::reg_fixed_use(int(0), p(0)),
+                Operand::reg_use(int(3)),
+            ],
+        );
+        let f = b.build();
+
+        let env = test_env(4);
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+        // v2 is moved out of the way of the edge move into p3.
+        assert_eq!(out.inst_allocs(branch), &[reg(1)]);
+        assert_eq!(
+            edits_at(&out, ProgPoint::before(branch)),
+            [mv(reg(3), reg(1)), mv(reg(0), reg(3))]
+        );
+    }
+
+    #[test]
+    fn test_entry_liveins() {
+        // The live-ins arrive in p2 and p3 and are used there, with
+        // no moves.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        b.add_entry_livein(int(0), p(2));
+        b.add_entry_livein(int(1), p(3));
+        let op = b.add_inst(
+            entry,
+            &[
+                Operand::reg_def(int(2)),
+                Operand::reg_use(int(0)),
+                Operand::reg_use(int(1)),
+            ],
+        );
+        b.add_ret(entry, &[Operand::reg_use(int(2))]);
+        let f = b.build();
+
+        let env = test_env(4);
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+        assert_eq!(&out.inst_allocs(op)[1..], &[reg(2), reg(3)]);
+        assert!(edits_at(&out, ProgPoint::before(op)).is_empty());
+    }
+
+    /// Allocates a return that needs the live-ins in p0, p1 and p2
+    /// rotated by one register, and returns the edits.
+    fn rotate(env: &MachineEnv) -> Vec<Edit> {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        for i in 0..3 {
+            b.add_entry_livein(int(i), p(i));
+        }
+        let ret = b.add_ret(
+            entry,
+            &[
+                Operand::reg_fixed_use(int(0), p(1)),
+                Operand::reg_fixed_use(int(1), p(2)),
+                Operand::reg_fixed_use(int(2), p(0)),
+            ],
+        );
+        let f = b.build();
+
+        let out = run(&f, env, &RegallocOptions::default()).unwrap();
+        check(&f, env, &out);
+        assert_eq!(
+            out.edits.len(),
+            edits_at(&out, ProgPoint::before(ret)).len()
+        );
+        edits_at(&out, ProgPoint::before(ret))
+    }
+
+    #[test]
+    fn test_swap_cycle() {
+        // A 3-register cycle takes two swaps and no scratch.
+        let env = MachineEnv {
+            reg_swaps_by_class: [true, false],
+            ..test_env(4)
+        };
+        assert_eq!(
+            rotate(&env),
+            [
+                Edit::Swap {
+                    a: reg(2),
+                    b: reg(0)
+                },
+                Edit::Swap {
+                    a: reg(2),
+                    b: reg(1)
+                },
+            ]
+        );
+        // Without swaps, it goes through the free p3.
+        assert_eq!(
+            rotate(&test_env(4)),
+            [
+                mv(reg(0), reg(3)),
+                mv(reg(2), reg(0)),
+                mv(reg(1), reg(2)),
+                mv(reg(3), reg(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dedicated_scratch() {
+        // With every allocatable register in the cycle, the dedicated
+        // scratch p7 is used rather than a spillslot, and is also
+        // preferred to the free p3.
+        let expected = [
+            mv(reg(0), reg(7)),
+            mv(reg(2), reg(0)),
+            mv(reg(1), reg(2)),
+            mv(reg(7), reg(1)),
+        ];
+        for n in 3..5 {
+            let env = MachineEnv {
+                scratch_by_class: [Some(p(7)), None],
+                ..test_env(n)
+            };
+            assert_eq!(rotate(&env), expected);
+        }
+    }
+
+    #[test]
+    fn test_stack_to_stack() {
+        // v0 and v1 live on the stack in different slots, so passing
+        // v0 as v1 is a stack-to-stack move, which goes through a
+        // register unless the target can do it directly.
+        let stack = |vreg, kind| {
+            let pos = match kind {
+                OperandKind::Def => OperandPos::Late,
+                _ => OperandPos::Early,
+            };
+            Operand::new(vreg, OperandConstraint::Stack, kind, pos)
+        };
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        let b1 = b.add_block();
+        b.add_inst(b0, &[stack(int(0), OperandKind::Def)]);
+        b.add_branch(b0, &[], &[b1]);
+        b.set_branch_args(b0, 0, &[int(0)]);
+        b.add_block_param(b1, int(1));
+        let ret = b.add_ret(
+            b1,
+            &[
+                stack(int(1), OperandKind::Use),
+                stack(int(0), OperandKind::Use),
+            ],
+        );
+        let f = b.build();
+
+        let slot = |i| Allocation::stack(SpillSlot::new(i));
+        let edits = |env: &MachineEnv| {
+            let out = run(&f, env, &RegallocOptions::default()).unwrap();
+            check(&f, env, &out);
+            edits_at(&out, ProgPoint::before(ret))
+        };
+        assert_eq!(
+            edits(&test_env(4)),
+            [mv(slot(0), reg(0)), mv(reg(0), slot(1))]
+        );
+        let env = MachineEnv {
+            stack_to_stack_moves_by_class: [true, false],
+            ..test_env(4)
+        };
+        assert_eq!(edits(&env), [mv(slot(0), slot(1))]);
+    }
+}
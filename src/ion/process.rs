@@ -13,21 +13,20 @@
 //! Main allocation loop that processes bundles.
 
 use super::{
-    spill_weight_from_constraint, Env, LiveBundleIndex, LiveBundleVec, LiveRangeFlag,
-    LiveRangeIndex, LiveRangeKey, LiveRangeList, LiveRangeListEntry, PRegIndex, RegTraversalIter,
-    Requirement, SpillWeight, UseList, VRegIndex,
+    Env, Heuristics, LiveBundleIndex, LiveBundleVec, LiveRangeFlag, LiveRangeIndex, LiveRangeKey,
+    LiveRangeList, LiveRangeListEntry, LoopInfo, PRegIndex, RegTraversalIter, Requirement,
+    SpillWeight, UseList, VRegIndex,
 };
 use crate::{
     ion::data_structures::{
         CodeRange, BUNDLE_MAX_NORMAL_SPILL_WEIGHT, MAX_SPLITS_PER_SPILLSET,
         MINIMAL_BUNDLE_SPILL_WEIGHT, MINIMAL_FIXED_BUNDLE_SPILL_WEIGHT,
     },
-    Allocation, Function, Inst, InstPosition, OperandConstraint, OperandKind, PReg, ProgPoint,
-    RegAllocError,
+    Allocation, Function, FxHashSet, Inst, InstPosition, OperandConstraint, OperandKind, PReg,
+    ProgPoint, RegAllocError,
 };
-use fxhash::FxHashSet;
+use core::fmt::Debug;
 use smallvec::{smallvec, SmallVec};
-use std::fmt::Debug;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum AllocRegResult {
@@ -37,7 +36,7 @@ pub enum AllocRegResult {
     ConflictHighCost,
 }
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     pub fn process_bundles(&mut self) -> Result<(), RegAllocError> {
         while let Some((bundle, reg_hint)) = self.allocation_queue.pop() {
             self.stats.process_bundle_count += 1;
@@ -159,7 +158,7 @@ impl<'a, F: Function> Env<'a, F> {
                     trace!("   -> conflict bundle {:?}", conflict_bundle);
                     if self.conflict_set.insert(conflict_bundle) {
                         conflicts.push(conflict_bundle);
-                        max_conflict_weight = std::cmp::max(
+                        max_conflict_weight = core::cmp::max(
                             max_conflict_weight,
                             self.bundles[conflict_bundle.index()].cached_spill_weight(),
                         );
@@ -172,7 +171,7 @@ impl<'a, F: Function> Env<'a, F> {
                     }
 
                     if first_conflict.is_none() {
-                        first_conflict = Some(ProgPoint::from_index(std::cmp::max(
+                        first_conflict = Some(ProgPoint::from_index(core::cmp::max(
                             preg_key.from,
                             key.from,
                         )));
@@ -334,7 +333,7 @@ impl<'a, F: Function> Env<'a, F> {
                     self.bundles[bundle.index()].prio,
                     final_weight
                 );
-                std::cmp::min(BUNDLE_MAX_NORMAL_SPILL_WEIGHT, final_weight)
+                core::cmp::min(BUNDLE_MAX_NORMAL_SPILL_WEIGHT, final_weight)
             } else {
                 0
             }
@@ -824,7 +823,7 @@ impl<'a, F: Function> Env<'a, F> {
                 // (up to the Before of the next inst), *unless*
                 // the original LR was only over the Before (up to
                 // the After) of this inst.
-                let to = std::cmp::min(ProgPoint::before(u.pos.inst().next()), lr_to);
+                let to = core::cmp::min(ProgPoint::before(u.pos.inst().next()), lr_to);
 
                 // If the last bundle was at the same inst, add a new
                 // LR to the same bundle; otherwise, create a LR and a
@@ -863,7 +862,7 @@ impl<'a, F: Function> Env<'a, F> {
 
                 // Otherwise, create a new LR.
                 let pos = ProgPoint::before(u.pos.inst());
-                let pos = std::cmp::max(lr_from, pos);
+                let pos = core::cmp::max(lr_from, pos);
                 let cr = CodeRange { from: pos, to };
                 let lr = self.create_liverange(cr);
                 new_lrs.push((vreg, lr));
@@ -1036,7 +1035,7 @@ impl<'a, F: Function> Env<'a, F> {
                     self.get_or_create_spill_bundle(bundle, /* create_if_absent = */ false)
                 {
                     let mut list =
-                        std::mem::replace(&mut self.bundles[bundle.index()].ranges, smallvec![]);
+                        core::mem::replace(&mut self.bundles[bundle.index()].ranges, smallvec![]);
                     for entry in &list {
                         self.ranges[entry.index.index()].bundle = spill;
                     }
@@ -1073,6 +1072,7 @@ impl<'a, F: Function> Env<'a, F> {
 
             let mut lowest_cost_evict_conflict_set: Option<LiveBundleVec> = None;
             let mut lowest_cost_evict_conflict_cost: Option<u32> = None;
+            let mut lowest_cost_evict_conflict_reg_cost = 0;
 
             let mut lowest_cost_split_conflict_cost: Option<u32> = None;
             let mut lowest_cost_split_conflict_point = ProgPoint::before(Inst::new(0));
@@ -1090,10 +1090,17 @@ impl<'a, F: Function> Env<'a, F> {
                 .index()
                 + bundle.index();
 
+            // The cost of a register (see `MachineEnv::reg_costs`)
+            // counts once for each of our uses.
+            let num_uses = self.bundles[bundle.index()]
+                .ranges
+                .iter()
+                .map(|entry| self.ranges[entry.index.index()].uses.len() as u32)
+                .sum::<u32>();
+
             self.stats.process_bundle_reg_probe_start_any += 1;
             for preg in RegTraversalIter::new(
-                self.env,
-                class,
+                &self.reg_tiers_by_class[class as usize],
                 hint_reg,
                 PReg::invalid(),
                 scan_offset,
@@ -1107,7 +1114,7 @@ impl<'a, F: Function> Env<'a, F> {
                     lowest_cost_evict_conflict_cost,
                     lowest_cost_split_conflict_cost,
                 ) {
-                    (Some(a), Some(b)) => Some(std::cmp::max(a, b)),
+                    (Some(a), Some(b)) => Some(core::cmp::max(a, b)),
                     _ => None,
                 };
                 match self.try_to_allocate_bundle_to_reg(bundle, preg_idx, scan_limit_cost) {
@@ -1127,20 +1134,27 @@ impl<'a, F: Function> Env<'a, F> {
 
                         let conflict_cost = self.maximum_spill_weight_in_bundle_set(&bundles);
 
+                        // Among registers to evict from, prefer those
+                        // that are cheaper to allocate to as well.
+                        let reg_cost = self.pregs[preg.index()].cost.saturating_mul(num_uses);
                         if lowest_cost_evict_conflict_cost.is_none()
-                            || conflict_cost < lowest_cost_evict_conflict_cost.unwrap()
+                            || conflict_cost.saturating_add(reg_cost)
+                                < lowest_cost_evict_conflict_cost
+                                    .unwrap()
+                                    .saturating_add(lowest_cost_evict_conflict_reg_cost)
                         {
                             lowest_cost_evict_conflict_cost = Some(conflict_cost);
+                            lowest_cost_evict_conflict_reg_cost = reg_cost;
                             lowest_cost_evict_conflict_set = Some(bundles);
                         }
 
                         let loop_depth = self.cfginfo.approx_loop_depth
                             [self.cfginfo.insn_block[first_conflict_point.inst().index()].index()];
-                        let move_cost = spill_weight_from_constraint(
+                        let move_cost = SpillWeight::from_f32(self.heuristics.use_spill_weight(
                             OperandConstraint::Reg,
-                            loop_depth as usize,
+                            loop_depth,
                             /* is_def = */ true,
-                        )
+                        ))
                         .to_int();
                         if lowest_cost_split_conflict_cost.is_none()
                             || (conflict_cost + move_cost)
@@ -1156,11 +1170,11 @@ impl<'a, F: Function> Env<'a, F> {
 
                         let loop_depth = self.cfginfo.approx_loop_depth
                             [self.cfginfo.insn_block[point.inst().index()].index()];
-                        let move_cost = spill_weight_from_constraint(
+                        let move_cost = SpillWeight::from_f32(self.heuristics.use_spill_weight(
                             OperandConstraint::Reg,
-                            loop_depth as usize,
+                            loop_depth,
                             /* is_def = */ true,
-                        )
+                        ))
                         .to_int();
 
                         if lowest_cost_split_conflict_cost.is_none()
@@ -1290,27 +1304,16 @@ impl<'a, F: Function> Env<'a, F> {
                     self.bundle_spill_weight(bundle)
                 );
                 let bundle_start = self.bundles[bundle.index()].ranges[0].range.from;
-                let mut split_at_point =
-                    std::cmp::max(lowest_cost_split_conflict_point, bundle_start);
+                let conflict_point = core::cmp::max(lowest_cost_split_conflict_point, bundle_start);
                 let requeue_with_reg = lowest_cost_split_conflict_reg;
 
-                // Adjust `split_at_point` if it is within a deeper loop
-                // than the bundle start -- hoist it to just before the
-                // first loop header it encounters.
-                let bundle_start_depth = self.cfginfo.approx_loop_depth
-                    [self.cfginfo.insn_block[bundle_start.inst().index()].index()];
-                let split_at_depth = self.cfginfo.approx_loop_depth
-                    [self.cfginfo.insn_block[split_at_point.inst().index()].index()];
-                if split_at_depth > bundle_start_depth {
-                    for block in (self.cfginfo.insn_block[bundle_start.inst().index()].index() + 1)
-                        ..=self.cfginfo.insn_block[split_at_point.inst().index()].index()
-                    {
-                        if self.cfginfo.approx_loop_depth[block] > bundle_start_depth {
-                            split_at_point = self.cfginfo.block_entry[block];
-                            break;
-                        }
-                    }
-                }
+                // Let the heuristics move the split point back, e.g.
+                // out of a loop, but not before the bundle start nor
+                // past the conflict.
+                let split_at_point = self
+                    .heuristics
+                    .split_point(&LoopInfo::new(&self.cfginfo), bundle_start, conflict_point)
+                    .clamp(bundle_start, conflict_point);
 
                 self.split_and_requeue_bundle(
                     bundle,
@@ -1,4 +1,5 @@
-use crate::{MachineEnv, PReg, RegClass};
+use crate::{MachineEnv, PReg};
+use alloc::{vec, vec::Vec};
 
 /// This iterator represents a traversal through all allocatable
 /// registers of a given class, in a certain order designed to
@@ -6,31 +7,30 @@ use crate::{MachineEnv, PReg, RegClass};
 ///
 /// The order in which we try registers is somewhat complex:
 /// - First, if there is a hint, we try that.
-/// - Then, we try registers in a traversal order that is based on an
-///   "offset" (usually the bundle index) spreading pressure evenly
-///   among registers to reduce commitment-map contention.
-/// - Within that scan, we try registers in two groups: first,
-///   prferred registers; then, non-preferred registers. (In normal
-///   usage, these consist of caller-save and callee-save registers
-///   respectively, to minimize clobber-saves; but they need not.)
-
+/// - Then, we try registers in tiers of increasing cost: registers of
+///   the same cost form a tier, and preferred registers come before
+///   non-preferred ones of the same cost. (In normal usage, without
+///   costs, the tiers consist of caller-save and callee-save
+///   registers respectively, to minimize clobber-saves; but they need
+///   not.)
+/// - Within each tier, we try registers in a traversal order that is
+///   based on an "offset" (usually the bundle index) spreading
+///   pressure evenly among registers to reduce commitment-map
+///   contention.
 pub struct RegTraversalIter<'a> {
-    env: &'a MachineEnv,
-    class: usize,
+    tiers: &'a [Vec<PReg>],
     hints: [Option<PReg>; 2],
     hint_idx: usize,
-    pref_idx: usize,
-    non_pref_idx: usize,
-    offset_pref: usize,
-    offset_non_pref: usize,
+    tier_idx: usize,
+    reg_idx: usize,
+    offset: usize,
     is_fixed: bool,
     fixed: Option<PReg>,
 }
 
 impl<'a> RegTraversalIter<'a> {
     pub fn new(
-        env: &'a MachineEnv,
-        class: RegClass,
+        tiers: &'a [Vec<PReg>],
         hint_reg: PReg,
         hint2_reg: PReg,
         offset: usize,
@@ -52,33 +52,20 @@ impl<'a> RegTraversalIter<'a> {
             hint2_reg = None;
         }
         let hints = [hint_reg, hint2_reg];
-        let class = class as u8 as usize;
-        let offset_pref = if env.preferred_regs_by_class[class].len() > 0 {
-            offset % env.preferred_regs_by_class[class].len()
-        } else {
-            0
-        };
-        let offset_non_pref = if env.non_preferred_regs_by_class[class].len() > 0 {
-            offset % env.non_preferred_regs_by_class[class].len()
-        } else {
-            0
-        };
         Self {
-            env,
-            class,
+            tiers,
             hints,
             hint_idx: 0,
-            pref_idx: 0,
-            non_pref_idx: 0,
-            offset_pref,
-            offset_non_pref,
+            tier_idx: 0,
+            reg_idx: 0,
+            offset,
             is_fixed: fixed.is_some(),
             fixed,
         }
     }
 }
 
-impl<'a> std::iter::Iterator for RegTraversalIter<'a> {
+impl<'a> core::iter::Iterator for RegTraversalIter<'a> {
     type Item = PReg;
 
     fn next(&mut self) -> Option<PReg> {
@@ -88,31 +75,20 @@ impl<'a> std::iter::Iterator for RegTraversalIter<'a> {
             return ret;
         }
 
-        fn wrap(idx: usize, limit: usize) -> usize {
-            if idx >= limit {
-                idx - limit
-            } else {
-                idx
-            }
-        }
         if self.hint_idx < 2 && self.hints[self.hint_idx].is_some() {
             let h = self.hints[self.hint_idx];
             self.hint_idx += 1;
             return h;
         }
-        while self.pref_idx < self.env.preferred_regs_by_class[self.class].len() {
-            let arr = &self.env.preferred_regs_by_class[self.class][..];
-            let r = arr[wrap(self.pref_idx + self.offset_pref, arr.len())];
-            self.pref_idx += 1;
-            if Some(r) == self.hints[0] || Some(r) == self.hints[1] {
+        while self.tier_idx < self.tiers.len() {
+            let arr = &self.tiers[self.tier_idx][..];
+            if self.reg_idx >= arr.len() {
+                self.tier_idx += 1;
+                self.reg_idx = 0;
                 continue;
             }
-            return Some(r);
-        }
-        while self.non_pref_idx < self.env.non_preferred_regs_by_class[self.class].len() {
-            let arr = &self.env.non_preferred_regs_by_class[self.class][..];
-            let r = arr[wrap(self.non_pref_idx + self.offset_non_pref, arr.len())];
-            self.non_pref_idx += 1;
+            let r = arr[(self.reg_idx + self.offset % arr.len()) % arr.len()];
+            self.reg_idx += 1;
             if Some(r) == self.hints[0] || Some(r) == self.hints[1] {
                 continue;
             }
@@ -121,3 +97,74 @@ impl<'a> std::iter::Iterator for RegTraversalIter<'a> {
         None
     }
 }
+
+/// Groups the allocatable registers of each class into the tiers
+/// that `RegTraversalIter` tries in order: registers are ordered by
+/// cost, then preferred before non-preferred, and those with the same
+/// cost and preference form a tier, in their order in `env`.
+pub fn reg_tiers_by_class(env: &MachineEnv) -> [Vec<Vec<PReg>>; 2] {
+    let mut costs = vec![0; PReg::NUM_INDEX];
+    for &(preg, cost) in &env.reg_costs {
+        costs[preg.index()] = cost;
+    }
+    let mut tiers_by_class = [vec![], vec![]];
+    for (class, tiers) in tiers_by_class.iter_mut().enumerate() {
+        let mut regs: Vec<(u32, bool, PReg)> = env.preferred_regs_by_class[class]
+            .iter()
+            .map(|&preg| (costs[preg.index()], false, preg))
+            .chain(
+                env.non_preferred_regs_by_class[class]
+                    .iter()
+                    .map(|&preg| (costs[preg.index()], true, preg)),
+            )
+            .collect();
+        // The sort is stable, keeping each tier in `env`'s order.
+        regs.sort_by_key(|&(cost, non_preferred, _)| (cost, non_preferred));
+        for (i, &(cost, non_preferred, preg)) in regs.iter().enumerate() {
+            if i == 0 || (regs[i - 1].0, regs[i - 1].1) != (cost, non_preferred) {
+                tiers.push(vec![]);
+            }
+            tiers.last_mut().unwrap().push(preg);
+        }
+    }
+    tiers_by_class
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::RegClass;
+
+    #[test]
+    fn test_reg_costs() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        let env = MachineEnv {
+            preferred_regs_by_class: [vec![r(0), r(1), r(2)], vec![]],
+            non_preferred_regs_by_class: [vec![r(3), r(4)], vec![]],
+            reg_costs: vec![(r(1), 1), (r(4), 1), (r(0), 2)],
+            ..Default::default()
+        };
+        let tiers = reg_tiers_by_class(&env);
+        assert_eq!(
+            tiers[0],
+            vec![vec![r(2)], vec![r(3)], vec![r(1)], vec![r(4)], vec![r(0)]]
+        );
+        assert!(tiers[1].is_empty());
+
+        // The hint comes first; the offset only rotates within tiers.
+        let order: Vec<_> =
+            RegTraversalIter::new(&tiers[0], r(4), PReg::invalid(), 1, None).collect();
+        assert_eq!(order, vec![r(4), r(2), r(3), r(1), r(0)]);
+
+        // Without costs, the tiers are the preferred and non-preferred
+        // registers.
+        let env = MachineEnv {
+            reg_costs: vec![],
+            ..env
+        };
+        let tiers = reg_tiers_by_class(&env);
+        let order: Vec<_> =
+            RegTraversalIter::new(&tiers[0], PReg::invalid(), PReg::invalid(), 1, None).collect();
+        assert_eq!(order, vec![r(1), r(2), r(0), r(4), r(3)]);
+    }
+}
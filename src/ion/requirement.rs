@@ -12,7 +12,7 @@
 
 //! Requirements computation.
 
-use super::{Env, LiveBundleIndex};
+use super::{Env, Heuristics, LiveBundleIndex};
 use crate::{Function, Inst, Operand, OperandConstraint, PReg, ProgPoint};
 
 pub struct RequirementConflict;
@@ -104,7 +104,7 @@ impl Requirement {
     }
 }
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     #[inline(always)]
     pub fn requirement_from_operand(&self, op: Operand) -> Requirement {
         match op.constraint() {
@@ -115,7 +115,9 @@ impl<'a, F: Function> Env<'a, F> {
                     Requirement::FixedReg(preg)
                 }
             }
-            OperandConstraint::Reg | OperandConstraint::Reuse(_) => Requirement::Register,
+            OperandConstraint::Reg
+            | OperandConstraint::Reuse(_)
+            | OperandConstraint::DistinctFrom(_) => Requirement::Register,
             OperandConstraint::Stack => Requirement::Stack,
             OperandConstraint::Any => Requirement::Any,
         }
@@ -0,0 +1,651 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Spill placement.
+//!
+//! Spill stores and reloads are inserted wherever a liverange split
+//! happens to fall, so a value that is spilled around a call inside a
+//! loop is stored and reloaded on every iteration, even if it is never
+//! redefined in the loop. This pass runs over the inserted moves once
+//! all of them are known, and:
+//!
+//! - adds a store just after the definition of each value whose stores
+//!   are all in deeper loops than its definition, so the spillslot
+//!   holds the value from the start;
+//! - drops every move whose destination provably already holds the
+//!   value being moved: stores that repeat an earlier store of the
+//!   same value into the same slot, and reloads into a register that
+//!   still holds the value from an earlier reload.
+//!
+//! The hoisted stores are kept only where they make a store in a loop
+//! redundant. A hoisted store is always safe: a spillslot is reserved
+//! for its spillset over *all* of its vregs' liveranges, including
+//! those in registers, so nothing else can be using the slot while
+//! the value is live.
+//!
+//! Redundancy is found with a forward dataflow analysis over the CFG,
+//! tracking for each allocation which vreg's current value it holds
+//! (a "must" analysis: at merge points, only facts that hold on every
+//! incoming edge survive). Because the analysis runs before parallel
+//! moves are resolved, a register only keeps its value across a move
+//! position if it is allocated there or named by one of the moves:
+//! any other register may be taken as a scratch register.
+//!
+//! Reloads are not hoisted, sunk or merged: a reload in a loop stays
+//! in the loop, even if the value is never redefined there, and the
+//! only reloads removed are the redundant ones above. The register a
+//! reload targets is only known to be free within the liverange that
+//! starts at the reload, so moving a reload out of a loop would need
+//! the allocator to keep that register free over the whole loop.
+
+use super::{
+    CodeRange, Env, Heuristics, InsertMovePrio, InsertedMove, LiveRangeKey, PosWithPrio, VRegIndex,
+};
+use crate::{
+    Allocation, Block, Function, FxHashMap, FxHashSet, Inst, InstPosition, OperandKind, ProgPoint,
+};
+use alloc::{vec, vec::Vec};
+use smallvec::SmallVec;
+
+/// For each allocation, the vreg whose current value it is known to
+/// hold.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ValueLocations {
+    vreg_in: FxHashMap<Allocation, VRegIndex>,
+    locations_of: FxHashMap<VRegIndex, SmallVec<[Allocation; 4]>>,
+}
+
+impl ValueLocations {
+    fn get(&self, alloc: Allocation) -> Option<VRegIndex> {
+        self.vreg_in.get(&alloc).cloned()
+    }
+
+    fn remove(&mut self, alloc: Allocation) {
+        if let Some(vreg) = self.vreg_in.remove(&alloc) {
+            let locations = self.locations_of.get_mut(&vreg).unwrap();
+            locations.retain(|&mut loc| loc != alloc);
+        }
+    }
+
+    fn set(&mut self, alloc: Allocation, vreg: VRegIndex) {
+        self.remove(alloc);
+        self.vreg_in.insert(alloc, vreg);
+        self.locations_of.entry(vreg).or_default().push(alloc);
+    }
+
+    /// The vreg takes on a new value: no location holds it any more.
+    fn kill(&mut self, vreg: VRegIndex) {
+        if let Some(locations) = self.locations_of.remove(&vreg) {
+            for alloc in locations {
+                self.vreg_in.remove(&alloc);
+            }
+        }
+    }
+
+    /// Keep only the facts that also hold in `other`. Returns whether
+    /// anything changed.
+    fn meet(&mut self, other: &ValueLocations) -> bool {
+        let before = self.vreg_in.len();
+        self.vreg_in
+            .retain(|alloc, vreg| other.vreg_in.get(alloc) == Some(vreg));
+        if self.vreg_in.len() == before {
+            return false;
+        }
+        let vreg_in = &self.vreg_in;
+        self.locations_of.retain(|&vreg, locations| {
+            locations.retain(|&mut alloc| vreg_in.get(&alloc) == Some(&vreg));
+            !locations.is_empty()
+        });
+        true
+    }
+}
+
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
+    pub fn optimize_spill_placement(&mut self) {
+        self.inserted_moves.sort_by_key(|m| m.pos_prio.key());
+
+        let hoisted = self.hoisted_spill_stores();
+        if !hoisted.is_empty() {
+            // Keep only the hoisted stores that make more stores in
+            // deeper loops redundant than were already, and redo the
+            // analysis without the others.
+            let before = self.redundant_deeper_stores(&hoisted);
+            self.inserted_moves.extend(hoisted.values().cloned());
+            self.inserted_moves.sort_by_key(|m| m.pos_prio.key());
+            let after = self.redundant_deeper_stores(&hoisted);
+            let useful: FxHashSet<VRegIndex> = after
+                .iter()
+                .filter(|&(vreg, &count)| count > before.get(vreg).cloned().unwrap_or(0))
+                .map(|(&vreg, _)| vreg)
+                .collect();
+            self.inserted_moves.retain(|m| {
+                let vreg = VRegIndex::new(m.to_vreg.vreg());
+                match hoisted.get(&vreg) {
+                    Some(store) if !useful.contains(&vreg) => !is_same_move(m, store),
+                    _ => true,
+                }
+            });
+            self.stats.spill_placement_hoisted_stores = useful.len();
+        }
+
+        // Redundant moves become moves from their destination to
+        // itself rather than being removed: the destination must stay
+        // reserved while the parallel move is resolved, because a
+        // liverange starting there doesn't keep the scratch register
+        // search away from it.
+        let redundant = self.find_redundant_moves();
+        for (m, &redundant) in self.inserted_moves.iter_mut().zip(redundant.iter()) {
+            if redundant {
+                m.from_alloc = m.to_alloc;
+            }
+        }
+        self.stats.spill_placement_removed_moves = redundant.iter().filter(|&&r| r).count();
+    }
+
+    /// For each vreg with a hoisted store, the number of its stores in
+    /// deeper loops that are redundant.
+    fn redundant_deeper_stores(
+        &self,
+        hoisted: &FxHashMap<VRegIndex, InsertedMove>,
+    ) -> FxHashMap<VRegIndex, usize> {
+        let redundant = self.find_redundant_moves();
+        let mut counts = FxHashMap::default();
+        for (m, _) in self
+            .inserted_moves
+            .iter()
+            .zip(redundant.iter())
+            .filter(|&(_, &redundant)| redundant)
+        {
+            let vreg = VRegIndex::new(m.to_vreg.vreg());
+            if let Some(store) = hoisted.get(&vreg) {
+                if m.to_alloc == store.to_alloc
+                    && self.move_loop_depth(m) > self.move_loop_depth(store)
+                {
+                    *counts.entry(vreg).or_insert(0) += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    fn move_loop_depth(&self, m: &InsertedMove) -> u32 {
+        let block = self.cfginfo.insn_block[m.pos_prio.pos.inst().index()];
+        self.cfginfo.approx_loop_depth[block.index()]
+    }
+
+    /// The spillslot reserved for the given vreg, if any.
+    fn vreg_spillslot(&self, vreg: VRegIndex) -> Option<Allocation> {
+        let range = self.vregs[vreg.index()].ranges.first()?.index;
+        let bundle = self.ranges[range.index()].bundle;
+        let spillset = self.bundles[bundle.index()].spillset;
+        let slot = self.spillsets[spillset.index()].slot;
+        if slot.is_valid() {
+            Some(self.spillslots[slot.index()].alloc)
+        } else {
+            None
+        }
+    }
+
+    /// For each vreg that is stored to its spillslot only in loops
+    /// deeper than its definition, a store to put just after the
+    /// definition.
+    fn hoisted_spill_stores(&self) -> FxHashMap<VRegIndex, InsertedMove> {
+        let is_reg = |alloc: Allocation| matches!(alloc.as_reg(), Some(preg) if !self.pregs[preg.index()].is_stack);
+
+        // The deepest loop containing a store of each vreg.
+        let mut store_depth: FxHashMap<VRegIndex, u32> = FxHashMap::default();
+        for m in &self.inserted_moves {
+            if is_reg(m.from_alloc) && m.to_alloc.is_stack() {
+                let depth = store_depth
+                    .entry(VRegIndex::new(m.to_vreg.vreg()))
+                    .or_insert(0);
+                *depth = core::cmp::max(*depth, self.move_loop_depth(m));
+            }
+        }
+        if store_depth.is_empty() {
+            return FxHashMap::default();
+        }
+        let reftypes: FxHashSet<usize> = self
+            .func
+            .reftype_vregs()
+            .iter()
+            .map(|vreg| vreg.vreg())
+            .collect();
+
+        // The single def of each stored vreg, if it has one.
+        let mut defs: FxHashMap<VRegIndex, Option<(Inst, usize)>> = FxHashMap::default();
+        for inst in 0..self.func.num_insts() {
+            let inst = Inst::new(inst);
+            for (i, op) in self.func.inst_operands(inst).iter().enumerate() {
+                if op.kind() == OperandKind::Use || op.as_fixed_nonallocatable().is_some() {
+                    continue;
+                }
+                let vreg = VRegIndex::new(op.vreg().vreg());
+                if store_depth.contains_key(&vreg) {
+                    defs.entry(vreg)
+                        .and_modify(|def| *def = None)
+                        .or_insert(Some((inst, i)));
+                }
+            }
+        }
+
+        let mut hoisted = FxHashMap::default();
+        for (vreg, def) in defs {
+            let (inst, slot) = match def {
+                Some(def) => def,
+                None => continue,
+            };
+            if reftypes.contains(&vreg.index())
+                || self.func.is_pinned_vreg(self.vreg(vreg)).is_some()
+            {
+                continue;
+            }
+            let block = self.cfginfo.insn_block[inst.index()];
+            if inst == self.func.block_insns(block).last()
+                || self.cfginfo.approx_loop_depth[block.index()] >= store_depth[&vreg]
+            {
+                continue;
+            }
+            let spillslot = match self.vreg_spillslot(vreg) {
+                Some(alloc) => alloc,
+                None => continue,
+            };
+            let alloc = self.get_alloc(inst, slot);
+            if !is_reg(alloc) {
+                continue;
+            }
+
+            // Don't interfere with other moves of the value, or into
+            // its register, right after the def.
+            let pos = ProgPoint::after(inst);
+            let start = self
+                .inserted_moves
+                .partition_point(|m| m.pos_prio.pos < pos);
+            if self.inserted_moves[start..]
+                .iter()
+                .take_while(|m| m.pos_prio.pos == pos)
+                .any(|m| m.to_alloc == alloc || m.to_vreg.vreg() == vreg.index())
+            {
+                continue;
+            }
+
+            trace!(
+                "spill placement: hoisting store of {:?} to {} after def at {:?}",
+                vreg,
+                spillslot,
+                inst
+            );
+            hoisted.insert(
+                vreg,
+                InsertedMove {
+                    pos_prio: PosWithPrio {
+                        pos,
+                        prio: InsertMovePrio::Regular as u32,
+                    },
+                    from_alloc: alloc,
+                    to_alloc: spillslot,
+                    to_vreg: self.vreg(vreg),
+                },
+            );
+        }
+        hoisted
+    }
+
+    /// Find the inserted moves whose destination already holds the
+    /// value being moved. `inserted_moves` must be sorted.
+    fn find_redundant_moves(&self) -> Vec<bool> {
+        let num_blocks = self.func.num_blocks();
+        let mut block_moves = Vec::with_capacity(num_blocks);
+        for block in 0..num_blocks {
+            let entry = self.cfginfo.block_entry[block];
+            let exit = self.cfginfo.block_exit[block];
+            let start = self
+                .inserted_moves
+                .partition_point(|m| m.pos_prio.pos < entry);
+            let end = self
+                .inserted_moves
+                .partition_point(|m| m.pos_prio.pos <= exit);
+            block_moves.push(start..end);
+        }
+
+        let mut entry_state = ValueLocations::default();
+        for &(vreg, preg) in self.func.entry_liveins() {
+            entry_state.set(Allocation::reg(preg), VRegIndex::new(vreg.vreg()));
+        }
+        let mut block_in: Vec<Option<ValueLocations>> = vec![None; num_blocks];
+        block_in[self.func.entry_block().index()] = Some(entry_state);
+
+        let mut workqueue: Vec<Block> = (0..num_blocks).rev().map(Block::new).collect();
+        let mut queued = vec![true; num_blocks];
+        while let Some(block) = workqueue.pop() {
+            queued[block.index()] = false;
+            let mut state = match &block_in[block.index()] {
+                Some(state) => state.clone(),
+                None => continue,
+            };
+            self.redundant_moves_in_block(block, &block_moves[block.index()], &mut state, None);
+            for &succ in self.func.block_succs(block) {
+                let changed = match &mut block_in[succ.index()] {
+                    Some(succ_in) => succ_in.meet(&state),
+                    succ_in @ None => {
+                        *succ_in = Some(state.clone());
+                        true
+                    }
+                };
+                if changed && !queued[succ.index()] {
+                    queued[succ.index()] = true;
+                    workqueue.push(succ);
+                }
+            }
+        }
+
+        let mut redundant = vec![false; self.inserted_moves.len()];
+        for block in 0..num_blocks {
+            if let Some(mut state) = block_in[block].take() {
+                self.redundant_moves_in_block(
+                    Block::new(block),
+                    &block_moves[block],
+                    &mut state,
+                    Some(&mut redundant),
+                );
+            }
+        }
+        redundant
+    }
+
+    /// Update `state` through `block`, whose moves are at `moves` in
+    /// `inserted_moves`, marking redundant moves if asked to.
+    fn redundant_moves_in_block(
+        &self,
+        block: Block,
+        moves: &core::ops::Range<usize>,
+        state: &mut ValueLocations,
+        mut redundant: Option<&mut Vec<bool>>,
+    ) {
+        // Values arriving on the edge into the block.
+        for &(vreg, preg) in self.edge_defs_into(block) {
+            let vreg = VRegIndex::new(vreg.vreg());
+            state.kill(vreg);
+            state.set(Allocation::reg(preg), vreg);
+        }
+
+        let mut i = moves.start;
+        for inst in self.func.block_insns(block).iter() {
+            for pos in [ProgPoint::before(inst), ProgPoint::after(inst)] {
+                // Each run of moves with the same position and
+                // priority is a parallel move.
+                while i < moves.end && self.inserted_moves[i].pos_prio.pos == pos {
+                    let pos_prio = self.inserted_moves[i].pos_prio;
+                    let start = i;
+                    while i < moves.end && self.inserted_moves[i].pos_prio == pos_prio {
+                        i += 1;
+                    }
+                    let redundant = redundant.as_deref_mut().map(|r| &mut r[start..i]);
+                    self.redundant_moves_in_parallel_move(
+                        &self.inserted_moves[start..i],
+                        state,
+                        redundant,
+                    );
+                }
+                if pos.pos() == InstPosition::Before {
+                    self.update_value_locations_for_inst(block, inst, state);
+                }
+            }
+        }
+    }
+
+    fn redundant_moves_in_parallel_move(
+        &self,
+        moves: &[InsertedMove],
+        state: &mut ValueLocations,
+        mut redundant: Option<&mut [bool]>,
+    ) {
+        // Resolving the parallel move may use any register that is
+        // neither allocated to a liverange here nor named by one of
+        // the moves as a scratch register, so we can't rely on what
+        // such a register holds from here on.
+        let pos = moves[0].pos_prio.pos;
+        let key = LiveRangeKey::from_range(&CodeRange {
+            from: pos,
+            to: pos.next(),
+        });
+        let scratch_candidates: SmallVec<[Allocation; 8]> = state
+            .vreg_in
+            .keys()
+            .cloned()
+            .filter(|&alloc| match alloc.as_reg() {
+                Some(preg) => {
+                    !self.pregs[preg.index()].is_stack
+                        && !self.pregs[preg.index()]
+                            .allocations
+                            .btree
+                            .contains_key(&key)
+                        && !moves
+                            .iter()
+                            .any(|m| m.from_alloc == alloc || m.to_alloc == alloc)
+                }
+                None => false,
+            })
+            .collect();
+        for alloc in scratch_candidates {
+            state.remove(alloc);
+        }
+
+        // All sources are read before any destination is written. A
+        // move whose source is not known to hold the current value of
+        // its vreg may give the vreg a new value (as blockparam moves
+        // do), so we forget every other location of that vreg.
+        let srcs: SmallVec<[Option<VRegIndex>; 8]> =
+            moves.iter().map(|m| state.get(m.from_alloc)).collect();
+        let mut new_values: SmallVec<[VRegIndex; 4]> = SmallVec::new();
+        for (m, &src) in moves.iter().zip(srcs.iter()) {
+            let vreg = VRegIndex::new(m.to_vreg.vreg());
+            if src != Some(vreg) && !new_values.contains(&vreg) {
+                new_values.push(vreg);
+            }
+        }
+        for (k, (m, &src)) in moves.iter().zip(srcs.iter()).enumerate() {
+            let vreg = VRegIndex::new(m.to_vreg.vreg());
+            if src == Some(vreg)
+                && !new_values.contains(&vreg)
+                && m.from_alloc != m.to_alloc
+                && state.get(m.to_alloc) == Some(vreg)
+            {
+                if let Some(redundant) = redundant.as_deref_mut() {
+                    trace!(
+                        "spill placement: redundant move {} -> {} for {:?} at {:?}",
+                        m.from_alloc,
+                        m.to_alloc,
+                        vreg,
+                        m.pos_prio.pos
+                    );
+                    redundant[k] = true;
+                }
+            }
+        }
+        for &vreg in &new_values {
+            state.kill(vreg);
+        }
+        for (m, &src) in moves.iter().zip(srcs.iter()) {
+            let vreg = VRegIndex::new(m.to_vreg.vreg());
+            if src == Some(vreg) && new_values.contains(&vreg) {
+                // A copy of the old value alongside the new one.
+                state.remove(m.to_alloc);
+            } else {
+                state.set(m.to_alloc, vreg);
+            }
+        }
+    }
+
+    fn update_value_locations_for_inst(
+        &self,
+        block: Block,
+        inst: Inst,
+        state: &mut ValueLocations,
+    ) {
+        let operands = self.func.inst_operands(inst);
+        for op in operands {
+            if op.kind() != OperandKind::Use && op.as_fixed_nonallocatable().is_none() {
+                state.kill(VRegIndex::new(op.vreg().vreg()));
+            }
+        }
+        for preg in self.func.inst_clobbers(inst) {
+            state.remove(Allocation::reg(preg));
+        }
+        if self.func.is_branch(inst) {
+            for preg in self.branch_edge_clobbers(block, inst) {
+                state.remove(Allocation::reg(preg));
+            }
+        }
+        for (i, op) in operands.iter().enumerate() {
+            if op.kind() == OperandKind::Use {
+                continue;
+            }
+            let alloc = self.get_alloc(inst, i);
+            if op.as_fixed_nonallocatable().is_some() {
+                state.remove(alloc);
+            } else {
+                state.set(alloc, VRegIndex::new(op.vreg().vreg()));
+            }
+        }
+        // A safepoint may move the objects that reftyped values
+        // point to, updating their spillslots but not other copies.
+        if self.func.requires_refs_on_stack(inst) {
+            for vreg in self.func.reftype_vregs() {
+                state.kill(VRegIndex::new(vreg.vreg()));
+            }
+        }
+    }
+}
+
+fn is_same_move(a: &InsertedMove, b: &InsertedMove) -> bool {
+    a.pos_prio == b.pos_prio
+        && a.from_alloc == b.from_alloc
+        && a.to_alloc == b.to_alloc
+        && a.to_vreg == b.to_vreg
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ion::heuristics::LoopInfo;
+    use crate::ion::DefaultHeuristics;
+    use crate::serialize::SerializableFunction;
+    use crate::testutil::{check, int, test_env};
+    use crate::{
+        run_with_heuristics, Edit, FunctionBuilder, Operand, Output, PReg, PRegSet, RegClass,
+        RegallocOptions, SpillSlot,
+    };
+
+    /// Splits bundles right at the conflict, rather than before the
+    /// loop containing it, so spills land inside loops.
+    struct SplitAtConflict;
+
+    impl Heuristics for SplitAtConflict {
+        fn split_point(&self, _: &LoopInfo, _: ProgPoint, conflict: ProgPoint) -> ProgPoint {
+            conflict
+        }
+    }
+
+    /// v0 is defined before a loop, whose header uses it around a call
+    /// that clobbers every register, and returned after it. The loop
+    /// is insts 2 to 6.
+    fn looped() -> SerializableFunction {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let header = b.add_block();
+        let latch = b.add_block();
+        let exit = b.add_block();
+        b.add_inst(entry, &[Operand::reg_def(int(0))]);
+        b.add_branch(entry, &[], &[header]);
+        b.add_inst(
+            header,
+            &[Operand::reg_def(int(1)), Operand::reg_use(int(0))],
+        );
+        let call = b.add_inst(header, &[Operand::reg_use(int(1))]);
+        let mut all = PRegSet::empty();
+        for i in 0..4 {
+            all.add(PReg::new(i, RegClass::Int));
+        }
+        b.set_clobbers(call, all);
+        b.add_inst(
+            header,
+            &[Operand::reg_def(int(2)), Operand::reg_use(int(0))],
+        );
+        b.add_branch(header, &[Operand::reg_use(int(2))], &[latch, exit]);
+        b.add_branch(latch, &[], &[header]);
+        b.add_ret(exit, &[Operand::reg_use(int(0))]);
+        b.build()
+    }
+
+    /// Allocate `f` with spill placement off and then on, and check
+    /// both results.
+    fn allocate<H: Heuristics>(f: &SerializableFunction, heuristics: &H) -> [Output; 2] {
+        let env = test_env(4);
+        [false, true].map(|optimize_spill_placement| {
+            let options = RegallocOptions {
+                optimize_spill_placement,
+                ..RegallocOptions::default()
+            };
+            let out = run_with_heuristics(f, &env, &options, heuristics).unwrap();
+            check(f, &env, &out);
+            out
+        })
+    }
+
+    fn mv(pos: ProgPoint, from: Allocation, to: Allocation) -> (ProgPoint, Edit) {
+        (pos, Edit::Move { from, to })
+    }
+
+    #[test]
+    fn test_hoisted_store() {
+        // Split at the call, v0 is stored in the loop on every
+        // iteration. The store is hoisted to just after the def, which
+        // makes the one in the loop redundant. The reload stays in the
+        // loop.
+        let p0 = Allocation::reg(PReg::new(0, RegClass::Int));
+        let slot = Allocation::stack(SpillSlot::new(0));
+        let reload = mv(ProgPoint::before(Inst::new(4)), slot, p0);
+        let [off, on] = allocate(&looped(), &SplitAtConflict);
+        assert_eq!(
+            off.edits,
+            [
+                mv(ProgPoint::before(Inst::new(3)), p0, slot),
+                reload.clone()
+            ]
+        );
+        assert_eq!(
+            on.edits,
+            [mv(ProgPoint::after(Inst::new(0)), p0, slot), reload]
+        );
+        assert_eq!(off.stats.spill_placement_hoisted_stores, 0);
+        assert_eq!(on.stats.spill_placement_hoisted_stores, 1);
+        assert_eq!(on.stats.spill_placement_removed_moves, 1);
+    }
+
+    #[test]
+    fn test_redundant_store() {
+        // With the split before the loop, v0 is stored on entry to it,
+        // and again in the loop, where the slot still holds it. The
+        // store in the loop is dropped, and none is hoisted, since the
+        // one on entry is already outside the loop. The reload stays
+        // in the loop.
+        let p0 = Allocation::reg(PReg::new(0, RegClass::Int));
+        let slot = Allocation::stack(SpillSlot::new(0));
+        let store = mv(ProgPoint::before(Inst::new(1)), p0, slot);
+        let reload = mv(ProgPoint::before(Inst::new(4)), slot, p0);
+        let [off, on] = allocate(&looped(), &DefaultHeuristics);
+        assert_eq!(
+            off.edits,
+            [
+                store.clone(),
+                mv(ProgPoint::before(Inst::new(3)), p0, slot),
+                reload.clone()
+            ]
+        );
+        assert_eq!(on.edits, [store, reload]);
+        assert_eq!(on.stats.spill_placement_hoisted_stores, 0);
+    }
+}
@@ -12,10 +12,11 @@
 
 //! Stackmap computation.
 
-use super::{Env, ProgPoint, VRegIndex};
+use super::{Env, Heuristics, ProgPoint, VRegIndex};
 use crate::{ion::data_structures::u64_key, Function};
+use alloc::vec::Vec;
 
-impl<'a, F: Function> Env<'a, F> {
+impl<'a, F: Function, H: Heuristics> Env<'a, F, H> {
     pub fn compute_stackmaps(&mut self) {
         // For each ref-typed vreg, iterate through ranges and find
         // safepoints in-range. Add the SpillSlot to the stackmap.
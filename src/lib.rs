@@ -11,6 +11,21 @@
  */
 
 #![allow(dead_code)]
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+
+// Hash maps and sets are always hashbrown's, keyed with the Fx hash:
+// they are fast, deterministic, and don't need `std`.
+pub(crate) type FxHashMap<K, V> =
+    hashbrown::HashMap<K, V, core::hash::BuildHasherDefault<rustc_hash::FxHasher>>;
+pub(crate) type FxHashSet<V> =
+    hashbrown::HashSet<V, core::hash::BuildHasherDefault<rustc_hash::FxHasher>>;
 
 // Even when trace logging is disabled, the trace macro has a significant
 // performance cost so we disable it in release builds.
@@ -32,14 +47,32 @@ pub(crate) mod cfg;
 pub(crate) mod domtree;
 pub mod indexset;
 pub(crate) mod ion;
+pub use ion::data_structures::CodeRange;
+pub use ion::heuristics::{DefaultHeuristics, Heuristics, LoopInfo};
 pub(crate) mod moves;
 pub(crate) mod postorder;
+pub(crate) mod split_edges;
+mod validate;
+pub use validate::{validate, ValidationError, ValidationErrors};
+mod builder;
+pub use builder::FunctionBuilder;
+mod rewrite;
+pub use rewrite::{rewrite, Emitter};
+mod pressure;
+pub use pressure::{compute_pressure, BlockPressure, PressureReport};
+#[cfg(test)]
+mod testutil;
 
 #[macro_use]
 mod index;
 pub use index::{Block, Inst, InstRange, InstRangeIter};
 
 pub mod checker;
+#[cfg(feature = "interp")]
+pub mod interp;
+#[cfg(feature = "reduce")]
+pub mod reduce;
+pub mod serialize;
 
 #[cfg(feature = "fuzzing")]
 pub mod fuzzing;
@@ -141,8 +174,8 @@ impl PReg {
     }
 }
 
-impl std::fmt::Debug for PReg {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for PReg {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(
             f,
             "PReg(hw = {}, class = {:?}, index = {})",
@@ -153,8 +186,8 @@ impl std::fmt::Debug for PReg {
     }
 }
 
-impl std::fmt::Display for PReg {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for PReg {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         let class = match self.class() {
             RegClass::Int => "i",
             RegClass::Float => "f",
@@ -291,8 +324,8 @@ impl VReg {
     }
 }
 
-impl std::fmt::Debug for VReg {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for VReg {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(
             f,
             "VReg(vreg = {}, class = {:?})",
@@ -302,8 +335,8 @@ impl std::fmt::Debug for VReg {
     }
 }
 
-impl std::fmt::Display for VReg {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for VReg {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "v{}", self.vreg())
     }
 }
@@ -362,8 +395,8 @@ impl SpillSlot {
     }
 }
 
-impl std::fmt::Display for SpillSlot {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for SpillSlot {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "stack{}", self.index())
     }
 }
@@ -374,10 +407,10 @@ impl std::fmt::Display for SpillSlot {
 /// class", for example -- or very specific, such as "this particular
 /// physical register". The allocator's result will always satisfy all
 /// given constraints; however, if the input has a combination of
-/// constraints that are impossible to satisfy, then allocation may
-/// fail or the allocator may panic (providing impossible constraints
-/// is usually a programming error in the client, rather than a
-/// function of bad input).
+/// constraints that are impossible to satisfy, then allocation will
+/// fail with a `RegAllocError` describing the conflict (providing
+/// impossible constraints is usually a programming error in the
+/// client, rather than a function of bad input).
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub enum OperandConstraint {
@@ -389,18 +422,27 @@ pub enum OperandConstraint {
     Stack,
     /// Operand must be in a fixed register.
     FixedReg(PReg),
-    /// On defs only: reuse a use's register.
+    /// On a def: reuse a use's register. On a use: the same
+    /// allocation as another use of the same vreg at the same point,
+    /// whatever that use's own constraint places it in; or the
+    /// register of a def, exactly as if the def reused the use. Uses
+    /// of different vregs can't be tied to each other.
     Reuse(usize),
+    /// On defs only: in a register, but not in the register of the
+    /// given use. The use's value is kept until the Late point, so it
+    /// also conflicts with any other Late def of the instruction.
+    DistinctFrom(usize),
 }
 
-impl std::fmt::Display for OperandConstraint {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for OperandConstraint {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             Self::Any => write!(f, "any"),
             Self::Reg => write!(f, "reg"),
             Self::Stack => write!(f, "stack"),
             Self::FixedReg(preg) => write!(f, "fixed({})", preg),
             Self::Reuse(idx) => write!(f, "reuse({})", idx),
+            Self::DistinctFrom(idx) => write!(f, "distinct({})", idx),
         }
     }
 }
@@ -476,6 +518,7 @@ pub struct Operand {
     /// The constraints are encoded as follows:
     /// - 1xxxxxx => FixedReg(preg)
     /// - 01xxxxx => Reuse(index)
+    /// - 001xxxx => DistinctFrom(index)
     /// - 0000000 => Any
     /// - 0000001 => Reg
     /// - 0000010 => Stack
@@ -504,6 +547,10 @@ impl Operand {
                 debug_assert!(which <= 31);
                 0b0100000 | which as u32
             }
+            OperandConstraint::DistinctFrom(which) => {
+                debug_assert!(which <= 15);
+                0b0010000 | which as u32
+            }
         };
         let class_field = vreg.class() as u8 as u32;
         let pos_field = pos as u8 as u32;
@@ -618,6 +665,56 @@ impl Operand {
         )
     }
 
+    /// Create an `Operand` that designates a use of a vreg that must
+    /// be in the same place as another operand of the instruction,
+    /// identified by `idx` (is the `idx`th `Operand` for the
+    /// instruction).
+    ///
+    /// If that operand is a use, it must be an early use of the same
+    /// vreg, and decides the allocation of both, e.g. for an
+    /// instruction that reads one value through two operand slots
+    /// which must name the same register.
+    ///
+    /// If it is a def, which may be of any vreg, it must be a late
+    /// def constrained to a register, and it is written to the
+    /// register that this use is read from, just as if it were the
+    /// result of `Operand::reg_reuse_def(vreg, i)` for this use's
+    /// index `i`. The use's vreg is copied there first if it must
+    /// outlive the instruction.
+    ///
+    /// A tie between uses of two different vregs is not supported:
+    /// one register can't hold both values at once, and no copy is
+    /// made to merge them, so `validate` reports it as
+    /// [`ValidationError::TiedUseMismatch`]. To get both into one
+    /// register, tie one use to the def that combines them instead.
+    #[inline(always)]
+    pub fn reg_tied_use(vreg: VReg, idx: usize) -> Self {
+        Operand::new(
+            vreg,
+            OperandConstraint::Reuse(idx),
+            OperandKind::Use,
+            OperandPos::Early,
+        )
+    }
+
+    /// Create an `Operand` that designates a def of a vreg that must
+    /// be in a register other than the one assigned to an input to
+    /// the instruction, e.g. for a multiply whose destination may not
+    /// be its second source. The input is identified by `idx` (is the
+    /// `idx`th `Operand` for the instruction), which must be at most
+    /// 15. Unlike `reg_def_at_start`, this conflicts only with that
+    /// one input, though the input also conflicts with any other
+    /// outputs.
+    #[inline(always)]
+    pub fn reg_distinct_def(vreg: VReg, idx: usize) -> Self {
+        Operand::new(
+            vreg,
+            OperandConstraint::DistinctFrom(idx),
+            OperandKind::Def,
+            OperandPos::Late,
+        )
+    }
+
     /// Create an `Operand` that designates a use of a vreg and
     /// ensures that it is placed in the given, fixed PReg at the
     /// use. It is guaranteed that the `Allocation` resulting for this
@@ -742,6 +839,8 @@ impl Operand {
             OperandConstraint::FixedReg(PReg::new(constraint_field & 0b0111111, self.class()))
         } else if constraint_field & 0b0100000 != 0 {
             OperandConstraint::Reuse(constraint_field & 0b0011111)
+        } else if constraint_field & 0b0010000 != 0 {
+            OperandConstraint::DistinctFrom(constraint_field & 0b0001111)
         } else {
             match constraint_field {
                 0 => OperandConstraint::Any,
@@ -763,6 +862,17 @@ impl Operand {
         }
     }
 
+    /// If this operand is a use tied to another operand (see
+    /// [`Operand::reg_tied_use`]), then returns the index of the
+    /// operand whose allocation it shares.
+    #[inline(always)]
+    pub fn as_tied_use(self) -> Option<usize> {
+        match (self.kind(), self.constraint()) {
+            (OperandKind::Use, OperandConstraint::Reuse(idx)) => Some(idx),
+            _ => None,
+        }
+    }
+
     /// Get the raw 32-bit encoding of this operand's fields.
     #[inline(always)]
     pub fn bits(self) -> u32 {
@@ -778,14 +888,40 @@ impl Operand {
     }
 }
 
-impl std::fmt::Debug for Operand {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        std::fmt::Display::fmt(self, f)
+/// The index of the use whose register the def at `idx` in
+/// `operands` reuses, through its own `Reuse` constraint or a use
+/// tied to it.
+pub(crate) fn input_reused_by(operands: &[Operand], idx: usize) -> Option<usize> {
+    let op = operands[idx];
+    if op.kind() != OperandKind::Def {
+        return None;
     }
+    if let OperandConstraint::Reuse(input) = op.constraint() {
+        return Some(input);
+    }
+    operands
+        .iter()
+        .position(|input| input.as_tied_use() == Some(idx))
 }
 
-impl std::fmt::Display for Operand {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+/// If the operand at `idx` in `operands` is a use tied to another
+/// use, returns the index of that use.
+pub(crate) fn tied_to_use(operands: &[Operand], idx: usize) -> Option<usize> {
+    let tied = operands[idx].as_tied_use()?;
+    match operands.get(tied) {
+        Some(op) if op.kind() == OperandKind::Use => Some(tied),
+        _ => None,
+    }
+}
+
+impl core::fmt::Debug for Operand {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
+}
+
+impl core::fmt::Display for Operand {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match (self.kind(), self.pos()) {
             (OperandKind::Def, OperandPos::Late)
             | (OperandKind::Mod | OperandKind::Use, OperandPos::Early) => {
@@ -819,14 +955,14 @@ pub struct Allocation {
     bits: u32,
 }
 
-impl std::fmt::Debug for Allocation {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        std::fmt::Display::fmt(self, f)
+impl core::fmt::Debug for Allocation {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
     }
 }
 
-impl std::fmt::Display for Allocation {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Allocation {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self.kind() {
             AllocationKind::None => write!(f, "none"),
             AllocationKind::Reg => write!(f, "{}", self.as_reg().unwrap()),
@@ -997,6 +1133,33 @@ pub trait Function {
     /// for each respective successor block.
     fn branch_blockparams(&self, block: Block, insn: Inst, succ_idx: usize) -> &[VReg];
 
+    /// If `insn` is a branch at the end of `block`, returns the
+    /// registers that are clobbered only when control leaves along
+    /// the edge to the given successor, in addition to
+    /// `inst_clobbers(insn)`. For example, a call with an exceptional
+    /// successor can give all caller-saved registers here for the
+    /// edge to its landing pad.
+    ///
+    /// Values live across the branch are kept out of the edge
+    /// clobbers of every successor, not only of the successors they
+    /// flow to. An edge with clobbers or defs (below) must lead to a
+    /// block with a single predecessor, as no moves can be placed on
+    /// the edge after the branch otherwise.
+    fn branch_edge_clobbers(&self, _block: Block, _insn: Inst, _succ_idx: usize) -> PRegSet {
+        PRegSet::empty()
+    }
+
+    /// If `insn` is a branch at the end of `block`, returns vregs
+    /// that are defined only when control leaves along the edge to
+    /// the given successor, each in the fixed register in which it
+    /// arrives, e.g. an exception payload. They are defined at the
+    /// start of the successor block, and are moved out of those
+    /// registers if needed just as for `entry_liveins`; the registers
+    /// are treated as edge clobbers.
+    fn branch_edge_defs(&self, _block: Block, _insn: Inst, _succ_idx: usize) -> &[(VReg, PReg)] {
+        &[]
+    }
+
     /// Determine whether an instruction requires all reference-typed
     /// values to be placed onto the stack. For these instructions,
     /// stackmaps will be provided.
@@ -1022,6 +1185,24 @@ pub trait Function {
     /// Get the Operands for an instruction.
     fn inst_operands(&self, insn: Inst) -> &[Operand];
 
+    /// Get the indices of two commutative inputs of an instruction,
+    /// if it has any, e.g. the sources of a two-address `add`.
+    ///
+    /// If a def of the instruction reuses one of the two inputs, and
+    /// that input's vreg is still live after the instruction while
+    /// the other input's is not, the allocator may have the def reuse
+    /// the other input instead, avoiding a copy. The two inputs then
+    /// swap roles: each takes the other's constraint, and the def's
+    /// allocation is that of the other input. Such instructions are
+    /// listed in `Output::commuted`, and the client must swap the
+    /// operands when emitting them.
+    ///
+    /// Both inputs must be early uses of the same class with a `Reg`
+    /// or `Any` constraint.
+    fn commutative_operands(&self, _insn: Inst) -> Option<(usize, usize)> {
+        None
+    }
+
     /// Get the clobbers for an instruction; these are the registers
     /// that, after the instruction has executed, hold values that are
     /// arbitrary, separately from the usual outputs to the
@@ -1113,6 +1294,22 @@ pub trait Function {
         None
     }
 
+    /// Return the vregs that are live-in to the function, each with
+    /// the location in which its value arrives: an ordinary `PReg`,
+    /// or a `PReg` from `MachineEnv::fixed_stack_slots` for a value
+    /// passed on the stack. Each such vreg is treated as defined at
+    /// the start of the entry block in that location, and may be used
+    /// without any other def; the allocator moves it elsewhere if it
+    /// needs to, so the location is not reserved beyond the entry
+    /// point. The entry block must have no predecessors if this is
+    /// non-empty.
+    ///
+    /// Any other vreg that is live-in to the entry block (other than
+    /// a pinned vreg) causes a `RegAllocError::EntryLivein`.
+    fn entry_liveins(&self) -> &[(VReg, PReg)] {
+        &[]
+    }
+
     // --------------
     // Spills/reloads
     // --------------
@@ -1177,8 +1374,8 @@ pub struct ProgPoint {
     bits: u32,
 }
 
-impl std::fmt::Debug for ProgPoint {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for ProgPoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(
             f,
             "progpoint{}{}",
@@ -1263,17 +1460,24 @@ impl ProgPoint {
 }
 
 /// An instruction to insert into the program to perform some data movement.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub enum Edit {
     /// Move one allocation to another. Each allocation may be a
     /// register or a stack slot (spillslot). However, stack-to-stack
-    /// moves will never be generated.
+    /// moves will never be generated, unless
+    /// `MachineEnv::stack_to_stack_moves_by_class` is set for the
+    /// class.
     ///
     /// `Move` edits will be generated even if src and dst allocation
     /// are the same if the vreg changes; this allows proper metadata
     /// tracking even when moves are elided.
     Move { from: Allocation, to: Allocation },
+
+    /// Exchange the contents of two registers of the same class. Only
+    /// generated for classes for which `MachineEnv::reg_swaps_by_class`
+    /// is set, and never for fixed stack slots.
+    Swap { a: Allocation, b: Allocation },
 }
 
 /// Wrapper around either an original instruction or an inserted edit.
@@ -1320,7 +1524,11 @@ impl<'a> Iterator for OutputIter<'a> {
 /// are available to allocate and what register may be used as a
 /// scratch register for each class, and some other miscellaneous info
 /// as well.
-#[derive(Clone, Debug)]
+///
+/// The default environment has no registers and every option off.
+/// Build one with `..Default::default()` for the options it does not
+/// set, so that options added later take their defaults.
+#[derive(Clone, Debug, Default)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct MachineEnv {
     /// Preferred physical registers for each class. These are the
@@ -1339,6 +1547,41 @@ pub struct MachineEnv {
     ///
     /// `PReg`s in this list cannot be used as an allocatable register.
     pub fixed_stack_slots: Vec<PReg>,
+
+    /// For each class, whether the client can cheaply swap the
+    /// contents of two registers of that class (e.g. with `xchg` on
+    /// x86). If so, cycles among register moves are performed with
+    /// `Edit::Swap`s, rather than through a scratch register or, if
+    /// none is free, an extra stack slot.
+    pub reg_swaps_by_class: [bool; 2],
+
+    /// For each class, an optional register that the client reserves
+    /// as a scratch register for the allocator's moves. It must not
+    /// be in any other list of the `MachineEnv`, and must not be
+    /// used by any operand. If none is given, the allocator searches
+    /// for a free register where one is needed and, if there is none,
+    /// uses an extra stack slot, and may save and restore a "victim"
+    /// register around stack-to-stack moves.
+    pub scratch_by_class: [Option<PReg>; 2],
+
+    /// For each class, whether the client can move a value directly
+    /// from one stack slot to another. If so, the allocator may
+    /// generate stack-to-stack `Edit::Move`s, and needs no scratch
+    /// register for them.
+    pub stack_to_stack_moves_by_class: [bool; 2],
+
+    /// Extra costs of allocating a value to particular registers, per
+    /// use of the value, e.g. for registers whose encoding is longer
+    /// or whose access is slower. Registers not listed cost nothing.
+    /// Each register listed must be allocatable.
+    ///
+    /// After any register hinted to avoid a move, the allocator tries
+    /// registers in order of increasing cost, preferred before
+    /// non-preferred registers of the same cost. When it must evict
+    /// other values, it weighs these costs against those of the
+    /// evicted values, which are measured in
+    /// `Heuristics::use_spill_weight` units.
+    pub reg_costs: Vec<(PReg, u32)>,
 }
 
 /// The output of the register allocator.
@@ -1352,6 +1595,14 @@ pub struct Output {
     /// program point.
     pub edits: Vec<(ProgPoint, Edit)>,
 
+    /// Edits that must be performed on a control-flow edge, given as
+    /// (from_block, to_block, edit). These only occur on critical
+    /// edges, and only when `RegallocOptions::allow_critical_edges` is
+    /// set: the client must split each edge that has edits by
+    /// inserting a block that performs them in order and then jumps
+    /// to `to_block`. Guaranteed to be sorted by edge.
+    pub edge_edits: Vec<(Block, Block, Edit)>,
+
     /// Allocations for each operand. Mapping from instruction to
     /// allocations provided by `inst_alloc_offsets` below.
     pub allocs: Vec<Allocation>,
@@ -1373,6 +1624,33 @@ pub struct Output {
     /// be disjoint.
     pub debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
 
+    /// Human-readable commentary from the allocator (e.g., why a
+    /// bundle was spilled or split, or which move was inserted),
+    /// keyed by the program point it refers to. Only populated when
+    /// `RegallocOptions::debug_annotations` is set. Guaranteed to be
+    /// sorted by program point; annotations at the same point appear
+    /// in the order they were generated.
+    pub debug_annotations: Vec<(ProgPoint, String)>,
+
+    /// The location of every vreg: the vreg is in the given
+    /// allocation from the first program point (inclusive) to the
+    /// second (exclusive). Only populated when
+    /// `RegallocOptions::vreg_locations` is set. Guaranteed to be
+    /// sorted by vreg and program point, and the ranges of each vreg
+    /// are disjoint; where a move copies the vreg from one allocation
+    /// to another, the move is at the boundary between two ranges.
+    /// Copies made just for one instruction, e.g. of an input reused
+    /// by an output or of a vreg used in several fixed registers at
+    /// once, are not included, so an operand's allocation may differ
+    /// from its vreg's location at that point.
+    pub vreg_locations: Vec<(VReg, ProgPoint, ProgPoint, Allocation)>,
+
+    /// The instructions whose commutative inputs (see
+    /// `Function::commutative_operands`) swapped roles. Allocations
+    /// are still given in the order of `Function::inst_operands`.
+    /// Guaranteed to be sorted.
+    pub commuted: Vec<Inst>,
+
     /// Internal stats from the allocator.
     pub stats: ion::Stats,
 }
@@ -1402,9 +1680,9 @@ impl Output {
                 // binary_search_by returns the index of where it would have
                 // been inserted in Err.
                 if pos < ProgPoint::before(inst_range.first()) {
-                    std::cmp::Ordering::Less
+                    core::cmp::Ordering::Less
                 } else {
-                    std::cmp::Ordering::Greater
+                    core::cmp::Ordering::Greater
                 }
             })
             .unwrap_err();
@@ -1412,13 +1690,86 @@ impl Output {
         let edits = &self.edits[edit_idx..];
         OutputIter { inst_range, edits }
     }
+
+    /// Returns the edits to perform on the edge from `from` to `to`,
+    /// in order. Always empty unless
+    /// `RegallocOptions::allow_critical_edges` was set.
+    pub fn edge_edits(&self, from: Block, to: Block) -> impl Iterator<Item = &Edit> {
+        let start = self
+            .edge_edits
+            .partition_point(|&(f, t, _)| (f, t) < (from, to));
+        self.edge_edits[start..]
+            .iter()
+            .take_while(move |&&(f, t, _)| (f, t) == (from, to))
+            .map(|(_, _, edit)| edit)
+    }
+
+    /// Returns whether the commutative inputs of `inst` swapped
+    /// roles, so that they must be swapped when emitting it.
+    pub fn is_commuted(&self, inst: Inst) -> bool {
+        self.commuted.binary_search(&inst).is_ok()
+    }
+
+    /// Returns the debug annotations attached to a given program
+    /// point, in the order they were generated. Always empty unless
+    /// `RegallocOptions::debug_annotations` was set.
+    pub fn debug_annotations_at(&self, pos: ProgPoint) -> impl Iterator<Item = &str> {
+        let start = self.debug_annotations.partition_point(|&(p, _)| p < pos);
+        self.debug_annotations[start..]
+            .iter()
+            .take_while(move |&&(p, _)| p == pos)
+            .map(|(_, s)| s.as_str())
+    }
 }
 
-/// An error that prevents allocation.
+/// The liveness of a function's vregs, as computed by
+/// `compute_liveness()`.
 #[derive(Clone, Debug)]
+pub struct Liveness {
+    /// The vregs that are live on entry to each block, sorted. A
+    /// block's own blockparams are not live-in: they are defined on
+    /// entry.
+    pub liveins: Vec<Vec<VReg>>,
+
+    /// The vregs that are live on exit from each block, sorted. This
+    /// is the union of the successors' live-ins, so it does not
+    /// include a vreg that is only passed as a branch arg.
+    pub liveouts: Vec<Vec<VReg>>,
+
+    /// For each vreg, the ranges of program points where it is live,
+    /// from its definition to its last use, sorted and disjoint.
+    /// Empty for a vreg that the function does not mention. The
+    /// ranges of a pinned vreg (see `Function::is_pinned_vreg`) may
+    /// stop short at program moves from it, which read its register
+    /// directly.
+    pub ranges: Vec<Vec<CodeRange>>,
+
+    /// The class of each vreg, or `None` for a vreg that the
+    /// function does not mention.
+    pub classes: Vec<Option<RegClass>>,
+}
+
+impl Liveness {
+    /// Returns the ranges where `vreg` is live.
+    pub fn vreg_ranges(&self, vreg: VReg) -> &[CodeRange] {
+        &self.ranges[vreg.vreg()][..]
+    }
+
+    /// Is `vreg` live at `pos`?
+    pub fn is_live_at(&self, vreg: VReg, pos: ProgPoint) -> bool {
+        let ranges = self.vreg_ranges(vreg);
+        let i = ranges.partition_point(|range| range.to <= pos);
+        i < ranges.len() && ranges[i].contains_point(pos)
+    }
+}
+
+/// An error that prevents allocation.
+#[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub enum RegAllocError {
-    /// Critical edge is not split between given blocks.
+    /// Critical edge is not split between given blocks. When
+    /// `RegallocOptions::allow_critical_edges` is set, this is only
+    /// returned if a block has two edges to the same successor.
     CritEdge(Block, Block),
     /// Invalid SSA for given vreg at given inst: multiple defs or
     /// illegal use. `inst` may be `Inst::invalid()` if this concerns
@@ -1430,25 +1781,64 @@ pub enum RegAllocError {
     /// Invalid branch: operand count does not match sum of block
     /// params of successor blocks.
     Branch(Inst),
-    /// A VReg is live-in on entry; this is not allowed.
+    /// A VReg is live-in on entry without being listed in
+    /// `Function::entry_liveins`, or entry live-ins were given for an
+    /// entry block that has predecessors.
     EntryLivein,
     /// A branch has non-blockparam arg(s) and at least one of the
     /// successor blocks has more than one predecessor, forcing
-    /// edge-moves before this branch. This is disallowed because it
-    /// places a use after the edge moves occur; insert an edge block
-    /// to avoid the situation.
+    /// edge-moves before this branch, and the args cannot be
+    /// accommodated: either the branch defines (writes) a vreg, which
+    /// would happen after the edge moves, or a use's constraint does
+    /// not allow it to be kept out of the way of the edge moves
+    /// (e.g., it is fixed to a register that the edge moves
+    /// overwrite). Insert an edge block to avoid the situation.
     DisallowedBranchArg(Inst),
+    /// A branch has edge clobbers or edge defs on an edge to a block
+    /// with more than one predecessor.
+    DisallowedEdgeEffects(Inst),
     /// Too many pinned VRegs + Reg-constrained Operands are live at
     /// once, making allocation impossible.
     TooManyLiveRegs,
+    /// Two operands of the given instruction (named by their indices)
+    /// that refer to different vregs require the same fixed register
+    /// at the same time.
+    FixedRegConflict {
+        inst: Inst,
+        operands: (usize, usize),
+        preg: PReg,
+    },
+    /// The given operand has a fixed-register constraint at a
+    /// position where it cannot be satisfied: a Use or Mod at the
+    /// Late point, or a Def or Mod at the Early point whose value is
+    /// used later.
+    InvalidFixedRegOperand {
+        inst: Inst,
+        operand: usize,
+        preg: PReg,
+    },
+    /// The given instruction needs more registers of a class at once
+    /// for its register-constrained operands than the `MachineEnv`
+    /// provides.
+    TooManyRegOperands {
+        inst: Inst,
+        class: RegClass,
+        needed: usize,
+        available: usize,
+    },
+    /// The given instruction refers to a vreg more than once at the
+    /// same point, and one of those references has a `Stack`
+    /// constraint; this combination is not supported.
+    MultipleStackUses { inst: Inst, vreg: VReg },
 }
 
-impl std::fmt::Display for RegAllocError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for RegAllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for RegAllocError {}
 
 /// Run the allocator.
@@ -1457,7 +1847,32 @@ pub fn run<F: Function>(
     env: &MachineEnv,
     options: &RegallocOptions,
 ) -> Result<Output, RegAllocError> {
-    ion::run(func, env, options.verbose_log)
+    ion::run(func, env, options)
+}
+
+/// Run the allocator with a custom cost model in place of
+/// `DefaultHeuristics`.
+pub fn run_with_heuristics<F: Function, H: Heuristics>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+    heuristics: &H,
+) -> Result<Output, RegAllocError> {
+    ion::run_with_heuristics(func, env, options, heuristics)
+}
+
+/// Compute the liveness of `func`'s vregs without allocating
+/// registers: the same analysis with which `run()` begins. `func`
+/// must satisfy the same requirements as for `run()` with `options`,
+/// except that its operand constraints need not be satisfiable by any
+/// `MachineEnv`. With `RegallocOptions::allow_critical_edges`, the
+/// analysis runs on the function with its critical edges split, and
+/// the results are given for the original blocks and instructions.
+pub fn compute_liveness<F: Function>(
+    func: &F,
+    options: &RegallocOptions,
+) -> Result<Liveness, RegAllocError> {
+    ion::liveness(func, options)
 }
 
 /// Options for allocation.
@@ -1465,4 +1880,25 @@ pub fn run<F: Function>(
 pub struct RegallocOptions {
     /// Add extra verbosity to debug logs.
     pub verbose_log: bool,
+
+    /// Accept critical edges in the CFG rather than failing with
+    /// `RegAllocError::CritEdge`. Moves needed on such an edge are
+    /// returned in `Output::edge_edits`, and the client must split
+    /// only those edges that have edits.
+    pub allow_critical_edges: bool,
+
+    /// Collect the allocator's debug annotations and return them in
+    /// `Output::debug_annotations`.
+    pub debug_annotations: bool,
+
+    /// Run a pass after allocation that hoists spill stores out of
+    /// loops, to just after the value's definition, and removes spill
+    /// stores and reloads made redundant by earlier ones. Reloads are
+    /// never moved, so a reload inside a loop stays there. This costs
+    /// a dataflow analysis over the whole function.
+    pub optimize_spill_placement: bool,
+
+    /// Report where each vreg is located over its whole lifetime in
+    /// `Output::vreg_locations`.
+    pub vreg_locations: bool,
 }
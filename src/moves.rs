@@ -4,13 +4,16 @@
  */
 
 use crate::{ion::data_structures::u64_key, Allocation, PReg};
+use core::fmt::Debug;
 use smallvec::{smallvec, SmallVec};
-use std::fmt::Debug;
 
 /// A list of moves to be performed in sequence, with auxiliary data
 /// attached to each.
 pub type MoveVec<T> = SmallVec<[(Allocation, Allocation, T); 16]>;
 
+/// A list of register swaps to be performed in sequence.
+pub type SwapVec = SmallVec<[(Allocation, Allocation); 4]>;
+
 /// A list of moves to be performance in sequence, like a
 /// `MoveVec<T>`, except that an unchosen scratch space may occur as
 /// well, represented by `Allocation::none()`.
@@ -57,6 +60,85 @@ impl<T: Clone + Copy + Default> ParallelMoves<T> {
         false
     }
 
+    /// Remove every cycle of moves among allocations that `can_swap`
+    /// accepts (that is, registers that can be exchanged directly),
+    /// and return a sequence of swaps that performs those moves
+    /// instead. A cycle of N moves becomes N-1 swaps, and needs no
+    /// scratch register.
+    ///
+    /// The swaps must happen *after* the moves that `resolve()`
+    /// produces from what remains: those moves may still read the
+    /// old values in the cycle, but never write to it, because each
+    /// allocation in a cycle is written only by the cycle's own move.
+    pub fn take_swap_cycles(&mut self, can_swap: impl Fn(Allocation) -> bool) -> SwapVec {
+        let mut swaps = smallvec![];
+        if self.parallel_moves.len() <= 1 {
+            return swaps;
+        }
+
+        // For each move, the move that writes its source, if any:
+        // following these links walks backward around a cycle.
+        self.parallel_moves.sort_by_key(|&(_, dst, _)| dst);
+        let writer_of_src: SmallVec<[Option<usize>; 16]> = self
+            .parallel_moves
+            .iter()
+            .map(|&(src, _, _)| {
+                self.parallel_moves
+                    .binary_search_by_key(&src, |&(_, dst, _)| dst)
+                    .ok()
+            })
+            .collect();
+
+        // Find the cycles by walking from each move, marking every
+        // move on the walk with the walk's starting index; reaching
+        // a move marked by the current walk closes a cycle.
+        let mut walk: SmallVec<[Option<usize>; 16]> = smallvec![None; self.parallel_moves.len()];
+        let mut in_cycle: SmallVec<[bool; 16]> = smallvec![false; self.parallel_moves.len()];
+        for start in 0..self.parallel_moves.len() {
+            let mut i = start;
+            while walk[i].is_none() {
+                walk[i] = Some(start);
+                match writer_of_src[i] {
+                    Some(next) => i = next,
+                    None => break,
+                }
+            }
+            if walk[i] != Some(start) || writer_of_src[i].is_none() || in_cycle[i] {
+                continue;
+            }
+
+            // `i` is on a cycle. Collect it backward, from the move
+            // writing r1 (reading r0) to the move writing r0.
+            let mut cycle: SmallVec<[usize; 8]> = smallvec![i];
+            let mut j = writer_of_src[i].unwrap();
+            while j != i {
+                cycle.push(j);
+                j = writer_of_src[j].unwrap();
+            }
+            if !cycle.iter().all(|&m| can_swap(self.parallel_moves[m].1)) {
+                continue;
+            }
+            for &m in &cycle {
+                in_cycle[m] = true;
+            }
+
+            // With moves r1 := r0, r2 := r1, ..., r0 := r(n-1),
+            // swapping r0 with each of r1, ..., r(n-1) in turn puts
+            // the right value into each, and finally into r0.
+            let (r0, _, _) = self.parallel_moves[i];
+            for &m in cycle[1..].iter().rev() {
+                swaps.push((r0, self.parallel_moves[m].0));
+            }
+        }
+
+        let mut idx = 0;
+        self.parallel_moves.retain(|_| {
+            idx += 1;
+            !in_cycle[idx - 1]
+        });
+        swaps
+    }
+
     /// Resolve the parallel-moves problem to a sequence of separate
     /// moves, such that the combined effect of the sequential moves
     /// is as-if all of the moves added to this `ParallelMoves`
@@ -320,6 +402,11 @@ impl<T> MoveVecWithScratch<T> {
 /// Sometimes move elision will be able to clean this up a bit. But,
 /// for simplicity reasons, let's keep the concerns separated! So we
 /// always do the full expansion above.
+///
+/// Targets can avoid most of this: a dedicated scratch register,
+/// reserved by the client and never allocated, serves as the scratch
+/// in either step without a search, and a target that can move
+/// directly from stack to stack needs no expansion at all.
 pub struct MoveAndScratchResolver<GetReg, GetStackSlot, IsStackAlloc>
 where
     GetReg: FnMut() -> Option<Allocation>,
@@ -343,6 +430,10 @@ where
     /// available. Provided by caller and statically chosen. This is a
     /// very last-ditch option, so static choice is OK.
     victim: PReg,
+    /// A register reserved by the client for our use, if any.
+    dedicated_scratch: Option<Allocation>,
+    /// Whether the target can move directly from stack to stack.
+    stack_to_stack_ok: bool,
 }
 
 impl<GetReg, GetStackSlot, IsStackAlloc> MoveAndScratchResolver<GetReg, GetStackSlot, IsStackAlloc>
@@ -364,30 +455,64 @@ where
             get_stackslot,
             is_stack_alloc,
             victim,
+            dedicated_scratch: None,
+            stack_to_stack_ok: false,
         }
     }
 
+    /// Use the given register, which must be free everywhere, as the
+    /// scratch register before searching for a free one. It is never
+    /// saved and restored, so no victim is ever needed.
+    pub fn with_dedicated_scratch(mut self, scratch: Option<Allocation>) -> Self {
+        self.dedicated_scratch = scratch;
+        self
+    }
+
+    /// Leave stack-to-stack moves in the result rather than expanding
+    /// them, for targets that can perform them directly.
+    pub fn with_stack_to_stack_moves(mut self, ok: bool) -> Self {
+        self.stack_to_stack_ok = ok;
+        self
+    }
+
     pub fn compute<T: Debug + Copy>(mut self, moves: MoveVecWithScratch<T>) -> MoveVec<T> {
         // First, do we have a vec with no stack-to-stack moves or use
         // of a scratch register? Fast return if so.
-        if !moves.needs_scratch() && !moves.stack_to_stack(&self.is_stack_alloc) {
+        let stack_to_stack = !self.stack_to_stack_ok && moves.stack_to_stack(&self.is_stack_alloc);
+        if !moves.needs_scratch() && !stack_to_stack {
             return moves.without_scratch().unwrap();
         }
 
         let mut result = smallvec![];
 
         // Now, find a scratch allocation in order to resolve cycles.
-        let scratch = (self.find_free_reg)().unwrap_or_else(|| (self.get_stackslot)());
+        // The dedicated scratch register, if any, is kept for
+        // stack-to-stack moves if there are any, as those may occur
+        // inside a cycle while the cycle's scratch is live.
+        let scratch = match self.dedicated_scratch {
+            _ if !moves.needs_scratch() => Allocation::none(),
+            Some(reg) if !stack_to_stack => {
+                self.dedicated_scratch = None;
+                reg
+            }
+            _ => (self.find_free_reg)().unwrap_or_else(|| (self.get_stackslot)()),
+        };
         trace!("scratch resolver: scratch alloc {:?}", scratch);
 
         let moves = moves.with_scratch(scratch);
         for &(src, dst, data) in &moves {
             // Do we have a stack-to-stack move? If so, resolve.
-            if (self.is_stack_alloc)(src) && (self.is_stack_alloc)(dst) {
+            if !self.stack_to_stack_ok && (self.is_stack_alloc)(src) && (self.is_stack_alloc)(dst) {
                 trace!("scratch resolver: stack to stack: {:?} -> {:?}", src, dst);
                 // Lazily allocate a stack-to-stack scratch.
                 if self.stack_stack_scratch_reg.is_none() {
-                    if let Some(reg) = (self.find_free_reg)() {
+                    if let Some(reg) = self.dedicated_scratch {
+                        trace!(
+                            "scratch resolver: using dedicated stack-to-stack scratch preg: {:?}",
+                            reg
+                        );
+                        self.stack_stack_scratch_reg = Some(reg);
+                    } else if let Some(reg) = (self.find_free_reg)() {
                         trace!(
                             "scratch resolver: have free stack-to-stack scratch preg: {:?}",
                             reg
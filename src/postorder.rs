@@ -6,6 +6,7 @@
 //! Fast postorder computation.
 
 use crate::Block;
+use alloc::{vec, vec::Vec};
 use smallvec::{smallvec, SmallVec};
 
 pub fn calculate<'a, SuccFn: Fn(Block) -> &'a [Block]>(
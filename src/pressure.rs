@@ -0,0 +1,291 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Register pressure: how many vregs of each class are live at each
+//! program point, compared with the number of registers that the
+//! `MachineEnv` offers for the class. Wherever more vregs are live
+//! than there are registers, the allocator must spill some of them,
+//! so an embedder can use the report to steer instruction selection
+//! and scheduling before allocation, or to explain spills after it.
+
+use crate::{
+    compute_liveness, Block, Function, Liveness, MachineEnv, ProgPoint, RegAllocError, RegClass,
+    RegallocOptions, VReg,
+};
+use alloc::{vec, vec::Vec};
+
+/// The register pressure in a function, as computed by
+/// `compute_pressure()`. Arrays with an entry per class are indexed
+/// by `RegClass as usize`.
+#[derive(Clone, Debug)]
+pub struct PressureReport {
+    /// The number of registers available to allocate in each class:
+    /// the preferred and non-preferred registers in the `MachineEnv`.
+    pub available: [usize; 2],
+
+    /// The number of vregs of each class live at each program point,
+    /// indexed by `ProgPoint::to_index()`.
+    pub points: Vec<[usize; 2]>,
+
+    /// The pressure in each block.
+    pub blocks: Vec<BlockPressure>,
+}
+
+/// The register pressure in one block.
+#[derive(Clone, Debug)]
+pub struct BlockPressure {
+    /// The greatest number of vregs of each class live at any point
+    /// in the block.
+    pub max: [usize; 2],
+
+    /// The first point in the block at which `max` is reached.
+    pub peak: [ProgPoint; 2],
+
+    /// The vregs of each class live at `peak`, sorted.
+    pub peak_vregs: [Vec<VReg>; 2],
+}
+
+impl PressureReport {
+    /// Compute the pressure from liveness that has already been
+    /// computed for `func`.
+    pub fn from_liveness<F: Function>(func: &F, env: &MachineEnv, liveness: &Liveness) -> Self {
+        let available = [0, 1].map(|c| {
+            env.preferred_regs_by_class[c].len() + env.non_preferred_regs_by_class[c].len()
+        });
+
+        // Count the ranges that start and end at each point, then sum
+        // them up.
+        let num_points = 2 * func.num_insts();
+        let mut deltas = vec![[0isize; 2]; num_points + 1];
+        for (ranges, class) in liveness.ranges.iter().zip(&liveness.classes) {
+            let class = match class {
+                Some(class) => *class,
+                None => continue,
+            };
+            for range in ranges {
+                deltas[range.from.to_index() as usize][class as usize] += 1;
+                deltas[range.to.to_index() as usize][class as usize] -= 1;
+            }
+        }
+        let mut live = [0isize; 2];
+        let points: Vec<[usize; 2]> = deltas[..num_points]
+            .iter()
+            .map(|delta| {
+                live[0] += delta[0];
+                live[1] += delta[1];
+                [live[0] as usize, live[1] as usize]
+            })
+            .collect();
+
+        let mut blocks: Vec<BlockPressure> = (0..func.num_blocks())
+            .map(|block| {
+                let insts = func.block_insns(Block::new(block));
+                let from = ProgPoint::before(insts.first()).to_index();
+                let to = ProgPoint::after(insts.last()).to_index();
+                let mut max = [0; 2];
+                let mut peak = [ProgPoint::from_index(from); 2];
+                for index in from..=to {
+                    for class in 0..2 {
+                        if points[index as usize][class] > max[class] {
+                            max[class] = points[index as usize][class];
+                            peak[class] = ProgPoint::from_index(index);
+                        }
+                    }
+                }
+                BlockPressure {
+                    max,
+                    peak,
+                    peak_vregs: [vec![], vec![]],
+                }
+            })
+            .collect();
+
+        // Collect the vregs live at each peak in one sweep over the
+        // ranges. Blocks are laid out in order, so the peaks of each
+        // class are sorted, and the ranges that contain a peak can be
+        // found by binary search. Vregs are visited in order, so each
+        // block's list comes out sorted.
+        let peaks = [0, 1].map(|c| {
+            blocks
+                .iter()
+                .map(|b| b.peak[c].to_index())
+                .collect::<Vec<_>>()
+        });
+        for (vreg, (ranges, class)) in liveness.ranges.iter().zip(&liveness.classes).enumerate() {
+            let class = match class {
+                Some(class) => *class,
+                None => continue,
+            };
+            let peaks = &peaks[class as usize];
+            for range in ranges {
+                let (from, to) = (range.from.to_index(), range.to.to_index());
+                let mut block = peaks.partition_point(|&peak| peak < from);
+                while block < peaks.len() && peaks[block] < to {
+                    blocks[block].peak_vregs[class as usize].push(VReg::new(vreg, class));
+                    block += 1;
+                }
+            }
+        }
+
+        PressureReport {
+            available,
+            points,
+            blocks,
+        }
+    }
+
+    /// Returns the number of vregs of each class live at `pos`.
+    pub fn at(&self, pos: ProgPoint) -> [usize; 2] {
+        self.points[pos.to_index() as usize]
+    }
+
+    /// Returns the points at which more vregs of `class` are live
+    /// than there are registers in the class.
+    pub fn excess_points(&self, class: RegClass) -> impl Iterator<Item = ProgPoint> + '_ {
+        let available = self.available[class as usize];
+        self.points
+            .iter()
+            .enumerate()
+            .filter(move |(_, live)| live[class as usize] > available)
+            .map(|(index, _)| ProgPoint::from_index(index as u32))
+    }
+
+    /// Returns the blocks in which more vregs of some class are live
+    /// at once than there are registers in the class.
+    pub fn excess_blocks(&self) -> impl Iterator<Item = Block> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(move |(_, b)| b.max[0] > self.available[0] || b.max[1] > self.available[1])
+            .map(|(block, _)| Block::new(block))
+    }
+}
+
+impl core::fmt::Display for PressureReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let mut first = true;
+        for block in self.excess_blocks() {
+            let b = &self.blocks[block.index()];
+            for class in [RegClass::Int, RegClass::Float] {
+                let c = class as usize;
+                if b.max[c] <= self.available[c] {
+                    continue;
+                }
+                if !first {
+                    writeln!(f)?;
+                }
+                first = false;
+                write!(
+                    f,
+                    "block{}: {} {:?} vregs live at {:?}, {} registers:",
+                    block.index(),
+                    b.max[c],
+                    class,
+                    b.peak[c],
+                    self.available[c]
+                )?;
+                for vreg in &b.peak_vregs[c] {
+                    write!(f, " {}", vreg)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compute the register pressure in `func` for the registers in
+/// `env`. `func` must satisfy the same requirements as for
+/// `compute_liveness()` with `options`.
+pub fn compute_pressure<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<PressureReport, RegAllocError> {
+    let liveness = compute_liveness(func, options)?;
+    Ok(PressureReport::from_liveness(func, env, &liveness))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{float, int, test_env};
+    use crate::{FunctionBuilder, Inst, Operand};
+
+    #[test]
+    fn test_excess() {
+        // Four int vregs live at once, with three int registers.
+        let mut b = FunctionBuilder::new();
+        let block = b.add_block();
+        for i in 0..4 {
+            b.add_inst(block, &[Operand::reg_def(int(i))]);
+        }
+        let uses: Vec<_> = (0..4).map(|i| Operand::reg_use(int(i))).collect();
+        b.add_ret(block, &uses);
+        let f = b.build();
+
+        let env = test_env(3);
+        let report = compute_pressure(&f, &env, &RegallocOptions::default()).unwrap();
+        assert_eq!(report.available, [3, 3]);
+        assert_eq!(report.at(ProgPoint::before(Inst::new(3))), [3, 0]);
+        assert_eq!(report.at(ProgPoint::before(Inst::new(4))), [4, 0]);
+        let b = &report.blocks[0];
+        assert_eq!(b.max, [4, 0]);
+        assert_eq!(b.peak[0], ProgPoint::after(Inst::new(3)));
+        assert_eq!(b.peak_vregs[0], (0..4).map(int).collect::<Vec<_>>());
+        assert_eq!(report.excess_blocks().collect::<Vec<_>>(), [block]);
+        assert_eq!(
+            report.excess_points(RegClass::Int).collect::<Vec<_>>(),
+            [
+                ProgPoint::after(Inst::new(3)),
+                ProgPoint::before(Inst::new(4))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blocks_and_classes() {
+        // Three float vregs live across two blocks, with two float
+        // registers; the int pressure stays low.
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        let b1 = b.add_block();
+        b.add_inst(b0, &[Operand::reg_def(int(0))]);
+        for i in 1..4 {
+            b.add_inst(b0, &[Operand::reg_def(float(i))]);
+        }
+        b.add_branch(b0, &[], &[b1]);
+        b.add_inst(b1, &[Operand::reg_def(int(4)), Operand::reg_use(int(0))]);
+        let mut uses: Vec<_> = (1..4).map(|i| Operand::reg_use(float(i))).collect();
+        uses.push(Operand::reg_use(int(4)));
+        b.add_ret(b1, &uses);
+        let f = b.build();
+
+        let env = test_env(2);
+        let liveness = compute_liveness(&f, &RegallocOptions::default()).unwrap();
+        let report = PressureReport::from_liveness(&f, &env, &liveness);
+        assert_eq!(report.blocks[0].max, [1, 3]);
+        assert_eq!(report.blocks[0].peak[1], ProgPoint::after(Inst::new(3)));
+        assert_eq!(report.blocks[1].max, [1, 3]);
+        assert_eq!(report.blocks[1].peak[1], ProgPoint::before(Inst::new(5)));
+        let floats: Vec<_> = (1..4).map(float).collect();
+        assert_eq!(report.blocks[0].peak_vregs[1], floats);
+        assert_eq!(report.blocks[1].peak_vregs[1], floats);
+        assert_eq!(report.excess_blocks().collect::<Vec<_>>(), [b0, b1]);
+        assert_eq!(report.excess_points(RegClass::Int).count(), 0);
+
+        // The peak vregs are exactly those live at each peak.
+        for b in &report.blocks {
+            for class in [RegClass::Int, RegClass::Float] {
+                let c = class as usize;
+                let live: Vec<_> = (0..f.num_vregs())
+                    .map(|i| VReg::new(i, class))
+                    .filter(|&vreg| liveness.classes[vreg.vreg()] == Some(class))
+                    .filter(|&vreg| liveness.is_live_at(vreg, b.peak[c]))
+                    .collect();
+                assert_eq!(b.peak_vregs[c], live);
+            }
+        }
+    }
+}
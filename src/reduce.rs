@@ -0,0 +1,944 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Test-case reduction.
+//!
+//! Given a function that the allocator mishandles and a predicate
+//! that recognizes the failure, `reduce` repeatedly deletes edges
+//! (and the blocks that become unreachable), merges straight-line
+//! blocks, and deletes instructions, operands, blockparams,
+//! constraints, clobbers and other attributes. It keeps
+//! each deletion after which the function is still as valid as it was
+//! to begin with (passing `validate` and in SSA form) and still fails,
+//! until no more can be deleted; then it renumbers the remaining
+//! vregs densely. The result is a `SerializableFunction`, which can be
+//! printed with `Display` and read back with `FromStr`, or saved
+//! together with its `MachineEnv` as a regression test.
+
+use crate::checker::{Checker, CheckerErrors};
+use crate::interp::{InterpError, Interpreter};
+use crate::serialize::SerializableFunction;
+use crate::{
+    domtree, postorder, validate, Block, FxHashMap, Inst, MachineEnv, Operand, OperandConstraint,
+    OperandKind, PRegSet, RegAllocError, RegallocOptions, VReg, ValidationError,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::Discriminant;
+
+/// A way in which allocating a function can fail.
+#[derive(Clone, Debug)]
+pub enum Failure {
+    /// The allocator returned an error.
+    RegAlloc(RegAllocError),
+    /// The checker rejected the allocator's output.
+    Checker(CheckerErrors),
+    /// Executing the allocated function gave different results.
+    Interp(InterpError),
+    /// The allocator or the checker panicked with the given message.
+    #[cfg(feature = "std")]
+    Panic(alloc::string::String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::RegAlloc(e) => write!(f, "allocation failed: {}", e),
+            Failure::Checker(e) => write!(f, "checker failed: {}", e),
+            Failure::Interp(e) => write!(f, "interpreter failed: {}", e),
+            #[cfg(feature = "std")]
+            Failure::Panic(msg) => write!(f, "panicked: {}", msg),
+        }
+    }
+}
+
+/// Allocate `func` and check the result, with the checker and then
+/// by interpreting a few paths through it, returning how that
+/// failed, if it did. This is a convenient building block for the
+/// predicate given to `reduce`.
+pub fn check(
+    func: &SerializableFunction,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<(), Failure> {
+    #[cfg(feature = "std")]
+    {
+        let run = core::panic::AssertUnwindSafe(|| allocate_and_check(func, env, options));
+        match std::panic::catch_unwind(run) {
+            Ok(result) => result,
+            Err(payload) => {
+                let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                    (*msg).into()
+                } else if let Some(msg) = payload.downcast_ref::<alloc::string::String>() {
+                    msg.clone()
+                } else {
+                    "unknown panic".into()
+                };
+                Err(Failure::Panic(msg))
+            }
+        }
+    }
+    #[cfg(not(feature = "std"))]
+    allocate_and_check(func, env, options)
+}
+
+/// How many paths `check` interprets, and for how many instructions.
+const INTERP_SEEDS: u64 = 4;
+const INTERP_FUEL: usize = 10_000;
+
+fn allocate_and_check(
+    func: &SerializableFunction,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<(), Failure> {
+    let out = crate::run(func, env, options).map_err(Failure::RegAlloc)?;
+    let mut checker = Checker::new(func, env);
+    checker.prepare(&out);
+    checker.run().map_err(Failure::Checker)?;
+    let mut interp = Interpreter::new(func, &out);
+    for seed in 0..INTERP_SEEDS {
+        interp.run(seed, INTERP_FUEL).map_err(Failure::Interp)?;
+    }
+    Ok(())
+}
+
+/// Reduce `func`, which `fails` must return true for, to a smaller
+/// function for which `fails` still returns true. The function is
+/// validated for allocation with `options`.
+pub fn reduce<P>(
+    func: SerializableFunction,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+    fails: P,
+) -> SerializableFunction
+where
+    P: FnMut(&SerializableFunction) -> bool,
+{
+    let mut reducer = Reducer::new(func, env, options, fails);
+    loop {
+        let mut changed = reducer.remove_edges();
+        changed |= reducer.merge_blocks();
+        changed |= reducer.remove_insts();
+        changed |= reducer.remove_blockparams();
+        changed |= reducer.remove_operands();
+        changed |= reducer.relax_constraints();
+        changed |= reducer.remove_attributes();
+        if !changed {
+            break;
+        }
+    }
+    reducer.attempt(compact_vregs);
+    reducer.func
+}
+
+struct Reducer<'a, P> {
+    func: SerializableFunction,
+    env: &'a MachineEnv,
+    options: &'a RegallocOptions,
+    fails: P,
+    allowed_errors: Vec<Discriminant<ValidationError>>,
+    require_ssa: bool,
+}
+
+impl<'a, P: FnMut(&SerializableFunction) -> bool> Reducer<'a, P> {
+    fn new(
+        func: SerializableFunction,
+        env: &'a MachineEnv,
+        options: &'a RegallocOptions,
+        fails: P,
+    ) -> Self {
+        // Keep the function as valid as it was to begin with: any
+        // problem found by `validate` must be of a kind it already had.
+        let allowed_errors = match validate(&func, env, options) {
+            Ok(()) => vec![],
+            Err(errors) => errors.errors.iter().map(core::mem::discriminant).collect(),
+        };
+        let require_ssa = is_ssa(&func);
+        Reducer {
+            func,
+            env,
+            options,
+            fails,
+            allowed_errors,
+            require_ssa,
+        }
+    }
+
+    /// Apply `edit` to a copy of the function, and keep the result if
+    /// `edit` returns true and the result is valid and still fails.
+    fn attempt<E: FnOnce(&mut SerializableFunction) -> bool>(&mut self, edit: E) -> bool {
+        let mut candidate = self.func.clone();
+        if !edit(&mut candidate) {
+            return false;
+        }
+        if let Err(errors) = validate(&candidate, self.env, self.options) {
+            let allowed = &self.allowed_errors;
+            if !errors
+                .errors
+                .iter()
+                .all(|e| allowed.contains(&core::mem::discriminant(e)))
+            {
+                return false;
+            }
+        }
+        if (self.require_ssa && !is_ssa(&candidate)) || !(self.fails)(&candidate) {
+            return false;
+        }
+        self.func = candidate;
+        true
+    }
+
+    /// Delete each block's edges, first all at once and then one at a
+    /// time, together with any blocks that become unreachable.
+    fn remove_edges(&mut self) -> bool {
+        let mut changed = false;
+        for block in (0..self.func.blocks.len()).rev() {
+            if block >= self.func.blocks.len() {
+                continue;
+            }
+            let num_succs = self.func.blocks[block].succs.len();
+            if num_succs > 1
+                && self.attempt(|f| {
+                    for succ_idx in (0..num_succs).rev() {
+                        remove_edge(f, block, succ_idx);
+                    }
+                    prune_unreachable(f);
+                    true
+                })
+            {
+                changed = true;
+                continue;
+            }
+            for succ_idx in (0..num_succs).rev() {
+                changed |= self.attempt(|f| {
+                    if block >= f.blocks.len() || succ_idx >= f.blocks[block].succs.len() {
+                        return false;
+                    }
+                    remove_edge(f, block, succ_idx);
+                    prune_unreachable(f);
+                    true
+                });
+            }
+        }
+        changed
+    }
+
+    /// Merge each block into the block before it, when that is its
+    /// only predecessor and it is that block's only successor.
+    fn merge_blocks(&mut self) -> bool {
+        let mut changed = false;
+        for block in (1..self.func.blocks.len()).rev() {
+            changed |= self.attempt(|f| {
+                let pred = block - 1;
+                let b = &f.blocks[block];
+                if block == f.entry_block.index()
+                    || b.preds.len() != 1
+                    || b.preds[0].index() != pred
+                    || !b.params.is_empty()
+                    || f.blocks[pred].succs.len() != 1
+                    || !f.blocks[pred].edge_defs[0].is_empty()
+                {
+                    return false;
+                }
+                // Drop the branch between the two.
+                let branch = f.blocks[pred].end.index() - 1;
+                remove_insts(f, branch, branch + 1);
+                let b = f.blocks[block].clone();
+                for &succ in &b.succs {
+                    for p in &mut f.blocks[succ.index()].preds {
+                        if p.index() == block {
+                            *p = Block::new(pred);
+                        }
+                    }
+                }
+                let p = &mut f.blocks[pred];
+                p.end = b.end;
+                p.succs = b.succs;
+                p.branch_args = b.branch_args;
+                p.edge_clobbers = b.edge_clobbers;
+                p.edge_defs = b.edge_defs;
+                let mut keep = vec![true; f.blocks.len()];
+                keep[block] = false;
+                remove_blocks(f, &keep[..]);
+                true
+            });
+        }
+        changed
+    }
+
+    /// Delete the non-terminator instructions of each block, first
+    /// all at once and then one at a time from the end.
+    fn remove_insts(&mut self) -> bool {
+        let mut changed = false;
+        for block in (0..self.func.blocks.len()).rev() {
+            let first = self.func.blocks[block].first.index();
+            let last = self.func.blocks[block].end.index() - 1;
+            if last - first > 1
+                && self.attempt(|f| {
+                    remove_insts(f, first, last);
+                    true
+                })
+            {
+                changed = true;
+                continue;
+            }
+            for inst in (first..last).rev() {
+                changed |= self.attempt(|f| {
+                    remove_insts(f, inst, inst + 1);
+                    true
+                });
+            }
+        }
+        changed
+    }
+
+    /// Delete each blockparam, along with the args passed to it.
+    fn remove_blockparams(&mut self) -> bool {
+        let mut changed = false;
+        for block in 0..self.func.blocks.len() {
+            for i in (0..self.func.blocks[block].params.len()).rev() {
+                changed |= self.attempt(|f| {
+                    f.blocks[block].params.remove(i);
+                    for pred in 0..f.blocks.len() {
+                        let b = &mut f.blocks[pred];
+                        for (succ_idx, succ) in b.succs.iter().enumerate() {
+                            if succ.index() == block {
+                                b.branch_args[succ_idx].remove(i);
+                            }
+                        }
+                    }
+                    true
+                });
+            }
+        }
+        changed
+    }
+
+    /// Delete each use operand, except those of moves and those that
+    /// another operand reuses or must differ from.
+    fn remove_operands(&mut self) -> bool {
+        let mut changed = false;
+        for inst in 0..self.func.insts.len() {
+            if self.func.insts[inst].is_move.is_some() {
+                continue;
+            }
+            for i in (0..self.func.insts[inst].operands.len()).rev() {
+                changed |= self.attempt(|f| {
+                    let data = &mut f.insts[inst];
+                    let operands = &mut data.operands;
+                    if operands[i].kind() != OperandKind::Use
+                        || operands.iter().any(|op| {
+                            matches!(
+                                op.constraint(),
+                                OperandConstraint::Reuse(j) | OperandConstraint::DistinctFrom(j)
+                                    if j == i
+                            )
+                        })
+                    {
+                        return false;
+                    }
+                    operands.remove(i);
+                    for op in operands.iter_mut() {
+                        let constraint = match op.constraint() {
+                            OperandConstraint::Reuse(j) if j > i => OperandConstraint::Reuse(j - 1),
+                            OperandConstraint::DistinctFrom(j) if j > i => {
+                                OperandConstraint::DistinctFrom(j - 1)
+                            }
+                            _ => continue,
+                        };
+                        *op = Operand::new(op.vreg(), constraint, op.kind(), op.pos());
+                    }
+                    let renumber = |j: usize| if j > i { j - 1 } else { j };
+                    data.commutative = match data.commutative {
+                        Some((a, b)) if a != i && b != i => Some((renumber(a), renumber(b))),
+                        _ => None,
+                    };
+                    true
+                });
+            }
+        }
+        changed
+    }
+
+    /// Replace each operand's constraint with `Any`.
+    fn relax_constraints(&mut self) -> bool {
+        let mut changed = false;
+        for inst in 0..self.func.insts.len() {
+            if self.func.insts[inst].is_move.is_some() {
+                continue;
+            }
+            for i in 0..self.func.insts[inst].operands.len() {
+                changed |= self.attempt(|f| {
+                    let op = f.insts[inst].operands[i];
+                    if op.constraint() == OperandConstraint::Any
+                        || op.as_fixed_nonallocatable().is_some()
+                    {
+                        return false;
+                    }
+                    f.insts[inst].operands[i] =
+                        Operand::new(op.vreg(), OperandConstraint::Any, op.kind(), op.pos());
+                    true
+                });
+            }
+        }
+        changed
+    }
+
+    /// Delete clobbers, safepoints, commutative operands, moves
+    /// (leaving ordinary instructions), reftypes, debug labels,
+    /// pinned vregs, entry liveins and edge defs.
+    fn remove_attributes(&mut self) -> bool {
+        let mut changed = false;
+        for inst in 0..self.func.insts.len() {
+            changed |= self.attempt(|f| {
+                let data = &mut f.insts[inst];
+                let had = data.clobbers != PRegSet::empty()
+                    || data.is_safepoint
+                    || data.commutative.is_some();
+                data.clobbers = PRegSet::empty();
+                data.is_safepoint = false;
+                data.commutative = None;
+                had
+            });
+            changed |= self.attempt(|f| f.insts[inst].is_move.take().is_some());
+        }
+        for block in 0..self.func.blocks.len() {
+            for succ_idx in 0..self.func.blocks[block].succs.len() {
+                changed |= self.attempt(|f| {
+                    let b = &mut f.blocks[block];
+                    let had = b.edge_clobbers[succ_idx] != PRegSet::empty();
+                    b.edge_clobbers[succ_idx] = PRegSet::empty();
+                    had
+                });
+                for i in (0..self.func.blocks[block].edge_defs[succ_idx].len()).rev() {
+                    changed |= self.attempt(|f| {
+                        f.blocks[block].edge_defs[succ_idx].remove(i);
+                        true
+                    });
+                }
+            }
+        }
+        changed |= self.attempt(|f| {
+            let had = !f.debug_value_labels.is_empty();
+            f.debug_value_labels.clear();
+            had
+        });
+        for i in (0..self.func.reftype_vregs.len()).rev() {
+            changed |= self.attempt(|f| {
+                f.reftype_vregs.remove(i);
+                true
+            });
+        }
+        for i in (0..self.func.pinned_vregs.len()).rev() {
+            changed |= self.attempt(|f| {
+                f.pinned_vregs.remove(i);
+                true
+            });
+        }
+        for i in (0..self.func.entry_liveins.len()).rev() {
+            changed |= self.attempt(|f| {
+                f.entry_liveins.remove(i);
+                true
+            });
+        }
+        changed
+    }
+}
+
+/// Remove the edge to `block`'s `succ_idx`th successor, turning its
+/// branch into a return if it has no successors left.
+fn remove_edge(f: &mut SerializableFunction, block: usize, succ_idx: usize) {
+    let b = &mut f.blocks[block];
+    let succ = b.succs.remove(succ_idx);
+    b.branch_args.remove(succ_idx);
+    b.edge_clobbers.remove(succ_idx);
+    b.edge_defs.remove(succ_idx);
+    if b.succs.is_empty() {
+        let last = &mut f.insts[b.end.index() - 1];
+        last.is_branch = false;
+        last.is_ret = true;
+    }
+    let preds = &mut f.blocks[succ.index()].preds;
+    let i = preds.iter().position(|p| p.index() == block).unwrap();
+    preds.remove(i);
+}
+
+/// Remove the blocks that can't be reached from the entry block.
+fn prune_unreachable(f: &mut SerializableFunction) {
+    let mut reachable = vec![false; f.blocks.len()];
+    let mut stack = vec![f.entry_block];
+    reachable[f.entry_block.index()] = true;
+    while let Some(block) = stack.pop() {
+        for &succ in &f.blocks[block.index()].succs {
+            if !reachable[succ.index()] {
+                reachable[succ.index()] = true;
+                stack.push(succ);
+            }
+        }
+    }
+
+    for block in (0..f.blocks.len()).rev() {
+        if !reachable[block] {
+            let (first, end) = (f.blocks[block].first.index(), f.blocks[block].end.index());
+            remove_insts(f, first, end);
+        }
+    }
+    remove_blocks(f, &reachable[..]);
+}
+
+/// Remove the blocks that aren't to be kept, and any edges from them,
+/// renumbering the rest. Their instructions must already be gone or
+/// belong to other blocks.
+fn remove_blocks(f: &mut SerializableFunction, keep: &[bool]) {
+    let mut new_index = vec![Block::invalid(); f.blocks.len()];
+    let mut next = 0;
+    for block in 0..f.blocks.len() {
+        if keep[block] {
+            new_index[block] = Block::new(next);
+            next += 1;
+        }
+    }
+    let mut block = 0;
+    f.blocks.retain(|_| {
+        block += 1;
+        keep[block - 1]
+    });
+    for b in &mut f.blocks {
+        b.preds.retain(|p| keep[p.index()]);
+        for p in b.preds.iter_mut().chain(b.succs.iter_mut()) {
+            *p = new_index[p.index()];
+        }
+    }
+    f.entry_block = new_index[f.entry_block.index()];
+}
+
+/// Remove the instructions `start..end`, renumbering the rest.
+fn remove_insts(f: &mut SerializableFunction, start: usize, end: usize) {
+    let shift = |inst: Inst| {
+        if inst.index() >= end {
+            Inst::new(inst.index() - (end - start))
+        } else if inst.index() > start {
+            Inst::new(start)
+        } else {
+            inst
+        }
+    };
+    f.insts.drain(start..end);
+    for b in &mut f.blocks {
+        b.first = shift(b.first);
+        b.end = shift(b.end);
+    }
+    for label in &mut f.debug_value_labels {
+        label.1 = shift(label.1);
+        label.2 = shift(label.2);
+    }
+    f.debug_value_labels.retain(|label| label.1 < label.2);
+}
+
+/// Renumber the vregs that are still mentioned densely, keeping their
+/// order.
+fn compact_vregs(f: &mut SerializableFunction) -> bool {
+    let mut used = vec![false; f.num_vregs];
+    visit_vregs(f, |vreg| {
+        used[vreg.vreg()] = true;
+        *vreg
+    });
+    let mut new_index = vec![0; f.num_vregs];
+    let mut next = 0;
+    for (vreg, &used) in used.iter().enumerate() {
+        if used {
+            new_index[vreg] = next;
+            next += 1;
+        }
+    }
+    if next == f.num_vregs {
+        return false;
+    }
+    visit_vregs(f, |vreg| VReg::new(new_index[vreg.vreg()], vreg.class()));
+    f.num_vregs = next;
+    true
+}
+
+/// Replace every vreg in `f` with `map(vreg)`.
+fn visit_vregs<M: FnMut(&VReg) -> VReg>(f: &mut SerializableFunction, mut map: M) {
+    let mut map_op = |op: &mut Operand| {
+        if op.as_fixed_nonallocatable().is_none() {
+            *op = Operand::new(map(&op.vreg()), op.constraint(), op.kind(), op.pos());
+        }
+    };
+    for inst in &mut f.insts {
+        inst.operands.iter_mut().for_each(&mut map_op);
+        if let Some((src, dst)) = &mut inst.is_move {
+            map_op(src);
+            map_op(dst);
+        }
+    }
+    for b in &mut f.blocks {
+        for vreg in b
+            .params
+            .iter_mut()
+            .chain(b.branch_args.iter_mut().flatten())
+        {
+            *vreg = map(vreg);
+        }
+        for (vreg, _) in b.edge_defs.iter_mut().flatten() {
+            *vreg = map(vreg);
+        }
+    }
+    for (vreg, _) in f.pinned_vregs.iter_mut().chain(f.entry_liveins.iter_mut()) {
+        *vreg = map(vreg);
+    }
+    for vreg in &mut f.reftype_vregs {
+        *vreg = map(vreg);
+    }
+    for (vreg, ..) in &mut f.debug_value_labels {
+        *vreg = map(vreg);
+    }
+}
+
+/// Is every use of a vreg dominated by its def? Entry liveins and
+/// pinned vregs are defined on entry; blockparams and edge defs at the
+/// top of their blocks.
+fn is_ssa(f: &SerializableFunction) -> bool {
+    let num_blocks = f.blocks.len();
+    let postorder = postorder::calculate(num_blocks, f.entry_block, |block| {
+        &f.blocks[block.index()].succs[..]
+    });
+    if postorder.len() != num_blocks {
+        return false;
+    }
+    let idom = domtree::calculate(
+        num_blocks,
+        |block| &f.blocks[block.index()].preds[..],
+        &postorder[..],
+        f.entry_block,
+    );
+
+    // Where each vreg is defined: its block, and the instruction
+    // after which it is available there, if not from the top.
+    let mut defs: FxHashMap<usize, (Block, Option<Inst>)> = FxHashMap::default();
+    for &(vreg, _) in f.entry_liveins.iter().chain(f.pinned_vregs.iter()) {
+        defs.insert(vreg.vreg(), (f.entry_block, None));
+    }
+    for (block, b) in f.blocks.iter().enumerate() {
+        for &param in &b.params {
+            defs.insert(param.vreg(), (Block::new(block), None));
+        }
+        for (succ_idx, edge_defs) in b.edge_defs.iter().enumerate() {
+            for &(vreg, _) in edge_defs {
+                defs.insert(vreg.vreg(), (b.succs[succ_idx], None));
+            }
+        }
+        for inst in b.first.index()..b.end.index() {
+            for op in &f.insts[inst].operands {
+                if op.kind() == OperandKind::Def && op.as_fixed_nonallocatable().is_none() {
+                    defs.entry(op.vreg().vreg())
+                        .or_insert((Block::new(block), Some(Inst::new(inst))));
+                }
+            }
+        }
+    }
+
+    let available = |vreg: VReg, block: Block, inst: Inst| match defs.get(&vreg.vreg()) {
+        Some(&(def_block, def_inst)) if def_block == block => match def_inst {
+            Some(def_inst) => def_inst < inst,
+            None => true,
+        },
+        Some(&(def_block, _)) => domtree::dominates(&idom[..], def_block, block),
+        None => false,
+    };
+    for (block, b) in f.blocks.iter().enumerate() {
+        let block = Block::new(block);
+        for inst in b.first.index()..b.end.index() {
+            let data = &f.insts[inst];
+            let uses = data
+                .operands
+                .iter()
+                .chain(data.is_move.iter().map(|(src, _)| src))
+                .filter(|op| {
+                    op.kind() != OperandKind::Def && op.as_fixed_nonallocatable().is_none()
+                });
+            for op in uses {
+                if !available(op.vreg(), block, Inst::new(inst)) {
+                    return false;
+                }
+            }
+        }
+        for &arg in b.branch_args.iter().flatten() {
+            if !available(arg, block, b.end) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{int, test_env};
+    use crate::{FunctionBuilder, PReg, RegClass};
+    use alloc::string::{String, ToString};
+
+    fn p(i: usize) -> PReg {
+        PReg::new(i, RegClass::Int)
+    }
+
+    /// Whether some operand of `f` is fixed to `preg`.
+    fn mentions(f: &SerializableFunction, preg: PReg) -> bool {
+        f.insts.iter().any(|inst| {
+            inst.operands
+                .iter()
+                .any(|op| op.constraint() == OperandConstraint::FixedReg(preg))
+        })
+    }
+
+    /// Run one pass of a reducer that keeps `preg` mentioned, and
+    /// return the result.
+    fn run_pass<R>(f: SerializableFunction, preg: PReg, pass: R) -> String
+    where
+        R: FnOnce(&mut Reducer<'_, &dyn Fn(&SerializableFunction) -> bool>) -> bool,
+    {
+        let env = test_env(8);
+        let options = RegallocOptions::default();
+        let fails = |f: &SerializableFunction| mentions(f, preg);
+        let mut reducer = Reducer::new(f, &env, &options, &fails as &dyn Fn(&_) -> bool);
+        assert!(pass(&mut reducer));
+        reducer.func.to_string()
+    }
+
+    /// A diamond whose entry block defines v0 in p2.
+    fn diamond() -> SerializableFunction {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let right = b.add_block();
+        let join = b.add_block();
+        b.add_inst(entry, &[Operand::reg_fixed_def(int(0), p(2))]);
+        b.add_branch(entry, &[], &[left, right]);
+        b.add_inst(left, &[Operand::reg_def(int(1)), Operand::reg_use(int(0))]);
+        b.add_branch(left, &[], &[join]);
+        b.set_branch_args(left, 0, &[int(1)]);
+        b.add_branch(right, &[], &[join]);
+        b.set_branch_args(right, 0, &[int(0)]);
+        b.add_block_param(join, int(2));
+        b.add_ret(join, &[Operand::reg_use(int(2))]);
+        b.build()
+    }
+
+    #[test]
+    fn test_remove_edges() {
+        // Both of the entry block's edges go at once, and the other
+        // blocks with them.
+        let f = run_pass(diamond(), p(2), |r| r.remove_edges());
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 3\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v0i fixed(p2i)]\n  \
+             inst1: ret []\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_blocks() {
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        let b1 = b.add_block();
+        b.add_inst(b0, &[Operand::reg_fixed_def(int(0), p(2))]);
+        b.add_branch(b0, &[], &[b1]);
+        b.add_ret(b1, &[Operand::reg_use(int(0))]);
+        let f = run_pass(b.build(), p(2), |r| r.merge_blocks());
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 1\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v0i fixed(p2i)]\n  \
+             inst1: ret [Use: v0i reg]\n"
+        );
+    }
+
+    #[test]
+    fn test_remove_insts_and_operands() {
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        b.add_inst(b0, &[Operand::reg_def(int(0))]);
+        b.add_inst(
+            b0,
+            &[
+                Operand::reg_fixed_def(int(1), p(2)),
+                Operand::reg_use(int(0)),
+            ],
+        );
+        b.add_inst(b0, &[Operand::reg_def(int(2)), Operand::reg_use(int(1))]);
+        b.add_ret(b0, &[Operand::reg_use(int(1))]);
+        let f = b.build();
+
+        // The instruction that defines v0 cannot go while v0 is used.
+        let f = run_pass(f, p(2), |r| r.remove_insts() && !r.remove_insts());
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 3\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v0i reg]\n  \
+             inst1: op [Def: v1i fixed(p2i), Use: v0i reg]\n  \
+             inst2: ret [Use: v1i reg]\n"
+        );
+        let f = run_pass(f.parse().unwrap(), p(2), |r| r.remove_operands());
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 3\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v0i reg]\n  \
+             inst1: op [Def: v1i fixed(p2i)]\n  \
+             inst2: ret []\n"
+        );
+        let f = run_pass(f.parse().unwrap(), p(2), |r| r.remove_insts());
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 3\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v1i fixed(p2i)]\n  \
+             inst1: ret []\n"
+        );
+    }
+
+    #[test]
+    fn test_remove_blockparams() {
+        // The diamond's join param is used, so it must stay; here it
+        // isn't, and goes along with its arg.
+        let env = test_env(8);
+        let options = RegallocOptions::default();
+        let mut r = Reducer::new(diamond(), &env, &options, |f: &SerializableFunction| {
+            mentions(f, p(2))
+        });
+        assert!(!r.remove_blockparams());
+
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        let b1 = b.add_block();
+        b.add_inst(b0, &[Operand::reg_fixed_def(int(0), p(2))]);
+        b.add_branch(b0, &[], &[b1]);
+        b.set_branch_args(b0, 0, &[int(0)]);
+        b.add_block_param(b1, int(1));
+        b.add_ret(b1, &[]);
+        let f = run_pass(b.build(), p(2), |r| r.remove_blockparams());
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 2\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v0i fixed(p2i)]\n  \
+             inst1: branch []\n  \
+             -> block1()\n\
+             block1(): preds [0]\n  \
+             inst2: ret []\n"
+        );
+    }
+
+    #[test]
+    fn test_relax_constraints() {
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        b.add_inst(b0, &[Operand::reg_fixed_def(int(0), p(2))]);
+        b.add_inst(
+            b0,
+            &[
+                Operand::reg_fixed_def(int(1), p(3)),
+                Operand::reg_use(int(0)),
+            ],
+        );
+        b.add_ret(b0, &[Operand::reg_use(int(1))]);
+        let f = run_pass(b.build(), p(2), |r| r.relax_constraints());
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 2\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v0i fixed(p2i)]\n  \
+             inst1: op [Def: v1i any, Use: v0i any]\n  \
+             inst2: ret [Use: v1i any]\n"
+        );
+    }
+
+    #[test]
+    fn test_remove_attributes() {
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        b.add_entry_livein(int(0), p(0));
+        b.add_reftype_vreg(int(1));
+        let call = b.add_inst(b0, &[Operand::reg_fixed_def(int(1), p(2))]);
+        b.set_clobbers(call, PRegSet::empty().with(p(3)));
+        b.set_safepoint(call);
+        b.add_move(b0, int(1), int(2));
+        b.add_ret(b0, &[Operand::reg_use(int(2))]);
+        let f = run_pass(b.build(), p(2), |r| r.remove_attributes());
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 3\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v1i fixed(p2i)]\n  \
+             inst1: op [Use: v1i reg, Def: v2i reg]\n  \
+             inst2: ret [Use: v2i reg]\n"
+        );
+    }
+
+    #[test]
+    fn test_compact_vregs() {
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        b.add_inst(b0, &[Operand::reg_fixed_def(int(3), p(2))]);
+        b.add_inst(b0, &[Operand::reg_def(int(7)), Operand::reg_use(int(3))]);
+        b.add_ret(b0, &[Operand::reg_use(int(7))]);
+        let f = run_pass(b.build(), p(2), |r| r.attempt(compact_vregs));
+        assert_eq!(
+            f,
+            "entry: block0\n\
+             vregs: 2\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v0i fixed(p2i)]\n  \
+             inst1: op [Def: v1i reg, Use: v0i reg]\n  \
+             inst2: ret [Use: v1i reg]\n"
+        );
+    }
+
+    #[test]
+    fn test_reduce() {
+        // The whole reduction leaves only the def in p2, with its vreg
+        // renumbered, and the result can be read back.
+        let env = test_env(8);
+        let f = reduce(diamond(), &env, &RegallocOptions::default(), |f| {
+            mentions(f, p(2))
+        });
+        let text = f.to_string();
+        assert_eq!(
+            text,
+            "entry: block0\n\
+             vregs: 1\n\
+             spillslot sizes: 1 1\n\
+             block0(): preds []\n  \
+             inst0: op [Def: v0i fixed(p2i)]\n  \
+             inst1: ret []\n"
+        );
+        let parsed: SerializableFunction = text.parse().unwrap();
+        assert_eq!(parsed.to_string(), text);
+    }
+}
@@ -0,0 +1,399 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Rewriting a function with the allocator's results.
+//!
+//! Every client walks the `Output` in the same way: block by block,
+//! each instruction with its allocations, interleaved with the edits
+//! that the allocator inserted, each of which becomes a register
+//! move, a spill or a reload depending on where it moves from and
+//! to. `rewrite()` does that walk and calls an `Emitter` with each
+//! piece of code to emit, so that the client only has to say how to
+//! emit it.
+
+use crate::{
+    Allocation, AllocationKind, Block, Edit, Function, Inst, InstOrEdit, Output, PReg, SpillSlot,
+};
+
+/// The client side of `rewrite()`, which emits code for the
+/// allocated function.
+///
+/// A `PReg` from `MachineEnv::fixed_stack_slots` is passed to these
+/// methods like any other register, so `emit_move` must turn a move
+/// to or from one into a store or a load.
+///
+/// There is no method to rematerialize a value: the allocator never
+/// does so, and every edit it makes is a move or a swap.
+pub trait Emitter {
+    /// Start emitting `block`. Blocks are visited in the order of
+    /// their indices, which is also the order of their instructions.
+    fn begin_block(&mut self, _block: Block) {}
+
+    /// Start emitting the moves on the edge from `from` to `to`,
+    /// which need a block of their own on that edge. This is called
+    /// after the whole of `from` has been emitted, and only for edges
+    /// with moves, which there are only if
+    /// `RegallocOptions::allow_critical_edges` was set.
+    fn begin_edge(&mut self, _from: Block, _to: Block) {}
+
+    /// Emit `inst`, with the allocation for each of its operands, in
+    /// the order of `Function::inst_operands`. If
+    /// `Output::is_commuted(inst)`, its commutative inputs must be
+    /// swapped.
+    fn emit_inst(&mut self, inst: Inst, allocs: &[Allocation]);
+
+    /// Emit a move from one register to another.
+    fn emit_move(&mut self, from: PReg, to: PReg);
+
+    /// Emit a store of a register to a spillslot.
+    fn emit_spill(&mut self, from: PReg, to: SpillSlot);
+
+    /// Emit a load of a register from a spillslot.
+    fn emit_reload(&mut self, from: SpillSlot, to: PReg);
+
+    /// Emit a move from one spillslot to another. Only called for
+    /// classes for which `MachineEnv::stack_to_stack_moves_by_class`
+    /// is set.
+    fn emit_stack_move(&mut self, from: SpillSlot, to: SpillSlot);
+
+    /// Emit an exchange of two registers. Only called for classes
+    /// for which `MachineEnv::reg_swaps_by_class` is set.
+    fn emit_swap(&mut self, a: PReg, b: PReg);
+}
+
+/// Emit the whole of `func`, as allocated by `output`, through
+/// `emitter`.
+///
+/// Program moves (for which `Function::is_move` returns `Some`) are
+/// not emitted: the allocator has implemented them with edits.
+/// Neither are moves from an allocation to itself, which the
+/// allocator generates when a value changes vreg without moving, to
+/// keep debug locations accurate.
+pub fn rewrite<F: Function, E: Emitter>(func: &F, output: &Output, emitter: &mut E) {
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        emitter.begin_block(block);
+        for inst_or_edit in output.block_insts_and_edits(func, block) {
+            match inst_or_edit {
+                InstOrEdit::Inst(inst) => {
+                    if func.is_move(inst).is_none() {
+                        emitter.emit_inst(inst, output.inst_allocs(inst));
+                    }
+                }
+                InstOrEdit::Edit(edit) => emit_edit(edit, emitter),
+            }
+        }
+
+        for &succ in func.block_succs(block) {
+            let mut edits = output.edge_edits(block, succ).peekable();
+            if edits.peek().is_none() {
+                continue;
+            }
+            emitter.begin_edge(block, succ);
+            for edit in edits {
+                emit_edit(edit, emitter);
+            }
+        }
+    }
+}
+
+fn emit_edit<E: Emitter>(edit: &Edit, emitter: &mut E) {
+    match *edit {
+        Edit::Move { from, to } if from == to => {}
+        Edit::Move { from, to } => match (from.kind(), to.kind()) {
+            (AllocationKind::Reg, AllocationKind::Reg) => {
+                emitter.emit_move(from.as_reg().unwrap(), to.as_reg().unwrap())
+            }
+            (AllocationKind::Reg, AllocationKind::Stack) => {
+                emitter.emit_spill(from.as_reg().unwrap(), to.as_stack().unwrap())
+            }
+            (AllocationKind::Stack, AllocationKind::Reg) => {
+                emitter.emit_reload(from.as_stack().unwrap(), to.as_reg().unwrap())
+            }
+            (AllocationKind::Stack, AllocationKind::Stack) => {
+                emitter.emit_stack_move(from.as_stack().unwrap(), to.as_stack().unwrap())
+            }
+            _ => panic!("move between {} and {}", from, to),
+        },
+        Edit::Swap { a, b } => emitter.emit_swap(a.as_reg().unwrap(), b.as_reg().unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{check, int, test_env};
+    use crate::{run, FunctionBuilder, MachineEnv, Operand, ProgPoint, RegClass, RegallocOptions};
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Event {
+        Block(Block),
+        Edge(Block, Block),
+        Inst(Inst),
+        Move(PReg, PReg),
+        Spill(PReg, SpillSlot),
+        Reload(SpillSlot, PReg),
+        StackMove(SpillSlot, SpillSlot),
+        Swap(PReg, PReg),
+    }
+
+    /// Records everything emitted, in order.
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<Event>,
+    }
+
+    impl Recorder {
+        fn insts(&self) -> Vec<Inst> {
+            self.events
+                .iter()
+                .filter_map(|e| match *e {
+                    Event::Inst(inst) => Some(inst),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn count(&self, pred: impl Fn(&Event) -> bool) -> usize {
+            self.events.iter().filter(|e| pred(e)).count()
+        }
+    }
+
+    impl Emitter for Recorder {
+        fn begin_block(&mut self, block: Block) {
+            self.events.push(Event::Block(block));
+        }
+        fn begin_edge(&mut self, from: Block, to: Block) {
+            self.events.push(Event::Edge(from, to));
+        }
+        fn emit_inst(&mut self, inst: Inst, allocs: &[Allocation]) {
+            assert!(allocs.iter().all(|alloc| alloc.is_some()));
+            self.events.push(Event::Inst(inst));
+        }
+        fn emit_move(&mut self, from: PReg, to: PReg) {
+            assert_ne!(from, to);
+            self.events.push(Event::Move(from, to));
+        }
+        fn emit_spill(&mut self, from: PReg, to: SpillSlot) {
+            self.events.push(Event::Spill(from, to));
+        }
+        fn emit_reload(&mut self, from: SpillSlot, to: PReg) {
+            self.events.push(Event::Reload(from, to));
+        }
+        fn emit_stack_move(&mut self, from: SpillSlot, to: SpillSlot) {
+            self.events.push(Event::StackMove(from, to));
+        }
+        fn emit_swap(&mut self, a: PReg, b: PReg) {
+            self.events.push(Event::Swap(a, b));
+        }
+    }
+
+    fn p(i: usize) -> PReg {
+        PReg::new(i, RegClass::Int)
+    }
+
+    #[test]
+    fn test_spills() {
+        // Define more values than there are registers, then use them
+        // all in registers, so that some must be spilled and
+        // reloaded; and move one, which must not be emitted.
+        let mut b = FunctionBuilder::new();
+        let block = b.add_block();
+        for i in 0..6 {
+            b.add_inst(block, &[Operand::reg_def(int(i))]);
+        }
+        let mov = b.add_move(block, int(0), int(6));
+        for i in 1..7 {
+            b.add_inst(block, &[Operand::reg_use(int(i))]);
+        }
+        b.add_ret(block, &[]);
+        let f = b.build();
+
+        let env = test_env(3);
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        let mut recorder = Recorder::default();
+        rewrite(&f, &out, &mut recorder);
+
+        let expected: Vec<_> = (0..f.num_insts())
+            .map(Inst::new)
+            .filter(|&inst| inst != mov)
+            .collect();
+        assert_eq!(recorder.insts(), expected);
+        assert!(recorder.count(|e| matches!(e, Event::Spill(..))) > 0);
+        assert!(recorder.count(|e| matches!(e, Event::Reload(..))) > 0);
+        let edits = out
+            .edits
+            .iter()
+            .filter(|(_, edit)| !matches!(edit, Edit::Move { from, to } if from == to))
+            .count();
+        let emitted = recorder.count(|e| {
+            matches!(
+                e,
+                Event::Move(..) | Event::Spill(..) | Event::Reload(..) | Event::StackMove(..)
+            )
+        });
+        assert_eq!(emitted, edits);
+    }
+
+    #[test]
+    fn test_order() {
+        // Edits go before or after their instruction as their
+        // program point says, moves from an allocation to itself are
+        // dropped, and each kind of move goes to its own method.
+        let mut b = FunctionBuilder::new();
+        let b0 = b.add_block();
+        let b1 = b.add_block();
+        let i0 = b.add_inst(b0, &[Operand::reg_def(int(0))]);
+        let i1 = b.add_branch(b0, &[], &[b1]);
+        let i2 = b.add_ret(b1, &[Operand::reg_use(int(0))]);
+        let f = b.build();
+
+        let slot = |i| Allocation::stack(SpillSlot::new(i));
+        let reg = |i| Allocation::reg(p(i));
+        let mv = |from, to| Edit::Move { from, to };
+        let out = Output {
+            num_spillslots: 2,
+            edits: vec![
+                (ProgPoint::after(i0), mv(reg(0), slot(0))),
+                (ProgPoint::after(i0), mv(reg(0), reg(0))),
+                (ProgPoint::before(i1), mv(slot(0), slot(1))),
+                (ProgPoint::before(i2), mv(slot(1), reg(1))),
+                (
+                    ProgPoint::before(i2),
+                    Edit::Swap {
+                        a: reg(1),
+                        b: reg(2),
+                    },
+                ),
+                (ProgPoint::before(i2), mv(reg(2), reg(0))),
+            ],
+            edge_edits: vec![],
+            allocs: vec![reg(0), reg(0)],
+            inst_alloc_offsets: vec![0, 1, 1],
+            safepoint_slots: vec![],
+            debug_locations: vec![],
+            debug_annotations: vec![],
+            vreg_locations: vec![],
+            commuted: vec![],
+            stats: Default::default(),
+        };
+        let mut recorder = Recorder::default();
+        rewrite(&f, &out, &mut recorder);
+
+        let (s0, s1) = (SpillSlot::new(0), SpillSlot::new(1));
+        assert_eq!(
+            recorder.events,
+            [
+                Event::Block(b0),
+                Event::Inst(i0),
+                Event::Spill(p(0), s0),
+                Event::StackMove(s0, s1),
+                Event::Inst(i1),
+                Event::Block(b1),
+                Event::Reload(s1, p(1)),
+                Event::Swap(p(1), p(2)),
+                Event::Move(p(2), p(0)),
+                Event::Inst(i2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_edge_edits() {
+        // The edge entry -> join is critical. v0 stays live in p0
+        // while v2 takes the value of v0 or v1, so the edge needs a
+        // move, which goes in an edge block emitted after entry.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let join = b.add_block();
+        b.add_inst(entry, &[Operand::reg_fixed_def(int(0), p(0))]);
+        b.add_branch(entry, &[], &[left, join]);
+        b.set_branch_args(entry, 1, &[int(0)]);
+        b.add_inst(left, &[Operand::reg_def(int(1))]);
+        b.add_branch(left, &[], &[join]);
+        b.set_branch_args(left, 0, &[int(1)]);
+        b.add_block_param(join, int(2));
+        b.add_ret(
+            join,
+            &[
+                Operand::reg_fixed_use(int(0), p(0)),
+                Operand::reg_use(int(2)),
+            ],
+        );
+        let f = b.build();
+
+        let env = test_env(3);
+        let options = RegallocOptions {
+            allow_critical_edges: true,
+            ..Default::default()
+        };
+        let out = run(&f, &env, &options).unwrap();
+        check(&f, &env, &out);
+        assert!(out
+            .edge_edits
+            .iter()
+            .all(|&(from, to, _)| (from, to) == (entry, join)));
+        let mut recorder = Recorder::default();
+        rewrite(&f, &out, &mut recorder);
+
+        // The edge's moves come right after the branch ending entry,
+        // and before left.
+        let events = &recorder.events;
+        let edge = events
+            .iter()
+            .position(|&e| e == Event::Edge(entry, join))
+            .unwrap();
+        assert_eq!(events[edge - 1], Event::Inst(Inst::new(1)));
+        let left_start = events
+            .iter()
+            .position(|&e| e == Event::Block(left))
+            .unwrap();
+        let edge_moves = &events[edge + 1..left_start];
+        assert_eq!(edge_moves.len(), out.edge_edits.len());
+        assert!(!edge_moves.is_empty());
+        assert!(edge_moves
+            .iter()
+            .all(|e| matches!(e, Event::Move(..) | Event::Spill(..) | Event::Reload(..))));
+        assert_eq!(recorder.count(|e| matches!(e, Event::Edge(..))), 1);
+    }
+
+    #[test]
+    fn test_swaps() {
+        // v0 and v1 trade registers for the use, which a target with
+        // swaps does with one.
+        let mut b = FunctionBuilder::new();
+        let block = b.add_block();
+        b.add_inst(block, &[Operand::reg_fixed_def(int(0), p(0))]);
+        b.add_inst(block, &[Operand::reg_fixed_def(int(1), p(1))]);
+        let use_ = b.add_inst(
+            block,
+            &[
+                Operand::reg_fixed_use(int(0), p(1)),
+                Operand::reg_fixed_use(int(1), p(0)),
+            ],
+        );
+        b.add_ret(block, &[]);
+        let f = b.build();
+
+        let env = MachineEnv {
+            reg_swaps_by_class: [true, true],
+            ..test_env(2)
+        };
+        let out = run(&f, &env, &RegallocOptions::default()).unwrap();
+        check(&f, &env, &out);
+        let mut recorder = Recorder::default();
+        rewrite(&f, &out, &mut recorder);
+        let swap = recorder
+            .events
+            .iter()
+            .position(|e| matches!(e, Event::Swap(..)))
+            .unwrap();
+        assert_eq!(recorder.events[swap + 1], Event::Inst(use_));
+        assert_eq!(recorder.count(|e| matches!(e, Event::Move(..))), 0);
+    }
+}
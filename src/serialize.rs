@@ -0,0 +1,876 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! An owned copy of a `Function`, which can be saved (with the
+//! `enable-serde` feature) and replayed without the embedder that
+//! produced it, and which the reducer edits. A `FunctionBuilder` also
+//! builds one from scratch.
+
+use crate::{
+    Block, Function, Inst, InstRange, Operand, OperandConstraint, OperandKind, OperandPos, PReg,
+    PRegSet, RegClass, VReg,
+};
+use alloc::vec::Vec;
+use alloc::{format, string::String};
+use core::fmt;
+
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub(crate) struct InstData {
+    pub(crate) operands: Vec<Operand>,
+    pub(crate) clobbers: PRegSet,
+    pub(crate) is_ret: bool,
+    pub(crate) is_branch: bool,
+    pub(crate) is_move: Option<(Operand, Operand)>,
+    pub(crate) is_safepoint: bool,
+    pub(crate) commutative: Option<(usize, usize)>,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub(crate) struct BlockData {
+    /// The block's instructions are `first..end`.
+    pub(crate) first: Inst,
+    pub(crate) end: Inst,
+    pub(crate) preds: Vec<Block>,
+    pub(crate) succs: Vec<Block>,
+    pub(crate) params: Vec<VReg>,
+    /// Indexed by successor: the args passed by the block's branch,
+    /// and the clobbers and defs on that edge.
+    pub(crate) branch_args: Vec<Vec<VReg>>,
+    pub(crate) edge_clobbers: Vec<PRegSet>,
+    pub(crate) edge_defs: Vec<Vec<(VReg, PReg)>>,
+}
+
+/// A function with all of the information the allocator queries
+/// through `Function` copied out of it.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct SerializableFunction {
+    pub(crate) insts: Vec<InstData>,
+    pub(crate) blocks: Vec<BlockData>,
+    pub(crate) entry_block: Block,
+    pub(crate) num_vregs: usize,
+    pub(crate) reftype_vregs: Vec<VReg>,
+    pub(crate) debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
+    pub(crate) pinned_vregs: Vec<(VReg, PReg)>,
+    pub(crate) entry_liveins: Vec<(VReg, PReg)>,
+    pub(crate) spillslot_size: [usize; 2],
+    pub(crate) multi_spillslot_named_by_last_slot: bool,
+    pub(crate) allow_multiple_vreg_defs: bool,
+}
+
+impl SerializableFunction {
+    /// Copy `f`. Each block's instructions must be a forward range.
+    pub fn new<F: Function>(f: &F) -> Self {
+        let insts = (0..f.num_insts())
+            .map(|i| {
+                let inst = Inst::new(i);
+                InstData {
+                    operands: f.inst_operands(inst).to_vec(),
+                    clobbers: f.inst_clobbers(inst),
+                    is_ret: f.is_ret(inst),
+                    is_branch: f.is_branch(inst),
+                    is_move: f.is_move(inst),
+                    is_safepoint: f.requires_refs_on_stack(inst),
+                    commutative: f.commutative_operands(inst),
+                }
+            })
+            .collect();
+
+        let mut pinned_vregs: Vec<(VReg, PReg)> = Vec::new();
+        let mut blocks = Vec::with_capacity(f.num_blocks());
+        for block in 0..f.num_blocks() {
+            let block = Block::new(block);
+            let range = f.block_insns(block);
+            let last = range.last();
+            let succs = f.block_succs(block).to_vec();
+            let mut data = BlockData {
+                first: range.first(),
+                end: last.next(),
+                preds: f.block_preds(block).to_vec(),
+                succs: succs.clone(),
+                params: f.block_params(block).to_vec(),
+                branch_args: Vec::with_capacity(succs.len()),
+                edge_clobbers: Vec::with_capacity(succs.len()),
+                edge_defs: Vec::with_capacity(succs.len()),
+            };
+            for succ_idx in 0..succs.len() {
+                if f.is_branch(last) {
+                    data.branch_args
+                        .push(f.branch_blockparams(block, last, succ_idx).to_vec());
+                    data.edge_clobbers
+                        .push(f.branch_edge_clobbers(block, last, succ_idx));
+                    data.edge_defs
+                        .push(f.branch_edge_defs(block, last, succ_idx).to_vec());
+                } else {
+                    data.branch_args.push(Vec::new());
+                    data.edge_clobbers.push(PRegSet::empty());
+                    data.edge_defs.push(Vec::new());
+                }
+            }
+            for vreg in data.mentioned_vregs(range, f) {
+                if let Some(preg) = f.is_pinned_vreg(vreg) {
+                    if !pinned_vregs.iter().any(|&(v, _)| v == vreg) {
+                        pinned_vregs.push((vreg, preg));
+                    }
+                }
+            }
+            blocks.push(data);
+        }
+
+        SerializableFunction {
+            insts,
+            blocks,
+            entry_block: f.entry_block(),
+            num_vregs: f.num_vregs(),
+            reftype_vregs: f.reftype_vregs().to_vec(),
+            debug_value_labels: f.debug_value_labels().to_vec(),
+            pinned_vregs,
+            entry_liveins: f.entry_liveins().to_vec(),
+            spillslot_size: [
+                f.spillslot_size(RegClass::Int),
+                f.spillslot_size(RegClass::Float),
+            ],
+            multi_spillslot_named_by_last_slot: f.multi_spillslot_named_by_last_slot(),
+            allow_multiple_vreg_defs: f.allow_multiple_vreg_defs(),
+        }
+    }
+}
+
+impl BlockData {
+    /// The vregs that the block mentions, other than in debug labels.
+    fn mentioned_vregs<'a, F: Function>(
+        &'a self,
+        range: InstRange,
+        f: &'a F,
+    ) -> impl Iterator<Item = VReg> + 'a {
+        let operands = range.iter().flat_map(move |inst| {
+            f.inst_operands(inst)
+                .iter()
+                .filter(|op| op.as_fixed_nonallocatable().is_none())
+                .map(|op| op.vreg())
+                .chain(
+                    f.is_move(inst)
+                        .into_iter()
+                        .flat_map(|(s, d)| [s.vreg(), d.vreg()]),
+                )
+        });
+        self.params
+            .iter()
+            .cloned()
+            .chain(self.branch_args.iter().flatten().cloned())
+            .chain(operands)
+    }
+}
+
+impl Function for SerializableFunction {
+    fn num_insts(&self) -> usize {
+        self.insts.len()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn entry_block(&self) -> Block {
+        self.entry_block
+    }
+
+    fn block_insns(&self, block: Block) -> InstRange {
+        let b = &self.blocks[block.index()];
+        InstRange::forward(b.first, b.end)
+    }
+
+    fn block_succs(&self, block: Block) -> &[Block] {
+        &self.blocks[block.index()].succs[..]
+    }
+
+    fn block_preds(&self, block: Block) -> &[Block] {
+        &self.blocks[block.index()].preds[..]
+    }
+
+    fn block_params(&self, block: Block) -> &[VReg] {
+        &self.blocks[block.index()].params[..]
+    }
+
+    fn is_ret(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_ret
+    }
+
+    fn is_branch(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_branch
+    }
+
+    fn branch_blockparams(&self, block: Block, _: Inst, succ_idx: usize) -> &[VReg] {
+        &self.blocks[block.index()].branch_args[succ_idx][..]
+    }
+
+    fn branch_edge_clobbers(&self, block: Block, _: Inst, succ_idx: usize) -> PRegSet {
+        self.blocks[block.index()].edge_clobbers[succ_idx]
+    }
+
+    fn branch_edge_defs(&self, block: Block, _: Inst, succ_idx: usize) -> &[(VReg, PReg)] {
+        &self.blocks[block.index()].edge_defs[succ_idx][..]
+    }
+
+    fn requires_refs_on_stack(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_safepoint
+    }
+
+    fn is_move(&self, insn: Inst) -> Option<(Operand, Operand)> {
+        self.insts[insn.index()].is_move
+    }
+
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        &self.insts[insn.index()].operands[..]
+    }
+
+    fn commutative_operands(&self, insn: Inst) -> Option<(usize, usize)> {
+        self.insts[insn.index()].commutative
+    }
+
+    fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+        self.insts[insn.index()].clobbers
+    }
+
+    fn num_vregs(&self) -> usize {
+        self.num_vregs
+    }
+
+    fn reftype_vregs(&self) -> &[VReg] {
+        &self.reftype_vregs[..]
+    }
+
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        &self.debug_value_labels[..]
+    }
+
+    fn is_pinned_vreg(&self, vreg: VReg) -> Option<PReg> {
+        self.pinned_vregs
+            .iter()
+            .find(|&&(v, _)| v == vreg)
+            .map(|&(_, preg)| preg)
+    }
+
+    fn entry_liveins(&self) -> &[(VReg, PReg)] {
+        &self.entry_liveins[..]
+    }
+
+    fn spillslot_size(&self, regclass: RegClass) -> usize {
+        self.spillslot_size[regclass as usize]
+    }
+
+    fn multi_spillslot_named_by_last_slot(&self) -> bool {
+        self.multi_spillslot_named_by_last_slot
+    }
+
+    fn allow_multiple_vreg_defs(&self) -> bool {
+        self.allow_multiple_vreg_defs
+    }
+}
+
+fn pregs(set: PRegSet) -> String {
+    let pregs: Vec<_> = set.into_iter().map(|preg| format!("{}", preg)).collect();
+    pregs.join(", ")
+}
+
+/// A vreg with its class, as operands print it: `v3i`.
+fn vreg(vreg: VReg) -> String {
+    let class = match vreg.class() {
+        RegClass::Int => "i",
+        RegClass::Float => "f",
+    };
+    format!("{}{}", vreg, class)
+}
+
+fn vregs(vregs: &[VReg]) -> String {
+    let vregs: Vec<_> = vregs.iter().map(|&v| vreg(v)).collect();
+    vregs.join(", ")
+}
+
+/// The format is line-based and can be read back with `FromStr`, so
+/// that a failing function, e.g. one produced by the reducer, can be
+/// saved as text and replayed.
+impl fmt::Display for SerializableFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "entry: block{}", self.entry_block.index())?;
+        writeln!(f, "vregs: {}", self.num_vregs)?;
+        writeln!(
+            f,
+            "spillslot sizes: {} {}",
+            self.spillslot_size[0], self.spillslot_size[1]
+        )?;
+        if self.multi_spillslot_named_by_last_slot {
+            writeln!(f, "multi spillslot named by last slot")?;
+        }
+        if self.allow_multiple_vreg_defs {
+            writeln!(f, "allow multiple vreg defs")?;
+        }
+        for &(v, preg) in &self.entry_liveins {
+            writeln!(f, "livein {} in {}", vreg(v), preg)?;
+        }
+        for &(v, preg) in &self.pinned_vregs {
+            writeln!(f, "pinned {} to {}", vreg(v), preg)?;
+        }
+        for &v in &self.reftype_vregs {
+            writeln!(f, "ref {}", vreg(v))?;
+        }
+        for &(v, from, to, label) in &self.debug_value_labels {
+            writeln!(
+                f,
+                "label {} for {} from inst{} to inst{}",
+                label,
+                vreg(v),
+                from.index(),
+                to.index()
+            )?;
+        }
+        for (i, b) in self.blocks.iter().enumerate() {
+            let preds: Vec<_> = b.preds.iter().map(|b| format!("{}", b.index())).collect();
+            writeln!(
+                f,
+                "block{}({}): preds [{}]",
+                i,
+                vregs(&b.params),
+                preds.join(", ")
+            )?;
+            for inst in b.first.index()..b.end.index() {
+                let data = &self.insts[inst];
+                let kind = if data.is_branch {
+                    "branch"
+                } else if data.is_ret {
+                    "ret"
+                } else if data.is_move.is_some() {
+                    "move"
+                } else {
+                    "op"
+                };
+                write!(f, "  inst{}: {} {:?}", inst, kind, data.operands)?;
+                if let Some((src, dst)) = data.is_move {
+                    if data.operands.get(..2) != Some(&[src, dst][..]) {
+                        write!(f, " moves [{}, {}]", src, dst)?;
+                    }
+                }
+                if data.clobbers != PRegSet::empty() {
+                    write!(f, " clobbers [{}]", pregs(data.clobbers))?;
+                }
+                if data.is_safepoint {
+                    write!(f, " safepoint")?;
+                }
+                if let Some((a, b)) = data.commutative {
+                    write!(f, " commutative {} {}", a, b)?;
+                }
+                writeln!(f)?;
+            }
+            for (succ_idx, succ) in b.succs.iter().enumerate() {
+                write!(
+                    f,
+                    "  -> block{}({})",
+                    succ.index(),
+                    vregs(&b.branch_args[succ_idx])
+                )?;
+                if b.edge_clobbers[succ_idx] != PRegSet::empty() {
+                    write!(f, " clobbers [{}]", pregs(b.edge_clobbers[succ_idx]))?;
+                }
+                for &(v, preg) in &b.edge_defs[succ_idx] {
+                    write!(f, " def {} in {}", vreg(v), preg)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An error in the text form of a `SerializableFunction`.
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    /// The line at which the error was found, counting from 1.
+    pub line: usize,
+    /// What is wrong with it.
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+/// Parses the format written by `Display`.
+impl core::str::FromStr for SerializableFunction {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Self, ParseError> {
+        let mut f = SerializableFunction {
+            insts: Vec::new(),
+            blocks: Vec::new(),
+            entry_block: Block::new(0),
+            num_vregs: 0,
+            reftype_vregs: Vec::new(),
+            debug_value_labels: Vec::new(),
+            pinned_vregs: Vec::new(),
+            entry_liveins: Vec::new(),
+            spillslot_size: [1, 1],
+            multi_spillslot_named_by_last_slot: false,
+            allow_multiple_vreg_defs: false,
+        };
+        // The instructions, by index, as they are found.
+        let mut insts: Vec<Option<InstData>> = Vec::new();
+        let mut line = 0;
+        for text in text.lines() {
+            line += 1;
+            parse_line(&mut f, &mut insts, text.trim())
+                .map_err(|message| ParseError { line, message })?;
+        }
+
+        let error = |message: String| ParseError { line, message };
+        for (i, inst) in insts.into_iter().enumerate() {
+            f.insts
+                .push(inst.ok_or_else(|| error(format!("inst{} is missing", i)))?);
+        }
+        // Give blocks without instructions an empty range where their
+        // instructions would be, as `FunctionBuilder` does.
+        let mut next = Inst::new(0);
+        for b in &mut f.blocks {
+            if b.first.is_invalid() {
+                b.first = next;
+                b.end = next;
+            }
+            next = b.end;
+        }
+        Ok(f)
+    }
+}
+
+fn parse_line(
+    f: &mut SerializableFunction,
+    insts: &mut Vec<Option<InstData>>,
+    text: &str,
+) -> Result<(), String> {
+    let mut c = Cursor(text);
+    if text.is_empty() {
+        return Ok(());
+    } else if c.eat("entry: ") {
+        f.entry_block = c.block()?;
+    } else if c.eat("vregs: ") {
+        f.num_vregs = c.number()?;
+    } else if c.eat("spillslot sizes: ") {
+        let int = c.number()?;
+        c.expect(" ")?;
+        f.spillslot_size = [int, c.number()?];
+    } else if c.eat("multi spillslot named by last slot") {
+        f.multi_spillslot_named_by_last_slot = true;
+    } else if c.eat("allow multiple vreg defs") {
+        f.allow_multiple_vreg_defs = true;
+    } else if c.eat("livein ") {
+        let vreg = c.vreg()?;
+        c.expect(" in ")?;
+        f.entry_liveins.push((vreg, c.preg()?));
+    } else if c.eat("pinned ") {
+        let vreg = c.vreg()?;
+        c.expect(" to ")?;
+        f.pinned_vregs.push((vreg, c.preg()?));
+    } else if c.eat("ref ") {
+        f.reftype_vregs.push(c.vreg()?);
+    } else if c.eat("label ") {
+        let label = c.number()? as u32;
+        c.expect(" for ")?;
+        let vreg = c.vreg()?;
+        c.expect(" from ")?;
+        let from = c.inst()?;
+        c.expect(" to ")?;
+        f.debug_value_labels.push((vreg, from, c.inst()?, label));
+    } else if c.eat("block") {
+        let index = c.number_until("(")?;
+        if index != f.blocks.len() {
+            return Err(format!("expected block{}", f.blocks.len()));
+        }
+        let params = c.vregs_until(")")?;
+        c.expect(": preds ")?;
+        let preds = c
+            .list()?
+            .into_iter()
+            .map(|p| parse_number(p).map(Block::new))
+            .collect::<Result<_, _>>()?;
+        f.blocks.push(BlockData {
+            first: Inst::invalid(),
+            end: Inst::invalid(),
+            preds,
+            succs: Vec::new(),
+            params,
+            branch_args: Vec::new(),
+            edge_clobbers: Vec::new(),
+            edge_defs: Vec::new(),
+        });
+    } else if c.eat("inst") {
+        let b = f.blocks.last_mut().ok_or("instruction outside a block")?;
+        let inst = Inst::new(c.number_until(": ")?);
+        if b.first.is_invalid() {
+            b.first = inst;
+        } else if inst != b.end {
+            return Err(format!("expected inst{}", b.end.index()));
+        }
+        b.end = inst.next();
+        let kind = c.word()?;
+        c.expect(" ")?;
+        let operands = c.operands()?;
+        let mut data = InstData {
+            is_ret: kind == "ret",
+            is_branch: kind == "branch",
+            is_move: None,
+            clobbers: PRegSet::empty(),
+            is_safepoint: false,
+            commutative: None,
+            operands,
+        };
+        if kind == "move" {
+            if data.operands.len() < 2 {
+                return Err("move has fewer than two operands".into());
+            }
+            data.is_move = Some((data.operands[0], data.operands[1]));
+        } else if !["branch", "ret", "op"].contains(&kind) {
+            return Err(format!("unknown instruction kind {}", kind));
+        }
+        while c.eat(" ") {
+            if c.eat("moves ") {
+                match c.operands()?[..] {
+                    [src, dst] => data.is_move = Some((src, dst)),
+                    _ => return Err("moves must list two operands".into()),
+                }
+            } else if c.eat("clobbers ") {
+                data.clobbers = c.pregs()?;
+            } else if c.eat("safepoint") {
+                data.is_safepoint = true;
+            } else if c.eat("commutative ") {
+                let a = c.number()?;
+                c.expect(" ")?;
+                data.commutative = Some((a, c.number()?));
+            } else {
+                return Err(format!("unexpected {}", c.0));
+            }
+        }
+        if insts.len() <= inst.index() {
+            insts.resize(inst.index() + 1, None);
+        }
+        if insts[inst.index()].replace(data).is_some() {
+            return Err(format!("inst{} appears twice", inst.index()));
+        }
+    } else if c.eat("-> block") {
+        let b = f.blocks.last_mut().ok_or("edge outside a block")?;
+        b.succs.push(Block::new(c.number_until("(")?));
+        b.branch_args.push(c.vregs_until(")")?);
+        let mut clobbers = PRegSet::empty();
+        let mut defs = Vec::new();
+        while c.eat(" ") {
+            if c.eat("clobbers ") {
+                clobbers = c.pregs()?;
+            } else if c.eat("def ") {
+                let vreg = c.vreg()?;
+                c.expect(" in ")?;
+                defs.push((vreg, c.preg()?));
+            } else {
+                return Err(format!("unexpected {}", c.0));
+            }
+        }
+        b.edge_clobbers.push(clobbers);
+        b.edge_defs.push(defs);
+    } else {
+        return Err(format!("unexpected {}", text));
+    }
+    if !c.is_empty() {
+        return Err(format!("unexpected {}", c.0));
+    }
+    Ok(())
+}
+
+/// The unparsed rest of a line.
+struct Cursor<'a>(&'a str);
+
+impl<'a> Cursor<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Skip `prefix` if the rest starts with it.
+    fn eat(&mut self, prefix: &str) -> bool {
+        match self.0.strip_prefix(prefix) {
+            Some(rest) => {
+                self.0 = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, prefix: &str) -> Result<(), String> {
+        if self.eat(prefix) {
+            Ok(())
+        } else {
+            Err(format!("expected {:?} at {}", prefix, self.0))
+        }
+    }
+
+    /// The text up to `delim`, which is skipped.
+    fn until(&mut self, delim: &str) -> Result<&'a str, String> {
+        let (text, rest) = self
+            .0
+            .split_once(delim)
+            .ok_or_else(|| format!("expected {:?} in {}", delim, self.0))?;
+        self.0 = rest;
+        Ok(text)
+    }
+
+    /// The text up to the next space or the end.
+    fn word(&mut self) -> Result<&'a str, String> {
+        let end = self.0.find(' ').unwrap_or(self.0.len());
+        let (word, rest) = self.0.split_at(end);
+        self.0 = rest;
+        if word.is_empty() {
+            return Err("unexpected end of line".into());
+        }
+        Ok(word)
+    }
+
+    /// A bracketed, comma-separated list.
+    fn list(&mut self) -> Result<Vec<&'a str>, String> {
+        self.expect("[")?;
+        let items = self.until("]")?;
+        if items.is_empty() {
+            Ok(Vec::new())
+        } else {
+            Ok(items.split(", ").collect())
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        parse_number(self.word()?)
+    }
+
+    fn number_until(&mut self, delim: &str) -> Result<usize, String> {
+        parse_number(self.until(delim)?)
+    }
+
+    fn block(&mut self) -> Result<Block, String> {
+        let word = self.word()?;
+        let index = word.strip_prefix("block").ok_or("expected a block")?;
+        Ok(Block::new(parse_number(index)?))
+    }
+
+    fn inst(&mut self) -> Result<Inst, String> {
+        let word = self.word()?;
+        let index = word.strip_prefix("inst").ok_or("expected an instruction")?;
+        Ok(Inst::new(parse_number(index)?))
+    }
+
+    fn vreg(&mut self) -> Result<VReg, String> {
+        parse_vreg(self.word()?)
+    }
+
+    fn vregs_until(&mut self, delim: &str) -> Result<Vec<VReg>, String> {
+        let vregs = self.until(delim)?;
+        if vregs.is_empty() {
+            return Ok(Vec::new());
+        }
+        vregs.split(", ").map(parse_vreg).collect()
+    }
+
+    fn preg(&mut self) -> Result<PReg, String> {
+        parse_preg(self.word()?)
+    }
+
+    fn pregs(&mut self) -> Result<PRegSet, String> {
+        let mut set = PRegSet::empty();
+        for preg in self.list()? {
+            set.add(parse_preg(preg)?);
+        }
+        Ok(set)
+    }
+
+    fn operands(&mut self) -> Result<Vec<Operand>, String> {
+        self.list()?.into_iter().map(parse_operand).collect()
+    }
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    text.parse()
+        .map_err(|_| format!("expected a number, not {:?}", text))
+}
+
+/// Parses a register written as `prefix`, index and class, such as
+/// `v3i` or `p0f`.
+fn parse_reg(text: &str, prefix: char, max: usize) -> Result<(usize, RegClass), String> {
+    let rest = text
+        .strip_prefix(prefix)
+        .ok_or_else(|| format!("expected a {}reg, not {:?}", prefix, text))?;
+    let (index, class) = if let Some(index) = rest.strip_suffix('i') {
+        (index, RegClass::Int)
+    } else if let Some(index) = rest.strip_suffix('f') {
+        (index, RegClass::Float)
+    } else {
+        return Err(format!("{} has no class", text));
+    };
+    let index = parse_number(index)?;
+    if index > max {
+        return Err(format!("{} is out of range", text));
+    }
+    Ok((index, class))
+}
+
+fn parse_vreg(text: &str) -> Result<VReg, String> {
+    let (index, class) = parse_reg(text, 'v', VReg::MAX)?;
+    Ok(VReg::new(index, class))
+}
+
+fn parse_preg(text: &str) -> Result<PReg, String> {
+    let (index, class) = parse_reg(text, 'p', PReg::MAX)?;
+    Ok(PReg::new(index, class))
+}
+
+/// Parses the format written by `Operand`'s `Display`, such as
+/// `Def@Early: v1i fixed(p0i)`.
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let mut c = Cursor(text);
+    let kind_and_pos = c.until(": ")?;
+    let (kind, pos) = match kind_and_pos.split_once('@') {
+        Some((kind, pos)) => (kind, Some(pos)),
+        None => (kind_and_pos, None),
+    };
+    let kind = match kind {
+        "Def" => OperandKind::Def,
+        "Mod" => OperandKind::Mod,
+        "Use" => OperandKind::Use,
+        _ => return Err(format!("unknown operand kind {}", kind)),
+    };
+    let pos = match pos {
+        Some("Early") => OperandPos::Early,
+        Some("Late") => OperandPos::Late,
+        Some(pos) => return Err(format!("unknown operand position {}", pos)),
+        None if kind == OperandKind::Def => OperandPos::Late,
+        None => OperandPos::Early,
+    };
+    let vreg = c.vreg()?;
+    c.expect(" ")?;
+    let constraint = c.word()?;
+    let arg = |name: &str| {
+        constraint
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('('))
+            .and_then(|rest| rest.strip_suffix(')'))
+    };
+    let constraint = match constraint {
+        "any" => OperandConstraint::Any,
+        "reg" => OperandConstraint::Reg,
+        "stack" => OperandConstraint::Stack,
+        _ => {
+            if let Some(preg) = arg("fixed") {
+                let preg = parse_preg(preg)?;
+                if preg.class() != vreg.class() {
+                    return Err(format!("{} is fixed to {} of another class", text, preg));
+                }
+                OperandConstraint::FixedReg(preg)
+            } else if let Some(index) = arg("reuse").map(parse_number).transpose()? {
+                if index > 31 {
+                    return Err(format!("{} reuses an operand out of range", text));
+                }
+                OperandConstraint::Reuse(index)
+            } else if let Some(index) = arg("distinct").map(parse_number).transpose()? {
+                if index > 15 {
+                    return Err(format!("{} differs from an operand out of range", text));
+                }
+                OperandConstraint::DistinctFrom(index)
+            } else {
+                return Err(format!("unknown constraint {}", constraint));
+            }
+        }
+    };
+    if !c.is_empty() {
+        return Err(format!("unexpected {}", c.0));
+    }
+    Ok(Operand::new(vreg, constraint, kind, pos))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{float, int};
+    use crate::{FunctionBuilder, OperandConstraint, OperandKind, OperandPos};
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_parse_display() {
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let exit = b.add_block();
+        b.add_entry_livein(int(0), p(0));
+        b.pin_vreg(int(9), p(5));
+        b.add_reftype_vreg(int(2));
+        b.set_spillslot_size(RegClass::Float, 2);
+        b.set_allow_multiple_vreg_defs(true);
+        let call = b.add_inst(
+            entry,
+            &[
+                Operand::reg_fixed_def(int(1), p(1)),
+                Operand::reg_reuse_def(int(2), 2),
+                Operand::reg_use(int(0)),
+                Operand::reg_tied_use(int(0), 2),
+                Operand::fixed_nonallocatable(p(6)),
+                Operand::new(
+                    float(3),
+                    OperandConstraint::Stack,
+                    OperandKind::Def,
+                    OperandPos::Early,
+                ),
+            ],
+        );
+        b.set_clobbers(call, PRegSet::empty().with(p(3)).with(p(4)));
+        b.set_safepoint(call);
+        let add = b.add_inst(
+            entry,
+            &[
+                Operand::reg_distinct_def(int(4), 1),
+                Operand::any_use(int(1)),
+                Operand::reg_use_at_end(int(2)),
+            ],
+        );
+        b.set_commutative_operands(add, 1, 2);
+        b.add_move(entry, int(4), int(5));
+        b.add_debug_value_label(int(5), add, call, 7);
+        b.add_branch(entry, &[Operand::reg_use(int(5))], &[exit]);
+        b.set_branch_args(entry, 0, &[int(5)]);
+        b.set_edge_clobbers(entry, 0, PRegSet::empty().with(p(2)));
+        b.add_edge_def(entry, 0, int(7), p(2));
+        b.add_block_param(exit, int(6));
+        b.add_ret(exit, &[Operand::reg_use(int(6)), Operand::reg_use(int(7))]);
+        let f = b.build();
+
+        let text = f.to_string();
+        let parsed: SerializableFunction = text.parse().unwrap();
+        assert_eq!(parsed.to_string(), text);
+        assert_eq!(parsed.num_vregs(), f.num_vregs());
+        assert_eq!(
+            parsed.block_insns(exit).first(),
+            f.block_insns(exit).first()
+        );
+        assert_eq!(parsed.is_move(Inst::new(2)), f.is_move(Inst::new(2)));
+        assert!(parsed.inst_operands(call)[4].as_fixed_nonallocatable() == Some(p(6)));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let text = "entry: block0\nvregs: 1\nblock0(): preds []\n  inst0: ret [Use: v0 reg]\n";
+        let error = text.parse::<SerializableFunction>().unwrap_err();
+        assert_eq!(error.line, 4);
+        assert_eq!(error.to_string(), "line 4: v0 has no class");
+    }
+}
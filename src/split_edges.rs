@@ -0,0 +1,388 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Allocator-side splitting of critical edges.
+//!
+//! The allocator core requires a CFG with no critical edges, so that
+//! every edge move has a block of its own in which to be placed. When
+//! the client sets `RegallocOptions::allow_critical_edges`, we instead
+//! present the allocator with a view of the function in which each
+//! critical edge passes through an empty "edge block" containing only
+//! a branch. Edge blocks and their branches are numbered after all of
+//! the original blocks and instructions, so every original index stays
+//! valid; whatever edits the allocator places in an edge block are
+//! reported back in `Output::edge_edits`, and the client only needs to
+//! materialize the edge blocks that actually received edits.
+
+use crate::cfg::compute_approx_loop_depth;
+use crate::{
+    Block, Function, Inst, InstRange, Liveness, Operand, Output, PReg, PRegSet, ProgPoint,
+    RegAllocError, RegClass, VReg,
+};
+use alloc::{vec, vec::Vec};
+
+pub(crate) struct SplitEdges<'a, F: Function> {
+    func: &'a F,
+    /// Number of blocks and instructions in the original function.
+    num_orig_blocks: usize,
+    num_orig_insts: usize,
+    /// For each edge block: the original edge, and the index of its
+    /// target among the successors of its source.
+    edges: Vec<(Block, Block, usize)>,
+    /// Successors and predecessors of every block, including the edge
+    /// blocks, with each split edge redirected through its edge block.
+    succs: Vec<Vec<Block>>,
+    preds: Vec<Vec<Block>>,
+}
+
+impl<'a, F: Function> SplitEdges<'a, F> {
+    /// Build the split view of `func`, or return `None` if it has no
+    /// critical edges to split.
+    pub fn new(func: &'a F) -> Result<Option<Self>, RegAllocError> {
+        let num_blocks = func.num_blocks();
+        // The entry block has an implicit predecessor.
+        let num_preds =
+            |block: Block| func.block_preds(block).len() + (block == func.entry_block()) as usize;
+
+        let mut edges = vec![];
+        for block in 0..num_blocks {
+            let block = Block::new(block);
+            let succs = func.block_succs(block);
+            if succs.len() < 2 {
+                continue;
+            }
+            for (i, &succ) in succs.iter().enumerate() {
+                if num_preds(succ) < 2 {
+                    continue;
+                }
+                // Two parallel edges to the same block could not be
+                // told apart in the output, which names edges by
+                // their endpoints.
+                if succs.iter().filter(|&&s| s == succ).count() > 1 {
+                    return Err(RegAllocError::CritEdge(block, succ));
+                }
+                edges.push((block, succ, i));
+            }
+        }
+        if edges.is_empty() {
+            return Ok(None);
+        }
+
+        let mut succs: Vec<Vec<Block>> = (0..num_blocks)
+            .map(|block| func.block_succs(Block::new(block)).to_vec())
+            .collect();
+        let mut preds: Vec<Vec<Block>> = (0..num_blocks)
+            .map(|block| func.block_preds(Block::new(block)).to_vec())
+            .collect();
+        for (i, &(from, to, succ_idx)) in edges.iter().enumerate() {
+            let edge_block = Block::new(num_blocks + i);
+            succs[from.index()][succ_idx] = edge_block;
+            for pred in preds[to.index()].iter_mut() {
+                if *pred == from {
+                    *pred = edge_block;
+                }
+            }
+            succs.push(vec![to]);
+            preds.push(vec![from]);
+        }
+
+        Ok(Some(SplitEdges {
+            func,
+            num_orig_blocks: num_blocks,
+            num_orig_insts: func.num_insts(),
+            edges,
+            succs,
+            preds,
+        }))
+    }
+
+    /// The loop depth of each block. This is computed on the original
+    /// CFG, because the edge blocks, numbered after all other blocks,
+    /// would otherwise look like loop backedges.
+    pub fn approx_loop_depth(&self) -> Vec<u32> {
+        let mut depth = compute_approx_loop_depth(self.func);
+        for &(from, to, _) in &self.edges {
+            let edge_depth = core::cmp::min(depth[from.index()], depth[to.index()]);
+            depth.push(edge_depth);
+        }
+        depth
+    }
+
+    fn edge_block(&self, block: Block) -> Option<(Block, Block, usize)> {
+        block
+            .index()
+            .checked_sub(self.num_orig_blocks)
+            .map(|i| self.edges[i])
+    }
+
+    fn is_edge_inst(&self, inst: Inst) -> bool {
+        inst.index() >= self.num_orig_insts
+    }
+
+    /// Translate the output for the split view back to the original
+    /// function: edits in edge blocks become edge edits, and anything
+    /// else referring to the edge blocks is dropped.
+    pub fn finish_output(&self, output: &mut Output) {
+        let end = ProgPoint::before(Inst::new(self.num_orig_insts));
+        let split_at = output.edits.partition_point(|&(pos, _)| pos < end);
+        output.edge_edits = output
+            .edits
+            .drain(split_at..)
+            .map(|(pos, edit)| {
+                let (from, to, _) = self.edges[pos.inst().index() - self.num_orig_insts];
+                (from, to, edit)
+            })
+            .collect();
+        // Stable sort: edits on each edge stay in order.
+        output.edge_edits.sort_by_key(|&(from, to, _)| (from, to));
+
+        output.inst_alloc_offsets.truncate(self.num_orig_insts);
+        output.debug_locations.retain(|&(_, from, _, _)| from < end);
+        for loc in &mut output.debug_locations {
+            loc.2 = core::cmp::min(loc.2, end);
+        }
+        output.debug_annotations.retain(|&(pos, _)| pos < end);
+        output.vreg_locations.retain(|&(_, from, _, _)| from < end);
+        for loc in &mut output.vreg_locations {
+            loc.2 = core::cmp::min(loc.2, end);
+        }
+    }
+
+    /// Translate liveness computed on the split view back to the
+    /// original function: the edge blocks and their points are
+    /// dropped. Live-outs are recomputed from the original
+    /// successors' live-ins, since the args of a branch on a split
+    /// edge are read in its edge block.
+    pub fn finish_liveness(&self, liveness: &mut Liveness) {
+        let end = ProgPoint::before(Inst::new(self.num_orig_insts));
+        liveness.liveins.truncate(self.num_orig_blocks);
+        liveness.liveouts = (0..self.num_orig_blocks)
+            .map(|block| {
+                let mut vregs: Vec<VReg> = self
+                    .func
+                    .block_succs(Block::new(block))
+                    .iter()
+                    .flat_map(|succ| liveness.liveins[succ.index()].iter().cloned())
+                    .collect();
+                vregs.sort_unstable();
+                vregs.dedup();
+                vregs
+            })
+            .collect();
+        for ranges in &mut liveness.ranges {
+            ranges.retain(|range| range.from < end);
+            if let Some(last) = ranges.last_mut() {
+                last.to = core::cmp::min(last.to, end);
+            }
+        }
+    }
+}
+
+impl<'a, F: Function> Function for SplitEdges<'a, F> {
+    fn num_insts(&self) -> usize {
+        self.num_orig_insts + self.edges.len()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.num_orig_blocks + self.edges.len()
+    }
+
+    fn entry_block(&self) -> Block {
+        self.func.entry_block()
+    }
+
+    fn block_insns(&self, block: Block) -> InstRange {
+        if block.index() < self.num_orig_blocks {
+            self.func.block_insns(block)
+        } else {
+            let inst = Inst::new(self.num_orig_insts + block.index() - self.num_orig_blocks);
+            InstRange::forward(inst, inst.next())
+        }
+    }
+
+    fn block_succs(&self, block: Block) -> &[Block] {
+        &self.succs[block.index()][..]
+    }
+
+    fn block_preds(&self, block: Block) -> &[Block] {
+        &self.preds[block.index()][..]
+    }
+
+    fn block_params(&self, block: Block) -> &[VReg] {
+        if block.index() < self.num_orig_blocks {
+            self.func.block_params(block)
+        } else {
+            &[]
+        }
+    }
+
+    fn is_ret(&self, insn: Inst) -> bool {
+        !self.is_edge_inst(insn) && self.func.is_ret(insn)
+    }
+
+    fn is_branch(&self, insn: Inst) -> bool {
+        self.is_edge_inst(insn) || self.func.is_branch(insn)
+    }
+
+    fn branch_blockparams(&self, block: Block, insn: Inst, succ_idx: usize) -> &[VReg] {
+        if let Some((from, _, from_succ_idx)) = self.edge_block(block) {
+            // The edge block passes on the original branch's args.
+            let from_insn = self.func.block_insns(from).last();
+            self.func.branch_blockparams(from, from_insn, from_succ_idx)
+        } else if self
+            .edge_block(self.succs[block.index()][succ_idx])
+            .is_some()
+        {
+            &[]
+        } else {
+            self.func.branch_blockparams(block, insn, succ_idx)
+        }
+    }
+
+    fn branch_edge_clobbers(&self, block: Block, insn: Inst, succ_idx: usize) -> PRegSet {
+        // Successor indices of original blocks are unchanged, and
+        // the effects of an edge happen before its edge block.
+        if self.edge_block(block).is_some() {
+            PRegSet::empty()
+        } else {
+            self.func.branch_edge_clobbers(block, insn, succ_idx)
+        }
+    }
+
+    fn branch_edge_defs(&self, block: Block, insn: Inst, succ_idx: usize) -> &[(VReg, PReg)] {
+        if self.edge_block(block).is_some() {
+            &[]
+        } else {
+            self.func.branch_edge_defs(block, insn, succ_idx)
+        }
+    }
+
+    fn requires_refs_on_stack(&self, insn: Inst) -> bool {
+        !self.is_edge_inst(insn) && self.func.requires_refs_on_stack(insn)
+    }
+
+    fn is_move(&self, insn: Inst) -> Option<(Operand, Operand)> {
+        if self.is_edge_inst(insn) {
+            None
+        } else {
+            self.func.is_move(insn)
+        }
+    }
+
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        if self.is_edge_inst(insn) {
+            &[]
+        } else {
+            self.func.inst_operands(insn)
+        }
+    }
+
+    fn commutative_operands(&self, insn: Inst) -> Option<(usize, usize)> {
+        if self.is_edge_inst(insn) {
+            None
+        } else {
+            self.func.commutative_operands(insn)
+        }
+    }
+
+    fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+        if self.is_edge_inst(insn) {
+            PRegSet::empty()
+        } else {
+            self.func.inst_clobbers(insn)
+        }
+    }
+
+    fn num_vregs(&self) -> usize {
+        self.func.num_vregs()
+    }
+
+    fn reftype_vregs(&self) -> &[VReg] {
+        self.func.reftype_vregs()
+    }
+
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        self.func.debug_value_labels()
+    }
+
+    fn is_pinned_vreg(&self, vreg: VReg) -> Option<PReg> {
+        self.func.is_pinned_vreg(vreg)
+    }
+
+    fn entry_liveins(&self) -> &[(VReg, PReg)] {
+        self.func.entry_liveins()
+    }
+
+    fn spillslot_size(&self, regclass: RegClass) -> usize {
+        self.func.spillslot_size(regclass)
+    }
+
+    fn multi_spillslot_named_by_last_slot(&self) -> bool {
+        self.func.multi_spillslot_named_by_last_slot()
+    }
+
+    fn allow_multiple_vreg_defs(&self) -> bool {
+        self.func.allow_multiple_vreg_defs()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testutil::{check, int, test_env};
+    use crate::{
+        run, Allocation, Edit, Function, FunctionBuilder, Inst, Operand, PReg, RegAllocError,
+        RegClass, RegallocOptions,
+    };
+
+    #[test]
+    fn test_edge_edits() {
+        // The edge entry -> join is critical. v0 stays in p0 until
+        // the return, so passing it as v2 on that edge is a move,
+        // which lands on the edge rather than in either block.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let join = b.add_block();
+        b.add_inst(entry, &[Operand::reg_fixed_def(int(0), p(0))]);
+        b.add_branch(entry, &[], &[left, join]);
+        b.set_branch_args(entry, 1, &[int(0)]);
+        b.add_inst(left, &[Operand::reg_def(int(1))]);
+        b.add_branch(left, &[], &[join]);
+        b.set_branch_args(left, 0, &[int(1)]);
+        b.add_block_param(join, int(2));
+        b.add_ret(
+            join,
+            &[
+                Operand::reg_fixed_use(int(0), p(0)),
+                Operand::reg_use(int(2)),
+            ],
+        );
+        let f = b.build();
+
+        let env = test_env(4);
+        assert_eq!(
+            run(&f, &env, &RegallocOptions::default()).unwrap_err(),
+            RegAllocError::CritEdge(entry, join)
+        );
+        let options = RegallocOptions {
+            allow_critical_edges: true,
+            ..Default::default()
+        };
+        let out = run(&f, &env, &options).unwrap();
+        check(&f, &env, &out);
+        let (p0, p1) = (Allocation::reg(p(0)), Allocation::reg(p(1)));
+        assert!(out.edits.is_empty());
+        assert_eq!(
+            out.edge_edits,
+            [(entry, join, Edit::Move { from: p0, to: p1 })]
+        );
+        assert!(out.edge_edits(left, join).next().is_none());
+        // Nothing refers to the edge block's branch.
+        assert_eq!(out.inst_alloc_offsets.len(), f.num_insts());
+        let ret = Inst::new(f.num_insts() - 1);
+        assert_eq!(out.inst_allocs(ret), &[p0, p1]);
+    }
+}
@@ -0,0 +1,36 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Fixtures shared by the unit tests.
+
+use crate::checker::Checker;
+use crate::{Function, MachineEnv, Output, PReg, RegClass, VReg};
+
+/// The int vreg `i`.
+pub fn int(i: usize) -> VReg {
+    VReg::new(i, RegClass::Int)
+}
+
+/// The float vreg `i`.
+pub fn float(i: usize) -> VReg {
+    VReg::new(i, RegClass::Float)
+}
+
+/// An environment with `n` preferred registers, numbered from 0, in
+/// each class.
+pub fn test_env(n: usize) -> MachineEnv {
+    let regs = |class| (0..n).map(|i| PReg::new(i, class)).collect();
+    MachineEnv {
+        preferred_regs_by_class: [regs(RegClass::Int), regs(RegClass::Float)],
+        ..Default::default()
+    }
+}
+
+/// Check `out`, an allocation of `f` in `env`, panicking on errors.
+pub fn check<F: Function>(f: &F, env: &MachineEnv, out: &Output) {
+    let mut checker = Checker::new(f, env);
+    checker.prepare(out);
+    checker.run().unwrap();
+}
@@ -0,0 +1,1540 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Standalone validation of allocator input.
+//!
+//! The allocator itself only checks a handful of properties of its
+//! input, reporting them with terse `RegAllocError`s, and otherwise
+//! trusts the embedder to uphold the contracts documented on the
+//! `Function` trait; a violation may surface as a debug assertion or
+//! panic deep inside the allocator. `validate()` checks those
+//! contracts up-front, without allocating, and reports every problem
+//! it finds with a readable explanation. It is meant as a debugging
+//! aid for embedders and is not run by `run()`.
+
+use crate::{domtree, postorder};
+use crate::{
+    input_reused_by, Block, Function, Inst, MachineEnv, OperandConstraint, OperandKind, OperandPos,
+    PReg, PRegSet, RegClass, RegallocOptions, VReg,
+};
+use alloc::{vec, vec::Vec};
+
+/// A set of errors detected by `validate()`.
+#[derive(Clone, Debug)]
+pub struct ValidationErrors {
+    /// All problems found, in the order they were discovered.
+    pub errors: Vec<ValidationError>,
+}
+
+impl core::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ValidationErrors {}
+
+/// A single violation of the allocator's input contract.
+///
+/// Where an instruction is reported for a problem that concerns a
+/// block parameter rather than an instruction operand, `inst` is
+/// `Inst::invalid()`.
+#[derive(Clone, Debug)]
+pub enum ValidationError {
+    /// The function has no blocks.
+    NoBlocks,
+    /// The entry block index is out of range.
+    EntryBlockOutOfRange { entry: Block },
+    /// The entry block has block parameters.
+    EntryBlockParams { entry: Block },
+    /// A block contains no instructions.
+    EmptyBlock { block: Block },
+    /// A block's instruction range is not laid out immediately after
+    /// the previous block's range (or at instruction 0 for the first
+    /// block).
+    BlockRangeNotContiguous {
+        block: Block,
+        expected_first: Inst,
+        first: Inst,
+    },
+    /// A block's instruction range extends past `num_insts()`.
+    BlockRangeOutOfBounds { block: Block, end: Inst },
+    /// Instructions at the end of the function belong to no block.
+    InstsNotInBlock { first: Inst },
+    /// A successor or predecessor index is out of range.
+    EdgeOutOfRange { block: Block, target: Block },
+    /// An edge appears a different number of times in the successor
+    /// list of `from` than in the predecessor list of `to`.
+    PredSuccMismatch {
+        from: Block,
+        to: Block,
+        in_succs: usize,
+        in_preds: usize,
+    },
+    /// An edge from a block with more than one successor to a block
+    /// with more than one predecessor, which the allocator does not
+    /// split: `RegallocOptions::allow_critical_edges` is not set, or
+    /// the source has another edge to the same block.
+    CriticalEdge { from: Block, to: Block },
+    /// The last instruction of a block is neither a branch nor a
+    /// return.
+    BlockNotTerminated { block: Block, inst: Inst },
+    /// A branch or return appears before the end of a block.
+    TerminatorInMiddle { block: Block, inst: Inst },
+    /// A block ending in a return has successors, or a block ending
+    /// in a branch has none.
+    TerminatorSuccMismatch {
+        block: Block,
+        inst: Inst,
+        num_succs: usize,
+    },
+    /// A branch passes a different number of arguments than its
+    /// successor has block parameters.
+    BlockparamArity {
+        inst: Inst,
+        succ: Block,
+        expected: usize,
+        actual: usize,
+    },
+    /// A branch argument and the corresponding block parameter have
+    /// different register classes.
+    BlockparamClassMismatch {
+        inst: Inst,
+        succ: Block,
+        arg: VReg,
+        param: VReg,
+    },
+    /// A branch defines a register even though one of its successors
+    /// has multiple predecessors.
+    DisallowedBranchArg { inst: Inst },
+    /// A branch has edge clobbers or edge defs on an edge to a block
+    /// with multiple predecessors.
+    DisallowedEdgeEffects { inst: Inst, succ: Block },
+    /// A vreg index is not below `num_vregs()`.
+    VRegOutOfRange { inst: Inst, vreg: VReg },
+    /// The same vreg index is used with two different register
+    /// classes.
+    VRegClassMismatch {
+        inst: Inst,
+        vreg: VReg,
+        expected: RegClass,
+    },
+    /// A vreg is defined more than once.
+    MultipleDefs { inst: Inst, vreg: VReg },
+    /// A vreg is used (by an operand or as a branch argument) but
+    /// never defined.
+    UndefinedVReg { inst: Inst, vreg: VReg },
+    /// A use of a vreg in `block` is not dominated by its def in
+    /// `def_block`: either the def is in another block that does not
+    /// dominate `block`, or it follows the use in the same block.
+    UseNotDominatedByDef {
+        block: Block,
+        inst: Inst,
+        vreg: VReg,
+        def_block: Block,
+    },
+    /// A `Reuse` constraint appears on a mod operand.
+    ReuseOnNonDef { inst: Inst, op: usize },
+    /// A `Reuse` constraint names an operand that does not exist.
+    ReuseOutOfRange {
+        inst: Inst,
+        op: usize,
+        reused: usize,
+    },
+    /// A `Reuse` constraint names an operand that is not a use with a
+    /// `Reg`, `Any` or `FixedReg` constraint.
+    ReuseOfInvalidOperand {
+        inst: Inst,
+        op: usize,
+        reused: usize,
+    },
+    /// A `Reuse` constraint ties operands of different classes.
+    ReuseClassMismatch {
+        inst: Inst,
+        op: usize,
+        reused: usize,
+    },
+    /// A use is tied to an operand that is neither an untied use of
+    /// the same vreg at the same point nor a late `Reg` def of the
+    /// same class that no other use is tied to.
+    TiedUseMismatch { inst: Inst, op: usize, tied: usize },
+    /// The commutative operands of an instruction are not two
+    /// distinct early uses of the same class with `Reg` or `Any`
+    /// constraints.
+    InvalidCommutativeOperands { inst: Inst },
+    /// A `DistinctFrom` constraint appears on an operand that is not
+    /// a def.
+    DistinctOnNonDef { inst: Inst, op: usize },
+    /// A `DistinctFrom` constraint names an operand that does not
+    /// exist.
+    DistinctOutOfRange { inst: Inst, op: usize, other: usize },
+    /// A `DistinctFrom` constraint names an operand that is not a use
+    /// of the same class, or that another def reuses.
+    DistinctFromInvalidOperand { inst: Inst, op: usize, other: usize },
+    /// A fixed-register constraint names a register that the
+    /// `MachineEnv` does not know about.
+    FixedRegNotInEnv { inst: Inst, op: usize, preg: PReg },
+    /// A fixed non-allocatable operand names an allocatable register.
+    NonallocatableRegIsAllocatable { inst: Inst, op: usize, preg: PReg },
+    /// A fixed non-allocatable operand names the scratch register.
+    NonallocatableRegIsScratch { inst: Inst, op: usize, preg: PReg },
+    /// A `move` instruction's operands have different classes.
+    MoveClassMismatch { inst: Inst },
+    /// A vreg is pinned to a register of a different class.
+    PinnedVRegClassMismatch { vreg: VReg, preg: PReg },
+    /// An entry livein arrives in a register of a different class.
+    EntryLiveinClassMismatch { vreg: VReg, preg: PReg },
+    /// An entry livein arrives in a register that the `MachineEnv`
+    /// does not know about.
+    EntryLiveinRegNotInEnv { vreg: VReg, preg: PReg },
+    /// An edge def arrives in a register of a different class.
+    EdgeDefClassMismatch { inst: Inst, vreg: VReg, preg: PReg },
+    /// An edge def arrives in a register that the `MachineEnv` does
+    /// not know about.
+    EdgeDefRegNotInEnv { inst: Inst, vreg: VReg, preg: PReg },
+    /// Entry liveins are given, but the entry block has predecessors.
+    EntryLiveinsWithPreds { entry: Block },
+    /// A register appears in the wrong class list of the
+    /// `MachineEnv`.
+    EnvRegClassMismatch { preg: PReg, class: RegClass },
+    /// A register appears more than once in the `MachineEnv`.
+    EnvRegDuplicate { preg: PReg },
+    /// `MachineEnv::reg_costs` lists a register that is not
+    /// allocatable, or lists one more than once.
+    InvalidRegCost { preg: PReg },
+    /// `debug_value_labels()` is not sorted by vreg.
+    DebugValueLabelsUnsorted { index: usize },
+}
+
+impl core::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            Self::NoBlocks => write!(f, "function has no blocks"),
+            Self::EntryBlockOutOfRange { entry } => write!(
+                f,
+                "entry block block{} is not a valid block index",
+                entry.index()
+            ),
+            Self::EntryBlockParams { entry } => write!(
+                f,
+                "entry block block{} has block parameters; their values would be undefined",
+                entry.index()
+            ),
+            Self::EmptyBlock { block } => write!(
+                f,
+                "block{} is empty; every block must end in a branch or return",
+                block.index()
+            ),
+            Self::BlockRangeNotContiguous {
+                block,
+                expected_first,
+                first,
+            } => write!(
+                f,
+                "block{} starts at inst{} but must start at inst{}, immediately after the previous block",
+                block.index(),
+                first.index(),
+                expected_first.index()
+            ),
+            Self::BlockRangeOutOfBounds { block, end } => write!(
+                f,
+                "block{} extends to inst{}, past the end of the function",
+                block.index(),
+                end.index()
+            ),
+            Self::InstsNotInBlock { first } => write!(
+                f,
+                "instructions from inst{} onward do not belong to any block",
+                first.index()
+            ),
+            Self::EdgeOutOfRange { block, target } => write!(
+                f,
+                "block{} has an edge to or from block{}, which is not a valid block index",
+                block.index(),
+                target.index()
+            ),
+            Self::PredSuccMismatch {
+                from,
+                to,
+                in_succs,
+                in_preds,
+            } => write!(
+                f,
+                "edge block{} -> block{} appears {} time(s) in the successors of block{} but {} time(s) in the predecessors of block{}",
+                from.index(),
+                to.index(),
+                in_succs,
+                from.index(),
+                in_preds,
+                to.index()
+            ),
+            Self::CriticalEdge { from, to } => write!(
+                f,
+                "edge block{} -> block{} is critical: the source has multiple successors and the target multiple predecessors; insert an edge block",
+                from.index(),
+                to.index()
+            ),
+            Self::BlockNotTerminated { block, inst } => write!(
+                f,
+                "block{} ends in inst{}, which is neither a branch nor a return",
+                block.index(),
+                inst.index()
+            ),
+            Self::TerminatorInMiddle { block, inst } => write!(
+                f,
+                "inst{} is a branch or return but is not the last instruction of block{}",
+                inst.index(),
+                block.index()
+            ),
+            Self::TerminatorSuccMismatch {
+                block,
+                inst,
+                num_succs,
+            } => write!(
+                f,
+                "block{} ends in inst{}, which does not match its {} successor(s): returns must have none and branches at least one",
+                block.index(),
+                inst.index(),
+                num_succs
+            ),
+            Self::BlockparamArity {
+                inst,
+                succ,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "branch inst{} passes {} argument(s) to block{}, which has {} block parameter(s)",
+                inst.index(),
+                actual,
+                succ.index(),
+                expected
+            ),
+            Self::BlockparamClassMismatch {
+                inst,
+                succ,
+                arg,
+                param,
+            } => write!(
+                f,
+                "branch inst{} passes {} of class {:?} to block{} parameter {} of class {:?}",
+                inst.index(),
+                arg,
+                arg.class(),
+                succ.index(),
+                param,
+                param.class()
+            ),
+            Self::DisallowedBranchArg { inst } => write!(
+                f,
+                "branch inst{} defines a register, but a successor has multiple predecessors so edge moves would be placed before it",
+                inst.index()
+            ),
+            Self::DisallowedEdgeEffects { inst, succ } => write!(
+                f,
+                "branch inst{} has edge clobbers or defs on its edge to block{}, which has multiple predecessors",
+                inst.index(),
+                succ.index()
+            ),
+            Self::VRegOutOfRange { inst, vreg } => write!(
+                f,
+                "{} at inst{} is not below num_vregs()",
+                vreg,
+                inst.index()
+            ),
+            Self::VRegClassMismatch {
+                inst,
+                vreg,
+                expected,
+            } => write!(
+                f,
+                "{} is used with class {:?} at inst{} but with class {:?} elsewhere",
+                vreg,
+                vreg.class(),
+                inst.index(),
+                expected
+            ),
+            Self::MultipleDefs { inst, vreg } => {
+                write!(f, "{} is defined again at inst{}", vreg, inst.index())
+            }
+            Self::UndefinedVReg { inst, vreg } => write!(
+                f,
+                "{} is used at inst{} but never defined",
+                vreg,
+                inst.index()
+            ),
+            Self::UseNotDominatedByDef {
+                block,
+                inst,
+                vreg,
+                def_block,
+            } => write!(
+                f,
+                "{} is used at inst{} in block{}, which its def in block{} does not dominate",
+                vreg,
+                inst.index(),
+                block.index(),
+                def_block.index()
+            ),
+            Self::ReuseOnNonDef { inst, op } => write!(
+                f,
+                "operand {} of inst{} has a reuse constraint but is a mod",
+                op,
+                inst.index()
+            ),
+            Self::ReuseOutOfRange { inst, op, reused } => write!(
+                f,
+                "operand {} of inst{} reuses operand {}, which does not exist",
+                op,
+                inst.index(),
+                reused
+            ),
+            Self::ReuseOfInvalidOperand { inst, op, reused } => write!(
+                f,
+                "operand {} of inst{} reuses operand {}, which is not a use with a Reg, Any or FixedReg constraint",
+                op,
+                inst.index(),
+                reused
+            ),
+            Self::ReuseClassMismatch { inst, op, reused } => write!(
+                f,
+                "operand {} of inst{} reuses operand {}, which has a different class",
+                op,
+                inst.index(),
+                reused
+            ),
+            Self::TiedUseMismatch { inst, op, tied } => write!(
+                f,
+                "operand {} of inst{} is tied to operand {}, which is neither an untied use of the same vreg at the same point nor a late register def of its class that no other use is tied to",
+                op,
+                inst.index(),
+                tied
+            ),
+            Self::InvalidCommutativeOperands { inst } => write!(
+                f,
+                "the commutative operands of inst{} are not two early uses of one class in registers or anywhere",
+                inst.index()
+            ),
+            Self::DistinctOnNonDef { inst, op } => write!(
+                f,
+                "operand {} of inst{} has a distinct constraint but is not a def",
+                op,
+                inst.index()
+            ),
+            Self::DistinctOutOfRange { inst, op, other } => write!(
+                f,
+                "operand {} of inst{} must differ from operand {}, which does not exist",
+                op,
+                inst.index(),
+                other
+            ),
+            Self::DistinctFromInvalidOperand { inst, op, other } => write!(
+                f,
+                "operand {} of inst{} must differ from operand {}, which is not a use of the same class that no def reuses",
+                op,
+                inst.index(),
+                other
+            ),
+            Self::FixedRegNotInEnv { inst, op, preg } => write!(
+                f,
+                "operand {} of inst{} is fixed to {}, which is not in the MachineEnv",
+                op,
+                inst.index(),
+                preg
+            ),
+            Self::NonallocatableRegIsAllocatable { inst, op, preg } => write!(
+                f,
+                "operand {} of inst{} is a fixed non-allocatable use of {}, which is allocatable",
+                op,
+                inst.index(),
+                preg
+            ),
+            Self::NonallocatableRegIsScratch { inst, op, preg } => write!(
+                f,
+                "operand {} of inst{} is a fixed non-allocatable use of {}, which is a scratch register",
+                op,
+                inst.index(),
+                preg
+            ),
+            Self::MoveClassMismatch { inst } => write!(
+                f,
+                "move inst{} has source and destination of different classes",
+                inst.index()
+            ),
+            Self::PinnedVRegClassMismatch { vreg, preg } => write!(
+                f,
+                "{} of class {:?} is pinned to {} of class {:?}",
+                vreg,
+                vreg.class(),
+                preg,
+                preg.class()
+            ),
+            Self::EntryLiveinClassMismatch { vreg, preg } => write!(
+                f,
+                "entry livein {} of class {:?} arrives in {} of class {:?}",
+                vreg,
+                vreg.class(),
+                preg,
+                preg.class()
+            ),
+            Self::EntryLiveinRegNotInEnv { vreg, preg } => write!(
+                f,
+                "entry livein {} arrives in {}, which is not in the MachineEnv",
+                vreg, preg
+            ),
+            Self::EdgeDefClassMismatch { inst, vreg, preg } => write!(
+                f,
+                "edge def {} of class {:?} on branch inst{} arrives in {} of class {:?}",
+                vreg,
+                vreg.class(),
+                inst.index(),
+                preg,
+                preg.class()
+            ),
+            Self::EdgeDefRegNotInEnv { inst, vreg, preg } => write!(
+                f,
+                "edge def {} on branch inst{} arrives in {}, which is not in the MachineEnv",
+                vreg,
+                inst.index(),
+                preg
+            ),
+            Self::EntryLiveinsWithPreds { entry } => write!(
+                f,
+                "entry liveins are given, but entry block block{} has predecessors",
+                entry.index()
+            ),
+            Self::EnvRegClassMismatch { preg, class } => write!(
+                f,
+                "{} is listed among the {:?} registers of the MachineEnv",
+                preg, class
+            ),
+            Self::EnvRegDuplicate { preg } => write!(
+                f,
+                "{} appears more than once in the MachineEnv",
+                preg
+            ),
+            Self::InvalidRegCost { preg } => write!(
+                f,
+                "the MachineEnv gives a cost for {}, which is not an allocatable register or already has one",
+                preg
+            ),
+            Self::DebugValueLabelsUnsorted { index } => write!(
+                f,
+                "debug_value_labels() entry {} is out of order; entries must be sorted by vreg",
+                index
+            ),
+        }
+    }
+}
+
+/// Check that `func` and `env` satisfy every documented contract of
+/// the allocator's input when run with `options`, without running
+/// allocation. Returns all problems found.
+pub fn validate<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<(), ValidationErrors> {
+    let mut v = Validator {
+        func,
+        env,
+        options,
+        errors: vec![],
+    };
+    v.check_env();
+    if v.check_layout() {
+        let edges_ok = v.check_cfg();
+        v.check_operands();
+        if edges_ok {
+            v.check_dominance();
+        }
+    }
+    if v.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationErrors { errors: v.errors })
+    }
+}
+
+struct Validator<'a, F: Function> {
+    func: &'a F,
+    env: &'a MachineEnv,
+    options: &'a RegallocOptions,
+    errors: Vec<ValidationError>,
+}
+
+impl<'a, F: Function> Validator<'a, F> {
+    fn allocatable_regs(&self) -> Vec<(RegClass, PReg)> {
+        let mut regs = vec![];
+        for class in [RegClass::Int, RegClass::Float] {
+            let env = self.env;
+            for &preg in env.preferred_regs_by_class[class as usize]
+                .iter()
+                .chain(env.non_preferred_regs_by_class[class as usize].iter())
+            {
+                regs.push((class, preg));
+            }
+        }
+        regs
+    }
+
+    fn scratch_regs(&self) -> Vec<(RegClass, PReg)> {
+        let mut regs = vec![];
+        for class in [RegClass::Int, RegClass::Float] {
+            if let Some(preg) = self.env.scratch_by_class[class as usize] {
+                regs.push((class, preg));
+            }
+        }
+        regs
+    }
+
+    fn check_env(&mut self) {
+        let mut seen = PRegSet::empty();
+        let all = self
+            .allocatable_regs()
+            .into_iter()
+            .map(|(class, preg)| (Some(class), preg))
+            .chain(self.env.fixed_stack_slots.iter().map(|&preg| (None, preg)))
+            .chain(
+                self.scratch_regs()
+                    .into_iter()
+                    .map(|(class, preg)| (Some(class), preg)),
+            )
+            .collect::<Vec<_>>();
+        for (class, preg) in all {
+            if let Some(class) = class {
+                if preg.class() != class {
+                    self.errors
+                        .push(ValidationError::EnvRegClassMismatch { preg, class });
+                }
+            }
+            if seen.contains(preg) {
+                self.errors.push(ValidationError::EnvRegDuplicate { preg });
+            }
+            seen.add(preg);
+        }
+
+        let mut allocatable = PRegSet::empty();
+        for (_, preg) in self.allocatable_regs() {
+            allocatable.add(preg);
+        }
+        let mut costed = PRegSet::empty();
+        for &(preg, _) in &self.env.reg_costs {
+            if !allocatable.contains(preg) || costed.contains(preg) {
+                self.errors.push(ValidationError::InvalidRegCost { preg });
+            }
+            costed.add(preg);
+        }
+    }
+
+    /// Check the block layout. Returns `false` if it is too broken to
+    /// safely examine anything else.
+    fn check_layout(&mut self) -> bool {
+        let num_blocks = self.func.num_blocks();
+        let num_insts = self.func.num_insts();
+        if num_blocks == 0 {
+            self.errors.push(ValidationError::NoBlocks);
+            return false;
+        }
+        let entry = self.func.entry_block();
+        if entry.index() >= num_blocks {
+            self.errors
+                .push(ValidationError::EntryBlockOutOfRange { entry });
+            return false;
+        }
+
+        let mut ok = true;
+        let mut next = 0;
+        for block in 0..num_blocks {
+            let block = Block::new(block);
+            let range = self.func.block_insns(block);
+            if range.len() == 0 {
+                self.errors.push(ValidationError::EmptyBlock { block });
+                ok = false;
+                continue;
+            }
+            let (first, last) = (range.first(), range.last());
+            let (start, end) = (
+                core::cmp::min(first.index(), last.index()),
+                core::cmp::max(first.index(), last.index()) + 1,
+            );
+            if start != next {
+                self.errors.push(ValidationError::BlockRangeNotContiguous {
+                    block,
+                    expected_first: Inst::new(next),
+                    first: Inst::new(start),
+                });
+                ok = false;
+            }
+            if end > num_insts {
+                self.errors.push(ValidationError::BlockRangeOutOfBounds {
+                    block,
+                    end: Inst::new(end),
+                });
+                ok = false;
+            }
+            next = end;
+
+            for &target in self
+                .func
+                .block_succs(block)
+                .iter()
+                .chain(self.func.block_preds(block).iter())
+            {
+                if target.index() >= num_blocks {
+                    self.errors
+                        .push(ValidationError::EdgeOutOfRange { block, target });
+                    ok = false;
+                }
+            }
+        }
+        if ok && next < num_insts {
+            self.errors.push(ValidationError::InstsNotInBlock {
+                first: Inst::new(next),
+            });
+        }
+        ok
+    }
+
+    /// Check the CFG. Returns `false` if the predecessor and successor
+    /// lists disagree, so that no dominator tree can be computed.
+    fn check_cfg(&mut self) -> bool {
+        let func = self.func;
+        let mut edges_ok = true;
+        let entry = func.entry_block();
+        if !func.block_params(entry).is_empty() {
+            self.errors
+                .push(ValidationError::EntryBlockParams { entry });
+        }
+        if !func.entry_liveins().is_empty() && !func.block_preds(entry).is_empty() {
+            self.errors
+                .push(ValidationError::EntryLiveinsWithPreds { entry });
+        }
+
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            let succs = func.block_succs(block);
+
+            // Every edge must appear as often in the successor list
+            // of its source as in the predecessor list of its target.
+            for (i, &succ) in succs.iter().enumerate() {
+                if succs[..i].contains(&succ) {
+                    continue;
+                }
+                let in_succs = succs.iter().filter(|&&b| b == succ).count();
+                let in_preds = func
+                    .block_preds(succ)
+                    .iter()
+                    .filter(|&&b| b == block)
+                    .count();
+                if in_succs != in_preds {
+                    edges_ok = false;
+                    self.errors.push(ValidationError::PredSuccMismatch {
+                        from: block,
+                        to: succ,
+                        in_succs,
+                        in_preds,
+                    });
+                }
+            }
+            for &pred in func.block_preds(block) {
+                if !func.block_succs(pred).contains(&block) {
+                    edges_ok = false;
+                    self.errors.push(ValidationError::PredSuccMismatch {
+                        from: pred,
+                        to: block,
+                        in_succs: 0,
+                        in_preds: func
+                            .block_preds(block)
+                            .iter()
+                            .filter(|&&b| b == pred)
+                            .count(),
+                    });
+                }
+            }
+
+            // The entry block has an implicit predecessor. Edge moves
+            // on an edge to a block with other predecessors go before
+            // the branch, unless the allocator splits the edge.
+            let num_preds = |b: Block| func.block_preds(b).len() + (b == entry) as usize;
+            let allow_critical_edges = self.options.allow_critical_edges;
+            let split = |succ: Block| {
+                allow_critical_edges
+                    && succs.len() > 1
+                    && succs.iter().filter(|&&s| s == succ).count() == 1
+            };
+            let moves_before_branch = |succ: Block| num_preds(succ) > 1 && !split(succ);
+            if succs.len() > 1 {
+                for &succ in succs {
+                    if moves_before_branch(succ) {
+                        self.errors.push(ValidationError::CriticalEdge {
+                            from: block,
+                            to: succ,
+                        });
+                    }
+                }
+            }
+
+            let insns = func.block_insns(block);
+            for inst in insns.iter() {
+                let is_term = func.is_branch(inst) || func.is_ret(inst);
+                if inst != insns.last() && is_term {
+                    self.errors
+                        .push(ValidationError::TerminatorInMiddle { block, inst });
+                }
+            }
+            let last = insns.last();
+            if func.is_branch(last) {
+                if succs.is_empty() {
+                    self.errors.push(ValidationError::TerminatorSuccMismatch {
+                        block,
+                        inst: last,
+                        num_succs: 0,
+                    });
+                }
+                for (i, &succ) in succs.iter().enumerate() {
+                    if moves_before_branch(succ)
+                        && (func.branch_edge_clobbers(block, last, i) != PRegSet::empty()
+                            || !func.branch_edge_defs(block, last, i).is_empty())
+                    {
+                        self.errors
+                            .push(ValidationError::DisallowedEdgeEffects { inst: last, succ });
+                    }
+                    let params = func.block_params(succ);
+                    let args = func.branch_blockparams(block, last, i);
+                    if params.len() != args.len() {
+                        self.errors.push(ValidationError::BlockparamArity {
+                            inst: last,
+                            succ,
+                            expected: params.len(),
+                            actual: args.len(),
+                        });
+                        continue;
+                    }
+                    for (&arg, &param) in args.iter().zip(params.iter()) {
+                        if arg.class() != param.class() {
+                            self.errors.push(ValidationError::BlockparamClassMismatch {
+                                inst: last,
+                                succ,
+                                arg,
+                                param,
+                            });
+                        }
+                    }
+                }
+                if func
+                    .inst_operands(last)
+                    .iter()
+                    .any(|op| op.kind() != OperandKind::Use)
+                    && succs.iter().any(|&succ| moves_before_branch(succ))
+                {
+                    self.errors
+                        .push(ValidationError::DisallowedBranchArg { inst: last });
+                }
+            } else if func.is_ret(last) {
+                if !succs.is_empty() {
+                    self.errors.push(ValidationError::TerminatorSuccMismatch {
+                        block,
+                        inst: last,
+                        num_succs: succs.len(),
+                    });
+                }
+            } else {
+                self.errors
+                    .push(ValidationError::BlockNotTerminated { block, inst: last });
+            }
+        }
+        edges_ok
+    }
+
+    fn check_operands(&mut self) {
+        let func = self.func;
+        let num_vregs = func.num_vregs();
+        let mut classes: Vec<Option<RegClass>> = vec![None; num_vregs];
+        let mut defined = vec![false; num_vregs];
+
+        let mut allocatable = PRegSet::empty();
+        for (_, preg) in self.allocatable_regs() {
+            allocatable.add(preg);
+        }
+        let mut known = allocatable;
+        for &preg in &self.env.fixed_stack_slots {
+            known.add(preg);
+        }
+        let mut scratch = PRegSet::empty();
+        for (_, preg) in self.scratch_regs() {
+            scratch.add(preg);
+        }
+
+        // Records a mention of `vreg`, returning whether it is in
+        // range.
+        let mut mention = |errors: &mut Vec<ValidationError>, inst: Inst, vreg: VReg| -> bool {
+            if vreg.vreg() >= num_vregs {
+                errors.push(ValidationError::VRegOutOfRange { inst, vreg });
+                return false;
+            }
+            match classes[vreg.vreg()] {
+                Some(expected) if expected != vreg.class() => {
+                    errors.push(ValidationError::VRegClassMismatch {
+                        inst,
+                        vreg,
+                        expected,
+                    });
+                }
+                Some(_) => {}
+                None => classes[vreg.vreg()] = Some(vreg.class()),
+            }
+            true
+        };
+
+        for &(vreg, preg) in func.entry_liveins() {
+            if mention(&mut self.errors, Inst::invalid(), vreg) {
+                if defined[vreg.vreg()] {
+                    self.errors.push(ValidationError::MultipleDefs {
+                        inst: Inst::invalid(),
+                        vreg,
+                    });
+                }
+                defined[vreg.vreg()] = true;
+            }
+            if preg.class() != vreg.class() {
+                self.errors
+                    .push(ValidationError::EntryLiveinClassMismatch { vreg, preg });
+            }
+            if !known.contains(preg) {
+                self.errors
+                    .push(ValidationError::EntryLiveinRegNotInEnv { vreg, preg });
+            }
+        }
+
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            for &param in func.block_params(block) {
+                if mention(&mut self.errors, Inst::invalid(), param) {
+                    if defined[param.vreg()] {
+                        self.errors.push(ValidationError::MultipleDefs {
+                            inst: Inst::invalid(),
+                            vreg: param,
+                        });
+                    }
+                    defined[param.vreg()] = true;
+                }
+            }
+            let last = func.block_insns(block).last();
+            if func.is_branch(last) {
+                for i in 0..func.block_succs(block).len() {
+                    for &arg in func.branch_blockparams(block, last, i) {
+                        mention(&mut self.errors, last, arg);
+                    }
+                    for &(vreg, preg) in func.branch_edge_defs(block, last, i) {
+                        if mention(&mut self.errors, last, vreg) {
+                            if defined[vreg.vreg()] {
+                                self.errors
+                                    .push(ValidationError::MultipleDefs { inst: last, vreg });
+                            }
+                            defined[vreg.vreg()] = true;
+                        }
+                        if preg.class() != vreg.class() {
+                            self.errors.push(ValidationError::EdgeDefClassMismatch {
+                                inst: last,
+                                vreg,
+                                preg,
+                            });
+                        }
+                        if !known.contains(preg) {
+                            self.errors.push(ValidationError::EdgeDefRegNotInEnv {
+                                inst: last,
+                                vreg,
+                                preg,
+                            });
+                        }
+                    }
+                }
+            }
+
+            for inst in func.block_insns(block).iter() {
+                let operands = func.inst_operands(inst);
+                let mut defs_here: Vec<VReg> = vec![];
+                for (i, op) in operands.iter().enumerate() {
+                    if let Some(preg) = op.as_fixed_nonallocatable() {
+                        if allocatable.contains(preg) {
+                            self.errors
+                                .push(ValidationError::NonallocatableRegIsAllocatable {
+                                    inst,
+                                    op: i,
+                                    preg,
+                                });
+                        } else if scratch.contains(preg) {
+                            self.errors
+                                .push(ValidationError::NonallocatableRegIsScratch {
+                                    inst,
+                                    op: i,
+                                    preg,
+                                });
+                        }
+                        continue;
+                    }
+
+                    let vreg = op.vreg();
+                    if !mention(&mut self.errors, inst, vreg) {
+                        continue;
+                    }
+                    if op.kind() == OperandKind::Def {
+                        let repeated = defs_here.contains(&vreg);
+                        if defined[vreg.vreg()] && !(repeated && func.allow_multiple_vreg_defs()) {
+                            self.errors
+                                .push(ValidationError::MultipleDefs { inst, vreg });
+                        }
+                        defined[vreg.vreg()] = true;
+                        defs_here.push(vreg);
+                    }
+
+                    match op.constraint() {
+                        OperandConstraint::FixedReg(preg) => {
+                            if !known.contains(preg) {
+                                self.errors.push(ValidationError::FixedRegNotInEnv {
+                                    inst,
+                                    op: i,
+                                    preg,
+                                });
+                            }
+                        }
+                        OperandConstraint::Reuse(reused) => {
+                            if op.kind() == OperandKind::Mod {
+                                self.errors
+                                    .push(ValidationError::ReuseOnNonDef { inst, op: i });
+                            } else if reused >= operands.len() {
+                                self.errors.push(ValidationError::ReuseOutOfRange {
+                                    inst,
+                                    op: i,
+                                    reused,
+                                });
+                            } else if op.kind() == OperandKind::Use {
+                                let tied = operands[reused];
+                                let valid_tie = match tied.kind() {
+                                    // Both are read from one register,
+                                    // which holds one value.
+                                    OperandKind::Use => {
+                                        tied.as_fixed_nonallocatable().is_none()
+                                            && tied.as_tied_use().is_none()
+                                            && tied.vreg() == op.vreg()
+                                            && tied.pos() == op.pos()
+                                    }
+                                    // The def reuses this use.
+                                    OperandKind::Def => {
+                                        tied.constraint() == OperandConstraint::Reg
+                                            && tied.pos() == OperandPos::Late
+                                            && op.pos() == OperandPos::Early
+                                            && tied.class() == op.class()
+                                            && input_reused_by(operands, reused) == Some(i)
+                                    }
+                                    OperandKind::Mod => false,
+                                };
+                                if !valid_tie {
+                                    self.errors.push(ValidationError::TiedUseMismatch {
+                                        inst,
+                                        op: i,
+                                        tied: reused,
+                                    });
+                                }
+                            } else {
+                                let input = operands[reused];
+                                let valid_input = input.kind() == OperandKind::Use
+                                    && input.as_fixed_nonallocatable().is_none()
+                                    && match input.constraint() {
+                                        OperandConstraint::Reg
+                                        | OperandConstraint::Any
+                                        | OperandConstraint::FixedReg(_) => true,
+                                        OperandConstraint::Stack
+                                        | OperandConstraint::Reuse(_)
+                                        | OperandConstraint::DistinctFrom(_) => false,
+                                    };
+                                if !valid_input {
+                                    self.errors.push(ValidationError::ReuseOfInvalidOperand {
+                                        inst,
+                                        op: i,
+                                        reused,
+                                    });
+                                } else if input.class() != op.class() {
+                                    self.errors.push(ValidationError::ReuseClassMismatch {
+                                        inst,
+                                        op: i,
+                                        reused,
+                                    });
+                                }
+                            }
+                        }
+                        OperandConstraint::DistinctFrom(other) => {
+                            if op.kind() != OperandKind::Def {
+                                self.errors
+                                    .push(ValidationError::DistinctOnNonDef { inst, op: i });
+                            } else if other >= operands.len() {
+                                self.errors.push(ValidationError::DistinctOutOfRange {
+                                    inst,
+                                    op: i,
+                                    other,
+                                });
+                            } else {
+                                let input = operands[other];
+                                let reused = (0..operands.len())
+                                    .any(|def| input_reused_by(operands, def) == Some(other));
+                                if input.kind() != OperandKind::Use
+                                    || input.as_fixed_nonallocatable().is_some()
+                                    || input.class() != op.class()
+                                    || reused
+                                {
+                                    self.errors
+                                        .push(ValidationError::DistinctFromInvalidOperand {
+                                            inst,
+                                            op: i,
+                                            other,
+                                        });
+                                }
+                            }
+                        }
+                        OperandConstraint::Any
+                        | OperandConstraint::Reg
+                        | OperandConstraint::Stack => {}
+                    }
+                }
+
+                if let Some((a, b)) = func.commutative_operands(inst) {
+                    let plain = |i: usize| {
+                        matches!(operands.get(i), Some(op) if op.kind() == OperandKind::Use
+                        && op.pos() == OperandPos::Early
+                        && matches!(
+                            op.constraint(),
+                            OperandConstraint::Reg | OperandConstraint::Any
+                        ))
+                    };
+                    if a == b
+                        || !plain(a)
+                        || !plain(b)
+                        || operands[a].class() != operands[b].class()
+                    {
+                        self.errors
+                            .push(ValidationError::InvalidCommutativeOperands { inst });
+                    }
+                }
+
+                if let Some((src, dst)) = func.is_move(inst) {
+                    if src.class() != dst.class() {
+                        self.errors
+                            .push(ValidationError::MoveClassMismatch { inst });
+                    }
+                }
+            }
+        }
+
+        for (vreg, class) in classes.into_iter().enumerate() {
+            if let Some(class) = class {
+                let vreg = VReg::new(vreg, class);
+                if let Some(preg) = func.is_pinned_vreg(vreg) {
+                    if preg.class() != class {
+                        self.errors
+                            .push(ValidationError::PinnedVRegClassMismatch { vreg, preg });
+                    }
+                }
+            }
+        }
+
+        let labels = func.debug_value_labels();
+        for i in 1..labels.len() {
+            if labels[i].0.vreg() < labels[i - 1].0.vreg() {
+                self.errors
+                    .push(ValidationError::DebugValueLabelsUnsorted { index: i });
+            }
+        }
+    }
+
+    /// Check that every use of a vreg is dominated by its def. Ported
+    /// from `fuzzing::ssa::validate_ssa`, extended to entry liveins,
+    /// edge defs and branch arguments. Pinned vregs need no def.
+    fn check_dominance(&mut self) {
+        let func = self.func;
+        let num_vregs = func.num_vregs();
+        let postorder = postorder::calculate(func.num_blocks(), func.entry_block(), |block| {
+            func.block_succs(block)
+        });
+        let idom = domtree::calculate(
+            func.num_blocks(),
+            |block| func.block_preds(block),
+            &postorder[..],
+            func.entry_block(),
+        );
+
+        // The defs visible at the start of each block, and the block
+        // defining each vreg. Out-of-range vregs and repeated defs
+        // have already been reported by `check_operands()`.
+        let mut entry_defs: Vec<Vec<VReg>> = vec![vec![]; func.num_blocks()];
+        let mut defined_in = vec![Block::invalid(); num_vregs];
+        let mut def = |block: Block, vreg: VReg| {
+            if vreg.vreg() < num_vregs && defined_in[vreg.vreg()].is_invalid() {
+                defined_in[vreg.vreg()] = block;
+            }
+        };
+        let entry = func.entry_block();
+        for &(vreg, _) in func.entry_liveins() {
+            def(entry, vreg);
+            entry_defs[entry.index()].push(vreg);
+        }
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            for &param in func.block_params(block) {
+                def(block, param);
+                entry_defs[block.index()].push(param);
+            }
+            for inst in func.block_insns(block).iter() {
+                for op in func.inst_operands(inst) {
+                    if op.kind() == OperandKind::Def && op.as_fixed_nonallocatable().is_none() {
+                        def(block, op.vreg());
+                    }
+                }
+            }
+            let last = func.block_insns(block).last();
+            if func.is_branch(last) {
+                for (i, &succ) in func.block_succs(block).iter().enumerate() {
+                    for &(vreg, _) in func.branch_edge_defs(block, last, i) {
+                        def(succ, vreg);
+                        entry_defs[succ.index()].push(vreg);
+                    }
+                }
+            }
+        }
+
+        // Walk the blocks in order. Every use must be preceded by its
+        // def in the same block, or be defined in a block that
+        // dominates this one.
+        let mut local = vec![false; num_vregs];
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            local.iter_mut().for_each(|l| *l = false);
+            for vreg in &entry_defs[block.index()] {
+                if vreg.vreg() < num_vregs {
+                    local[vreg.vreg()] = true;
+                }
+            }
+
+            let check_use =
+                |errors: &mut Vec<ValidationError>, local: &[bool], inst, vreg: VReg| {
+                    if vreg.vreg() >= num_vregs || func.is_pinned_vreg(vreg).is_some() {
+                        return;
+                    }
+                    let def_block = defined_in[vreg.vreg()];
+                    if def_block.is_invalid() {
+                        errors.push(ValidationError::UndefinedVReg { inst, vreg });
+                    } else if !(if def_block == block {
+                        local[vreg.vreg()]
+                    } else {
+                        domtree::dominates(&idom[..], def_block, block)
+                    }) {
+                        errors.push(ValidationError::UseNotDominatedByDef {
+                            block,
+                            inst,
+                            vreg,
+                            def_block,
+                        });
+                    }
+                };
+
+            for inst in func.block_insns(block).iter() {
+                let operands = func.inst_operands(inst);
+                for op in operands {
+                    if op.kind() != OperandKind::Def && op.as_fixed_nonallocatable().is_none() {
+                        check_use(&mut self.errors, &local, inst, op.vreg());
+                    }
+                }
+                // An instruction cannot use a vreg that it defines,
+                // so record its defs only after checking its uses.
+                for op in operands {
+                    if op.kind() == OperandKind::Def
+                        && op.as_fixed_nonallocatable().is_none()
+                        && op.vreg().vreg() < num_vregs
+                    {
+                        local[op.vreg().vreg()] = true;
+                    }
+                }
+            }
+
+            let last = func.block_insns(block).last();
+            if func.is_branch(last) {
+                for i in 0..func.block_succs(block).len() {
+                    for &arg in func.branch_blockparams(block, last, i) {
+                        check_use(&mut self.errors, &local, last, arg);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{int, test_env};
+    use crate::{FunctionBuilder, Operand};
+    use alloc::string::ToString;
+
+    fn errors<F: Function>(f: &F) -> Vec<ValidationError> {
+        errors_with(f, &RegallocOptions::default())
+    }
+
+    fn errors_with<F: Function>(f: &F, options: &RegallocOptions) -> Vec<ValidationError> {
+        match validate(f, &test_env(4), options) {
+            Ok(()) => vec![],
+            Err(e) => e.errors,
+        }
+    }
+
+    #[test]
+    fn test_undefined_use() {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        b.add_inst(entry, &[Operand::reg_def(int(1)), Operand::reg_use(int(0))]);
+        b.add_ret(entry, &[Operand::reg_use(int(1))]);
+        let errors = errors(&b.build());
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            ValidationError::UndefinedVReg { inst, vreg } if inst.index() == 0 && vreg == int(0)
+        ));
+        assert_eq!(
+            errors[0].to_string(),
+            "v0 is used at inst0 but never defined"
+        );
+    }
+
+    #[test]
+    fn test_def_not_dominating() {
+        // v0 is defined on the left arm of a diamond only, and used in
+        // the join block.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let right = b.add_block();
+        let join = b.add_block();
+        b.add_branch(entry, &[], &[left, right]);
+        b.add_inst(left, &[Operand::reg_def(int(0))]);
+        b.add_branch(left, &[], &[join]);
+        b.add_branch(right, &[], &[join]);
+        let ret = b.add_ret(join, &[Operand::reg_use(int(0))]);
+        let errors = errors(&b.build());
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            ValidationError::UseNotDominatedByDef { block, inst, vreg, def_block }
+                if block == join && inst == ret && vreg == int(0) && def_block == left
+        ));
+    }
+
+    #[test]
+    fn test_use_before_def() {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        b.add_inst(entry, &[Operand::reg_def(int(1)), Operand::reg_use(int(0))]);
+        b.add_inst(entry, &[Operand::reg_def(int(0))]);
+        b.add_ret(entry, &[Operand::reg_use(int(1))]);
+        let errors = errors(&b.build());
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            ValidationError::UseNotDominatedByDef { block, def_block, .. }
+                if block == entry && def_block == entry
+        ));
+    }
+
+    #[test]
+    fn test_branch_arg_and_edge_def() {
+        // A branch argument defined on the other arm is reported; an
+        // edge def is visible in its target block.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let right = b.add_block();
+        let join = b.add_block();
+        b.add_branch(entry, &[], &[left, right]);
+        b.add_edge_def(entry, 0, int(0), PReg::new(0, RegClass::Int));
+        b.add_inst(left, &[Operand::reg_def(int(1)), Operand::reg_use(int(0))]);
+        b.add_branch(left, &[], &[join]);
+        b.set_branch_args(left, 0, &[int(1)]);
+        let branch = b.add_branch(right, &[], &[join]);
+        b.set_branch_args(right, 0, &[int(1)]);
+        b.add_block_param(join, int(2));
+        b.add_ret(join, &[Operand::reg_use(int(2))]);
+        let errors = errors(&b.build());
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            ValidationError::UseNotDominatedByDef { block, inst, vreg, .. }
+                if block == right && inst == branch && vreg == int(1)
+        ));
+    }
+
+    #[test]
+    fn test_operand_errors() {
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        b.add_inst(entry, &[Operand::reg_def(int(0))]);
+        b.add_inst(entry, &[Operand::reg_def(int(0))]);
+        b.add_inst(
+            entry,
+            &[
+                Operand::reg_reuse_def(int(1), 1),
+                Operand::new(
+                    int(0),
+                    OperandConstraint::Stack,
+                    OperandKind::Use,
+                    OperandPos::Early,
+                ),
+            ],
+        );
+        b.add_ret(entry, &[Operand::reg_use(int(1))]);
+        let errors = errors(&b.build());
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0],
+            ValidationError::MultipleDefs { inst, vreg } if inst.index() == 1 && vreg == int(0)
+        ));
+        assert!(matches!(
+            errors[1],
+            ValidationError::ReuseOfInvalidOperand {
+                op: 0,
+                reused: 1,
+                ..
+            }
+        ));
+        assert_eq!(
+            errors[1].to_string(),
+            "operand 0 of inst2 reuses operand 1, which is not a use with a Reg, Any or FixedReg constraint"
+        );
+    }
+
+    #[test]
+    fn test_tied_use_errors() {
+        // v1 may be tied to the def of v2, but only once, and not to
+        // the use of v0, a different vreg.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        b.add_inst(entry, &[Operand::reg_def(int(0))]);
+        b.add_inst(entry, &[Operand::reg_def(int(1))]);
+        b.add_inst(
+            entry,
+            &[
+                Operand::reg_def(int(2)),
+                Operand::reg_tied_use(int(1), 0),
+                Operand::reg_use(int(0)),
+                Operand::reg_tied_use(int(1), 2),
+                Operand::reg_tied_use(int(0), 0),
+            ],
+        );
+        b.add_ret(entry, &[Operand::reg_use(int(2))]);
+        let errors = errors(&b.build());
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0],
+            ValidationError::TiedUseMismatch { op: 3, tied: 2, .. }
+        ));
+        assert!(matches!(
+            errors[1],
+            ValidationError::TiedUseMismatch { op: 4, tied: 0, .. }
+        ));
+        assert_eq!(
+            errors[0].to_string(),
+            "operand 3 of inst2 is tied to operand 2, which is neither an untied use of the same vreg at the same point nor a late register def of its class that no other use is tied to"
+        );
+    }
+
+    #[test]
+    fn test_cfg_errors() {
+        // The edge entry -> join is critical, and the branch to join
+        // passes no argument for its parameter.
+        let mut b = FunctionBuilder::new();
+        let entry = b.add_block();
+        let left = b.add_block();
+        let join = b.add_block();
+        b.add_entry_livein(int(0), PReg::new(0, RegClass::Int));
+        let branch = b.add_branch(entry, &[], &[left, join]);
+        b.set_branch_args(entry, 1, &[int(0)]);
+        b.add_branch(left, &[], &[join]);
+        b.add_block_param(join, int(1));
+        b.add_ret(join, &[Operand::reg_use(int(1))]);
+        let f = b.build();
+        let errors = errors(&f);
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0],
+            ValidationError::CriticalEdge { from, to } if from == entry && to == join
+        ));
+        assert!(matches!(
+            errors[1],
+            ValidationError::BlockparamArity { inst, succ, expected: 1, actual: 0 }
+                if inst != branch && succ == join
+        ));
+
+        // The allocator splits the critical edge if allowed to.
+        let options = RegallocOptions {
+            allow_critical_edges: true,
+            ..Default::default()
+        };
+        let errors = errors_with(&f, &options);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], ValidationError::BlockparamArity { .. }));
+    }
+
+    #[test]
+    fn test_split_edges() {
+        // With critical edges allowed, the edge entry -> join may
+        // carry edge effects and the branch may define a vreg, as its
+        // edge moves go into an edge block. A second, parallel edge
+        // can't be split.
+        let build = |parallel: bool| {
+            let mut b = FunctionBuilder::new();
+            let entry = b.add_block();
+            let left = b.add_block();
+            let join = b.add_block();
+            b.add_entry_livein(int(0), PReg::new(0, RegClass::Int));
+            let succs: &[Block] = if parallel {
+                &[left, join, join]
+            } else {
+                &[left, join]
+            };
+            b.add_branch(entry, &[Operand::reg_def(int(1))], succs);
+            b.set_edge_clobbers(entry, 1, PRegSet::empty().with(PReg::new(1, RegClass::Int)));
+            b.add_branch(left, &[], &[join]);
+            b.add_ret(join, &[Operand::reg_use(int(0))]);
+            b.build()
+        };
+        let options = RegallocOptions {
+            allow_critical_edges: true,
+            ..Default::default()
+        };
+
+        let f = build(false);
+        assert_eq!(errors(&f).len(), 3);
+        assert!(errors_with(&f, &options).is_empty());
+        let env = test_env(4);
+        let out = crate::run(&f, &env, &options).unwrap();
+        crate::testutil::check(&f, &env, &out);
+
+        let errors = errors_with(&build(true), &options);
+        assert!(errors
+            .iter()
+            .any(|e| matches!(e, ValidationError::CriticalEdge { .. })));
+    }
+}